@@ -1,9 +1,17 @@
-use anyhow::Result;
-use clap::Parser;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use rustyline::Editor;
 use std::io::{stdout, Write};
+use std::time::Duration;
+
+/// Backoff before the first retry; doubled after each failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Minimum retries when re-establishing a connection that dropped mid-session.
+const RECONNECT_RETRIES: u32 = 3;
 
 #[derive(Parser, Debug)]
 #[command(name = "wundradb-cli")]
@@ -15,6 +23,39 @@ struct Args {
     /// Port to connect to
     #[arg(short, long, default_value_t = 3306)]
     port: u16,
+
+    /// Seconds to wait for each connection attempt
+    #[arg(long, default_value_t = 5)]
+    connect_timeout: u64,
+
+    /// Connection attempts to retry, with backoff, before giving up
+    #[arg(long, default_value_t = 0)]
+    connect_retries: u32,
+
+    /// Response encoding to ask the server for when connecting
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Tab-separated rows followed by a status line
+    Text,
+    /// One JSON object per response
+    Json,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    timeout: Duration,
+    retries: u32,
+    initial_backoff: Duration,
+}
+
+struct Connection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    format: Format,
 }
 
 #[tokio::main]
@@ -22,10 +63,18 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     let addr = format!("{}:{}", args.host, args.port);
 
+    let policy = RetryPolicy {
+        timeout: Duration::from_secs(args.connect_timeout),
+        retries: args.connect_retries,
+        initial_backoff: INITIAL_BACKOFF,
+    };
+
     println!("Connecting to WundraDB at {}...", addr);
-    let stream = TcpStream::connect(&addr).await?;
-    let (reader, mut writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
+    let mut conn = connect(&addr, policy, args.format).await?;
+
+    // Prepared statements live on the server, so keep the name unique to
+    // this client
+    let statement_name = format!("cli_{}", std::process::id());
 
     let mut rl = Editor::<(), _>::new()?;
     loop {
@@ -34,31 +83,297 @@ async fn main() -> Result<()> {
             Ok(line) => {
                 let trimmed = line.trim();
                 if trimmed.eq_ignore_ascii_case("exit") || trimmed.eq_ignore_ascii_case("quit") {
-                    writer.write_all(b"exit\n").await?;
+                    let _ = conn.writer.write_all(b"exit\n").await;
                     break;
                 }
 
-                writer.write_all(trimmed.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-
-                // Wait for response
-                while let Ok(Some(line)) = lines.next_line().await {
-                    if line.trim_start().starts_with("Query OK") || line.trim_start().starts_with("Error") {
-                        print!("{}", line); // use print! for inline prompt
-                        stdout().flush().unwrap(); // ✅ force it to appear immediately
-                        break;
-                    } else {
-                        println!("{}", line); // for normal output
-                    }
+                // A dropped connection is re-established rather than ending
+                // the session. The statement in flight is not resent, since
+                // it may already have been applied.
+                if let Err(e) = run_line(&mut conn, &mut rl, &statement_name, trimmed).await {
+                    println!("Connection lost ({}); reconnecting to {}...", e, addr);
+                    let reconnect = RetryPolicy { retries: policy.retries.max(RECONNECT_RETRIES), ..policy };
+                    conn = connect(&addr, reconnect, args.format).await?;
+                    println!("Reconnected. The last statement may not have run; session state was reset.");
                 }
             }
             Err(_) => {
                 println!("Exiting...");
-                writer.write_all(b"exit\n").await?;
+                let _ = conn.writer.write_all(b"exit\n").await;
                 break;
             }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Connects, retrying failed or timed-out attempts with exponential backoff,
+/// then asks the server for `format`.
+async fn connect(addr: &str, policy: RetryPolicy, format: Format) -> Result<Connection> {
+    let mut attempt = 0;
+    loop {
+        let error = match tokio::time::timeout(policy.timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                let (reader, writer) = stream.into_split();
+                let mut conn = Connection { lines: BufReader::new(reader).lines(), writer, format };
+                negotiate(&mut conn).await?;
+                return Ok(conn);
+            }
+            Ok(Err(e)) => anyhow!(e),
+            Err(_) => anyhow!("timed out after {:?}", policy.timeout),
+        };
+
+        if attempt >= policy.retries {
+            return Err(anyhow!("Could not connect to {}: {}", addr, error));
+        }
+        let delay = backoff_delay(policy.initial_backoff, attempt);
+        println!("Connection to {} failed ({}), retrying in {:?}...", addr, error, delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Sends the `HELLO` choosing the connection's encoding. Text is what the
+/// server speaks without one, so it is not asked for.
+async fn negotiate(conn: &mut Connection) -> Result<()> {
+    if conn.format == Format::Text {
+        return Ok(());
+    }
+    send(&mut conn.writer, "HELLO encoding=json").await?;
+    let response = read_response(&mut conn.lines, conn.format).await?;
+    if is_error(&response, conn.format) {
+        return Err(anyhow!("Server refused JSON encoding: {}", response.join("\n")));
+    }
+    Ok(())
+}
+
+fn backoff_delay(initial: Duration, attempt: u32) -> Duration {
+    initial.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_BACKOFF)
+}
+
+/// Sends one input line and prints the response. Errors mean the
+/// connection is no longer usable.
+async fn run_line(
+    conn: &mut Connection,
+    rl: &mut Editor<(), rustyline::history::DefaultHistory>,
+    statement_name: &str,
+    line: &str,
+) -> Result<()> {
+    let (sql, names) = bind_named_placeholders(line);
+    if names.is_empty() {
+        send(&mut conn.writer, line).await?;
+        return print_response(conn).await;
+    }
+
+    // Prompt once per distinct placeholder, then run it as a prepared
+    // statement so values are never spliced into SQL
+    let mut values = Vec::new();
+    for name in &names {
+        match rl.readline(&format!(":{} = ", name)) {
+            Ok(value) => values.push(sql_literal(value.trim())),
+            Err(_) => break,
+        }
+    }
+    if values.len() != names.len() {
+        println!("Cancelled");
+        return Ok(());
+    }
+
+    send(&mut conn.writer, &format!("PREPARE {} AS {}", statement_name, sql)).await?;
+    let response = read_response(&mut conn.lines, conn.format).await?;
+    if is_error(&response, conn.format) {
+        print_lines(&response, conn.format);
+        return Ok(());
+    }
+
+    send(&mut conn.writer, &format!("EXECUTE {} ({})", statement_name, values.join(", "))).await?;
+    print_response(conn).await
+}
+
+/// Sends one statement. The server reads until a `;`, so each input line
+/// is terminated unless the user already did.
+async fn send(writer: &mut OwnedWriteHalf, sql: &str) -> Result<()> {
+    writer.write_all(sql.as_bytes()).await?;
+    if !sql.trim_end().ends_with(';') {
+        writer.write_all(b";").await?;
+    }
+    writer.write_all(b"\n").await?;
+    Ok(())
+}
+
+/// Reads one response: in text, up to a "Query OK" or "Error" line; in
+/// JSON, a single line. The server closing the stream first is an error.
+async fn read_response(lines: &mut Lines<BufReader<OwnedReadHalf>>, format: Format) -> Result<Vec<String>> {
+    let mut response = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        let done = format == Format::Json
+            || line.trim_start().starts_with("Query OK")
+            || line.trim_start().starts_with("Error");
+        response.push(line);
+        if done {
+            return Ok(response);
+        }
+    }
+    Err(anyhow!("server closed the connection"))
+}
+
+fn is_error(response: &[String], format: Format) -> bool {
+    match format {
+        Format::Text => response.last().is_some_and(|line| line.trim_start().starts_with("Error")),
+        Format::Json => response.last().is_some_and(|line| line.starts_with("{\"error\"")),
+    }
+}
+
+async fn print_response(conn: &mut Connection) -> Result<()> {
+    let response = read_response(&mut conn.lines, conn.format).await?;
+    print_lines(&response, conn.format);
+    Ok(())
+}
+
+fn print_lines(response: &[String], format: Format) {
+    if format == Format::Json {
+        for line in response {
+            println!("{}", line);
+        }
+        return;
+    }
+    if let Some((last, body)) = response.split_last() {
+        for line in body {
+            println!("{}", line); // for normal output
+        }
+        print!("{}", last); // use print! for inline prompt
+        stdout().flush().unwrap(); // ✅ force it to appear immediately
+    }
+}
+
+/// Rewrites `:name` placeholders to numbered `$n` parameters, returning the
+/// rewritten statement and the distinct names in parameter order. A name
+/// used more than once maps to the same parameter. Text inside quotes and
+/// `::` casts are left alone.
+fn bind_named_placeholders(sql: &str) -> (String, Vec<String>) {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut names: Vec<String> = Vec::new();
+    let mut output = String::with_capacity(sql.len());
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if let Some(q) = quote {
+            output.push(c);
+            if c == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+
+        let starts_name = chars.get(i + 1).is_some_and(|n| n.is_ascii_alphabetic() || *n == '_');
+        let is_cast = i > 0 && chars[i - 1] == ':';
+        if c == ':' && starts_name && !is_cast {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|n| !(n.is_ascii_alphanumeric() || *n == '_'))
+                .map_or(chars.len(), |p| i + 1 + p);
+            let name: String = chars[i + 1..end].iter().collect();
+            let index = match names.iter().position(|n| *n == name) {
+                Some(index) => index,
+                None => {
+                    names.push(name);
+                    names.len() - 1
+                }
+            };
+            output.push_str(&format!("${}", index + 1));
+            i = end;
+            continue;
+        }
+
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+        }
+        output.push(c);
+        i += 1;
+    }
+
+    (output, names)
+}
+
+/// Renders a value typed at a placeholder prompt as a SQL literal. Numbers,
+/// booleans and NULL keep their type; anything else (or input wrapped in
+/// single quotes) becomes an escaped string.
+fn sql_literal(input: &str) -> String {
+    if input.eq_ignore_ascii_case("null") {
+        return "NULL".to_string();
+    }
+    if input.eq_ignore_ascii_case("true") || input.eq_ignore_ascii_case("false") {
+        return input.to_uppercase();
+    }
+    if input.parse::<i64>().is_ok() || input.parse::<f64>().is_ok_and(f64::is_finite) {
+        return input.to_string();
+    }
+
+    let text = input
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .unwrap_or(input);
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_named_placeholders() {
+        let (sql, names) = bind_named_placeholders(
+            "SELECT * FROM users WHERE id = :id AND name = :name OR parent = :id",
+        );
+        assert_eq!(sql, "SELECT * FROM users WHERE id = $1 AND name = $2 OR parent = $1");
+        assert_eq!(names, vec!["id", "name"]);
+
+        let (sql, names) = bind_named_placeholders("SELECT ':id', x::int FROM t WHERE y = :y");
+        assert_eq!(sql, "SELECT ':id', x::int FROM t WHERE y = $1");
+        assert_eq!(names, vec!["y"]);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let initial = Duration::from_millis(100);
+        assert_eq!(backoff_delay(initial, 0), Duration::from_millis(100));
+        assert_eq!(backoff_delay(initial, 3), Duration::from_millis(800));
+        assert_eq!(backoff_delay(initial, 40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_until_server_starts() {
+        // Reserve a port, then free it so the first attempts are refused
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+
+        let policy = RetryPolicy {
+            timeout: Duration::from_secs(1),
+            retries: 10,
+            initial_backoff: Duration::from_millis(20),
+        };
+        connect(&addr.to_string(), policy, Format::Text).await.unwrap();
+        server.await.unwrap();
+
+        let no_retries = RetryPolicy { retries: 0, ..policy };
+        assert!(connect(&addr.to_string(), no_retries, Format::Text).await.is_err());
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        assert_eq!(sql_literal("42"), "42");
+        assert_eq!(sql_literal("-1.5"), "-1.5");
+        assert_eq!(sql_literal("true"), "TRUE");
+        assert_eq!(sql_literal("null"), "NULL");
+        assert_eq!(sql_literal("O'Brien"), "'O''Brien'");
+        assert_eq!(sql_literal("'42'"), "'42'");
+    }
+}
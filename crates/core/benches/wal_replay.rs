@@ -0,0 +1,43 @@
+//! Replay time and file reads for a log of many small entries, through
+//! the 8 KiB buffer replay used to read with and through the default one.
+//! Run with `cargo bench -p wundradb-core --bench wal_replay --features bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use wundradb_core::txn::wal::{self, WalEntry, WalOperation};
+use wundradb_core::WriteAheadLog;
+
+const ENTRIES: u64 = 100_000;
+
+fn replay(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("replay.wal");
+    let path = path.to_str().unwrap();
+    let entries: Vec<WalEntry> =
+        (0..ENTRIES).map(|i| WalEntry { seq: i + 1, ..WalEntry::new(WalOperation::Begin(i)) }).collect();
+    runtime.block_on(WriteAheadLog::write_log(path, &entries)).unwrap();
+
+    let mut group = c.benchmark_group("wal_replay");
+    group.sample_size(20);
+    for (name, buffer_size) in [("8 KiB buffer", Some(8 * 1024)), ("default buffer", None)] {
+        let replay_once = || {
+            runtime.block_on(async {
+                let mut wal = WriteAheadLog::new(path).await.unwrap();
+                if let Some(bytes) = buffer_size {
+                    wal.set_replay_buffer_size(bytes);
+                }
+                assert_eq!(wal.replay().await.unwrap().len(), ENTRIES as usize);
+            })
+        };
+        let reads = wal::file_reads();
+        replay_once();
+        println!("{}: {} reads of the file per replay of {} entries", name, wal::file_reads() - reads, ENTRIES);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(replay_once));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, replay);
+criterion_main!(benches);
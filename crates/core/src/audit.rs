@@ -0,0 +1,173 @@
+use anyhow::Result;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// The active file is rotated once appending would grow it past this size.
+    pub max_bytes: u64,
+    /// Replace literal values in recorded statements with `?`.
+    pub redact_values: bool,
+}
+
+/// One executed statement.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub client: String,
+    pub statement: String,
+    pub success: bool,
+    pub rows: u64,
+}
+
+/// Append-only trail of executed statements, kept separate from the WAL.
+///
+/// Each record is one tab-separated line:
+/// `timestamp  client  ok|error  rows  statement`. When the file reaches
+/// `max_bytes` it is renamed to `<path>.1`, `<path>.2`, ... (oldest first)
+/// and a fresh file is started.
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    size: u64,
+    rotations: u32,
+}
+
+impl AuditLog {
+    pub async fn open(config: AuditConfig) -> Result<Self> {
+        let size = match tokio::fs::metadata(&config.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+
+        let mut rotations = 0;
+        while tokio::fs::metadata(rotated_path(&config.path, rotations + 1)).await.is_ok() {
+            rotations += 1;
+        }
+
+        Ok(Self { config, size, rotations })
+    }
+
+    pub async fn record(&mut self, record: &AuditRecord) -> Result<()> {
+        let statement = if self.config.redact_values {
+            redact_values(&record.statement)
+        } else {
+            record.statement.clone()
+        };
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            record.timestamp.to_rfc3339(),
+            record.client,
+            if record.success { "ok" } else { "error" },
+            record.rows,
+            escape(&statement),
+        );
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate().await?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        self.rotations += 1;
+        tokio::fs::rename(&self.config.path, rotated_path(&self.config.path, self.rotations)).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &std::path::Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Keeps each record on a single line.
+fn escape(statement: &str) -> String {
+    statement
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+}
+
+/// Replaces string and numeric literals with `?`, leaving the rest of the
+/// statement untouched. Statements that fail to tokenize are redacted
+/// entirely rather than risk leaking values.
+pub fn redact_values(statement: &str) -> String {
+    let tokens = match Tokenizer::new(&GenericDialect {}, statement).tokenize() {
+        Ok(tokens) => tokens,
+        Err(_) => return "<redacted>".to_string(),
+    };
+    tokens
+        .into_iter()
+        .map(|token| match token {
+            Token::Number(..)
+            | Token::SingleQuotedString(_)
+            | Token::DoubleQuotedString(_)
+            | Token::NationalStringLiteral(_)
+            | Token::EscapedStringLiteral(_)
+            | Token::HexStringLiteral(_) => "?".to_string(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(statement: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp: chrono::Utc::now(),
+            client: "127.0.0.1:5555".to_string(),
+            statement: statement.to_string(),
+            success: true,
+            rows: 1,
+        }
+    }
+
+    #[test]
+    fn test_redact_values() {
+        assert_eq!(
+            redact_values("INSERT INTO users (id, name) VALUES (1, 'O''Brien')"),
+            "INSERT INTO users (id, name) VALUES (?, ?)"
+        );
+        assert_eq!(redact_values("SELECT * FROM users"), "SELECT * FROM users");
+    }
+
+    #[tokio::test]
+    async fn test_rotates_by_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("audit.log");
+        let config = AuditConfig { path: path.clone(), max_bytes: 100, redact_values: false };
+        let mut log = AuditLog::open(config.clone()).await.unwrap();
+
+        for i in 0..3 {
+            log.record(&record(&format!("SELECT {}", i))).await.unwrap();
+        }
+
+        let first = std::fs::read_to_string(rotated_path(&path, 1)).unwrap();
+        assert!(first.contains("SELECT 0"));
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.contains("SELECT 2"));
+
+        // Reopening continues numbering after the existing rotated files
+        let reopened = AuditLog::open(config).await.unwrap();
+        assert_eq!(reopened.rotations, log.rotations);
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the current time, for code whose behaviour depends on it
+/// (election timeouts, WAL entry timestamps). Production uses
+/// `SystemClock`; tests inject a `MockClock` and move it by hand.
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time, for measuring intervals.
+    fn now(&self) -> Instant;
+    /// Wall-clock time, for timestamps that are stored or shown.
+    fn utc_now(&self) -> DateTime<Utc>;
+}
+
+/// A clock shared by everything that reads it.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until `advance` moves it. Both readings
+/// move together, starting from the real time it was created at.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { start: Instant::now(), start_utc: Utc::now(), elapsed: Mutex::new(Duration::ZERO) })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Time advanced since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        self.start_utc + chrono::Duration::from_std(self.elapsed()).expect("mock clock advanced too far")
+    }
+}
@@ -1,71 +1,632 @@
+pub mod audit;
+pub mod clock;
 pub mod sql;
 pub mod storage;
 pub mod txn;
 pub mod raft;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use sql::catalog::Catalog;
+use sql::engine::SqlValue;
+use sql::ident::IdentifierCase;
+use chrono::{DateTime, Utc};
+use sqlparser::ast::{Ident, ObjectName};
+use storage::codec::RowFormat;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-pub use sql::engine::SqlEngine;
+pub use sql::engine::{ConsistencyReport, ExecutionResult, SqlEngine};
+pub use sql::output::{Output, ResultSet};
+pub use sql::prepared::PreparedStatement;
+pub use sql::row::{FromSqlValue, Row, RowBuilder};
+pub use sql::session::{
+    Cancellation, LimitMode, ResultLimit, ScaleOverflow, Session, StatementTimeout, StringOverflow,
+};
 pub use storage::bptree::BPlusTree;
-pub use txn::wal::WriteAheadLog;
+pub use txn::wal::{WalRetention, WalVerification, WriteAheadLog};
 
 pub type DatabaseRef = Arc<RwLock<Database>>;
 
+/// The database and its SQL engine share the same storage and WAL handles,
+/// so there is a single source of truth for both.
 #[derive(Debug)]
 pub struct Database {
     pub engine: SqlEngine,
-    pub storage: BPlusTree,
-    pub wal: WriteAheadLog,
+    pub storage: Arc<RwLock<BPlusTree>>,
+    pub wal: Arc<RwLock<WriteAheadLog>>,
+    data_dir: String,
+    /// Storage mutations after which a statement triggers a checkpoint; 0
+    /// leaves checkpoints to `CHECKPOINT`.
+    checkpoint_threshold: usize,
+    /// WAL history checkpoints keep; by default they keep none.
+    wal_retention: WalRetention,
+    /// Encoding for new rows, replayed ones included.
+    row_format: RowFormat,
 }
 
 impl Database {
     pub async fn new(data_dir: &str) -> Result<Self> {
+        Self::with_row_format(data_dir, RowFormat::default()).await
+    }
+
+    /// Opens the database in `data_dir`, writing new rows in `row_format`.
+    /// Rows replayed from the WAL are written in it too; rows already
+    /// stored are read in whichever format they were written.
+    pub async fn with_row_format(data_dir: &str, row_format: RowFormat) -> Result<Self> {
+        Self::open(data_dir, row_format, IdentifierCase::default()).await
+    }
+
+    /// Opens the database in `data_dir` as `with_row_format` does, with
+    /// names in statements folded by `identifier_case`. Names created
+    /// before names were folded are folded too, unless it preserves case.
+    pub async fn open(data_dir: &str, row_format: RowFormat, identifier_case: IdentifierCase) -> Result<Self> {
         std::fs::create_dir_all(data_dir)?;
         
         let wal_path = format!("{}/wal.log", data_dir);
         let storage_path = format!("{}/storage.db", data_dir);
         
         let mut wal = WriteAheadLog::new(&wal_path).await?;
-        let mut storage = BPlusTree::new();
+
+        // Make the directory and the WAL file just created in it durable
+        storage::fsync::sync_parent_dir(data_dir)?;
+        storage::fsync::sync_dir(data_dir)?;
         
-        // Replay WAL entries to restore state
-        let entries = wal.replay().await?;
-        for entry in entries {
-            if let Err(e) = storage.apply_wal_entry(&entry) {
-                tracing::warn!("Failed to apply WAL entry: {}", e);
-            }
-        }
+        // Load the last snapshot, then replay the WAL entries it doesn't
+        // cover. A snapshot that exists but can't be loaded stops startup
+        // rather than being dropped for whatever the WAL still holds.
+        let mut storage = load_snapshot(&storage_path)
+            .map_err(|e| anyhow!("Cannot load snapshot {}: {}", storage_path, e))?;
         
-        // Try to load existing storage snapshot
-        if let Err(e) = storage.load_from_disk(&storage_path) {
-            tracing::info!("No existing storage found, starting fresh: {}", e);
+        let entries = wal.replay().await?;
+        if wal.has_name_keyed_entries() {
+            storage.expect_name_prefixed_keys();
         }
+        let unfolded = wal.unfolded_names().clone();
+        let mut catalog = Catalog::new();
+        replay_entries(&mut storage, &mut catalog, &entries, &mut RepairReport::default(), row_format);
+        wal.advance_seq_past(storage.applied_seq());
         
-        let engine = SqlEngine::new(storage.clone(), wal.clone());
-        
-        Ok(Database {
+        let storage = Arc::new(RwLock::new(storage));
+        let wal = Arc::new(RwLock::new(wal));
+        let mut engine = SqlEngine::with_shared(storage.clone(), wal.clone());
+        engine.set_row_format(row_format);
+        engine.set_identifier_case(identifier_case);
+        engine.install_catalog(catalog).await;
+
+        let db = Database {
             engine,
             storage,
             wal,
-        })
+            data_dir: data_dir.to_string(),
+            checkpoint_threshold: storage::bptree::DEFAULT_CHECKPOINT_THRESHOLD,
+            wal_retention: WalRetention::default(),
+            row_format,
+        };
+
+        // Snapshots and WALs from before table ids are re-keyed now that the
+        // catalog is known, and checkpointed so the WAL's name-keyed rows
+        // are gone. Names created before names were folded are folded
+        // first, so the checkpoint covers their rewritten rows too; under
+        // `Preserve` they are used as written, so they keep their case.
+        let tables = db.engine.table_ids().await;
+        let migrated = db.storage.write().await.migrate_to_table_ids(&tables)?;
+        if identifier_case != IdentifierCase::Preserve {
+            db.engine.fold_names(&unfolded).await?;
+        }
+        if migrated > 0 {
+            db.checkpoint().await?;
+        }
+        db.engine.seed_sequences().await?;
+        Ok(db)
     }
     
+    pub fn set_checkpoint_threshold(&mut self, threshold: usize) {
+        self.checkpoint_threshold = threshold;
+    }
+
+    /// Sets how much WAL history checkpoints keep for `restore_to`.
+    pub fn set_wal_retention(&mut self, retention: WalRetention) {
+        self.wal_retention = retention;
+    }
+
+    /// Sets the size past which the WAL starts a new segment file.
+    pub async fn set_wal_segment_bytes(&self, bytes: u64) {
+        self.wal.write().await.set_max_segment_bytes(bytes);
+    }
+
+    /// Executes statements in a fresh session, rolling back a transaction
+    /// they leave open, since no later statement can end it.
     pub async fn execute_sql(&mut self, sql: &str) -> Result<String> {
-        self.engine.execute(sql).await
+        let mut session = Session::new();
+        let result = self.execute_sql_in(&mut session, sql).await;
+        self.engine.close_session(&session).await;
+        result
+    }
+
+    /// Executes a statement within a connection's session, so `USE` carries
+    /// over to its later statements.
+    pub async fn execute_sql_in(&mut self, session: &mut Session, sql: &str) -> Result<String> {
+        Ok(sql::output::render(&self.execute_output_in(session, sql).await?))
+    }
+
+    /// Like `execute_sql_in`, returning each statement's output unrendered,
+    /// for clients that frame result sets themselves.
+    pub async fn execute_output_in(&mut self, session: &mut Session, sql: &str) -> Result<Vec<Output>> {
+        let message = match AdminCommand::parse(sql) {
+            Some(AdminCommand::ShowWalSize) => {
+                let wal = self.wal.read().await;
+                let bytes = wal.size_bytes().await?;
+                let columns = vec!["bytes".to_string(), "entries".to_string()];
+                let row = vec![SqlValue::Integer(bytes as i64), SqlValue::Integer(wal.entry_count() as i64)];
+                return Ok(vec![Output::Rows(ResultSet { compact: true, ..ResultSet::new(columns, vec![row]) })]);
+            }
+            Some(AdminCommand::Checkpoint) => {
+                let report = self.checkpoint().await?;
+                format!(
+                    "Checkpoint complete: reclaimed {} bytes of WAL ({} entries)",
+                    report.reclaimed_bytes, report.removed_entries
+                )
+            }
+            Some(AdminCommand::CheckTable(name)) => {
+                let set = self.engine.check_table(session, &name, CHECK_TABLE_MAX_PROBLEMS).await?;
+                return Ok(vec![Output::Rows(set)]);
+            }
+            Some(AdminCommand::DumpSchema) => self.engine.dump_schema().await?,
+            Some(AdminCommand::ShowTreeStructure) => {
+                if !session.is_admin() {
+                    return Err(anyhow!("SHOW TREE STRUCTURE requires an admin session"));
+                }
+                self.storage.read().await.render_structure()
+            }
+            None => {
+                let result = self.engine.execute_output_in(session, sql).await;
+                let due = self.checkpoint_threshold > 0
+                    && self.storage.read().await.should_checkpoint(self.checkpoint_threshold);
+                if due {
+                    // The statement already succeeded and is in the WAL; a
+                    // failed checkpoint is retried after the next one
+                    if let Err(e) = self.checkpoint().await {
+                        tracing::warn!("Automatic checkpoint failed: {}", e);
+                    }
+                }
+                return result;
+            }
+        };
+        Ok(vec![Output::Message(message)])
+    }
+
+    /// Writes a snapshot of the storage, then drops the WAL entries it
+    /// covers. Both locks are held throughout so no write lands between the
+    /// snapshot and the compaction.
+    ///
+    /// With a retention window set, only the WAL segments outside it are
+    /// dropped, and the base snapshot, `base.db`, is moved forward over
+    /// them: it holds the state just before the oldest history kept, for
+    /// `restore_to` to replay that history onto, and `base.time` the time
+    /// of the last entry it took in. The first such checkpoint starts the
+    /// base from the previous snapshot, since the WAL holds everything
+    /// after that.
+    pub async fn checkpoint(&self) -> Result<CheckpointReport> {
+        let mut wal = self.wal.write().await;
+        let mut storage = self.storage.write().await;
+
+        let entries_before = wal.entry_count();
+        let storage_path = format!("{}/storage.db", self.data_dir);
+        let base_path = format!("{}/base.db", self.data_dir);
+        wal.sync().await?;
+        if self.wal_retention.is_enabled() && !Path::new(&base_path).exists() {
+            let previous = load_snapshot(&storage_path)?;
+            // With nothing logged since, the previous snapshot is as of now
+            let time = (previous.applied_seq() >= wal.last_seq()).then(|| wal.utc_now());
+            save_base(&self.data_dir, &previous, time)?;
+        }
+        storage.save_to_disk(&storage_path)?;
+        let reclaimed_bytes = if self.wal_retention.is_enabled() {
+            match wal.expired_segments(&self.wal_retention).await? {
+                Some(expired) => {
+                    // Moved forward before the segments go, so a crash
+                    // between leaves history the base already covers
+                    let mut base = load_snapshot(&base_path)?;
+                    // The expired segments start with the first, which keeps
+                    // every earlier catalog entry
+                    let mut report = RepairReport::default();
+                    replay_entries(&mut base, &mut Catalog::new(), &expired.entries, &mut report, self.row_format);
+                    save_base(&self.data_dir, &base, expired.entries.last().map(|last| last.timestamp))?;
+                    wal.remove_expired(&expired).await?
+                }
+                None => 0,
+            }
+        } else {
+            let reclaimed_bytes = wal.compact().await?;
+            // The history a base snapshot is restored with is gone. Its time
+            // goes first, so a crash between leaves no time for a later base
+            let base_time_path = format!("{}/base.time", self.data_dir);
+            if Path::new(&base_time_path).exists() {
+                std::fs::remove_file(&base_time_path)?;
+            }
+            if Path::new(&base_path).exists() {
+                std::fs::remove_file(&base_path)?;
+            }
+            reclaimed_bytes
+        };
+        storage.reset_operation_count();
+
+        let report = CheckpointReport {
+            reclaimed_bytes,
+            removed_entries: entries_before - wal.entry_count(),
+        };
+        tracing::info!("Checkpoint completed: {:?}", report);
+        Ok(report)
     }
     
+    /// Reads a storage key directly, bypassing SQL and schemas.
+    #[cfg(any(test, feature = "raw-access"))]
+    pub async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.storage.read().await.get(key)
+    }
+
+    /// Writes a storage key directly, bypassing SQL and schemas. The write
+    /// is not logged to the WAL, so it only survives a restart once a
+    /// checkpoint has captured it.
+    #[cfg(any(test, feature = "raw-access"))]
+    pub async fn put_raw(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.storage.write().await.insert(key.to_string(), value)
+    }
+
+    /// Returns every storage entry whose key starts with `prefix`, in key
+    /// order.
+    #[cfg(any(test, feature = "raw-access"))]
+    pub async fn scan_raw(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        self.storage.read().await.for_each_prefix(prefix, |key, value| {
+            entries.push((key.to_string(), value.to_vec()));
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Checks the WAL in `data_dir` entry by entry without opening the
+    /// database or applying anything, for diagnosing one that won't start.
+    pub async fn verify_wal(data_dir: &str) -> Result<WalVerification> {
+        WriteAheadLog::verify(&format!("{}/wal.log", data_dir)).await
+    }
+
+    /// Reconciles the snapshot and WAL in `data_dir` after a crash left them
+    /// disagreeing. Entries the snapshot already contains are skipped, newer
+    /// ones are replayed, and the result is verified and written back as the
+    /// new snapshot. A snapshot that fails verification is discarded and
+    /// the tree is rebuilt from the WAL alone. Replayed rows are written in
+    /// `row_format`.
+    pub async fn repair(data_dir: &str, row_format: RowFormat) -> Result<RepairReport> {
+        let wal_path = format!("{}/wal.log", data_dir);
+        let storage_path = format!("{}/storage.db", data_dir);
+        let mut report = RepairReport::default();
+
+        let mut storage = BPlusTree::new();
+        if storage.load_from_disk(&storage_path).is_ok() {
+            if let Err(e) = storage.verify() {
+                tracing::warn!("Discarding corrupt snapshot: {}", e);
+                storage = BPlusTree::new();
+                report.snapshot_discarded = true;
+            }
+        }
+        report.snapshot_seq = storage.applied_seq();
+
+        let mut wal = WriteAheadLog::new(&wal_path).await?;
+        let entries = wal.replay().await?;
+        if let Some(first) = entries.first() {
+            if first.seq > report.snapshot_seq + 1 {
+                report.missing = Some((report.snapshot_seq + 1, first.seq - 1));
+            }
+        }
+        if wal.has_name_keyed_entries() {
+            storage.expect_name_prefixed_keys();
+        }
+        replay_entries(&mut storage, &mut Catalog::new(), &entries, &mut report, row_format);
+
+        storage.verify()?;
+        storage.save_to_disk(&storage_path)?;
+        report.applied_seq = storage.applied_seq();
+
+        tracing::info!("Repair completed: {:?}", report);
+        Ok(report)
+    }
+
+    /// Rebuilds the database in `data_dir` as it was at `until` into
+    /// `target_dir`, which mustn't hold a database yet, for opening with
+    /// `new`. The base snapshot is replayed with the WAL entries up to
+    /// `until`, so `until` must fall within the history a retention window
+    /// kept, no earlier than the time recorded for the base (or, before any
+    /// checkpoint, the whole log). Only transactions
+    /// committed by then are applied, with their rows written in
+    /// `row_format`.
+    pub async fn restore_to(
+        data_dir: &str,
+        target_dir: &str,
+        until: DateTime<Utc>,
+        row_format: RowFormat,
+    ) -> Result<RestoreReport> {
+        let base_path = format!("{}/base.db", data_dir);
+        let mut storage = if Path::new(&base_path).exists() {
+            load_snapshot(&base_path)?
+        } else if Path::new(&format!("{}/storage.db", data_dir)).exists() {
+            return Err(anyhow!("No WAL history is kept in {}: checkpoints need a retention window", data_dir));
+        } else {
+            BPlusTree::new()
+        };
+        let base_seq = storage.applied_seq();
+
+        let mut entries = Vec::new();
+        let mut iter = WriteAheadLog::iter_file(&format!("{}/wal.log", data_dir), 0);
+        while let Some(entry) = iter.next().await? {
+            entries.push(entry);
+        }
+        if iter.has_name_keyed_entries() {
+            storage.expect_name_prefixed_keys();
+        }
+        // The base holds from its last entry on, even once no later entry
+        // is left to tell when the history kept starts
+        let start = match load_base_time(data_dir)? {
+            Some(time) => Some(time),
+            None => entries.iter().find(|entry| entry.seq > base_seq).map(|entry| entry.timestamp),
+        };
+        if let Some(start) = start.filter(|&start| until < start) {
+            return Err(anyhow!("{} is before the WAL history kept, which starts at {}", until, start));
+        }
+        entries.retain(|entry| entry.timestamp <= until);
+        let mut report = RepairReport::default();
+        replay_entries(&mut storage, &mut Catalog::new(), &entries, &mut report, row_format);
+
+        let target_wal = format!("{}/wal.log", target_dir);
+        if Path::new(&target_wal).exists() {
+            return Err(anyhow!("{} already holds a database", target_dir));
+        }
+        std::fs::create_dir_all(target_dir)?;
+        storage.save_to_disk(&format!("{}/storage.db", target_dir))?;
+        // Snapshots don't record the catalog
+        entries.retain(|entry| txn::wal::is_catalog(&entry.operation));
+        WriteAheadLog::write_log(&target_wal, &entries).await?;
+
+        let report = RestoreReport { base_seq, replayed: report.replayed, restored_seq: storage.applied_seq() };
+        tracing::info!("Restore to {} completed: {:?}", until, report);
+        Ok(report)
+    }
+
+    /// Scans all of storage for keys of no known table and rows that don't
+    /// match their schema, logging what it finds, for running right after
+    /// opening. Problems are only reported; nothing is repaired.
+    pub async fn startup_check(&self) -> Result<ConsistencyReport> {
+        let report = self.engine.check_consistency(STARTUP_CHECK_MAX_PROBLEMS).await?;
+        for problem in &report.problems {
+            tracing::warn!("Startup check: {}", problem);
+        }
+        let summary = format!(
+            "Startup check: {} tables, {} rows, {} bad rows, {} orphan keys",
+            report.tables, report.rows, report.bad_rows, report.orphan_keys
+        );
+        match report.is_consistent() {
+            true => tracing::info!("{}", summary),
+            false => tracing::warn!("{}", summary),
+        }
+        Ok(report)
+    }
+
+    /// Leaves the data directory so the next open has little to replay: a
+    /// checkpoint, whose steps run strictly in order. The WAL is synced,
+    /// then the snapshot is written to a temporary file, synced and renamed
+    /// into place, and the directory synced; only once all that succeeded
+    /// is the WAL compacted. If any step fails the WAL is left whole, and
+    /// the next open replays it over the previous snapshot.
     pub async fn shutdown(&mut self) -> Result<()> {
-        self.wal.sync().await?;
-        self.storage.save_to_disk("data/storage.db")?;
+        if let Err(e) = self.checkpoint().await {
+            tracing::error!("Shutdown checkpoint failed, the WAL is kept for recovery: {}", e);
+            return Err(e);
+        }
         Ok(())
     }
 }
 
+/// Outcome of `Database::checkpoint`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CheckpointReport {
+    /// WAL bytes freed by dropping entries the snapshot covers.
+    pub reclaimed_bytes: u64,
+    /// WAL entries dropped.
+    pub removed_entries: usize,
+}
+
+/// Outcome of `Database::restore_to`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Last WAL sequence contained in the base snapshot.
+    pub base_seq: u64,
+    /// WAL entries applied on top of it.
+    pub replayed: usize,
+    /// Last WAL sequence contained in the restored snapshot.
+    pub restored_seq: u64,
+}
+
+/// Most problems `CHECK TABLE` lists before it stops looking.
+pub const CHECK_TABLE_MAX_PROBLEMS: usize = 100;
+
+/// Most problems `Database::startup_check` logs individually.
+pub const STARTUP_CHECK_MAX_PROBLEMS: usize = 100;
+
+/// Operational statements handled by the database rather than the SQL
+/// engine, since they act on the WAL and snapshot files or aren't SQL the
+/// parser knows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AdminCommand {
+    ShowWalSize,
+    Checkpoint,
+    CheckTable(ObjectName),
+    DumpSchema,
+    ShowTreeStructure,
+}
+
+impl AdminCommand {
+    fn parse(sql: &str) -> Option<Self> {
+        let words: Vec<&str> = sql.trim().trim_end_matches(';').split_whitespace().collect();
+        let keywords: Vec<String> = words.iter().map(|word| word.to_ascii_uppercase()).collect();
+        match keywords.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["SHOW", "WAL", "SIZE"] => Some(Self::ShowWalSize),
+            ["CHECKPOINT"] => Some(Self::Checkpoint),
+            ["DUMP", "SCHEMA"] => Some(Self::DumpSchema),
+            ["SHOW", "TREE", "STRUCTURE"] => Some(Self::ShowTreeStructure),
+            ["CHECK", "TABLE", _] => Some(Self::CheckTable(ObjectName(words[2].split('.').map(Ident::new).collect()))),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of `Database::repair`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Last WAL sequence contained in the snapshot that was loaded.
+    pub snapshot_seq: u64,
+    /// Whether the snapshot failed verification and was rebuilt from the WAL.
+    pub snapshot_discarded: bool,
+    /// WAL entries skipped because the snapshot already contained them.
+    pub skipped: usize,
+    /// WAL entries applied on top of the snapshot.
+    pub replayed: usize,
+    /// WAL entries that could not be applied.
+    pub failed: usize,
+    /// Writes dropped because their transaction has no Commit in the WAL.
+    pub uncommitted: usize,
+    /// Writes dropped because their table didn't exist at that point in
+    /// the WAL.
+    pub orphaned: usize,
+    /// Sequence range neither the snapshot nor the WAL contains, if any.
+    pub missing: Option<(u64, u64)>,
+    /// Last WAL sequence contained in the repaired snapshot.
+    pub applied_seq: u64,
+}
+
+/// Applies the entries newer than the tree's applied point, in order.
+/// Writes tagged with a transaction are held back until its Commit and then
+/// applied together; those of a transaction that never committed (a crash
+/// mid-transaction) are dropped.
+///
+/// `catalog` is built up in the same pass, from every catalog entry since
+/// snapshots don't record the catalog, so a write is only applied if its
+/// table exists at that point in the log; the tables and their rows can't
+/// disagree once replay is done.
+fn replay_entries(
+    storage: &mut BPlusTree,
+    catalog: &mut Catalog,
+    entries: &[txn::WalEntry],
+    report: &mut RepairReport,
+    format: RowFormat,
+) {
+    // Decided up front: applying one transaction's writes at its Commit
+    // moves the applied point past writes of others still pending
+    let snapshot_seq = storage.applied_seq();
+    let mut pending: HashMap<u64, Vec<&txn::WalEntry>> = HashMap::new();
+    for entry in entries {
+        catalog.apply(&entry.operation);
+        if entry.seq <= snapshot_seq {
+            report.skipped += 1;
+            continue;
+        }
+        match (&entry.operation, entry.tx_id) {
+            (txn::WalOperation::Begin(tx_id), _) => {
+                pending.insert(*tx_id, Vec::new());
+                apply_replayed(storage, catalog, entry, report, format);
+            }
+            (txn::WalOperation::Commit(tx_id), _) => {
+                for write in pending.remove(tx_id).unwrap_or_default() {
+                    apply_replayed(storage, catalog, write, report, format);
+                }
+                apply_replayed(storage, catalog, entry, report, format);
+            }
+            (_, Some(tx_id)) => pending.entry(tx_id).or_default().push(entry),
+            (_, None) => apply_replayed(storage, catalog, entry, report, format),
+        }
+    }
+    for (tx_id, writes) in pending {
+        tracing::warn!("Discarding {} writes of uncommitted transaction {}", writes.len(), tx_id);
+        report.uncommitted += writes.len();
+    }
+}
+
+/// The snapshot at `path`, or an empty tree if there is none yet.
+fn load_snapshot(path: &str) -> Result<BPlusTree> {
+    let mut storage = BPlusTree::new();
+    if Path::new(path).exists() {
+        storage.load_from_disk(path)?;
+    }
+    Ok(storage)
+}
+
+/// Writes `base` as `base.db`, and `time`, from which it holds, as
+/// `base.time` beside it, each to a temporary file that is synced and
+/// renamed into place. The time is written first, so a crash before the
+/// base is saved only turns away restores it could have served; with no
+/// time known, any earlier one is removed.
+fn save_base(data_dir: &str, base: &BPlusTree, time: Option<DateTime<Utc>>) -> Result<()> {
+    let time_path = format!("{}/base.time", data_dir);
+    match time {
+        Some(time) => {
+            let temp_path = format!("{}.tmp", time_path);
+            let mut file = std::fs::File::create(&temp_path)?;
+            file.write_all(time.to_rfc3339().as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, &time_path)?;
+            storage::fsync::sync_parent_dir(&time_path)?;
+        }
+        None if Path::new(&time_path).exists() => std::fs::remove_file(&time_path)?,
+        None => {}
+    }
+    base.save_to_disk(&format!("{}/base.db", data_dir))
+}
+
+/// The time `save_base` recorded, if any; bases from before it was
+/// recorded have none.
+fn load_base_time(data_dir: &str) -> Result<Option<DateTime<Utc>>> {
+    let path = format!("{}/base.time", data_dir);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(&path)?;
+    let time = DateTime::parse_from_rfc3339(text.trim()).map_err(|e| anyhow!("Invalid {}: {}", path, e))?;
+    Ok(Some(time.with_timezone(&Utc)))
+}
+
+fn apply_replayed(
+    storage: &mut BPlusTree,
+    catalog: &Catalog,
+    entry: &txn::WalEntry,
+    report: &mut RepairReport,
+    format: RowFormat,
+) {
+    if !catalog.covers(&entry.operation) {
+        tracing::warn!("Skipping WAL entry {}: it writes to a table that doesn't exist at that point", entry.seq);
+        storage.mark_applied(entry.seq);
+        report.orphaned += 1;
+        return;
+    }
+    match storage.apply_wal_entry(entry, format) {
+        Ok(()) => report.replayed += 1,
+        Err(e) => {
+            tracing::warn!("Failed to apply WAL entry {}: {}", entry.seq, e);
+            report.failed += 1;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sql::engine::{Column, Row, SqlDataType, SqlValue, TableSchema};
+    use crate::clock::{Clock, MockClock};
+    use crate::txn::wal::{CHECKSUMMED, PREFIX_CHECKSUMMED, VERSIONED};
+    use crate::txn::{layout, WalEntry, WalOperation};
+    use std::collections::HashMap;
+    use std::time::Duration;
     use tempfile::TempDir;
     
     #[tokio::test]
@@ -74,6 +635,114 @@ mod tests {
         let db = Database::new(temp_dir.path().to_str().unwrap()).await;
         assert!(db.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_opens_a_data_dir_written_by_the_first_release() {
+        // Its log predates sequence numbers, table ids and every schema
+        // field since, and holds one row keyed by table name
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/baseline-wal.log");
+        std::fs::copy(fixture, format!("{}/wal.log", data_dir)).unwrap();
+
+        let mut db = Database::new(data_dir).await.unwrap();
+        assert_eq!(db.execute_sql("SELECT * FROM demo").await.unwrap(), "id\n--\n1\n(1 rows)\n");
+        assert_eq!(db.execute_sql("SELECT * FROM users").await.unwrap(), "id\n--\n(0 rows)\n");
+        assert_eq!(db.storage.read().await.key_format(), storage::bptree::KEY_FORMAT_VERSION);
+        db.execute_sql("INSERT INTO demo (id) VALUES (2)").await.unwrap();
+        drop(db);
+
+        // Opening re-keyed the row and rewrote the log in the current layout
+        let mut db = Database::new(data_dir).await.unwrap();
+        assert!(!db.wal.read().await.has_name_keyed_entries());
+        let result = db.execute_sql("SELECT id FROM demo ORDER BY id").await.unwrap();
+        assert_eq!(result, "id\n--\n1\n2\n(2 rows)\n");
+    }
+
+    /// Rewrites the log at `path` in layout `NOT_NULL`, from before table and
+    /// column names were folded, and without checksums, as a release of
+    /// then would read it.
+    fn rewrite_before_folded_names(path: &str) {
+        let data = std::fs::read(path).unwrap();
+        let mut rewritten = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let prefix = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            let len = (prefix & !(CHECKSUMMED | PREFIX_CHECKSUMMED | VERSIONED)) as usize;
+            let body = &data[at + 4..at + 4 + len];
+            rewritten.extend_from_slice(&(len as u32 | VERSIONED).to_le_bytes());
+            rewritten.push(layout::NOT_NULL);
+            rewritten.extend_from_slice(&body[1..]);
+            at += 4 + len + 4;
+        }
+        std::fs::write(path, rewritten).unwrap();
+    }
+
+    /// Copies the files directly in `from` to a new directory `to`.
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() {
+                std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_folds_names_created_before_names_were_folded() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.engine.set_identifier_case(IdentifierCase::Preserve);
+        db.execute_sql("CREATE TABLE People (id INTEGER PRIMARY KEY, Name VARCHAR(20), Age INTEGER)").await.unwrap();
+        db.execute_sql("INSERT INTO People (id, Name, Age) VALUES (1, 'Ada', 36)").await.unwrap();
+        db.execute_sql("CREATE TABLE Pets (id INTEGER PRIMARY KEY, Name VARCHAR(20), name INTEGER)").await.unwrap();
+        db.execute_sql("CREATE TABLE pets (id INTEGER PRIMARY KEY)").await.unwrap();
+        drop(db);
+        rewrite_before_folded_names(&format!("{}/wal.log", data_dir));
+        let unfolded = temp_dir.path().join("unfolded");
+        copy_dir(temp_dir.path(), &unfolded);
+
+        // Opened preserving case, the names are used as written and stay so
+        let mut db = Database::open(unfolded.to_str().unwrap(), RowFormat::default(), IdentifierCase::Preserve)
+            .await
+            .unwrap();
+        let result = db.execute_sql("SELECT Name, Age FROM People").await.unwrap();
+        assert!(result.contains("Ada\t36"), "{}", result);
+        assert!(db.execute_sql("SELECT name FROM people").await.is_err());
+
+        // Names are folded as the default policy would have created them,
+        // rows and all, unless the folded name is taken
+        let mut db = Database::new(data_dir).await.unwrap();
+        let result = db.execute_sql("SELECT Name, AGE FROM people").await.unwrap();
+        assert!(result.contains("Ada\t36"), "{}", result);
+        assert!(db.execute_sql("SELECT \"Name\", name FROM \"Pets\"").await.is_ok());
+        assert!(db.execute_sql("SELECT id FROM pets").await.is_ok());
+
+        // A name quoted since keeps its case when the database is reopened
+        db.execute_sql("CREATE TABLE \"Toys\" (id INTEGER PRIMARY KEY, \"Kind\" VARCHAR(20))").await.unwrap();
+        drop(db);
+        let mut db = Database::new(data_dir).await.unwrap();
+        assert!(db.execute_sql("SELECT \"Kind\" FROM \"Toys\"").await.is_ok());
+        assert!(db.execute_sql("SELECT name FROM people").await.unwrap().contains("Ada"));
+    }
+
+    #[tokio::test]
+    async fn test_new_and_checkpoint_sync_data_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().join("db");
+
+        let before = storage::fsync::dir_syncs();
+        let mut db = Database::new(data_dir.to_str().unwrap()).await.unwrap();
+        assert_eq!(storage::fsync::dir_syncs(), before + 2);
+
+        db.execute_sql("CREATE TABLE t (id INTEGER PRIMARY KEY)").await.unwrap();
+        let before = storage::fsync::dir_syncs();
+        db.checkpoint().await.unwrap();
+        // Once for the snapshot, once for the compacted WAL's rename
+        assert_eq!(storage::fsync::dir_syncs(), before + 2);
+    }
     
     #[tokio::test]
     async fn test_sql_execution() {
@@ -90,4 +759,642 @@ mod tests {
         assert!(result.is_ok());
         assert!(result.unwrap().contains("Alice"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_wal_handles_agree() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+
+        // Appends go through the engine's handle
+        db.execute_sql("CREATE TABLE test (id INTEGER PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO test (id) VALUES (1)").await.unwrap();
+
+        // and are visible through the database's handle
+        let wal = db.wal.read().await;
+        assert_eq!(wal.entry_count(), 2);
+        assert!(matches!(wal.get_entries()[1].operation, txn::WalOperation::Insert { .. }));
+        assert_eq!(db.storage.read().await.scan_prefix("1:").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_execute_sql_rolls_back_a_transaction_left_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+        db.engine.set_transaction_limits(txn::TransactionLimits { max_open: 1, idle_timeout: Duration::from_secs(60) });
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+
+        db.execute_sql("BEGIN; INSERT INTO users VALUES (1)").await.unwrap();
+        // Had it stayed open, this would be one transaction too many
+        let mut session = Session::new();
+        db.execute_sql_in(&mut session, "BEGIN").await.unwrap();
+        db.execute_sql_in(&mut session, "ROLLBACK").await.unwrap();
+        assert!(db.execute_sql("SELECT id FROM users").await.unwrap().contains("(0 rows)"));
+    }
+
+    fn user_row(id: i64) -> Row {
+        let mut values = HashMap::new();
+        values.insert("id".to_string(), SqlValue::Integer(id));
+        Row { values, schema_version: 1 }
+    }
+
+    /// A table with only an `id` primary key.
+    fn id_schema(name: &str, id: u64) -> TableSchema {
+        let column = Column {
+            name: "id".to_string(),
+            data_type: SqlDataType::Integer,
+            nullable: false,
+            primary_key: true,
+            auto_increment: false,
+            generated: None,
+            generated_expr: None,
+            default: None,
+            added_in: 1,
+            not_null: false,
+        };
+        TableSchema { name: name.to_string(), columns: vec![column], version: 1, shard_count: 1, id }
+    }
+
+    #[tokio::test]
+    async fn test_repair_inconsistent_snapshot_and_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+
+        // WAL: create, insert 1, insert 2, delete 1, insert 3
+        let mut wal = WriteAheadLog::new(&format!("{}/wal.log", data_dir)).await.unwrap();
+        for op in [
+            WalOperation::CreateTable(id_schema("users", 1)),
+            WalOperation::Insert { table: "users".into(), key: "users:1".into(), row: user_row(1) },
+            WalOperation::Insert { table: "users".into(), key: "users:2".into(), row: user_row(2) },
+            WalOperation::Delete { table: "users".into(), key: "users:1".into() },
+            WalOperation::Insert { table: "users".into(), key: "users:3".into(), row: user_row(3) },
+        ] {
+            wal.append(&WalEntry::new(op)).await.unwrap();
+        }
+
+        // Snapshot taken after the delete: newer than the first three entries,
+        // older than the last one
+        let mut snapshot = BPlusTree::new();
+        snapshot.insert("users:2".into(), storage::codec::encode_row(&user_row(2), Default::default()).unwrap()).unwrap();
+        snapshot.mark_applied(4);
+        snapshot.save_to_disk(&format!("{}/storage.db", data_dir)).unwrap();
+
+        let report = Database::repair(data_dir, RowFormat::default()).await.unwrap();
+        assert_eq!(report.snapshot_seq, 4);
+        assert_eq!(report.skipped, 4);
+        assert_eq!(report.replayed, 1);
+        assert_eq!(report.applied_seq, 5);
+        assert!(!report.snapshot_discarded);
+        assert_eq!(report.missing, None);
+
+        let db = Database::new(data_dir).await.unwrap();
+        let storage = db.storage.read().await;
+        storage.verify().unwrap();
+        assert_eq!(storage.scan_prefix("users:").unwrap(), vec!["users:2", "users:3"]);
+        assert!(storage.get("users:1").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_applies_only_committed_transactions() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+
+        // Transaction 1 commits, transaction 2 is cut off by a crash; an
+        // untagged write lands between them
+        let insert = |id: i64| WalOperation::Insert { table: "users".into(), key: format!("users:{}", id), row: user_row(id) };
+        let mut wal = WriteAheadLog::new(&format!("{}/wal.log", data_dir)).await.unwrap();
+        for entry in [
+            WalEntry::new(WalOperation::CreateTable(id_schema("users", 1))),
+            WalEntry::new(WalOperation::Begin(1)),
+            WalEntry::new(WalOperation::Begin(2)),
+            WalEntry::new(insert(1)).in_transaction(1),
+            WalEntry::new(insert(2)).in_transaction(2),
+            WalEntry::new(insert(3)),
+            WalEntry::new(insert(4)).in_transaction(1),
+            WalEntry::new(WalOperation::Commit(1)),
+        ] {
+            wal.append(&entry).await.unwrap();
+        }
+        drop(wal);
+
+        let report = Database::repair(data_dir, RowFormat::default()).await.unwrap();
+        assert_eq!(report.uncommitted, 1);
+        assert_eq!(report.applied_seq, 8);
+
+        let db = Database::new(data_dir).await.unwrap();
+        let storage = db.storage.read().await;
+        assert_eq!(storage.scan_prefix("users:").unwrap(), vec!["users:1", "users:3", "users:4"]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_builds_the_catalog_in_log_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+
+        let key = |table: u64, id: i64| format!("{}:{}", table, sql::engine::encode_key_value(&SqlValue::Integer(id)));
+        let insert = |table: &str, table_id: u64, id: i64| WalOperation::Insert {
+            table: table.into(),
+            key: key(table_id, id),
+            row: user_row(id),
+        };
+        let mut wal = WriteAheadLog::new(&format!("{}/wal.log", data_dir)).await.unwrap();
+        for entry in [
+            WalEntry::new(WalOperation::CreateTable(id_schema("users", 1))),
+            WalEntry::new(insert("users", 1, 1)),
+            // Before its table is created
+            WalEntry::new(insert("tags", 3, 1)),
+            WalEntry::new(WalOperation::CreateTable(id_schema("orders", 2))),
+            WalEntry::new(WalOperation::CreateTable(id_schema("tags", 3))),
+            WalEntry::new(insert("orders", 2, 1)),
+            WalEntry::new(WalOperation::RenameTable { from: "users".into(), to: "customers".into() }),
+            // Made before the rename, committed after it
+            WalEntry::new(WalOperation::Begin(1)),
+            WalEntry::new(insert("users", 1, 2)).in_transaction(1),
+            WalEntry::new(WalOperation::Commit(1)),
+            WalEntry::new(WalOperation::DropTable { name: "orders".into(), id: 2 }),
+            // After its table is dropped
+            WalEntry::new(insert("orders", 2, 2)),
+        ] {
+            wal.append(&entry).await.unwrap();
+        }
+
+        let entries = wal.replay().await.unwrap();
+        let (mut catalog, mut report) = (Catalog::new(), RepairReport::default());
+        replay_entries(&mut BPlusTree::new(), &mut catalog, &entries, &mut report, RowFormat::default());
+        assert_eq!(report.orphaned, 2);
+        let mut tables: Vec<&String> = catalog.schemas.keys().collect();
+        tables.sort();
+        assert_eq!(tables, vec!["customers", "tags"]);
+        drop(wal);
+
+        let mut db = Database::new(data_dir).await.unwrap();
+        let result = db.execute_sql("SELECT id FROM customers ORDER BY id").await.unwrap();
+        assert!(result.ends_with("1\n2\n(2 rows)\n"), "{}", result);
+        let result = db.execute_sql("SELECT id FROM tags").await.unwrap();
+        assert!(result.ends_with("(0 rows)\n"), "{}", result);
+        let err = db.execute_sql("SELECT id FROM orders").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'orders' does not exist");
+        assert!(db.storage.read().await.scan_prefix("2:").unwrap().is_empty());
+        assert!(db.startup_check().await.unwrap().is_consistent());
+    }
+
+    #[tokio::test]
+    async fn test_show_wal_size_and_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+
+        let wal_size = |result: String| -> (u64, usize) {
+            let values: Vec<&str> = result.lines().nth(1).unwrap().split('\t').collect();
+            (values[0].parse().unwrap(), values[1].parse().unwrap())
+        };
+
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        let (empty_bytes, _) = wal_size(db.execute_sql("SHOW WAL SIZE").await.unwrap());
+        for i in 0..20 {
+            db.execute_sql(&format!("INSERT INTO users (id, name) VALUES ({}, 'user{}')", i, i)).await.unwrap();
+        }
+        let (grown_bytes, grown_entries) = wal_size(db.execute_sql("show wal size;").await.unwrap());
+        assert!(grown_bytes > empty_bytes);
+        assert_eq!(grown_entries, 21);
+
+        let result = db.execute_sql("CHECKPOINT").await.unwrap();
+        assert_eq!(
+            result,
+            format!("Checkpoint complete: reclaimed {} bytes of WAL (20 entries)", grown_bytes - empty_bytes)
+        );
+        assert_eq!(wal_size(db.execute_sql("SHOW WAL SIZE").await.unwrap()), (empty_bytes, 1));
+
+        let result = db.execute_sql("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("(20 rows)"));
+
+        // The snapshot holds the rows the WAL no longer does, and the
+        // catalog entries the WAL keeps map the table to them again
+        drop(db);
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        let key = format!("1:{}", sql::engine::encode_key_value(&SqlValue::Integer(7)));
+        assert!(reopened.storage.read().await.get(&key).unwrap().is_some());
+        let result = reopened.execute_sql("SELECT name FROM users WHERE id = 7").await.unwrap();
+        assert!(result.contains("user7"), "{}", result);
+        reopened.execute_sql("CREATE TABLE orders (id INTEGER PRIMARY KEY)").await.unwrap();
+        assert_eq!(reopened.engine.table_ids().await.into_iter().map(|(_, id)| id).max(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_failed_shutdown_keeps_the_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        let wal_path = format!("{}/wal.log", data_dir);
+        let wal_before = std::fs::read(&wal_path).unwrap();
+
+        // The snapshot's temporary file can't be created
+        let blocker = format!("{}/storage.db.tmp", data_dir);
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(db.shutdown().await.is_err());
+        assert_eq!(std::fs::read(&wal_path).unwrap(), wal_before);
+        assert!(!std::path::Path::new(&format!("{}/storage.db", data_dir)).exists());
+        drop(db);
+
+        std::fs::remove_dir(&blocker).unwrap();
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        let result = reopened.execute_sql("SELECT name FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("Ada\nGrace\n(2 rows)"), "{}", result);
+
+        // A shutdown that succeeds leaves the rows in the snapshot
+        reopened.shutdown().await.unwrap();
+        assert_eq!(reopened.wal.read().await.entry_count(), 1);
+        drop(reopened);
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        let result = reopened.execute_sql("SELECT name FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("(2 rows)"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_dump_schema_recreates_every_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().join("a").to_str().unwrap()).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, email VARCHAR(100) NOT NULL)").await.unwrap();
+        db.execute_sql("CREATE DATABASE shop").await.unwrap();
+        db.execute_sql("CREATE TABLE shop.orders (id INTEGER PRIMARY KEY AUTO_INCREMENT, user_id INTEGER)")
+            .await
+            .unwrap();
+        db.execute_sql("ALTER TABLE users ADD COLUMN age INTEGER").await.unwrap();
+
+        let dump = db.execute_sql("DUMP SCHEMA;").await.unwrap();
+        assert_eq!(
+            dump.lines().collect::<Vec<_>>(),
+            vec![
+                "CREATE DATABASE \"shop\";",
+                "CREATE TABLE \"users\" (\"id\" INTEGER PRIMARY KEY, \"email\" VARCHAR(100) NOT NULL, \"age\" INTEGER);",
+                "CREATE TABLE \"shop\".\"orders\" (\"id\" INTEGER PRIMARY KEY AUTO_INCREMENT, \"user_id\" INTEGER);",
+            ]
+        );
+
+        let mut copy = Database::new(temp_dir.path().join("b").to_str().unwrap()).await.unwrap();
+        for statement in dump.lines() {
+            copy.execute_sql(statement).await.unwrap();
+        }
+        assert_eq!(copy.execute_sql("DUMP SCHEMA").await.unwrap(), dump);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_table_stays_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Ada')").await.unwrap();
+        db.execute_sql("CHECKPOINT").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (2, 'Grace')").await.unwrap();
+        db.execute_sql("DROP TABLE users").await.unwrap();
+        drop(db);
+
+        // Replay removes the rows the snapshot still holds, and the
+        // dropped table's id isn't reused
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        assert!(reopened.execute_sql("SELECT * FROM users").await.is_err());
+        assert!(reopened.storage.read().await.scan_prefix("1:").unwrap().is_empty());
+        reopened.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        assert_eq!(reopened.engine.table_ids().await, vec![("users".to_string(), 2)]);
+
+        // Compaction keeps the drop, so the table doesn't come back
+        reopened.execute_sql("CHECKPOINT").await.unwrap();
+        drop(reopened);
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        assert!(reopened.execute_sql("SELECT * FROM users").await.unwrap().contains("(0 rows)"));
+        assert_eq!(reopened.engine.table_ids().await, vec![("users".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_schemas_survive_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        db.execute_sql("ALTER TABLE users ADD COLUMN age INTEGER").await.unwrap();
+        db.execute_sql("CREATE DATABASE shop").await.unwrap();
+        db.execute_sql("CREATE TABLE shop.orders (id INTEGER PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO shop.orders (id) VALUES (7)").await.unwrap();
+        drop(db);
+
+        // Nothing was checkpointed, so the catalog and rows come from the
+        // WAL alone
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        let result = reopened.execute_sql("SELECT id, name, age FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("1\tAda\tNULL\n2\tGrace\tNULL\n(2 rows)"), "{}", result);
+        assert!(reopened.execute_sql("SELECT * FROM shop.orders").await.unwrap().contains("(1 rows)"));
+        reopened.execute_sql("INSERT INTO users (id, name, age) VALUES (3, 'Edsger', 72)").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_writes_the_configured_row_format() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::with_row_format(data_dir, RowFormat::Json).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(20))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        drop(db);
+
+        // Nothing was checkpointed, so every stored row was replayed
+        let reopened = Database::with_row_format(data_dir, RowFormat::Json).await.unwrap();
+        let storage = reopened.storage.read().await;
+        let keys = storage.scan_prefix("1:").unwrap();
+        assert_eq!(keys.len(), 2);
+        for key in keys {
+            // Stored rows start with their format's tag
+            let bytes = storage.get(&key).unwrap().unwrap();
+            let row = storage::codec::decode_row(&bytes).unwrap();
+            assert_eq!(bytes[0], storage::codec::encode_row(&row, RowFormat::Json).unwrap()[0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_snapshot_stops_startup() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        db.checkpoint().await.unwrap();
+        drop(db);
+
+        std::fs::write(temp_dir.path().join("storage.db"), b"not a snapshot").unwrap();
+        let err = Database::new(data_dir).await.unwrap_err();
+        assert!(err.to_string().contains("Cannot load snapshot"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_auto_increment_resumes_after_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE notes (id INTEGER PRIMARY KEY AUTO_INCREMENT, body VARCHAR(50))").await.unwrap();
+        db.execute_sql("INSERT INTO notes (body) VALUES ('a'), ('b'), ('c')").await.unwrap();
+        db.checkpoint().await.unwrap();
+        drop(db);
+
+        let mut reopened = Database::new(data_dir).await.unwrap();
+        let result = reopened.execute_sql("INSERT INTO notes (body) VALUES ('d') RETURNING id").await.unwrap();
+        assert!(result.contains("\n4\n"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_show_tree_structure_needs_an_admin_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        for i in 0..300 {
+            db.execute_sql(&format!("INSERT INTO users (id) VALUES ({})", i)).await.unwrap();
+        }
+
+        let err = db.execute_sql("SHOW TREE STRUCTURE").await.unwrap_err();
+        assert_eq!(err.to_string(), "SHOW TREE STRUCTURE requires an admin session");
+        let mut admin = Session::new().with_admin(true);
+        let rendering = db.execute_sql_in(&mut admin, "show tree structure;").await.unwrap();
+        assert_eq!(rendering, db.storage.read().await.render_structure());
+        assert!(rendering.lines().nth(1).unwrap().starts_with("internal "), "{}", rendering);
+    }
+
+    #[tokio::test]
+    async fn test_check_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("CREATE TABLE other (id INTEGER PRIMARY KEY)").await.unwrap();
+        for i in 0..500 {
+            db.execute_sql(&format!("INSERT INTO users (id, name) VALUES ({}, 'user{}')", i, i)).await.unwrap();
+        }
+        assert_eq!(db.execute_sql("CHECK TABLE users;").await.unwrap(), "status\n------\nok\n(1 rows)\n");
+        assert!(db.execute_sql("CHECK TABLE missing").await.is_err());
+
+        let key = |id: i64| format!("1:{}", sql::engine::encode_key_value(&SqlValue::Integer(id)));
+        let mistyped = Row {
+            values: HashMap::from([
+                ("id".to_string(), SqlValue::Integer(3)),
+                ("name".to_string(), SqlValue::Integer(42)),
+            ]),
+            schema_version: 1,
+        };
+        let misplaced = Row { values: HashMap::from([("id".to_string(), SqlValue::Integer(4))]), schema_version: 1 };
+        {
+            let mut storage = db.storage.write().await;
+            storage.insert(key(1), vec![1, 0xff]).unwrap();
+            storage.insert(key(3), storage::codec::encode_row(&mistyped, Default::default()).unwrap()).unwrap();
+            storage.insert(key(5), storage::codec::encode_row(&misplaced, Default::default()).unwrap()).unwrap();
+        }
+
+        let result = db.execute_sql("check table users").await.unwrap();
+        assert!(result.contains(&format!("Row '{}' can't be decoded", key(1))), "{}", result);
+        assert!(result.contains(&format!("Row '{}' has value Integer(42) for Varchar(100) column 'name'", key(3))), "{}", result);
+        assert!(result.contains(&format!("Row '{}' is stored under the wrong key", key(5))), "{}", result);
+        assert!(result.ends_with("(3 rows)\n"), "{}", result);
+        assert!(db.execute_sql("CHECK TABLE other").await.unwrap().contains("ok"));
+
+        let limited = db.engine.check_table(&Session::new(), &ObjectName(vec![Ident::new("users")]), 2).await.unwrap();
+        let limited = limited.render();
+        assert!(limited.ends_with("(2 rows)\n"), "{}", limited);
+    }
+
+    #[tokio::test]
+    async fn test_startup_check_reports_orphans_and_bad_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        let report = db.startup_check().await.unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!((report.tables, report.rows), (1, 2));
+
+        // Rows left behind by a table the catalog no longer has, and a row
+        // of the wrong type, survive a restart through the snapshot
+        let orphan = Row { values: HashMap::from([("id".to_string(), SqlValue::Integer(1))]), schema_version: 1 };
+        let mistyped = Row {
+            values: HashMap::from([
+                ("id".to_string(), SqlValue::Integer(3)),
+                ("name".to_string(), SqlValue::Boolean(true)),
+            ]),
+            schema_version: 1,
+        };
+        let key = |table: u64, id: i64| format!("{}:{}", table, sql::engine::encode_key_value(&SqlValue::Integer(id)));
+        db.put_raw(&key(7, 1), storage::codec::encode_row(&orphan, Default::default()).unwrap()).await.unwrap();
+        db.put_raw(&key(1, 3), storage::codec::encode_row(&mistyped, Default::default()).unwrap()).await.unwrap();
+        db.checkpoint().await.unwrap();
+        drop(db);
+
+        let db = Database::new(data_dir).await.unwrap();
+        let report = db.startup_check().await.unwrap();
+        assert_eq!((report.rows, report.bad_rows, report.orphan_keys), (3, 1, 1));
+        assert!(report.problems.contains(&format!("Key '{}' belongs to no table", key(7, 1))), "{:?}", report);
+        assert!(report.problems.iter().any(|p| p.contains("Boolean(true) for Varchar(100)")), "{:?}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_checkpoints_never_miss_logged_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap().to_string();
+        let db = Arc::new(Database::new(&data_dir).await.unwrap());
+        db.engine.execute("CREATE TABLE events (id INTEGER PRIMARY KEY)").await.unwrap();
+
+        const ROWS: i64 = 300;
+        let writer = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..ROWS {
+                    db.engine.execute(&format!("INSERT INTO events (id) VALUES ({})", i)).await.unwrap();
+                }
+            })
+        };
+
+        // After every checkpoint, recover from what is on disk: the snapshot
+        // plus the WAL must hold every row the WAL has logged
+        while !writer.is_finished() {
+            db.checkpoint().await.unwrap();
+            let wal = db.wal.write().await;
+            let logged = wal.last_seq();
+            let mut recovered = BPlusTree::new();
+            recovered.load_from_disk(&format!("{}/storage.db", data_dir)).unwrap();
+            let entries = WriteAheadLog::new(&format!("{}/wal.log", data_dir)).await.unwrap().replay().await.unwrap();
+            let mut report = RepairReport::default();
+            replay_entries(&mut recovered, &mut Catalog::new(), &entries, &mut report, RowFormat::default());
+            drop(wal);
+
+            // Sequence 1 is the CREATE TABLE; each insert logs one entry
+            let rows = recovered.scan_prefix("1:").unwrap().len() as u64;
+            assert_eq!(rows, logged - 1, "recovery lost rows logged before seq {}", logged);
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mutations_trigger_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+        db.set_checkpoint_threshold(6);
+
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO users (id) VALUES (1), (2), (3), (4)").await.unwrap();
+        for _ in 0..10 {
+            db.execute_sql("SELECT * FROM users").await.unwrap();
+        }
+        assert_eq!(db.storage.read().await.operation_count(), 4);
+        // The CREATE, then the inserts between Begin and Commit markers
+        assert_eq!(db.wal.read().await.entry_count(), 7);
+
+        // Deletes count as much as inserts
+        db.execute_sql("DELETE FROM users").await.unwrap();
+        assert_eq!(db.storage.read().await.operation_count(), 0);
+        assert_eq!(db.wal.read().await.entry_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_raw_access() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+
+        db.put_raw("debug:a", b"one".to_vec()).await.unwrap();
+        db.put_raw("debug:b", b"two".to_vec()).await.unwrap();
+        assert_eq!(db.get_raw("debug:a").await.unwrap(), Some(b"one".to_vec()));
+        assert_eq!(db.get_raw("debug:missing").await.unwrap(), None);
+        let keys: Vec<String> = db.scan_raw("debug:").await.unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["debug:a", "debug:b"]);
+
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        db.execute_sql("INSERT INTO users (id, name) VALUES (1, 'Alice')").await.unwrap();
+        let key = format!("1:{}", sql::engine::encode_key_value(&SqlValue::Integer(1)));
+        let row = storage::codec::decode_row(&db.get_raw(&key).await.unwrap().unwrap()).unwrap();
+        assert!(matches!(row.get("name"), Some(SqlValue::Varchar(name)) if name == "Alice"));
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_keeps_a_retention_window_for_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        let data_dir = data_dir.to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        let clock = MockClock::new();
+        db.wal.write().await.set_clock(clock.clone());
+        db.set_checkpoint_threshold(0);
+        // Every entry gets a segment of its own
+        db.set_wal_segment_bytes(1).await;
+        db.set_wal_retention(WalRetention { max_age: Some(Duration::from_secs(3600)), max_bytes: None });
+
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        for id in 1..=3 {
+            db.execute_sql(&format!("INSERT INTO users (id) VALUES ({})", id)).await.unwrap();
+        }
+        let before_window = clock.utc_now() - chrono::Duration::minutes(1);
+        let in_base = clock.utc_now() + chrono::Duration::minutes(30);
+        clock.advance(Duration::from_secs(2 * 3600));
+        db.execute_sql("INSERT INTO users (id) VALUES (4)").await.unwrap();
+        db.execute_sql("INSERT INTO users (id) VALUES (5)").await.unwrap();
+        let until = clock.utc_now();
+        clock.advance(Duration::from_secs(60));
+        db.execute_sql("DELETE FROM users WHERE id = 4").await.unwrap();
+        db.execute_sql("INSERT INTO users (id) VALUES (6)").await.unwrap();
+        assert_eq!(db.wal.read().await.segment_count().await.unwrap(), 8);
+
+        // The CREATE and first three inserts are over an hour old; the
+        // CREATE is kept in the first segment for the catalog
+        db.checkpoint().await.unwrap();
+        let wal_path = format!("{}/wal.log", data_dir);
+        assert_eq!(db.wal.read().await.segment_count().await.unwrap(), 5);
+        for segment in 2..=4 {
+            assert!(!Path::new(&txn::wal::segment_path(&wal_path, segment)).exists());
+        }
+        assert!(Path::new(&txn::wal::segment_path(&wal_path, 5)).exists());
+        assert_eq!(db.wal.read().await.entry_count(), 5);
+        assert!(Path::new(&format!("{}/base.db", data_dir)).exists());
+
+        let restored_dir = temp_dir.path().join("restored");
+        let restored_dir = restored_dir.to_str().unwrap();
+        let report = Database::restore_to(data_dir, restored_dir, until, RowFormat::default()).await.unwrap();
+        assert_eq!(report, RestoreReport { base_seq: 4, replayed: 2, restored_seq: 6 });
+        let mut restored = Database::new(restored_dir).await.unwrap();
+        let result = restored.execute_sql("SELECT id FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("1\n2\n3\n4\n5\n(5 rows)"), "{}", result);
+
+        let early = format!("{}/early", restored_dir);
+        let err = Database::restore_to(data_dir, &early, before_window, RowFormat::default()).await.unwrap_err();
+        assert!(err.to_string().contains("is before the WAL history kept"), "{}", err);
+        // Between the base's last entry and the next one, the base is the answer
+        Database::restore_to(data_dir, &early, in_base, RowFormat::default()).await.unwrap();
+        let result = Database::new(&early).await.unwrap().execute_sql("SELECT id FROM users").await.unwrap();
+        assert!(result.contains("1\n2\n3\n(3 rows)"), "{}", result);
+
+        drop(db);
+        let mut db = Database::new(data_dir).await.unwrap();
+        let result = db.execute_sql("SELECT id FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("1\n2\n3\n5\n6\n(5 rows)"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_restore_rejects_a_time_before_a_base_with_no_history_after_it() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        let data_dir = data_dir.to_str().unwrap();
+        let mut db = Database::new(data_dir).await.unwrap();
+        let clock = MockClock::new();
+        db.wal.write().await.set_clock(clock.clone());
+        db.set_checkpoint_threshold(0);
+
+        db.execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        db.execute_sql("INSERT INTO users (id) VALUES (1)").await.unwrap();
+        let before = clock.utc_now();
+        db.checkpoint().await.unwrap();
+
+        // The base starts from the snapshot, with no entry left to date it
+        clock.advance(Duration::from_secs(3600));
+        db.set_wal_retention(WalRetention { max_age: Some(Duration::from_secs(3600)), max_bytes: None });
+        db.checkpoint().await.unwrap();
+        assert!(Path::new(&format!("{}/base.db", data_dir)).exists());
+
+        let restored_dir = temp_dir.path().join("restored");
+        let restored_dir = restored_dir.to_str().unwrap();
+        let err = Database::restore_to(data_dir, restored_dir, before, RowFormat::default()).await.unwrap_err();
+        assert!(err.to_string().contains("is before the WAL history kept"), "{}", err);
+        Database::restore_to(data_dir, restored_dir, clock.utc_now(), RowFormat::default()).await.unwrap();
+    }
+}
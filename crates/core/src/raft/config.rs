@@ -0,0 +1,70 @@
+use super::NodeId;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// One cluster member and the address its Raft transport listens on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerConfig {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// Static cluster membership, read once at startup. Every node is started
+/// with the same config; `bootstrap` names the node that starts the first
+/// election instead of waiting for a timeout.
+///
+/// Stored as JSON:
+/// `{"bootstrap": "n1", "peers": [{"id": "n1", "addr": "10.0.0.1:7000"}, ...]}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    pub bootstrap: NodeId,
+    pub peers: Vec<PeerConfig>,
+}
+
+impl ClusterConfig {
+    /// A cluster of just this node, for local development.
+    pub fn single_node(id: NodeId, addr: SocketAddr) -> Self {
+        Self { bootstrap: id.clone(), peers: vec![PeerConfig { id, addr }] }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read cluster config {}: {}", path.display(), e))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(text).map_err(|e| anyhow!("Invalid cluster config: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that ids and addresses are unique and the bootstrap node is
+    /// a member.
+    pub fn validate(&self) -> Result<()> {
+        if self.peers.is_empty() {
+            return Err(anyhow!("Cluster config lists no peers"));
+        }
+        let mut ids = HashSet::new();
+        let mut addrs = HashSet::new();
+        for peer in &self.peers {
+            if !ids.insert(&peer.id) {
+                return Err(anyhow!("Node '{}' is listed more than once", peer.id.0));
+            }
+            if !addrs.insert(peer.addr) {
+                return Err(anyhow!("Address {} is listed more than once", peer.addr));
+            }
+        }
+        if !ids.contains(&self.bootstrap) {
+            return Err(anyhow!("Bootstrap node '{}' is not in the peer list", self.bootstrap.0));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.peers.iter().any(|peer| &peer.id == id)
+    }
+}
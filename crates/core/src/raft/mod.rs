@@ -1,7 +1,14 @@
+pub mod config;
+pub mod transport;
+
+use crate::clock::{SharedClock, SystemClock};
+use anyhow::{anyhow, Result};
+use config::ClusterConfig;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use transport::Transport;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -77,10 +84,19 @@ pub struct RaftNode {
     pub heartbeat_interval: Duration,
     pub command_sender: mpsc::UnboundedSender<Vec<u8>>,
     pub command_receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Read for heartbeat times and election timeouts.
+    clock: SharedClock,
+    /// Publishes `last_applied` to `AppliedIndex` handles.
+    applied: watch::Sender<LogIndex>,
 }
 
 impl RaftNode {
     pub fn new(id: NodeId, peers: Vec<NodeId>) -> Self {
+        Self::with_clock(id, peers, SystemClock::shared())
+    }
+
+    /// Like `new`, but reading time from `clock`.
+    pub fn with_clock(id: NodeId, peers: Vec<NodeId>, clock: SharedClock) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
 
         Self {
@@ -95,11 +111,33 @@ impl RaftNode {
             leader_id: None,
             next_index: HashMap::new(),
             match_index: HashMap::new(),
-            last_heartbeat: Instant::now(),
+            last_heartbeat: clock.now(),
             election_timeout: Duration::from_millis(300),
             heartbeat_interval: Duration::from_millis(100),
             command_sender: tx,
             command_receiver: rx,
+            clock,
+            applied: watch::Sender::new(LogIndex(0)),
+        }
+    }
+
+    /// Builds node `id` of the cluster described by `config`, along with
+    /// the transport address table for reaching the other members.
+    pub fn from_config(config: &ClusterConfig, id: NodeId) -> Result<(Self, Transport)> {
+        if !config.contains(&id) {
+            return Err(anyhow!("Node '{}' is not in the cluster config", id.0));
+        }
+        let peers = config.peers.iter().map(|peer| peer.id.clone()).filter(|peer| peer != &id).collect();
+        let transport = Transport::from_config(config, &id);
+        Ok((Self::new(id, peers), transport))
+    }
+
+    /// Starts the cluster's first election from the designated bootstrap
+    /// node. A node with no peers is its own majority and leads at once.
+    pub fn bootstrap(&mut self) {
+        self.start_election();
+        if self.peers.is_empty() {
+            self.become_leader();
         }
     }
 
@@ -115,7 +153,23 @@ impl RaftNode {
         self.current_term.0 += 1;
         self.state = NodeState::Candidate;
         self.voted_for = Some(self.id.clone());
-        self.last_heartbeat = Instant::now();
+        self.last_heartbeat = self.clock.now();
+    }
+
+    /// Whether a follower or candidate has gone a full election timeout
+    /// without hearing from a leader or starting an election.
+    pub fn election_timed_out(&self) -> bool {
+        !self.is_leader() && self.clock.now().duration_since(self.last_heartbeat) >= self.election_timeout
+    }
+
+    /// Starts an election if the election timeout has run out. Returns
+    /// whether it did.
+    pub fn tick(&mut self) -> bool {
+        let timed_out = self.election_timed_out();
+        if timed_out {
+            self.start_election();
+        }
+        timed_out
     }
 
     pub fn become_leader(&mut self) {
@@ -129,6 +183,39 @@ impl RaftNode {
         }
     }
 
+    /// Index of the last log entry applied to the state machine.
+    pub fn last_applied_index(&self) -> LogIndex {
+        self.last_applied
+    }
+
+    /// A handle that follows `last_applied_index`, for a read to wait on
+    /// without holding the node, which must stay free to apply entries.
+    pub fn applied_index(&self) -> AppliedIndex {
+        AppliedIndex(self.applied.subscribe())
+    }
+
+    /// Applies every committed entry not yet applied, in log order, by
+    /// sending its command to the state machine through `command_sender`,
+    /// and then tells waiting reads. Stops early if the state machine's
+    /// receiver is gone, leaving the rest unapplied.
+    fn apply_committed(&mut self) {
+        let before = self.last_applied;
+        while self.last_applied < self.commit_index {
+            let entry = match self.log.get(self.last_applied.0 as usize) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if self.command_sender.send(entry.command.clone()).is_err() {
+                tracing::warn!(index = entry.index.0, "State machine is gone; committed entries left unapplied");
+                break;
+            }
+            self.last_applied = entry.index;
+        }
+        if self.last_applied > before {
+            self.applied.send_replace(self.last_applied);
+        }
+    }
+
     pub fn get_last_log_index(&self) -> LogIndex {
         self.log.last().map(|e| e.index).unwrap_or(LogIndex(0))
     }
@@ -171,13 +258,41 @@ impl RaftNode {
 
         self.leader_id = Some(req.leader_id.clone());
         self.state = NodeState::Follower;
-        self.last_heartbeat = Instant::now();
+        self.last_heartbeat = self.clock.now();
         self.current_term = req.term;
 
+        // The entries must follow on from one this log has; otherwise the
+        // leader retries from further back
+        let prev = req.prev_log_index.0 as usize;
+        if prev > 0 && self.log.get(prev - 1).map(|entry| entry.term) != Some(req.prev_log_term) {
+            return AppendEntriesResponse {
+                term: self.current_term,
+                success: false,
+                match_index: LogIndex(0),
+            };
+        }
+
+        // An entry that conflicts with the leader's, and all after it, are
+        // replaced; ones already present are kept
+        let last_new = LogIndex(req.prev_log_index.0 + req.entries.len() as u64);
+        for (position, entry) in (prev..).zip(req.entries) {
+            match self.log.get(position) {
+                Some(existing) if existing.term == entry.term => continue,
+                Some(_) => self.log.truncate(position),
+                None => {}
+            }
+            self.log.push(entry);
+        }
+
+        if req.leader_commit > self.commit_index {
+            self.commit_index = req.leader_commit.min(last_new);
+            self.apply_committed();
+        }
+
         AppendEntriesResponse {
             term: self.current_term,
             success: true,
-            match_index: self.get_last_log_index(),
+            match_index: last_new,
         }
     }
 
@@ -188,9 +303,54 @@ impl RaftNode {
     }
 }
 
+/// Follows a node's last applied index, from `RaftNode::applied_index`.
+#[derive(Debug, Clone)]
+pub struct AppliedIndex(watch::Receiver<LogIndex>);
+
+impl AppliedIndex {
+    pub fn get(&self) -> LogIndex {
+        *self.0.borrow()
+    }
+
+    /// Waits until the node has applied `min`, so a read served afterwards
+    /// sees every write up to it, and returns the index then applied.
+    /// Fails if `timeout` passes first or the node is dropped.
+    pub async fn wait_for(&mut self, min: LogIndex, timeout: Duration) -> Result<LogIndex> {
+        let waited = tokio::time::timeout(timeout, self.0.wait_for(|applied| *applied >= min)).await;
+        match waited.map(|reached| reached.map(|applied| *applied)) {
+            Ok(Ok(applied)) => Ok(applied),
+            Ok(Err(_)) => Err(anyhow!("Raft node stopped while waiting for index {} to be applied", min.0)),
+            Err(_) => Err(anyhow!(
+                "Timed out after {} ms waiting for index {} to be applied (applied: {})",
+                timeout.as_millis(),
+                min.0,
+                self.get().0
+            )),
+        }
+    }
+
+    /// Serves a read that requires the node to have applied `min_index`:
+    /// waits for it as `wait_for` does, then runs `read` with the index
+    /// applied by then. Without a minimum the read runs at once, as stale
+    /// as the node is.
+    pub async fn read<T>(
+        &mut self,
+        min_index: Option<LogIndex>,
+        timeout: Duration,
+        read: impl FnOnce(LogIndex) -> T,
+    ) -> Result<T> {
+        let applied = match min_index {
+            Some(min) => self.wait_for(min, timeout).await?,
+            None => self.get(),
+        };
+        Ok(read(applied))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_raft_node_init() {
@@ -204,6 +364,44 @@ mod tests {
         assert_eq!(node.state, NodeState::Follower);
     }
 
+    #[test]
+    fn test_node_from_three_peer_config() {
+        let config = ClusterConfig::parse(
+            r#"{"bootstrap": "n1", "peers": [
+                {"id": "n1", "addr": "10.0.0.1:7000"},
+                {"id": "n2", "addr": "10.0.0.2:7000"},
+                {"id": "n3", "addr": "10.0.0.3:7000"}
+            ]}"#,
+        )
+        .unwrap();
+        let (node, transport) = RaftNode::from_config(&config, NodeId("n2".into())).unwrap();
+
+        assert_eq!(node.peers, vec![NodeId("n1".into()), NodeId("n3".into())]);
+        assert_eq!(transport.len(), 2);
+        for peer in &config.peers {
+            let expected = (peer.id != node.id).then_some(peer.addr);
+            assert_eq!(transport.resolve(&peer.id), expected);
+        }
+        assert!(RaftNode::from_config(&config, NodeId("n4".into())).is_err());
+    }
+
+    #[test]
+    fn test_single_node_bootstrap_leads() {
+        let config = ClusterConfig::single_node(NodeId("n1".into()), "127.0.0.1:7000".parse().unwrap());
+        let (mut node, transport) = RaftNode::from_config(&config, NodeId("n1".into())).unwrap();
+        assert!(transport.is_empty());
+
+        node.bootstrap();
+        assert!(node.is_leader());
+        assert_eq!(node.current_term, Term(1));
+
+        let duplicate = r#"{"bootstrap": "n1", "peers": [
+            {"id": "n1", "addr": "10.0.0.1:7000"}, {"id": "n1", "addr": "10.0.0.2:7000"}
+        ]}"#;
+        assert!(ClusterConfig::parse(duplicate).is_err());
+        assert!(ClusterConfig::parse(r#"{"bootstrap": "n9", "peers": []}"#).is_err());
+    }
+
     #[test]
     fn test_start_election() {
         let id = NodeId("n1".into());
@@ -215,6 +413,109 @@ mod tests {
         assert_eq!(node.current_term, Term(1));
     }
 
+    #[test]
+    fn test_election_starts_at_timeout() {
+        let clock = MockClock::new();
+        let mut node = RaftNode::with_clock(NodeId("n1".into()), vec![NodeId("n2".into())], clock.clone());
+
+        clock.advance(Duration::from_millis(299));
+        assert!(!node.tick());
+        // A heartbeat restarts the timeout
+        node.handle_append_entries(AppendEntriesRequest {
+            term: Term(0),
+            leader_id: NodeId("n2".into()),
+            prev_log_index: LogIndex(0),
+            prev_log_term: Term(0),
+            entries: vec![],
+            leader_commit: LogIndex(0),
+        });
+        clock.advance(Duration::from_millis(299));
+        assert!(!node.tick());
+        assert!(node.is_follower());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(node.tick());
+        assert_eq!(node.state, NodeState::Candidate);
+        assert_eq!(node.current_term, Term(1));
+        // The next election waits for another full timeout
+        assert!(!node.tick());
+        clock.advance(Duration::from_millis(300));
+        assert!(node.tick());
+        assert_eq!(node.current_term, Term(2));
+    }
+
+    fn entry(term: u64, index: u64) -> LogEntry {
+        LogEntry { term: Term(term), index: LogIndex(index), command: vec![index as u8], id: Uuid::new_v4() }
+    }
+
+    fn append(prev: (u64, u64), entries: Vec<LogEntry>, leader_commit: u64) -> AppendEntriesRequest {
+        AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId("n2".into()),
+            prev_log_index: LogIndex(prev.0),
+            prev_log_term: Term(prev.1),
+            entries,
+            leader_commit: LogIndex(leader_commit),
+        }
+    }
+
+    #[test]
+    fn test_append_entries_advances_commit_and_applied() {
+        let mut node = RaftNode::new(NodeId("n1".into()), vec![NodeId("n2".into())]);
+        let res = node.handle_append_entries(append((0, 0), vec![entry(1, 1), entry(1, 2), entry(1, 3)], 1));
+        assert!(res.success);
+        assert_eq!(res.match_index, LogIndex(3));
+        assert_eq!(node.last_applied_index(), LogIndex(1));
+        // Applied entries reach the state machine in order, once each
+        assert_eq!(applied_commands(&mut node), vec![vec![1]]);
+
+        // A gap is refused; the commit can't pass what the request carried
+        let res = node.handle_append_entries(append((5, 1), vec![entry(1, 6)], 6));
+        assert!(!res.success);
+        let res = node.handle_append_entries(append((1, 1), vec![entry(1, 2)], 9));
+        assert!(res.success);
+        assert_eq!(node.last_applied_index(), LogIndex(2));
+        assert_eq!(applied_commands(&mut node), vec![vec![2]]);
+        assert_eq!(node.log.len(), 3);
+
+        // A conflicting entry replaces the rest of the log
+        let res = node.handle_append_entries(append((2, 1), vec![entry(2, 3)], 3));
+        assert!(res.success);
+        assert_eq!(node.log.len(), 3);
+        assert_eq!(node.log[2].term, Term(2));
+        assert_eq!(node.last_applied_index(), LogIndex(3));
+        assert_eq!(applied_commands(&mut node), vec![vec![3]]);
+    }
+
+    /// The commands applied since the last call, as the state machine
+    /// receives them.
+    fn applied_commands(node: &mut RaftNode) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| node.command_receiver.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_waits_for_applied_index() {
+        let node = std::sync::Arc::new(tokio::sync::Mutex::new(RaftNode::new(NodeId("n1".into()), vec![])));
+        let mut applied = node.lock().await.applied_index();
+        applied.wait_for(LogIndex(0), Duration::ZERO).await.unwrap();
+
+        let err = applied.wait_for(LogIndex(2), Duration::from_millis(20)).await.unwrap_err();
+        assert_eq!(err.to_string(), "Timed out after 20 ms waiting for index 2 to be applied (applied: 0)");
+
+        // Without a minimum the read is served at once; with one it blocks
+        // until AppendEntries brings the follower up to it
+        assert_eq!(applied.read(None, Duration::ZERO, |index| index).await.unwrap(), LogIndex(0));
+        let read = tokio::spawn(async move { applied.read(Some(LogIndex(2)), Duration::from_secs(5), |i| i).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+        node.lock().await.handle_append_entries(append((0, 0), vec![entry(1, 1)], 1));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+        node.lock().await.handle_append_entries(append((1, 1), vec![entry(1, 2), entry(1, 3)], 3));
+        assert_eq!(read.await.unwrap().unwrap(), LogIndex(3));
+        assert_eq!(applied_commands(&mut *node.lock().await), vec![vec![1], vec![2], vec![3]]);
+    }
+
     #[test]
     fn test_vote_granted() {
         let mut node = RaftNode::new(NodeId("n1".into()), vec![]);
@@ -0,0 +1,42 @@
+use super::config::ClusterConfig;
+use super::NodeId;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Where to reach every other cluster member. The node's own entry is left
+/// out, since it never sends messages to itself.
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    addresses: HashMap<NodeId, SocketAddr>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The address table for `local` as described by `config`.
+    pub fn from_config(config: &ClusterConfig, local: &NodeId) -> Self {
+        let mut transport = Self::new();
+        for peer in config.peers.iter().filter(|peer| &peer.id != local) {
+            transport.add_peer(peer.id.clone(), peer.addr);
+        }
+        transport
+    }
+
+    pub fn add_peer(&mut self, id: NodeId, addr: SocketAddr) {
+        self.addresses.insert(id, addr);
+    }
+
+    pub fn resolve(&self, id: &NodeId) -> Option<SocketAddr> {
+        self.addresses.get(id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+}
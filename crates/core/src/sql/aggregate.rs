@@ -0,0 +1,114 @@
+use crate::sql::engine::{Row, SqlValue};
+use crate::sql::expr;
+use crate::sql::functions::FunctionRegistry;
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{Expr, Function, FunctionArg, FunctionArgExpr};
+use std::cmp::Ordering;
+
+/// Whether `expr` is a call to an aggregate function, which collapses all
+/// rows of a query into one value.
+pub fn is_aggregate(expr: &Expr) -> bool {
+    matches!(expr, Expr::Function(function) if aggregate_name(function).is_some())
+}
+
+/// Whether `expr` is `COUNT(*)`, which only needs the number of rows.
+pub fn is_count_star(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Function(function)
+            if aggregate_name(function) == Some("COUNT")
+                && matches!(function.args.as_slice(), [FunctionArg::Unnamed(FunctionArgExpr::Wildcard)])
+    )
+}
+
+/// Evaluates an aggregate call over `rows`. NULL inputs are skipped.
+pub fn evaluate(expr: &Expr, rows: &[Row], functions: &FunctionRegistry) -> Result<SqlValue> {
+    if is_count_star(expr) {
+        return Ok(SqlValue::Integer(rows.len() as i64));
+    }
+    let function = match expr {
+        Expr::Function(function) => function,
+        _ => return Err(anyhow!("Expected an aggregate function, got {}", expr)),
+    };
+    let arg = match function.args.as_slice() {
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(arg))] => arg,
+        _ => return Err(anyhow!("{} expects a single argument", function.name)),
+    };
+
+    match aggregate_name(function) {
+        Some("COUNT") => {
+            let mut count = 0;
+            for row in rows {
+                if !matches!(expr::evaluate_with(arg, row, functions)?, SqlValue::Null) {
+                    count += 1;
+                }
+            }
+            Ok(SqlValue::Integer(count))
+        }
+        Some("AVG") => {
+            let mut sum = 0.0;
+            let mut count = 0usize;
+            for row in rows {
+                match expr::evaluate_with(arg, row, functions)? {
+                    SqlValue::Null => {}
+                    SqlValue::Integer(i) => {
+                        sum += i as f64;
+                        count += 1;
+                    }
+                    SqlValue::Decimal(d) => {
+                        sum += d;
+                        count += 1;
+                    }
+                    other => return Err(anyhow!("AVG expects numeric values, got {:?}", other)),
+                }
+            }
+            Ok(if count == 0 { SqlValue::Null } else { SqlValue::Decimal(sum / count as f64) })
+        }
+        // Stays an integer until a decimal is added, and is NULL when every
+        // input was
+        Some("SUM") => {
+            let mut sum = SqlValue::Null;
+            for row in rows {
+                sum = match (sum, expr::evaluate_with(arg, row, functions)?) {
+                    (sum, SqlValue::Null) => sum,
+                    (SqlValue::Null, value @ (SqlValue::Integer(_) | SqlValue::Decimal(_))) => value,
+                    (SqlValue::Integer(a), SqlValue::Integer(b)) => {
+                        SqlValue::Integer(a.checked_add(b).ok_or_else(|| anyhow!("integer overflow in SUM"))?)
+                    }
+                    (SqlValue::Integer(a), SqlValue::Decimal(b)) => SqlValue::Decimal(a as f64 + b),
+                    (SqlValue::Decimal(a), SqlValue::Integer(b)) => SqlValue::Decimal(a + b as f64),
+                    (SqlValue::Decimal(a), SqlValue::Decimal(b)) => SqlValue::Decimal(a + b),
+                    (_, other) => return Err(anyhow!("SUM expects numeric values, got {:?}", other)),
+                };
+            }
+            Ok(sum)
+        }
+        // Any values that compare with each other, e.g. strings or timestamps
+        Some(name @ ("MIN" | "MAX")) => {
+            let wanted = if name == "MIN" { Ordering::Less } else { Ordering::Greater };
+            let mut best = SqlValue::Null;
+            for row in rows {
+                let value = expr::evaluate_with(arg, row, functions)?;
+                if matches!(value, SqlValue::Null) {
+                    continue;
+                }
+                if matches!(best, SqlValue::Null) || expr::compare(&value, &best)? == Some(wanted) {
+                    best = value;
+                }
+            }
+            Ok(best)
+        }
+        _ => Err(anyhow!("Unsupported aggregate function: {}", function.name)),
+    }
+}
+
+fn aggregate_name(function: &Function) -> Option<&'static str> {
+    match function.name.to_string().to_ascii_uppercase().as_str() {
+        "AVG" => Some("AVG"),
+        "COUNT" => Some("COUNT"),
+        "MAX" => Some("MAX"),
+        "MIN" => Some("MIN"),
+        "SUM" => Some("SUM"),
+        _ => None,
+    }
+}
@@ -0,0 +1,78 @@
+use crate::sql::engine::TableSchema;
+use crate::sql::session::DEFAULT_DATABASE;
+use crate::txn::wal::WalOperation;
+use std::collections::{HashMap, HashSet};
+
+/// The databases and tables the WAL's catalog entries describe, built up
+/// in log order during replay, so each row write can be checked against
+/// the tables that existed at that point in the log.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    pub databases: HashSet<String>,
+    pub schemas: HashMap<String, TableSchema>,
+    /// One past the largest table id seen, dropped tables included, so no
+    /// id is handed out twice.
+    pub next_table_id: u64,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self {
+            databases: HashSet::from([DEFAULT_DATABASE.to_string()]),
+            schemas: HashMap::new(),
+            next_table_id: 1,
+        }
+    }
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `operation` if it is a catalog change; row writes and
+    /// transaction markers leave the catalog as it is.
+    pub fn apply(&mut self, operation: &WalOperation) {
+        match operation {
+            WalOperation::CreateDatabase { name } => {
+                self.databases.insert(name.clone());
+            }
+            WalOperation::CreateTable(schema)
+            | WalOperation::AlterTable(schema)
+            | WalOperation::RenameColumn { schema, .. } => {
+                self.next_table_id = self.next_table_id.max(schema.id + 1);
+                self.schemas.insert(schema.name.clone(), schema.clone());
+            }
+            WalOperation::RenameTable { from, to } => {
+                if let Some(mut schema) = self.schemas.remove(from) {
+                    schema.name = to.clone();
+                    self.schemas.insert(to.clone(), schema);
+                }
+            }
+            WalOperation::DropTable { name, id } => {
+                self.schemas.remove(name);
+                self.next_table_id = self.next_table_id.max(id + 1);
+            }
+            WalOperation::Insert { .. }
+            | WalOperation::Delete { .. }
+            | WalOperation::Begin(_)
+            | WalOperation::Commit(_) => {}
+        }
+    }
+
+    /// Whether `operation` may be applied to storage with the catalog as it
+    /// stands: a row write must be to a table it holds, found by the name
+    /// logged or, since a transaction's writes are logged at COMMIT with the
+    /// names they were made under, by the table id its key starts with.
+    pub fn covers(&self, operation: &WalOperation) -> bool {
+        let (table, key) = match operation {
+            WalOperation::Insert { table, key, .. } | WalOperation::Delete { table, key } => (table, key),
+            _ => return true,
+        };
+        self.schemas.contains_key(table)
+            || key
+                .split_once(':')
+                .and_then(|(id, _)| id.parse::<u64>().ok())
+                .is_some_and(|id| self.schemas.values().any(|schema| schema.id == id))
+    }
+}
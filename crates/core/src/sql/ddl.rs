@@ -0,0 +1,62 @@
+use crate::sql::engine::{SqlDataType, TableSchema};
+use sqlparser::ast::Ident;
+
+/// The CREATE TABLE statement that recreates `schema`, on one line and
+/// ending in `;`. Names are always quoted, so any name the engine accepted
+/// parses back unchanged; a table in a database other than the default is
+/// qualified with it.
+pub fn create_table(schema: &TableSchema) -> String {
+    let name: Vec<String> = schema.name.split('.').map(quoted).collect();
+    let columns: Vec<String> = schema
+        .columns
+        .iter()
+        .map(|column| {
+            let mut definition = format!("{} {}", quoted(&column.name), type_sql(&column.data_type));
+            // A primary key is NOT NULL without saying so
+            if column.primary_key {
+                definition.push_str(" PRIMARY KEY");
+            } else if column.not_null {
+                definition.push_str(" NOT NULL");
+            } else if column.nullable {
+                definition.push_str(" NULL");
+            }
+            if let Some(default) = &column.default {
+                definition.push_str(&format!(" DEFAULT {}", default));
+            }
+            if column.auto_increment {
+                definition.push_str(" AUTO_INCREMENT");
+            }
+            if let Some(generated) = &column.generated {
+                definition.push_str(&format!(" GENERATED ALWAYS AS ({})", generated));
+            }
+            definition
+        })
+        .collect();
+
+    let mut sql = format!("CREATE TABLE {} ({})", name.join("."), columns.join(", "));
+    if schema.shard_count != 1 {
+        sql.push_str(&format!(" WITH (shards = {})", schema.shard_count));
+    }
+    sql.push(';');
+    sql
+}
+
+/// The CREATE DATABASE statement for `name`.
+pub fn create_database(name: &str) -> String {
+    format!("CREATE DATABASE {};", quoted(name))
+}
+
+/// A column type as written in DDL, e.g. `VARCHAR(100)`.
+pub(crate) fn type_sql(data_type: &SqlDataType) -> String {
+    match data_type {
+        SqlDataType::Integer => "INTEGER".to_string(),
+        SqlDataType::Varchar(length) => format!("VARCHAR({})", length),
+        SqlDataType::Boolean => "BOOLEAN".to_string(),
+        SqlDataType::Decimal(precision, scale) => format!("DECIMAL({}, {})", precision, scale),
+        SqlDataType::Timestamp => "TIMESTAMP".to_string(),
+    }
+}
+
+fn quoted(name: &str) -> String {
+    Ident::with_quote('"', name).to_string()
+}
@@ -1,28 +1,111 @@
-use crate::storage::bptree::BPlusTree;
-use crate::txn::wal::{WriteAheadLog, WalEntry, WalOperation};
+use crate::sql::catalog::Catalog;
+use crate::sql::session::{LimitMode, ScaleOverflow, Session, StringOverflow, DEFAULT_DATABASE};
+use crate::sql::plan::{self, AccessPath, PlanCache};
+use crate::sql::explain::QueryStats;
+use crate::sql::functions::{self, Arity, FunctionRegistry};
+use crate::sql::ident::IdentifierCase;
+use crate::sql::memory::{self, MemoryBudget};
+use crate::sql::output::{self, Output, ResultSet};
+use crate::sql::prepared::{Parameterized, PreparedStatement};
+pub use crate::sql::row::Row;
+use crate::sql::row::fits_type;
+use crate::sql::{aggregate, ddl, expr, ident, information_schema, prepared, subquery, values};
+use crate::storage::bptree::{self, BPlusTree};
+use crate::storage::codec::{self, RowFormat};
+use crate::storage::shard;
+use crate::txn::wal::{WriteAheadLog, WalOperation};
+use crate::txn::{Mutation, Overlay, Transaction, TransactionLimits, TransactionRegistry};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use sqlparser::ast::{
-    ColumnDef, DataType, Expr, Ident, Query, SelectItem, SetExpr, Statement, TableFactor, Value, ObjectName, ColumnOption, ExactNumberInfo,
+    AlterTableOperation, ColumnDef, DataType, Expr, Ident, Query, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value, ObjectName, ColumnOption, ExactNumberInfo, SqlOption,
+    GroupByExpr, Select, ShowCreateObject, BinaryOperator, ObjectType, JoinConstraint, JoinOperator, Visit,
 };
+use sqlparser::ast::{visit_expressions, visit_expressions_mut};
 use sqlparser::dialect::GenericDialect;
-use sqlparser::parser::Parser;
-use std::collections::HashMap;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// A statement's output, rendered the way clients display it.
+pub type ExecutionResult = String;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TableSchema {
     pub name: String,
     pub columns: Vec<Column>,
+    /// Bumped by every ALTER TABLE. Rows record the version they were
+    /// written under, so older rows can be conformed when read.
+    pub version: u32,
+    /// Shards the table's keys are spread over, set with
+    /// `CREATE TABLE ... WITH (shards = N)`; 1 unless given.
+    pub shard_count: u32,
+    /// Assigned at creation and never reused. Storage keys are prefixed
+    /// with it rather than the name, so renaming a table moves no rows.
+    pub id: u64,
+}
+
+impl TableSchema {
+    /// Prefix of every storage key holding one of the table's rows.
+    pub fn key_prefix(&self) -> String {
+        format!("{}:", self.id)
+    }
+
+    /// Parses the expressions of the generated columns of a schema just
+    /// read back from the WAL.
+    pub fn parse_generated(&mut self) -> Result<()> {
+        for column in &mut self.columns {
+            if let Some(generated) = &column.generated {
+                column.generated_expr = Some(parse_generated_expr(generated)?);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub data_type: SqlDataType,
+    /// Declared `NULL`. A column declared neither `NULL` nor `NOT NULL`
+    /// accepts NULL too; see `accepts_null`.
     pub nullable: bool,
     pub primary_key: bool,
+    pub auto_increment: bool,
+    /// Expression for a `GENERATED ALWAYS AS (...)` column, computed from the
+    /// other columns when a row is written. Such columns can't be written
+    /// directly.
+    pub generated: Option<String>,
+    /// `generated` parsed, filled in when the schema is created or loaded
+    /// so rows written don't each parse it again. Not stored.
+    #[serde(skip)]
+    pub generated_expr: Option<Expr>,
+    /// SQL text of the column's `DEFAULT`, a literal or `CURRENT_TIMESTAMP`,
+    /// evaluated for each INSERT that leaves the column out.
+    pub default: Option<String>,
+    /// Schema version that added the column. Rows written before it read
+    /// the column as NULL, even if they still hold a value for an earlier,
+    /// dropped column of the same name.
+    pub added_in: u32,
+    /// Declared `NOT NULL`, so an INSERT must give it a non-NULL value.
+    /// Catalogs written before this was recorded didn't enforce NOT NULL,
+    /// and read it as false.
+    pub not_null: bool,
+}
+
+impl Column {
+    /// Whether the column may hold NULL: it is neither a key nor declared
+    /// `NOT NULL`.
+    pub fn accepts_null(&self) -> bool {
+        !self.primary_key && !self.not_null
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,11 +117,6 @@ pub enum SqlDataType {
     Timestamp,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Row {
-    pub values: HashMap<String, SqlValue>,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SqlValue {
     Integer(i64),
@@ -49,251 +127,1949 @@ pub enum SqlValue {
     Null,
 }
 
+/// Outcome of `SqlEngine::check_consistency`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// Tables in the catalog.
+    pub tables: usize,
+    /// Stored rows of those tables.
+    pub rows: usize,
+    /// Rows that don't decode or don't match their table's schema.
+    pub bad_rows: usize,
+    /// Stored keys that belong to no table in the catalog.
+    pub orphan_keys: usize,
+    /// The first problems found, one line each.
+    pub problems: Vec<String>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SqlEngine {
     storage: Arc<RwLock<BPlusTree>>,
     wal: Arc<RwLock<WriteAheadLog>>,
+    /// Keyed by qualified table name, see `resolve_table`.
     schemas: Arc<RwLock<HashMap<String, TableSchema>>>,
+    databases: Arc<RwLock<HashSet<String>>>,
+    sequences: Arc<RwLock<HashMap<String, i64>>>,
+    prepared: Arc<RwLock<HashMap<String, Parameterized>>>,
+    plans: Arc<RwLock<PlanCache>>,
+    next_table_id: Arc<AtomicU64>,
+    row_format: RowFormat,
+    identifier_case: IdentifierCase,
+    functions: Arc<FunctionRegistry>,
+    /// Writes of the transactions sessions have open, by id.
+    transactions: Arc<Mutex<TransactionRegistry<Transaction>>>,
 }
 
 impl SqlEngine {
     pub fn new(storage: BPlusTree, wal: WriteAheadLog) -> Self {
+        Self::with_shared(Arc::new(RwLock::new(storage)), Arc::new(RwLock::new(wal)))
+    }
+
+    /// Builds an engine over storage and WAL handles owned elsewhere, so
+    /// writes made through the engine are visible to the other holders.
+    pub fn with_shared(storage: Arc<RwLock<BPlusTree>>, wal: Arc<RwLock<WriteAheadLog>>) -> Self {
         Self {
-            storage: Arc::new(RwLock::new(storage)),
-            wal: Arc::new(RwLock::new(wal)),
+            storage,
+            wal,
             schemas: Arc::new(RwLock::new(HashMap::new())),
+            databases: Arc::new(RwLock::new(HashSet::from([DEFAULT_DATABASE.to_string()]))),
+            sequences: Arc::new(RwLock::new(HashMap::new())),
+            prepared: Arc::new(RwLock::new(HashMap::new())),
+            plans: Arc::new(RwLock::new(PlanCache::new())),
+            next_table_id: Arc::new(AtomicU64::new(1)),
+            row_format: RowFormat::default(),
+            identifier_case: IdentifierCase::default(),
+            functions: Arc::new(FunctionRegistry::new()),
+            transactions: Arc::new(Mutex::new(TransactionRegistry::new(TransactionLimits::default()))),
+        }
+    }
+
+    /// Sets the encoding for rows written from now on. Rows already stored
+    /// keep their encoding and remain readable.
+    pub fn set_row_format(&mut self, format: RowFormat) {
+        self.row_format = format;
+    }
+
+    /// Sets how names in statements parsed from now on are folded. Names
+    /// already in the catalog keep the case they were created with.
+    pub fn set_identifier_case(&mut self, case: IdentifierCase) {
+        self.identifier_case = case;
+    }
+
+    /// Sets the limits on open transactions. Transactions already open are
+    /// dropped, so call it before any is begun; clones of the engine taken
+    /// before the call keep the old limits.
+    pub fn set_transaction_limits(&mut self, limits: TransactionLimits) {
+        self.transactions = Arc::new(Mutex::new(TransactionRegistry::new(limits)));
+    }
+
+    /// Makes a scalar function callable from this engine's queries,
+    /// replacing a built-in or earlier registration of the same name.
+    /// Clones of the engine taken before the call don't see it.
+    pub fn register_function<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&[SqlValue]) -> Result<SqlValue> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.functions).register(name, arity, function);
+    }
+
+    /// The shard of `table` that holds the row with primary key `key`.
+    pub async fn shard_for(&self, table: &str, key: &SqlValue) -> Result<u32> {
+        let schemas = self.schemas.read().await;
+        let schema = schemas.get(table).ok_or_else(|| anyhow!("Table '{}' does not exist", table))?;
+        Ok(shard::shard_for_key(encode_key_value(key).as_bytes(), schema.shard_count))
+    }
+
+    /// Adds the databases and tables of `catalog`, as replay built it, to
+    /// the engine's.
+    pub async fn install_catalog(&self, catalog: Catalog) {
+        self.databases.write().await.extend(catalog.databases);
+        let mut schemas = self.schemas.write().await;
+        for (name, mut schema) in catalog.schemas {
+            // Parsed when the column was created, so this can't fail short
+            // of a corrupt log; rows written then report the error
+            if let Err(e) = schema.parse_generated() {
+                tracing::warn!("Table '{}': {}", name, e);
+            }
+            schemas.insert(name, schema);
+        }
+        let next_id = schemas.values().map(|schema| schema.id + 1).max().unwrap_or(1);
+        self.next_table_id.fetch_max(next_id.max(catalog.next_table_id), atomic::Ordering::Relaxed);
+    }
+
+    /// Seeds each AUTO_INCREMENT sequence from the largest value its table
+    /// holds, so ids keep counting up from there after a reopen rather
+    /// than from wherever the first insert happens to find them.
+    pub async fn seed_sequences(&self) -> Result<()> {
+        let schemas: Vec<TableSchema> = self.schemas.read().await.values().cloned().collect();
+        for schema in schemas {
+            if let Some(column) = schema.columns.iter().find(|c| c.auto_increment) {
+                let max = self.max_integer_value(&schema, &column.name).await?;
+                self.sequences.write().await.insert(schema.name.clone(), max);
+            }
+        }
+        Ok(())
+    }
+
+    /// Each table's name and id.
+    pub async fn table_ids(&self) -> Vec<(String, u64)> {
+        self.schemas.read().await.values().map(|schema| (schema.name.clone(), schema.id)).collect()
+    }
+
+    /// DDL recreating every database and table, one statement per line:
+    /// the databases by name, then the tables in the order they were
+    /// created. Rows aren't included.
+    pub async fn dump_schema(&self) -> Result<ExecutionResult> {
+        let mut databases: Vec<String> =
+            self.databases.read().await.iter().filter(|name| *name != DEFAULT_DATABASE).cloned().collect();
+        databases.sort();
+        let schemas = self.schemas.read().await;
+        let mut tables: Vec<&TableSchema> = schemas.values().collect();
+        tables.sort_by_key(|schema| schema.id);
+
+        let mut statements: Vec<String> = databases.iter().map(|name| ddl::create_database(name)).collect();
+        statements.extend(tables.into_iter().map(ddl::create_table));
+        Ok(statements.join("\n"))
+    }
+
+    /// Checks a table's stored rows while it stays online: the part of the
+    /// tree holding them must be structurally sound, and each row must
+    /// decode, conform to the schema, hold values of its columns' types and
+    /// be keyed by its primary key. Holds the storage read lock, so reads
+    /// proceed and writes wait. Lists at most `max_problems` problems, or a
+    /// single `ok`.
+    pub async fn check_table(&self, session: &Session, name: &ObjectName, max_problems: usize) -> Result<ResultSet> {
+        let table_name = self.resolve_table(session, &self.identifier_case.fold_name(name)).await?;
+        let schema = self
+            .schemas
+            .read()
+            .await
+            .get(&table_name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?;
+
+        let storage = self.storage.read().await;
+        let mut problems = Vec::new();
+        if let Err(e) = storage.verify_prefix(&schema.key_prefix()) {
+            problems.push(e.to_string());
+        }
+        let scan = storage.for_each_prefix(&schema.key_prefix(), |key, data| {
+            if problems.len() >= max_problems {
+                return Err(anyhow!("Problem limit reached"));
+            }
+            if let Err(e) = check_row(key, data, &schema) {
+                problems.push(e.to_string());
+            }
+            Ok(())
+        });
+        if let Err(e) = scan {
+            if problems.len() < max_problems {
+                problems.push(e.to_string());
+            }
+        }
+        problems.truncate(max_problems);
+
+        if problems.is_empty() {
+            problems.push("ok".to_string());
+        }
+        let rows: Vec<Vec<SqlValue>> = problems.into_iter().map(|p| vec![SqlValue::Varchar(p)]).collect();
+        Ok(ResultSet::new(vec!["status".to_string()], rows))
+    }
+
+    /// Checks all of storage against the catalog: the tree must be
+    /// structurally sound, every key must belong to a table, and every row
+    /// must pass `CHECK TABLE`'s checks. Unlike `check_table` it always
+    /// scans everything, counting problems past the first `max_problems`
+    /// it describes.
+    pub async fn check_consistency(&self, max_problems: usize) -> Result<ConsistencyReport> {
+        let schemas = self.schemas.read().await;
+        let tables: HashMap<String, &TableSchema> =
+            schemas.values().map(|schema| (schema.key_prefix(), schema)).collect();
+        let storage = self.storage.read().await;
+        let mut report = ConsistencyReport { tables: tables.len(), ..Default::default() };
+        let problem = |report: &mut ConsistencyReport, description: String| {
+            if report.problems.len() < max_problems {
+                report.problems.push(description);
+            }
+        };
+
+        if let Err(e) = storage.verify() {
+            problem(&mut report, e.to_string());
+        }
+        storage.for_each_prefix("", |key, data| {
+            match key.find(':').and_then(|end| tables.get(&key[..=end])) {
+                Some(schema) => {
+                    report.rows += 1;
+                    if let Err(e) = check_row(key, data, schema) {
+                        report.bad_rows += 1;
+                        problem(&mut report, e.to_string());
+                    }
+                }
+                None => {
+                    report.orphan_keys += 1;
+                    problem(&mut report, format!("Key '{}' belongs to no table", key));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
+    /// Executes a statement in a fresh session on the default database. A
+    /// transaction it leaves open is rolled back, since no later statement
+    /// can end it.
+    pub async fn execute(&self, sql: &str) -> Result<ExecutionResult> {
+        let mut session = Session::new();
+        let result = self.execute_in(&mut session, sql).await;
+        self.close_session(&session).await;
+        result
+    }
+
+    /// Executes the statements in `sql`, separated by semicolons, in
+    /// `session`, which `USE` updates. They are all parsed before any runs,
+    /// so a syntax error anywhere runs none of them. They then run in
+    /// order, each committed on its own, stopping at the first that fails.
+    /// The result is each statement's result, one after another.
+    pub async fn execute_in(&self, session: &mut Session, sql: &str) -> Result<ExecutionResult> {
+        Ok(output::render(&self.execute_output_in(session, sql).await?))
+    }
+
+    /// Like `execute_in`, returning each statement's output unrendered.
+    pub async fn execute_output_in(&self, session: &mut Session, sql: &str) -> Result<Vec<Output>> {
+        let dialect = GenericDialect {};
+        let mut tokens = Tokenizer::new(&dialect, sql).tokenize().map_err(|e| anyhow!("Parse error: {}", e))?;
+        self.identifier_case.fold(&mut tokens);
+
+        let mut statements = Vec::new();
+        for tokens in tokens.split(|token| *token == Token::SemiColon) {
+            if tokens.iter().all(|token| matches!(token, Token::Whitespace(_))) {
+                continue;
+            }
+            statements.extend(parse_batched(tokens)?);
+        }
+
+        if statements.is_empty() {
+            return Ok(vec![Output::Message("No statement to execute".to_string())]);
+        }
+
+        let count = statements.len();
+        let mut outputs = Vec::with_capacity(count);
+        for (i, statement) in statements.iter().enumerate() {
+            session.check_cancelled()?;
+            let result = match statement {
+                Batched::Statement(statement) => self.execute_statement_output_in(session, statement).await,
+                Batched::DefaultValuesInsert((table_name, returning)) => {
+                    self.execute_insert(session, table_name, &[], None, returning.as_deref()).await
+                }
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(e) if count == 1 => return Err(e),
+                Err(e) => return Err(anyhow!("Statement {} of {} failed: {}", i + 1, count, e)),
+            };
+            outputs.push(result);
         }
+        Ok(outputs)
     }
 
-    pub async fn execute(&self, sql: &str) -> Result<String> {
+    /// Parses `sql`, one statement with `?` or `$n` placeholders, once, to
+    /// be run with different values by `PreparedStatement::execute`. Each
+    /// parameter inserted into a column or compared with one must then be
+    /// a value of that column's type; the rest are unchecked, as are all of
+    /// a statement on a table that doesn't exist yet.
+    pub async fn prepare(&self, sql: &str) -> Result<PreparedStatement> {
         let dialect = GenericDialect {};
-        let ast = Parser::parse_sql(&dialect, sql)
+        let mut tokens = Tokenizer::new(&dialect, sql).tokenize().map_err(|e| anyhow!("Parse error: {}", e))?;
+        self.identifier_case.fold(&mut tokens);
+        let mut statements = Parser::new(&dialect)
+            .with_tokens(tokens)
+            .parse_statements()
             .map_err(|e| anyhow!("Parse error: {}", e))?;
+        if statements.len() != 1 {
+            return Err(anyhow!("Expected one statement to prepare, got {}", statements.len()));
+        }
+        let parameterized = self.parameterize(&Session::new(), statements.remove(0)).await?;
+        Ok(PreparedStatement::new(self.clone(), parameterized))
+    }
+
+    /// `statement` with its placeholders numbered, and each parameter
+    /// matched to the column whose type it must have among the tables the
+    /// statement names, for `prepare` and SQL `PREPARE` alike.
+    async fn parameterize(&self, session: &Session, mut statement: Statement) -> Result<Parameterized> {
+        prepared::number_placeholders(&mut statement);
 
-        if ast.is_empty() {
-            return Ok("No statement to execute".to_string());
+        let mut tables = Vec::new();
+        for (name, alias) in prepared::statement_tables(&statement) {
+            let Ok(table) = self.resolve_table(session, name).await else {
+                continue;
+            };
+            if let Some(schema) = self.schemas.read().await.get(&table) {
+                // A table is referred to by its alias if it has one, else as written or by its bare name
+                let names = match alias {
+                    Some(alias) => vec![alias.value.clone()],
+                    None => vec![name.to_string(), name.0.last().map(|i| i.value.clone()).unwrap_or_default()],
+                };
+                tables.push((names, schema.clone()));
+            }
         }
 
-        match &ast[0] {
-            Statement::CreateTable { name, columns, .. } => {
-                self.execute_create_table(name, columns).await
+        // Only an INSERT's own table has ordinary columns to fill
+        let ordinary: Vec<String> = match (&statement, tables.first()) {
+            (Statement::Insert { .. }, Some((_, schema))) => {
+                schema.columns.iter().filter(|c| c.generated.is_none()).map(|c| c.name.clone()).collect()
             }
-            Statement::Insert {
-                table_name,
-                columns,
-                source,
+            _ => Vec::new(),
+        };
+        let columns = prepared::parameter_columns(&statement, &ordinary)
+            .into_iter()
+            .map(|name| name.and_then(|name| prepared::resolve_column(&name, &tables)).cloned())
+            .collect();
+        Ok(Parameterized::new(statement, columns))
+    }
+
+    /// Executes an already-parsed statement in a fresh session, for callers
+    /// that build or rewrite the AST themselves. Like `execute`, it rolls
+    /// back a transaction it leaves open.
+    pub async fn execute_statement(&self, statement: &Statement) -> Result<ExecutionResult> {
+        let mut session = Session::new();
+        let result = self.execute_statement_in(&mut session, statement).await;
+        self.close_session(&session).await;
+        result
+    }
+
+    pub async fn execute_statement_in(&self, session: &mut Session, statement: &Statement) -> Result<ExecutionResult> {
+        Ok(self.execute_statement_output_in(session, statement).await?.render())
+    }
+
+    /// Like `execute_statement_in`, returning the output unrendered.
+    pub async fn execute_statement_output_in(&self, session: &mut Session, statement: &Statement) -> Result<Output> {
+        let message = match statement {
+            Statement::Insert { .. } => {
+                let insert = InsertParts::from_statement(statement)?;
+                return self
+                    .execute_insert(session, insert.table_name, insert.columns, insert.source, insert.returning)
+                    .await;
+            }
+            Statement::Delete {
+                from,
+                selection,
+                returning,
                 ..
-            } => self.execute_insert(table_name, columns, source).await,
-            Statement::Query(query) => self.execute_select(query).await,
+            } => return self.execute_delete(session, from, selection.as_ref(), returning.as_deref()).await,
+            Statement::Query(query) => return self.execute_select(session, query).await,
+            Statement::Explain { analyze, statement, .. } => {
+                return self.execute_explain(session, *analyze, statement).await.map(Output::Rows)
+            }
+            Statement::Execute { name, parameters } => return self.execute_prepared(session, name, parameters).await,
+            _ => self.execute_message_statement(session, statement).await?,
+        };
+        Ok(Output::Message(message))
+    }
+
+    /// Runs a statement whose output is a message rather than rows.
+    async fn execute_message_statement(&self, session: &mut Session, statement: &Statement) -> Result<String> {
+        if session.transaction().is_some()
+            && matches!(
+                statement,
+                Statement::CreateDatabase { .. }
+                    | Statement::CreateTable { .. }
+                    | Statement::AlterTable { .. }
+                    | Statement::Drop { .. }
+            )
+        {
+            return Err(anyhow!("Only INSERT and DELETE can write inside a transaction; COMMIT or ROLLBACK first"));
+        }
+        match statement {
+            Statement::StartTransaction { modes, .. } => {
+                if !modes.is_empty() {
+                    return Err(anyhow!("Transaction modes are not supported"));
+                }
+                self.execute_begin(session).await
+            }
+            Statement::Commit { chain: false } => self.execute_commit(session).await,
+            Statement::Rollback { chain: false } => self.execute_rollback(session).await,
+            Statement::CreateDatabase { db_name, if_not_exists, .. } => {
+                self.execute_create_database(db_name, *if_not_exists).await
+            }
+            Statement::Use { db_name } => {
+                if !self.databases.read().await.contains(&db_name.value) {
+                    return Err(anyhow!("Database '{}' does not exist", db_name));
+                }
+                session.set_database(db_name.value.clone());
+                Ok(format!("Using database '{}'", db_name))
+            }
+            Statement::CreateTable { name, columns, with_options, .. } => {
+                self.execute_create_table(session, name, columns, with_options).await
+            }
+            Statement::AlterTable { name, operations, .. } => {
+                self.execute_alter_table(session, name, operations).await
+            }
+            Statement::Drop { object_type: ObjectType::Table, if_exists, names, .. } => {
+                self.execute_drop_table(session, names, *if_exists).await
+            }
+            Statement::SetVariable { variable, value, .. } => Self::execute_set(session, variable, value),
+            Statement::ShowCreate { obj_type: ShowCreateObject::Table, obj_name } => {
+                let table_name = self.resolve_table(session, obj_name).await?;
+                let schemas = self.schemas.read().await;
+                let schema = schemas
+                    .get(&table_name)
+                    .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?;
+                Ok(ddl::create_table(schema))
+            }
+            Statement::Prepare { name, statement, .. } => self.execute_prepare(session, name, statement).await,
+            Statement::Deallocate { name, .. } => {
+                let mut prepared = self.prepared.write().await;
+                prepared
+                    .remove(&name.value)
+                    .ok_or_else(|| anyhow!("Prepared statement '{}' does not exist", name))?;
+                Ok(format!("Prepared statement '{}' deallocated", name))
+            }
             _ => Err(anyhow!("Unsupported statement type")),
         }
     }
 
+    async fn execute_begin(&self, session: &mut Session) -> Result<String> {
+        let mut transactions = self.transactions.lock().await;
+        // The session may still name one that was rolled back for idling
+        // or whose commit failed
+        if let Some(id) = session.transaction().filter(|&id| transactions.is_open(id)) {
+            return Err(anyhow!("Transaction {} is already open", id));
+        }
+        if let Some(stale) = session.transaction() {
+            transactions.forget(stale);
+        }
+        let id = transactions.begin(Transaction::new(), Instant::now())?;
+        session.set_transaction(Some(id));
+        Ok(format!("Transaction {} started", id))
+    }
+
+    /// Logs the session's transaction's writes to the WAL as one
+    /// transaction and applies them. The session leaves the transaction
+    /// whether or not the commit succeeds.
+    async fn execute_commit(&self, session: &mut Session) -> Result<String> {
+        let id = session.transaction().ok_or_else(|| anyhow!("No transaction is open"))?;
+        session.set_transaction(None);
+        let transaction = self.transactions.lock().await.finish(id, Instant::now())?;
+        let writes = transaction.operations().len();
+        if !transaction.is_empty() {
+            self.check_log_and_apply(
+                transaction.operations().to_vec(),
+                |storage| transaction.check(storage),
+                |storage, ()| transaction.apply(storage),
+            )
+            .await?;
+        }
+        Ok(format!("Transaction {} committed with {} write(s)", id, writes))
+    }
+
+    async fn execute_rollback(&self, session: &mut Session) -> Result<String> {
+        let id = session.transaction().ok_or_else(|| anyhow!("No transaction is open"))?;
+        session.set_transaction(None);
+        let mut transactions = self.transactions.lock().await;
+        if !transactions.is_open(id) {
+            transactions.forget(id);
+            return Ok(format!("Transaction {} was already rolled back", id));
+        }
+        let writes = transactions.finish(id, Instant::now())?.operations().len();
+        Ok(format!("Transaction {} rolled back, discarding {} write(s)", id, writes))
+    }
+
+    /// Drops what `session` left open, for a connection that went away: an
+    /// open transaction is rolled back.
+    pub async fn close_session(&self, session: &Session) {
+        if let Some(id) = session.transaction() {
+            self.transactions.lock().await.forget(id);
+        }
+    }
+
+    async fn execute_prepare(&self, session: &Session, name: &Ident, statement: &Statement) -> Result<String> {
+        if matches!(statement, Statement::Prepare { .. } | Statement::Execute { .. } | Statement::Deallocate { .. }) {
+            return Err(anyhow!("Cannot prepare a {} statement", statement.to_string().split_whitespace().next().unwrap_or("")));
+        }
+
+        let parameterized = self.parameterize(session, statement.clone()).await?;
+        let params = parameterized.parameter_count();
+        self.prepared.write().await.insert(name.value.clone(), parameterized);
+        Ok(format!("Statement '{}' prepared with {} parameter(s)", name, params))
+    }
+
+    /// Runs a statement SQL `PREPARE` named, its parameters type-checked
+    /// the way `PreparedStatement::execute` checks them.
+    async fn execute_prepared(&self, session: &mut Session, name: &Ident, parameters: &[Expr]) -> Result<Output> {
+        let values = parameters
+            .iter()
+            .map(|parameter| expr::literal_to_sql_value(&prepared::parameter_value(parameter)?))
+            .collect::<Result<Vec<_>>>()?;
+        let statement = {
+            let prepared = self.prepared.read().await;
+            prepared
+                .get(&name.value)
+                .ok_or_else(|| anyhow!("Prepared statement '{}' does not exist", name))?
+                .bind(&values)?
+        };
+
+        Box::pin(self.execute_statement_output_in(session, &statement)).await
+    }
+
+    async fn execute_create_database(&self, db_name: &ObjectName, if_not_exists: bool) -> Result<String> {
+        let name = match db_name.0.as_slice() {
+            [ident] => ident.value.clone(),
+            _ => return Err(anyhow!("Invalid database name '{}'", db_name)),
+        };
+        ident::validate("database", &name)?;
+        if name.eq_ignore_ascii_case(information_schema::DATABASE) {
+            return Err(anyhow!("Database name '{}' is reserved", name));
+        }
+
+        let mut databases = self.databases.write().await;
+        if databases.contains(&name) {
+            if if_not_exists {
+                return Ok(format!("Database '{}' already exists", name));
+            }
+            return Err(anyhow!("Database '{}' already exists", name));
+        }
+
+        self.log_and_apply(WalOperation::CreateDatabase { name: name.clone() }, |_| Ok(())).await?;
+
+        databases.insert(name.clone());
+        Ok(format!("Database '{}' created successfully\n", name))
+    }
+
+    /// Resolves a table reference to its qualified name, which keys both the
+    /// schema catalog and the table's rows in storage. Unqualified names
+    /// resolve in the session's database; tables in the default database
+    /// keep their bare name.
+    async fn resolve_table(&self, session: &Session, name: &ObjectName) -> Result<String> {
+        let (database, table) = match name.0.as_slice() {
+            [table] => (session.database(), table.value.as_str()),
+            [database, table] => (database.value.as_str(), table.value.as_str()),
+            _ => return Err(anyhow!("Invalid table name '{}'", name)),
+        };
+        if !self.databases.read().await.contains(database) {
+            return Err(anyhow!("Database '{}' does not exist", database));
+        }
+        if database == DEFAULT_DATABASE {
+            Ok(table.to_string())
+        } else {
+            Ok(format!("{}.{}", database, table))
+        }
+    }
+
     pub async fn execute_create_table(
         &self,
+        session: &Session,
         table_name: &ObjectName,
         columns: &[ColumnDef],
+        options: &[SqlOption],
     ) -> Result<String> {
-        let name = table_name.to_string();
-        println!("[ENGINE] Creating table '{}'", name);
+        if let Some(table) = table_name.0.last() {
+            ident::validate("table", &table.value)?;
+        }
+        let name = self.resolve_table(session, table_name).await?;
+        if self.schemas.read().await.contains_key(&name) {
+            return Err(anyhow!("Table '{}' already exists", name));
+        }
+        let shard_count = shard_count(options)?;
+        tracing::debug!(table = %name, "Creating table");
     
         let mut schema_columns = Vec::new();
     
         for col in columns {
-            let column = Column {
-                name: col.name.to_string(),
-                data_type: self.convert_data_type(&col.data_type)?,
-                nullable: col.options.iter().any(|opt| matches!(opt.option, ColumnOption::Null)),
-                primary_key: col.options.iter().any(|opt| matches!(opt.option, ColumnOption::Unique { is_primary: true })),
-            };
+            let column = self.column_from_def(col)?;
     
-            println!(
-                " - Column: {}, Type: {:?}, PK: {}, Nullable: {}",
-                column.name, column.data_type, column.primary_key, column.nullable
+            tracing::debug!(
+                column = %column.name,
+                data_type = ?column.data_type,
+                primary_key = column.primary_key,
+                nullable = column.nullable,
+                "Column definition"
             );
     
             schema_columns.push(column);
         }
     
+        // Generated columns may only refer to the table's ordinary columns
+        for column in &schema_columns {
+            if let Some(generated) = &column.generated {
+                let expr = parse_generated_expr(generated)?;
+                let flow = visit_expressions(&expr, |e| match e {
+                    Expr::Identifier(ident)
+                        if !schema_columns.iter().any(|c| c.name == ident.value && c.generated.is_none()) =>
+                    {
+                        ControlFlow::Break(ident.value.clone())
+                    }
+                    _ => ControlFlow::Continue(()),
+                });
+                if let ControlFlow::Break(reference) = flow {
+                    return Err(anyhow!(
+                        "Generated column '{}' must refer to ordinary columns of the table, not '{}'",
+                        column.name,
+                        reference
+                    ));
+                }
+            }
+        }
+
         let schema = TableSchema {
             name: name.clone(),
             columns: schema_columns,
+            version: 1,
+            shard_count,
+            id: self.next_table_id.fetch_add(1, atomic::Ordering::Relaxed),
         };
     
         // ✅ Write to WAL
-        let wal_entry = WalEntry {
-            id: uuid::Uuid::new_v4(),
-            timestamp: chrono::Utc::now(),
-            operation: WalOperation::CreateTable(schema.clone()),
-        };
-    
-        {
-            let mut wal = self.wal.write().await;
-            wal.append(&wal_entry).await?;
-        }
+        self.log_and_apply(WalOperation::CreateTable(schema.clone()), |_| Ok(())).await?;
     
         // ✅ Update in-memory schema
         {
             let mut schemas = self.schemas.write().await;
-            schemas.insert(name.clone(), schema);
+            schemas.insert(name, schema);
         }
     
-        Ok(format!("Table '{}' created successfully\n", name))
+        Ok(format!("Table '{}' created successfully\n", table_name))
     }    
 
-    async fn execute_insert(
-        &self,
-        table_name: &sqlparser::ast::ObjectName,
-        columns: &[Ident],
-        source: &Query,
-    ) -> Result<String> {
-        let table_name = table_name.to_string();
-        
-        // Get table schema
-        let schema = {
-            let schemas = self.schemas.read().await;
-            schemas.get(&table_name)
-                .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?
-                .clone()
+    fn column_from_def(&self, col: &ColumnDef) -> Result<Column> {
+        ident::validate("column", &col.name.value)?;
+        let declared_null = col.options.iter().any(|opt| matches!(opt.option, ColumnOption::Null));
+        let not_null = col.options.iter().any(|opt| matches!(opt.option, ColumnOption::NotNull));
+        let primary_key = col.options.iter().any(|opt| matches!(opt.option, ColumnOption::Unique { is_primary: true }));
+        let column = Column {
+            name: col.name.value.clone(),
+            data_type: self.convert_data_type(&col.data_type)?,
+            nullable: declared_null,
+            primary_key,
+            auto_increment: col.options.iter().any(|opt| Self::is_auto_increment_option(&opt.option)),
+            generated: col.options.iter().find_map(|opt| match &opt.option {
+                ColumnOption::Generated { generation_expr: Some(expr), .. } => Some(expr.to_string()),
+                _ => None,
+            }),
+            generated_expr: col.options.iter().find_map(|opt| match &opt.option {
+                ColumnOption::Generated { generation_expr: Some(expr), .. } => Some(expr.clone()),
+                _ => None,
+            }),
+            default: col.options.iter().find_map(|opt| match &opt.option {
+                ColumnOption::Default(expr) => Some(expr.to_string()),
+                _ => None,
+            }),
+            added_in: 1,
+            not_null,
         };
 
-        // Parse values from INSERT statement
-        let values = self.extract_insert_values(source)?;
-        let column_names: Vec<String> = columns.iter().map(|c| c.to_string()).collect();
-        
-        let mut rows_inserted = 0;
-        for value_row in values {
-            let mut row = Row {
-                values: HashMap::new(),
-            };
-
-            for (i, value) in value_row.iter().enumerate() {
-                let column_name = if i < column_names.len() {
-                    column_names[i].clone()
-                } else {
-                    return Err(anyhow!("Too many values provided"));
-                };
-
-                let sql_value = self.convert_value_to_sql_value(value)?;
-                row.values.insert(column_name, sql_value);
+        // A primary key identifies its row, so it is never NULL
+        if column.primary_key && column.nullable {
+            return Err(anyhow!("Primary key column '{}' cannot be NULL", column.name));
+        }
+        if not_null && declared_null {
+            return Err(anyhow!("Column '{}' cannot be both NULL and NOT NULL", column.name));
+        }
+        if column.auto_increment && !matches!(column.data_type, SqlDataType::Integer) {
+            return Err(anyhow!("AUTO_INCREMENT column '{}' must be an INTEGER", column.name));
+        }
+        if column.generated.is_some() && (column.primary_key || column.auto_increment) {
+            return Err(anyhow!("Generated column '{}' cannot be a key or AUTO_INCREMENT", column.name));
+        }
+        if let Some(default) = &column.default {
+            if column.generated.is_some() || column.auto_increment {
+                return Err(anyhow!("Generated or AUTO_INCREMENT column '{}' cannot have a DEFAULT", column.name));
             }
-
-            // Generate key for the row (using primary key if available)
-            let key = self.generate_row_key(&table_name, &row, &schema)?;
-
-            // Write to WAL first
-            let wal_entry = WalEntry {
-                id: uuid::Uuid::new_v4(),
-                timestamp: chrono::Utc::now(),
-                operation: WalOperation::Insert {
-                    table: table_name.clone(),
-                    key: key.clone(),
-                    row: row.clone(),
-                },
-            };
-            
-            {
-                let mut wal = self.wal.write().await;
-                wal.append(&wal_entry).await?;
+            // Checked once now, so a bad default fails the CREATE rather
+            // than every INSERT
+            let value = default_value(&column, default, chrono::Utc::now(), &Session::new())?;
+            if matches!(value, SqlValue::Null) && !column.accepts_null() {
+                return Err(anyhow!("NOT NULL column '{}' cannot default to NULL", column.name));
             }
+        }
+        Ok(column)
+    }
 
-            // Insert into storage
-            {
-                let mut storage = self.storage.write().await;
-                storage.insert(key, bincode::serialize(&row)?)?;
+    async fn execute_alter_table(
+        &self,
+        session: &Session,
+        table_name: &ObjectName,
+        operations: &[AlterTableOperation],
+    ) -> Result<String> {
+        let name = self.resolve_table(session, table_name).await?;
+        match operations {
+            [AlterTableOperation::RenameTable { table_name: new_name }] => {
+                return self.rename_table(session, name, new_name).await;
             }
-
-            rows_inserted += 1;
+            [AlterTableOperation::RenameColumn { old_column_name, new_column_name }] => {
+                return self.rename_column(name, &old_column_name.value, &new_column_name.value).await;
+            }
+            _ => {}
         }
 
-        Ok(format!("{} row(s) inserted", rows_inserted))
-    }
+        let mut schemas = self.schemas.write().await;
+        let mut schema = schemas
+            .get(&name)
+            .ok_or_else(|| anyhow!("Table '{}' does not exist", name))?
+            .clone();
 
-    async fn execute_select(&self, query: &Query) -> Result<String> {
-        match *query.body {
-            SetExpr::Select(ref select) => {
-
-                // Handle simple constant selects like `SELECT 1;` or `SELECT 'hello';`
-                if select.from.is_empty() && select.projection.len() == 1 {
-                    if let SelectItem::UnnamedExpr(expr) = &select.projection[0] {
-                        if let Expr::Value(val) = expr {
-                            let output = format!("?column?\n{}\n(1 row)\n", self.sql_value_to_string(&self.convert_value_to_sql_value(val)?));
-                            return Ok(output);
+        let mut changed = false;
+        for operation in operations {
+            match operation {
+                AlterTableOperation::AddColumn { if_not_exists, column_def, .. } => {
+                    if schema.columns.iter().any(|c| c.name == column_def.name.value) {
+                        if *if_not_exists {
+                            continue;
+                        }
+                        return Err(anyhow!("Column '{}' already exists", column_def.name.value));
+                    }
+                    // Existing rows read the new column as NULL, so it can't
+                    // be a key, NOT NULL, defaulted or computed from the row
+                    let mut column = self.column_from_def(column_def)?;
+                    if column.primary_key || column.auto_increment || column.generated.is_some() {
+                        return Err(anyhow!(
+                            "Cannot add key, AUTO_INCREMENT or generated column '{}' to an existing table",
+                            column.name
+                        ));
+                    }
+                    if column.not_null {
+                        return Err(anyhow!("Cannot add NOT NULL column '{}' to an existing table", column.name));
+                    }
+                    if column.default.is_some() {
+                        return Err(anyhow!("Cannot add column '{}' with a DEFAULT to an existing table", column.name));
+                    }
+                    column.added_in = schema.version + 1;
+                    schema.columns.push(column);
+                    changed = true;
+                }
+                AlterTableOperation::DropColumn { column_name, if_exists, cascade } => {
+                    let column = match schema.columns.iter().find(|c| c.name == column_name.value) {
+                        Some(column) => column,
+                        None if *if_exists => continue,
+                        None => return Err(anyhow!("Column '{}' does not exist", column_name.value)),
+                    };
+                    // Row keys are built from the primary key
+                    if column.primary_key {
+                        return Err(anyhow!("Cannot drop primary key column '{}'", column.name));
+                    }
+                    let mut dependents = Vec::new();
+                    for other in &schema.columns {
+                        if let Some(generated) = &other.generated {
+                            if references_column(&parse_generated_expr(generated)?, &column.name) {
+                                dependents.push(other.name.clone());
+                            }
                         }
                     }
+                    if !dependents.is_empty() && !*cascade {
+                        return Err(anyhow!(
+                            "Cannot drop column '{}': generated column(s) {} depend on it; use CASCADE to drop them too",
+                            column.name,
+                            dependents.join(", ")
+                        ));
+                    }
+                    // Stored values are left in place and ignored on read
+                    let dropped = column.name.clone();
+                    schema.columns.retain(|c| c.name != dropped && !dependents.contains(&c.name));
+                    changed = true;
+                }
+                AlterTableOperation::RenameTable { .. } | AlterTableOperation::RenameColumn { .. } => {
+                    return Err(anyhow!("RENAME must be the only operation in an ALTER TABLE"));
                 }
+                _ => return Err(anyhow!("Unsupported ALTER TABLE operation: {}", operation)),
+            }
+        }
+        // IF [NOT] EXISTS clauses that all skipped leave the schema, and its
+        // version, as they were
+        if !changed {
+            return Ok(format!("Table '{}' altered successfully", table_name));
+        }
+        schema.version += 1;
 
-                // Extract table name
-                let table_name = match &select.from.first() {
-                    Some(table) => match &table.relation {
-                        TableFactor::Table { name, .. } => name.to_string(),
-                        _ => return Err(anyhow!("Unsupported table factor")),
-                    },
-                    None => return Err(anyhow!("No table specified")),
-                };
+        self.log_and_apply(WalOperation::AlterTable(schema.clone()), |_| Ok(())).await?;
+        self.plans.write().await.invalidate_table(&name);
+        schemas.insert(name, schema);
 
-                // Get table schema
-                let schema = {
-                    let schemas = self.schemas.read().await;
-                    schemas.get(&table_name)
-                        .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?
-                        .clone()
-                };
+        Ok(format!("Table '{}' altered successfully", table_name))
+    }
 
-                // Read from storage
-                let storage = self.storage.read().await;
-                let all_keys = storage.scan_prefix(&format!("{}:", table_name))?;
-                
-                let mut rows = Vec::new();
-                for key in all_keys {
-                    if let Some(data) = storage.get(&key)? {
-                        let row: Row = bincode::deserialize(&data)?;
-                        rows.push(row);
-                    }
-                }
+    /// Drops each of `names` and deletes its rows. Without IF EXISTS a
+    /// missing table is an error, and none of the tables are dropped.
+    async fn execute_drop_table(&self, session: &Session, names: &[ObjectName], if_exists: bool) -> Result<String> {
+        let mut tables = Vec::with_capacity(names.len());
+        for name in names {
+            tables.push(self.resolve_table(session, name).await?);
+        }
+        let mut schemas = self.schemas.write().await;
+        if let Some(missing) = tables.iter().find(|table| !schemas.contains_key(*table)).filter(|_| !if_exists) {
+            return Err(anyhow!("Table '{}' does not exist", missing));
+        }
 
-                // Apply WHERE clause if present
-                if let Some(where_clause) = &select.selection {
-                    rows = self.filter_rows(rows, where_clause)?;
+        let mut messages = Vec::with_capacity(tables.len());
+        for table in tables {
+            let schema = match schemas.get(&table) {
+                Some(schema) => schema,
+                None => {
+                    messages.push(format!("Table '{}' does not exist, skipping", table));
+                    continue;
                 }
+            };
+            let prefix = schema.key_prefix();
+            let operation = WalOperation::DropTable { name: table.clone(), id: schema.id };
+            self.log_and_apply(operation, |storage| storage.remove_prefix(&prefix).map(|_| ())).await?;
 
-                // Apply ORDER BY if present
-                if !query.order_by.is_empty() {
-                    rows = self.sort_rows(rows, &query.order_by)?;
-                }
+            self.forget_table(&mut schemas, &table).await;
+            messages.push(format!("Table '{}' dropped", table));
+        }
+        Ok(messages.join("\n"))
+    }
 
-                // Apply LIMIT if present
-                if let Some(limit) = &query.limit {
-                    if let Expr::Value(Value::Number(ref n, _)) = &limit {
-                        let limit_count = n.parse::<usize>().unwrap_or(usize::MAX);
-                        rows.truncate(limit_count);
-                    }
-                }
+    /// Renames table `name`.
+    async fn rename_table(&self, session: &Session, name: String, new_name: &ObjectName) -> Result<String> {
+        if let Some(table) = new_name.0.last() {
+            ident::validate("table", &table.value)?;
+        }
+        let to = self.resolve_table(session, new_name).await?;
+        let mut schemas = self.schemas.write().await;
+        if !schemas.contains_key(&name) {
+            return Err(anyhow!("Table '{}' does not exist", name));
+        }
+        if schemas.contains_key(&to) {
+            return Err(anyhow!("Table '{}' already exists", to));
+        }
+
+        // Rows are keyed by table id, so only the catalog changes
+        let operation = WalOperation::RenameTable { from: name.clone(), to: to.clone() };
+        self.log_and_apply(operation, |_| Ok(())).await?;
+
+        let mut schema = schemas.remove(&name).expect("checked above");
+        schema.name = to.clone();
+        schemas.insert(to.clone(), schema);
+        let mut sequences = self.sequences.write().await;
+        if let Some(next) = sequences.remove(&name) {
+            sequences.insert(to.clone(), next);
+        }
+        self.plans.write().await.invalidate_table(&name);
+
+        Ok(format!("Table '{}' renamed to '{}'", name, to))
+    }
+
+    /// Renames column `from` of table `name`, in the schema, in generated
+    /// column expressions and in every stored row.
+    async fn rename_column(&self, name: String, from: &str, to: &str) -> Result<String> {
+        ident::validate("column", to)?;
+        let mut schemas = self.schemas.write().await;
+        let mut schema = schemas
+            .get(&name)
+            .ok_or_else(|| anyhow!("Table '{}' does not exist", name))?
+            .clone();
+        if !schema.columns.iter().any(|c| c.name == from) {
+            return Err(anyhow!("Column '{}' does not exist", from));
+        }
+        if schema.columns.iter().any(|c| c.name == to) {
+            return Err(anyhow!("Column '{}' already exists", to));
+        }
 
-                // Format results
-                self.format_select_results(&rows, &select.projection, &schema)
+        for column in &mut schema.columns {
+            if column.name == from {
+                column.name = to.to_string();
+            }
+            if let Some(generated) = &mut column.generated {
+                let mut expr = parse_generated_expr(generated)?;
+                rename_identifier(&mut expr, from, to);
+                *generated = expr.to_string();
+                column.generated_expr = Some(expr);
             }
-            _ => Err(anyhow!("Unsupported query type")),
         }
+        schema.version += 1;
+
+        // Every row is rewritten before anything is logged, so one that
+        // can't be decoded fails the rename instead of the replay
+        let prefix = schema.key_prefix();
+        let operation = WalOperation::RenameColumn { schema: schema.clone(), from: from.to_string(), to: to.to_string() };
+        let rewrite = |storage: &BPlusTree| {
+            let mut rows = Vec::new();
+            storage.for_each_prefix(&prefix, |key, bytes| {
+                rows.push((key.to_string(), codec::rename_column(bytes, from, to)?));
+                Ok(())
+            })?;
+            Ok(rows)
+        };
+        self.check_log_and_apply(vec![operation], rewrite, |storage, rows| {
+            for (key, value) in rows {
+                storage.insert(key, value)?;
+            }
+            Ok(())
+        })
+        .await?;
+        self.plans.write().await.invalidate_table(&name);
+        schemas.insert(name.clone(), schema);
+
+        Ok(format!("Table '{}' altered successfully", name))
     }
 
-    fn extract_insert_values(&self, query: &Query) -> Result<Vec<Vec<Value>>> {
-        match *query.body {
-            SetExpr::Values(ref values) => {
-                let mut result = Vec::new();
-                for row in &values.rows {
-                    let mut value_row = Vec::new();
-                    for expr in row {
-                        match expr {
-                            Expr::Value(value) => value_row.push(value.clone()),
-                            _ => return Err(anyhow!("Unsupported expression in VALUES")),
-                        }
-                    }
-                    result.push(value_row);
+    /// Folds to lower case the table and column names that `unfolded`, from
+    /// `WriteAheadLog::unfolded_names`, shows were created before names
+    /// were folded, so the default `IdentifierCase::Lower` finds them
+    /// unquoted. A table keeps its database's name, and a name whose folded
+    /// form is taken stays as it is. Returns how many names were folded.
+    pub async fn fold_names(&self, unfolded: &HashMap<u64, TableSchema>) -> Result<usize> {
+        let mut tables: Vec<TableSchema> =
+            self.schemas.read().await.values().filter(|s| unfolded.contains_key(&s.id)).cloned().collect();
+        tables.sort_by_key(|schema| schema.id);
+
+        let mut folded = 0;
+        for schema in tables {
+            let typed = &unfolded[&schema.id];
+            for column in schema.columns.iter().filter(|c| typed.columns.iter().any(|t| t.name == c.name)) {
+                let lower = column.name.to_lowercase();
+                if lower == column.name {
+                    continue;
                 }
-                Ok(result)
+                if schema.columns.iter().any(|c| c.name == lower) {
+                    tracing::warn!("Column '{}' of '{}' kept its case: '{}' exists", column.name, schema.name, lower);
+                    continue;
+                }
+                self.rename_column(schema.name.clone(), &column.name, &lower).await?;
+                folded += 1;
             }
-            _ => Err(anyhow!("Unsupported INSERT source")),
+
+            let lower = match schema.name.rsplit_once('.') {
+                Some((database, table)) => format!("{}.{}", database, table.to_lowercase()),
+                None => schema.name.to_lowercase(),
+            };
+            if lower == schema.name || schema.name != typed.name {
+                continue;
+            }
+            if self.schemas.read().await.contains_key(&lower) {
+                tracing::warn!("Table '{}' kept its case: '{}' exists", schema.name, lower);
+                continue;
+            }
+            let to = ObjectName(lower.split('.').map(Ident::new).collect());
+            self.rename_table(&Session::new(), schema.name.clone(), &to).await?;
+            folded += 1;
+        }
+        Ok(folded)
+    }
+
+    fn is_auto_increment_option(option: &ColumnOption) -> bool {
+        match option {
+            ColumnOption::DialectSpecific(tokens) => tokens.iter().any(|t| {
+                let t = t.to_string();
+                t.eq_ignore_ascii_case("AUTO_INCREMENT") || t.eq_ignore_ascii_case("AUTOINCREMENT")
+            }),
+            _ => false,
+        }
+    }
+
+    async fn execute_insert(
+        &self,
+        session: &Session,
+        table_name: &sqlparser::ast::ObjectName,
+        columns: &[Ident],
+        source: Option<&Query>,
+        returning: Option<&[SelectItem]>,
+    ) -> Result<Output> {
+        let table_name = self.resolve_table(session, table_name).await?;
+        
+        // Get table schema
+        let schema = {
+            let schemas = self.schemas.read().await;
+            schemas.get(&table_name)
+                .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?
+                .clone()
+        };
+
+        // Parse values from INSERT statement; without a source (DEFAULT
+        // VALUES) one row of defaults is inserted
+        let values = match source {
+            Some(source) => self.extract_insert_values(source)?,
+            None => vec![Vec::new()],
+        };
+        // Without a column list, values are given for every ordinary column
+        // in order
+        let column_names: Vec<String> = if source.is_none() {
+            Vec::new()
+        } else if columns.is_empty() {
+            schema.columns.iter().filter(|c| c.generated.is_none()).map(|c| c.name.clone()).collect()
+        } else {
+            columns.iter().map(|c| c.value.clone()).collect()
+        };
+        if let Some(column) = schema
+            .columns
+            .iter()
+            .find(|c| c.generated.is_some() && column_names.contains(&c.name))
+        {
+            return Err(anyhow!("Cannot write to generated column '{}'", column.name));
+        }
+
+        // Build and check every row before writing any, so a bad row
+        // doesn't leave the rows before it inserted. Errors in a multi-row
+        // INSERT name the row.
+        let multi_row = values.len() > 1;
+        let at_row = |index: usize, e: anyhow::Error| match multi_row {
+            true => anyhow!("Row {}: {}", index + 1, e),
+            false => e,
+        };
+        for (index, value_row) in values.iter().enumerate() {
+            if value_row.len() > column_names.len() {
+                return Err(at_row(index, anyhow!(
+                    "Too many values provided: expected {}, got {}",
+                    column_names.len(),
+                    value_row.len()
+                )));
+            }
+            if value_row.len() < column_names.len() {
+                return Err(at_row(index, anyhow!(
+                    "Too few values provided: expected {}, got {}",
+                    column_names.len(),
+                    value_row.len()
+                )));
+            }
+        }
+
+        // Every row of one INSERT gets the same CURRENT_TIMESTAMP default
+        let now = self.wal.read().await.utc_now();
+        let mut inserted_rows = Vec::with_capacity(values.len());
+        let mut keys = Vec::with_capacity(values.len());
+        for (index, value_row) in values.iter().enumerate() {
+            session.check_cancelled()?;
+            let (key, row) = self
+                .build_insert_row(&table_name, &schema, &column_names, value_row, now, session)
+                .await
+                .map_err(|e| at_row(index, e))?;
+            keys.push(key);
+            inserted_rows.push(row);
+        }
+
+        // Log to the WAL, then insert into storage, unless a primary key is
+        // taken, by a stored row or an earlier row of this INSERT
+        let mut operations = Vec::with_capacity(keys.len());
+        let mut encoded = Vec::with_capacity(keys.len());
+        for (key, row) in keys.iter().zip(&inserted_rows) {
+            operations.push(WalOperation::Insert {
+                table: table_name.clone(),
+                key: key.clone(),
+                row: row.clone(),
+            });
+            encoded.push((key.clone(), codec::encode_row(row, self.row_format)?));
+        }
+        // `taken` says whether a key is already stored
+        let unique = |taken: &dyn Fn(&str) -> Result<bool>| {
+            let pk = match schema.columns.iter().find(|c| c.primary_key) {
+                Some(pk) => pk,
+                None => return Ok(()),
+            };
+            let mut seen = HashSet::new();
+            for (index, (key, row)) in keys.iter().zip(&inserted_rows).enumerate() {
+                if !seen.insert(key) || taken(key)? {
+                    return Err(at_row(index, anyhow!(
+                        "Duplicate primary key {} = {} in table '{}'",
+                        pk.name,
+                        output::value_text(row.get(&pk.name).unwrap_or(&SqlValue::Null)),
+                        table_name
+                    )));
+                }
+            }
+            Ok(())
+        };
+        // The last chance to stop; once logged, the rows are written
+        session.check_cancelled()?;
+        match session.transaction() {
+            // Buffered until COMMIT, having checked the keys against what
+            // the transaction already wrote as well as storage
+            Some(id) => {
+                let mut transactions = self.transactions.lock().await;
+                let transaction = transactions.get_mut(id, Instant::now())?;
+                let storage = self.storage.read().await;
+                unique(&|key| transaction.key_present(key).map_or_else(|| storage.contains_key(key), Ok))?;
+                for (operation, (key, value)) in operations.into_iter().zip(encoded) {
+                    transaction.push(operation, Mutation::Insert { key, value });
+                }
+            }
+            None => {
+                let check = |storage: &BPlusTree| unique(&|key| storage.contains_key(key));
+                self.check_log_and_apply(operations, check, |storage, ()| {
+                    for (key, value) in encoded {
+                        storage.insert(key, value)?;
+                    }
+                    Ok(())
+                })
+                .await?;
+            }
+        }
+
+        if let Some(items) = returning {
+            return self.format_select_results(&inserted_rows, items, &schema).map(Output::Rows);
+        }
+
+        let rows = inserted_rows.len() as u64;
+        Ok(Output::Affected { rows, message: format!("{} row(s) inserted", rows) })
+    }
+
+    /// Removes every piece of in-memory state kept for `table_name` from
+    /// `schemas`, the locked catalog, and the engine's caches, so dropping
+    /// a table leaves nothing behind. Any new per-table cache must be
+    /// cleared here too.
+    async fn forget_table(&self, schemas: &mut HashMap<String, TableSchema>, table_name: &str) -> Option<TableSchema> {
+        self.sequences.write().await.remove(table_name);
+        self.plans.write().await.invalidate_table(table_name);
+        schemas.remove(table_name)
+    }
+
+    /// Turns one VALUES row into a stored row and its key: defaults of the
+    /// columns left out, auto-increment and generated columns are filled in.
+    async fn build_insert_row(
+        &self,
+        table_name: &str,
+        schema: &TableSchema,
+        column_names: &[String],
+        value_row: &[Value],
+        now: chrono::DateTime<chrono::Utc>,
+        session: &Session,
+    ) -> Result<(String, Row)> {
+        let mut builder = Row::builder(schema);
+        for (column_name, value) in column_names.iter().zip(value_row) {
+            let column = schema
+                .columns
+                .iter()
+                .find(|c| c.name == *column_name)
+                .ok_or_else(|| anyhow!("Column '{}' does not exist", column_name))?;
+            let sql_value = insert_value(self.convert_value_to_sql_value(value)?, column, session)?;
+            builder = builder.set(column_name, sql_value)?;
+        }
+        for column in schema.columns.iter().filter(|c| !column_names.contains(&c.name)) {
+            if let Some(default) = &column.default {
+                builder = builder.set(&column.name, default_value(column, default, now, session)?)?;
+            }
+        }
+
+        self.fill_auto_increment(table_name, builder.row_mut(), schema).await?;
+        compute_generated_columns(builder.row_mut(), schema, &self.functions)?;
+        let row = builder.build()?;
+
+        // Generate key for the row (using primary key if available)
+        let key = self.generate_row_key(&row, schema)?;
+        Ok((key, row))
+    }
+
+    /// Assigns the next sequence value to any AUTO_INCREMENT column left unset
+    /// (or explicitly NULL), and advances the sequence past explicit values.
+    async fn fill_auto_increment(&self, table_name: &str, row: &mut Row, schema: &TableSchema) -> Result<()> {
+        let column = match schema.columns.iter().find(|c| c.auto_increment) {
+            Some(column) => column,
+            None => return Ok(()),
+        };
+
+        let mut sequences = self.sequences.write().await;
+        let current = match sequences.get(table_name) {
+            Some(value) => *value,
+            None => self.max_integer_value(schema, &column.name).await?,
+        };
+
+        let next = match row.get(&column.name) {
+            None | Some(SqlValue::Null) => {
+                let next = current + 1;
+                row.set(column.name.clone(), SqlValue::Integer(next));
+                next
+            }
+            Some(SqlValue::Integer(value)) => current.max(*value),
+            Some(_) => current,
+        };
+
+        sequences.insert(table_name.to_string(), next);
+        Ok(())
+    }
+
+    /// Largest integer stored in `column` across the table, or 0 when empty.
+    /// Used to seed a sequence the first time it is touched.
+    async fn max_integer_value(&self, schema: &TableSchema, column: &str) -> Result<i64> {
+        let rows = self.load_table_rows(schema).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| match row.get(column) {
+                Some(SqlValue::Integer(value)) => Some(*value),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Logs `operation` to the WAL, then applies it to storage with `apply`.
+    /// Both locks are taken in checkpoint's order (WAL, then storage) and
+    /// held across both steps, so a checkpoint never snapshots storage
+    /// between them and then compacts away an entry it doesn't contain.
+    /// `apply` mustn't fail on the data it reads, since the entry is
+    /// already logged by then; `check_log_and_apply` takes the work that can.
+    async fn log_and_apply(
+        &self,
+        operation: WalOperation,
+        apply: impl FnOnce(&mut BPlusTree) -> Result<()>,
+    ) -> Result<()> {
+        self.check_log_and_apply(vec![operation], |_| Ok(()), |storage, ()| apply(storage)).await
+    }
+
+    /// Like `log_and_apply`, but first runs `check` against storage under
+    /// the same locks, so nothing can invalidate it before the write lands;
+    /// if it fails, nothing is logged. Whatever can fail on the stored data,
+    /// such as decoding rows to rewrite, belongs in `check`, which hands
+    /// its result to `apply`: once the operations are logged, replay will
+    /// apply them, so `apply` failing would leave storage behind the WAL.
+    /// Several operations are logged as one transaction, between `Begin`
+    /// and `Commit` markers, so replay after a crash part way through
+    /// applies none of them.
+    async fn check_log_and_apply<T>(
+        &self,
+        operations: Vec<WalOperation>,
+        check: impl FnOnce(&BPlusTree) -> Result<T>,
+        apply: impl FnOnce(&mut BPlusTree, T) -> Result<()>,
+    ) -> Result<()> {
+        self.plan_log_and_apply(|storage| Ok((operations, check(storage)?)), apply).await
+    }
+
+    /// Like `check_log_and_apply`, for a write whose operations depend on
+    /// what is stored, such as a DELETE's matching rows: `plan` reads them
+    /// under the locks and returns the operations with what `apply` needs.
+    /// If it plans no operations, nothing is logged or applied.
+    async fn plan_log_and_apply<T>(
+        &self,
+        plan: impl FnOnce(&BPlusTree) -> Result<(Vec<WalOperation>, T)>,
+        apply: impl FnOnce(&mut BPlusTree, T) -> Result<()>,
+    ) -> Result<()> {
+        let mut wal = self.wal.write().await;
+        let mut storage = self.storage.write().await;
+        let (operations, checked) = plan(&storage)?;
+        if operations.is_empty() {
+            return Ok(());
+        }
+        let entries = match <[WalOperation; 1]>::try_from(operations) {
+            Ok([operation]) => vec![wal.entry(operation)],
+            Err(operations) => {
+                // Named after the Begin entry's sequence number, which no
+                // other transaction can have, even across restarts
+                let tx_id = wal.last_seq() + 1;
+                let writes = operations.into_iter().map(|operation| wal.entry(operation).in_transaction(tx_id));
+                std::iter::once(wal.entry(WalOperation::Begin(tx_id)))
+                    .chain(writes)
+                    .chain([wal.entry(WalOperation::Commit(tx_id))])
+                    .collect()
+            }
+        };
+        let mut seq = 0;
+        for entry in &entries {
+            seq = wal.append(entry).await?;
+        }
+        debug_assert!(
+            storage.applied_seq() < seq,
+            "storage has applied seq {} before the WAL logged it",
+            seq
+        );
+        apply(&mut storage, checked)?;
+        storage.mark_applied(seq);
+        Ok(())
+    }
+
+    /// Decodes each row straight from the bytes held by the tree, without
+    /// copying keys or values first. Rows written under an older schema
+    /// version are conformed to the current one.
+    async fn load_table_rows(&self, schema: &TableSchema) -> Result<Vec<Row>> {
+        let mut memory = MemoryBudget::new(None);
+        self.scan_table_rows(&Session::new(), schema, &mut QueryStats::default(), &mut memory).await
+    }
+
+    /// Reads every row of `schema`'s table, adding the read to `stats` and
+    /// the rows to `memory`, and stops if `session`'s statement is
+    /// cancelled.
+    async fn scan_table_rows(
+        &self,
+        session: &Session,
+        schema: &TableSchema,
+        stats: &mut QueryStats,
+        memory: &mut MemoryBudget,
+    ) -> Result<Vec<Row>> {
+        self.scan_matching_rows(session, schema, None, usize::MAX, stats, memory).await
+    }
+
+    /// Reads the rows of `schema`'s table that `predicate` holds for, in key
+    /// order, and stops once `cap` of them are found, so a capped result
+    /// doesn't read the rest of the table. Each row kept is charged to
+    /// `memory` as it's read, so a query fails before buffering more than
+    /// its limit.
+    async fn scan_matching_rows(
+        &self,
+        session: &Session,
+        schema: &TableSchema,
+        predicate: Option<&Expr>,
+        cap: usize,
+        stats: &mut QueryStats,
+        memory: &mut MemoryBudget,
+    ) -> Result<Vec<Row>> {
+        let overlay = self.transaction_overlay(session, &schema.key_prefix()).await?;
+        let storage = self.storage.read().await;
+        let visits = bptree::node_visits();
+        let mut rows = Vec::new();
+        let mut scanned = 0;
+        for_each_visible(&storage, &schema.key_prefix(), &overlay, |key, data| {
+            session.check_cancelled()?;
+            scanned += 1;
+            let row = conform_row(key, codec::decode_row(data)?, schema)?;
+            if predicate.map_or(Ok(true), |predicate| expr::matches_with(predicate, &row, &self.functions))? {
+                memory.reserve(memory::row_size(&row))?;
+                rows.push(row);
+            }
+            Ok(rows.len() < cap)
+        })?;
+        stats.record(|| format!("Full scan on {}", schema.name), scanned, bptree::node_visits() - visits);
+        Ok(rows)
+    }
+
+    /// The writes `session`'s open transaction has buffered under `prefix`,
+    /// which its reads see on top of storage; none outside a transaction.
+    async fn transaction_overlay(&self, session: &Session, prefix: &str) -> Result<Overlay> {
+        match session.transaction() {
+            Some(id) => Ok(self.transactions.lock().await.get_mut(id, Instant::now())?.overlay(prefix)),
+            None => Ok(Overlay::new()),
+        }
+    }
+
+    /// The keys and rows of `schema`'s table that `selection` holds for,
+    /// read from `storage`, which the caller has locked, with `overlay`'s
+    /// writes on top.
+    fn matching_entries(
+        &self,
+        storage: &BPlusTree,
+        overlay: &Overlay,
+        session: &Session,
+        schema: &TableSchema,
+        selection: Option<&Expr>,
+    ) -> Result<Vec<(String, Row)>> {
+        let mut entries = Vec::new();
+        for_each_visible(storage, &schema.key_prefix(), overlay, |key, data| {
+            session.check_cancelled()?;
+            let row = conform_row(key, codec::decode_row(data)?, schema)?;
+            if selection.map_or(Ok(true), |selection| expr::matches_with(selection, &row, &self.functions))? {
+                entries.push((key.to_string(), row));
+            }
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    async fn execute_delete(
+        &self,
+        session: &Session,
+        from: &[TableWithJoins],
+        selection: Option<&Expr>,
+        returning: Option<&[SelectItem]>,
+    ) -> Result<Output> {
+        let table_name = match from.first() {
+            Some(table) => match &table.relation {
+                TableFactor::Table { name, .. } => self.resolve_table(session, name).await?,
+                _ => return Err(anyhow!("Unsupported table factor")),
+            },
+            None => return Err(anyhow!("No table specified")),
+        };
+
+        let schema = {
+            let schemas = self.schemas.read().await;
+            schemas.get(&table_name)
+                .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?
+                .clone()
+        };
+        if let Some(selection) = selection {
+            functions::check_coalesce_types(selection, &schema)?;
+        }
+
+        let delete_operation = |key: &String| WalOperation::Delete { table: table_name.clone(), key: key.clone() };
+        let mut deleted = Vec::new();
+        if let Some(id) = session.transaction() {
+            // Rows the transaction inserted can be deleted, and those it
+            // deleted already can't
+            let overlay = self.transaction_overlay(session, &schema.key_prefix()).await?;
+            deleted = self.matching_entries(&*self.storage.read().await, &overlay, session, &schema, selection)?;
+            session.check_cancelled()?;
+            let mut transactions = self.transactions.lock().await;
+            let transaction = transactions.get_mut(id, Instant::now())?;
+            for (key, _) in &deleted {
+                transaction.push(delete_operation(key), Mutation::Delete { key: key.clone() });
+            }
+        } else {
+            // The rows are matched under the locks they're deleted with, so
+            // a write landing in between can't be missed. Logged as one
+            // transaction, so a crash part way through deletes none of them;
+            // cancelling stops it only before it's logged.
+            self.plan_log_and_apply(
+                |storage| {
+                    let matched = self.matching_entries(storage, &Overlay::new(), session, &schema, selection)?;
+                    session.check_cancelled()?;
+                    Ok((matched.iter().map(|(key, _)| delete_operation(key)).collect(), matched))
+                },
+                |storage, matched| {
+                    for (key, _) in &matched {
+                        storage.remove(key)?;
+                    }
+                    deleted = matched;
+                    Ok(())
+                },
+            )
+            .await?;
+        }
+        let deleted_rows: Vec<Row> = deleted.into_iter().map(|(_, row)| row).collect();
+
+        if let Some(items) = returning {
+            return self.format_select_results(&deleted_rows, items, &schema).map(Output::Rows);
+        }
+
+        let rows = deleted_rows.len() as u64;
+        Ok(Output::Affected { rows, message: format!("{} row(s) deleted", rows) })
+    }
+
+    /// Applies a session setting: `max_result_rows` (a row count, or 0 for
+    /// no cap) or `result_limit_mode` (`truncate` or `error`).
+    fn execute_set(session: &mut Session, variable: &ObjectName, value: &[Expr]) -> Result<String> {
+        let value = match value {
+            [Expr::Identifier(ident)] => ident.value.clone(),
+            [Expr::Value(Value::SingleQuotedString(s))] | [Expr::Value(Value::Number(s, _))] => s.clone(),
+            _ => return Err(anyhow!("Invalid value for {}", variable)),
+        };
+        let mut limit = session.result_limit();
+        match variable.to_string().to_ascii_lowercase().as_str() {
+            "max_result_rows" => {
+                let rows: usize = value
+                    .parse()
+                    .map_err(|_| anyhow!("max_result_rows must be a non-negative integer, got '{}'", value))?;
+                limit.max_rows = (rows > 0).then_some(rows);
+            }
+            "result_limit_mode" => {
+                limit.mode = match value.to_ascii_lowercase().as_str() {
+                    "truncate" => LimitMode::Truncate,
+                    "error" => LimitMode::Error,
+                    _ => return Err(anyhow!("result_limit_mode must be 'truncate' or 'error', got '{}'", value)),
+                };
+            }
+            "statement_timeout" => {
+                let ms: u64 = value
+                    .parse()
+                    .map_err(|_| anyhow!("statement_timeout must be a whole number of milliseconds, got '{}'", value))?;
+                let timeout = (ms > 0).then(|| Duration::from_millis(ms));
+                if let (Some(timeout), Some(ceiling)) = (timeout, session.statement_timeout().ceiling) {
+                    if timeout > ceiling {
+                        let ceiling = ceiling.as_millis();
+                        return Err(anyhow!("statement_timeout may be at most {} ms on this server", ceiling));
+                    }
+                }
+                session.set_statement_timeout(timeout);
+            }
+            "string_overflow" => {
+                let overflow = match value.to_ascii_lowercase().as_str() {
+                    "error" => StringOverflow::Error,
+                    "truncate" => StringOverflow::Truncate,
+                    _ => return Err(anyhow!("string_overflow must be 'error' or 'truncate', got '{}'", value)),
+                };
+                session.set_string_overflow(overflow);
+            }
+            "scale_overflow" => {
+                let overflow = match value.to_ascii_lowercase().as_str() {
+                    "round" => ScaleOverflow::Round,
+                    "error" => ScaleOverflow::Error,
+                    _ => return Err(anyhow!("scale_overflow must be 'round' or 'error', got '{}'", value)),
+                };
+                session.set_scale_overflow(overflow);
+            }
+            _ => return Err(anyhow!("Unknown setting '{}'", variable)),
+        }
+        session.set_result_limit(limit);
+        Ok(format!("{} set to {}", variable, value))
+    }
+
+    async fn execute_select(&self, session: &Session, query: &Query) -> Result<Output> {
+        // One row past the cap is enough to tell the result went over it
+        let limit = session.result_limit();
+        let cap = limit.max_rows.filter(|_| query.limit.is_none()).map(|max| max + 1);
+        let (headers, mut rows) = self.query_rows(session, query, cap, &mut QueryStats::default()).await?;
+
+        let mut warning = None;
+        if let Some(max_rows) = limit.max_rows.filter(|max| query.limit.is_none() && rows.len() > *max) {
+            match limit.mode {
+                LimitMode::Error => {
+                    return Err(anyhow!(
+                        "Query returned more than {} rows (max_result_rows); add a LIMIT or raise the cap",
+                        max_rows
+                    ))
+                }
+                LimitMode::Truncate => {
+                    warning = Some(format!("Warning: result truncated to {} rows (max_result_rows)\n", max_rows));
+                    rows.truncate(max_rows);
+                }
+            }
+        }
+
+        Ok(Output::Rows(ResultSet {
+            columns: headers,
+            rows,
+            warning,
+            // Constant selects like `SELECT 1;` print without a separator
+            compact: matches!(*query.body, SetExpr::Select(ref select) if select.from.is_empty()),
+        }))
+    }
+
+    /// Runs a query under `EXPLAIN ANALYZE`, reporting how it read its
+    /// tables and what that cost instead of its rows.
+    async fn execute_explain(&self, session: &Session, analyze: bool, statement: &Statement) -> Result<ResultSet> {
+        let query = match statement {
+            Statement::Query(query) if analyze => query,
+            Statement::Query(_) => return Err(anyhow!("EXPLAIN is only supported as EXPLAIN ANALYZE")),
+            _ => return Err(anyhow!("EXPLAIN ANALYZE supports only queries")),
+        };
+        let mut stats = QueryStats::collecting();
+        let start = Instant::now();
+        let (_, rows) = self.query_rows(session, query, None, &mut stats).await?;
+        let report = stats.report(rows.len(), start.elapsed());
+        Ok(ResultSet::new(vec!["plan".to_string()], report))
+    }
+
+    /// Runs a query, returning its column headers and projected rows. What
+    /// its reads cost is added to `stats`. With a `cap`, no more than `cap`
+    /// rows are returned, and a query whose rows come straight from a table
+    /// scan stops scanning once it has them.
+    async fn query_rows(
+        &self,
+        session: &Session,
+        query: &Query,
+        cap: Option<usize>,
+        stats: &mut QueryStats,
+    ) -> Result<(Vec<String>, Vec<Vec<SqlValue>>)> {
+        let select = match *query.body {
+            SetExpr::Select(ref select) => select,
+            _ => return Err(anyhow!("Unsupported query type")),
+        };
+
+        // Handle constant selects like `SELECT 1;` or `SELECT 'hello', 2 * 3;`
+        if select.from.is_empty() {
+            let row = Row::new();
+            let mut headers = Vec::new();
+            let mut values = Vec::new();
+            for item in &select.projection {
+                let (header, expr) = match item {
+                    SelectItem::UnnamedExpr(expr) => ("?column?".to_string(), expr),
+                    SelectItem::ExprWithAlias { expr, alias } => (alias.to_string(), expr),
+                    _ => return Err(anyhow!("SELECT * requires a FROM clause")),
+                };
+                headers.push(header);
+                values.push(expr::evaluate_with(expr, &row, &self.functions)?);
+            }
+            return Ok((headers, vec![values]));
+        }
+
+        let from = select.from.first().ok_or_else(|| anyhow!("No table specified"))?;
+        let offset = match &query.offset {
+            Some(offset) => row_count(&offset.value, "OFFSET")?,
+            None => 0,
+        };
+        // Sorting, grouping and aggregating need every row
+        let streams = query.order_by.is_empty()
+            && select.distinct.is_none()
+            && matches!(&select.group_by, GroupByExpr::Expressions(exprs) if exprs.is_empty())
+            && select.having.is_none()
+            && !select.projection.iter().any(|item| {
+                matches!(item, SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. }
+                    if aggregate::is_aggregate(expr))
+            });
+        let scan_cap = cap.filter(|_| streams).map(|cap| cap.saturating_add(offset));
+        let mut filtered = false;
+        // Rows are charged as they're buffered, by the reads and joins
+        // producing them and by sorting
+        let mut memory = MemoryBudget::new(session.memory_limit());
+
+        // information_schema views are computed from the catalog and inline
+        // VALUES tables from their expressions; tables are read from
+        // storage, by key when the plan allows it
+        let (schema, mut rows) = match &from.relation {
+            _ if !from.joins.is_empty() => self.join_rows(session, from, stats, &mut memory).await?,
+            TableFactor::Derived { subquery, alias, .. } => {
+                let (schema, rows) = values::table(subquery, alias.as_ref(), &self.functions)?;
+                stats.record(|| format!("Inline VALUES {}", schema.name), rows.len(), 0);
+                memory.reserve(rows.iter().map(memory::row_size).sum())?;
+                (schema, rows)
+            }
+            TableFactor::Table { name, .. } => match information_schema::View::resolve(name) {
+                Some(view) => {
+                    let (schema, rows) = (view.schema(), view.rows(&*self.schemas.read().await));
+                    stats.record(|| format!("Catalog view {}", schema.name), rows.len(), 0);
+                    memory.reserve(rows.iter().map(memory::row_size).sum())?;
+                    (schema, rows)
+                }
+                None => {
+                    let table_name = self.resolve_table(session, name).await?;
+                    let schema = {
+                        let schemas = self.schemas.read().await;
+                        schemas.get(&table_name)
+                            .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?
+                            .clone()
+                    };
+
+                    let plan = {
+                        let shape = plan::normalize(query);
+                        let mut plans = self.plans.write().await;
+                        match plans.get(&table_name, &shape) {
+                            Some(plan) => plan,
+                            None => {
+                                let plan = plan::plan_select(&schema, select.selection.as_ref());
+                                plans.insert(shape, plan.clone());
+                                plan
+                            }
+                        }
+                    };
+                    if let AccessPath::PrimaryKeyRange { column } = &plan.access {
+                        let range = self.count_primary_key_range(session, &schema, column, select, query, stats).await?;
+                        if let Some(result) = range {
+                            return Ok(result);
+                        }
+                    }
+                    let rows = match (&plan.access, &select.selection) {
+                        (AccessPath::PrimaryKey { column }, Some(selection)) => {
+                            self.lookup_primary_key(session, &schema, column, selection, stats, &mut memory).await?
+                        }
+                        // Filtered as it's read, so rows WHERE rules out are
+                        // never buffered
+                        _ => {
+                            let predicate = match &select.selection {
+                                Some(selection) => Some(self.resolve_subqueries(session, selection, stats).await?),
+                                None => None,
+                            };
+                            filtered = true;
+                            let scan_cap = scan_cap.unwrap_or(usize::MAX);
+                            self.scan_matching_rows(session, &schema, predicate.as_ref(), scan_cap, stats, &mut memory)
+                                .await?
+                        }
+                    };
+                    (schema, rows)
+                }
+            },
+            _ => return Err(anyhow!("Unsupported table factor")),
+        };
+        if let Some(selection) = &select.selection {
+            functions::check_coalesce_types(selection, &schema)?;
+        }
+        functions::check_coalesce_types(&query.order_by, &schema)?;
+
+        // Apply WHERE clause if present and the scan didn't
+        if let Some(where_clause) = select.selection.as_ref().filter(|_| !filtered) {
+            let predicate = self.resolve_subqueries(session, where_clause, stats).await?;
+            rows = self.filter_rows(rows, &predicate)?;
+        }
+
+        // Apply ORDER BY if present
+        if !query.order_by.is_empty() {
+            rows = self.sort_rows(rows, &query.order_by, &mut memory)?;
+        }
+
+        let (headers, mut values) = self.project_rows(&rows, &select.projection, &schema)?;
+
+        // Apply OFFSET and LIMIT if present
+        let limit = match &query.limit {
+            Some(limit) => row_count(limit, "LIMIT")?,
+            None => cap.unwrap_or(usize::MAX),
+        };
+        if offset > 0 || limit < values.len() {
+            values = values.into_iter().skip(offset).take(limit).collect();
+        }
+
+        Ok((headers, values))
+    }
+
+    /// Joins the tables of `from` with nested loops, keeping the pairs of
+    /// rows each ON condition holds for. Joined rows and the returned schema
+    /// name every column by its table, as in `users.id`, so columns of the
+    /// same name don't collide; a table's alias replaces its name.
+    async fn join_rows(
+        &self,
+        session: &Session,
+        from: &TableWithJoins,
+        stats: &mut QueryStats,
+        memory: &mut MemoryBudget,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let (mut schema, mut rows) = self.qualified_rows(session, &from.relation, &[], stats, memory).await?;
+        for join in &from.joins {
+            let on = match &join.join_operator {
+                JoinOperator::Inner(JoinConstraint::On(on)) => on,
+                operator => {
+                    return Err(anyhow!("Unsupported join: {:?}; only INNER JOIN ... ON is supported", operator))
+                }
+            };
+            if !is_equality(on) {
+                return Err(anyhow!("Unsupported join condition: {}; only equalities joined by AND are", on));
+            }
+            let (right_schema, right_rows) =
+                self.qualified_rows(session, &join.relation, &schema.columns, stats, memory).await?;
+            schema.columns.extend(right_schema.columns);
+            Self::check_columns_exist(on, &schema)?;
+
+            let mut joined = Vec::new();
+            for left in &rows {
+                session.check_cancelled()?;
+                for right in &right_rows {
+                    let mut row = Row::new();
+                    for (name, value) in left.iter().chain(right.iter()) {
+                        row.set(name.clone(), value.clone());
+                    }
+                    if expr::matches_with(on, &row, &self.functions)? {
+                        memory.reserve(memory::row_size(&row))?;
+                        joined.push(row);
+                    }
+                }
+            }
+            rows = joined;
+        }
+        Ok((schema, rows))
+    }
+
+    /// Scans the table `relation` names, or evaluates its inline VALUES,
+    /// with its columns renamed to `<table>.<column>`. Fails if a column
+    /// would clash with one of `joined`, which happens when a table is
+    /// joined twice under one name.
+    async fn qualified_rows(
+        &self,
+        session: &Session,
+        relation: &TableFactor,
+        joined: &[Column],
+        stats: &mut QueryStats,
+        memory: &mut MemoryBudget,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let (qualifier, schema, rows) = match relation {
+            TableFactor::Table { name, alias, .. } => {
+                let table_name = self.resolve_table(session, name).await?;
+                let schema = self
+                    .schemas
+                    .read()
+                    .await
+                    .get(&table_name)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Table '{}' does not exist", table_name))?;
+                let qualifier = match alias {
+                    Some(alias) => alias.name.value.clone(),
+                    None => name.0[name.0.len() - 1].value.clone(),
+                };
+                let rows = self.scan_table_rows(session, &schema, stats, memory).await?;
+                (qualifier, schema, rows)
+            }
+            TableFactor::Derived { subquery, alias, .. } => {
+                let (schema, rows) = values::table(subquery, alias.as_ref(), &self.functions)?;
+                stats.record(|| format!("Inline VALUES {}", schema.name), rows.len(), 0);
+                memory.reserve(rows.iter().map(memory::row_size).sum())?;
+                (schema.name.clone(), schema, rows)
+            }
+            _ => return Err(anyhow!("Unsupported table factor")),
+        };
+        let qualify = |column: &str| format!("{}.{}", qualifier, column);
+        if joined.iter().any(|column| column.name.starts_with(&qualify(""))) {
+            return Err(anyhow!("Table name '{}' specified more than once; give it an alias", qualifier));
+        }
+
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let mut qualified = Row::new();
+                for (column, value) in row.iter() {
+                    qualified.set(qualify(column), value.clone());
+                }
+                qualified
+            })
+            .collect();
+        let columns =
+            schema.columns.iter().map(|column| Column { name: qualify(&column.name), ..column.clone() }).collect();
+        Ok((TableSchema { columns, ..schema }, rows))
+    }
+
+    /// Fetches the rows whose primary keys `selection` pins, without
+    /// scanning; an IN list is fetched in one pass over the tree. The
+    /// caller still applies the full WHERE clause. Falls back to a scan
+    /// when a constant's type differs from the key column's, since the
+    /// stored key would be encoded differently.
+    async fn lookup_primary_key(
+        &self,
+        session: &Session,
+        schema: &TableSchema,
+        column: &str,
+        selection: &Expr,
+        stats: &mut QueryStats,
+        memory: &mut MemoryBudget,
+    ) -> Result<Vec<Row>> {
+        let exprs = match plan::primary_key_values(selection, column) {
+            Some(exprs) => exprs,
+            None => return self.scan_table_rows(session, schema, stats, memory).await,
+        };
+        let empty = Row::new();
+        let data_type = schema.columns.iter().find(|c| c.name == column).map(|c| &c.data_type);
+        let mut keys = Vec::with_capacity(exprs.len());
+        for expr in exprs {
+            let value = expr::evaluate_with(expr, &empty, &self.functions)?;
+            let matches_type = matches!(
+                (&value, data_type),
+                (SqlValue::Integer(_), Some(SqlDataType::Integer))
+                    | (SqlValue::Varchar(_), Some(SqlDataType::Varchar(_)))
+            );
+            if !matches_type {
+                return self.scan_table_rows(session, schema, stats, memory).await;
+            }
+            keys.push(format!("{}{}", schema.key_prefix(), encode_key_value(&value)));
+        }
+        // In key order, as a scan would return them, and once each
+        keys.sort();
+        keys.dedup();
+
+        let overlay = self.transaction_overlay(session, &schema.key_prefix()).await?;
+        let storage = self.storage.read().await;
+        let visits = bptree::node_visits();
+        let mut rows = Vec::new();
+        for (key, stored) in keys.iter().zip(storage.multi_get(&keys)?) {
+            let data = match overlay.get(key) {
+                Some(written) => written.clone(),
+                None => stored,
+            };
+            if let Some(data) = data {
+                let row = conform_row(key, codec::decode_row(&data)?, schema)?;
+                memory.reserve(memory::row_size(&row))?;
+                rows.push(row);
+            }
+        }
+        let access = || match keys.len() {
+            1 => format!("Primary key lookup on {} ({})", schema.name, column),
+            n => format!("Primary key lookup of {} keys on {} ({})", n, schema.name, column),
+        };
+        stats.record(access, rows.len(), bptree::node_visits() - visits);
+        Ok(rows)
+    }
+
+    /// Answers `SELECT COUNT(*) ... WHERE <key> BETWEEN a AND b` from the
+    /// tree's subtree counts, without reading rows. Returns `None`, leaving
+    /// the query to the scan, when it needs more than the count or a
+    /// bound's type differs from the key column's, since the stored keys
+    /// would be encoded differently, or when `session`'s transaction has
+    /// written to the table, since the counts don't include its writes.
+    async fn count_primary_key_range(
+        &self,
+        session: &Session,
+        schema: &TableSchema,
+        column: &str,
+        select: &Select,
+        query: &Query,
+        stats: &mut QueryStats,
+    ) -> Result<Option<(Vec<String>, Vec<Vec<SqlValue>>)>> {
+        let header = match select.projection.as_slice() {
+            [SelectItem::UnnamedExpr(expr)] if aggregate::is_count_star(expr) => "?column?".to_string(),
+            [SelectItem::ExprWithAlias { expr, alias }] if aggregate::is_count_star(expr) => alias.to_string(),
+            _ => return Ok(None),
+        };
+        let count_only = select.distinct.is_none()
+            && matches!(&select.group_by, GroupByExpr::Expressions(exprs) if exprs.is_empty())
+            && select.having.is_none()
+            && query.order_by.is_empty()
+            && query.limit.is_none()
+            && query.offset.is_none();
+        let bounds = select.selection.as_ref().and_then(|selection| plan::primary_key_range(selection, column));
+        let (low, high) = match bounds {
+            Some(bounds) if count_only => bounds,
+            _ => return Ok(None),
+        };
+
+        let empty = Row::new();
+        let (low, high) =
+            (expr::evaluate_with(low, &empty, &self.functions)?, expr::evaluate_with(high, &empty, &self.functions)?);
+        let data_type = schema.columns.iter().find(|c| c.name == column).map(|c| &c.data_type);
+        let matches_type = |value: &SqlValue| {
+            matches!(
+                (value, data_type),
+                (SqlValue::Integer(_), Some(SqlDataType::Integer)) | (SqlValue::Varchar(_), Some(SqlDataType::Varchar(_)))
+            )
+        };
+        if !matches_type(&low) || !matches_type(&high) {
+            return Ok(None);
+        }
+
+        let prefix = schema.key_prefix();
+        if !self.transaction_overlay(session, &prefix).await?.is_empty() {
+            return Ok(None);
+        }
+        let storage = self.storage.read().await;
+        let visits = bptree::node_visits();
+        let count = storage.count_range(
+            &format!("{}{}", prefix, encode_key_value(&low)),
+            &format!("{}{}", prefix, encode_key_value(&high)),
+        );
+        let access = || format!("Primary key range count on {} ({})", schema.name, column);
+        stats.record(access, 0, bptree::node_visits() - visits);
+        Ok(Some((vec![header], vec![vec![SqlValue::Integer(count as i64)]])))
+    }
+
+    /// Runs the subqueries in `expr` and replaces each with its result, so
+    /// the returned expression can be evaluated row by row.
+    async fn resolve_subqueries(&self, session: &Session, expr: &Expr, stats: &mut QueryStats) -> Result<Expr> {
+        let mut resolved = expr.clone();
+        let mut results = Vec::new();
+        for (kind, query) in subquery::collect(expr) {
+            let (headers, rows) = Box::pin(self.query_rows(session, &query, None, stats)).await?;
+            results.push(subquery::materialize(kind, headers.len(), rows)?);
+        }
+        subquery::substitute(&mut resolved, results);
+        Ok(resolved)
+    }
+
+    fn extract_insert_values(&self, query: &Query) -> Result<Vec<Vec<Value>>> {
+        match *query.body {
+            SetExpr::Values(ref values) => {
+                let mut result = Vec::new();
+                for row in &values.rows {
+                    let mut value_row = Vec::new();
+                    for expr in row {
+                        let value = prepared::parameter_value(expr)
+                            .map_err(|_| anyhow!("Unsupported expression in VALUES"))?;
+                        value_row.push(value);
+                    }
+                    result.push(value_row);
+                }
+                Ok(result)
+            }
+            _ => Err(anyhow!("Unsupported INSERT source")),
         }
     }
 
@@ -332,116 +2108,689 @@ impl SqlEngine {
     }
 
     fn convert_value_to_sql_value(&self, value: &Value) -> Result<SqlValue> {
-        match value {
-            Value::Number(n, _) => {
-                if n.contains('.') {
-                    Ok(SqlValue::Decimal(n.parse()?))
-                } else {
-                    Ok(SqlValue::Integer(n.parse()?))
-                }
-            }
-            Value::SingleQuotedString(s) => Ok(SqlValue::Varchar(s.clone())),
-            Value::Boolean(b) => Ok(SqlValue::Boolean(*b)),
-            Value::Null => Ok(SqlValue::Null),
-            _ => Err(anyhow!("Unsupported value type: {:?}", value)),
-        }
+        expr::literal_to_sql_value(value)
     }
 
-    fn generate_row_key(&self, table_name: &str, row: &Row, schema: &TableSchema) -> Result<String> {
+    fn generate_row_key(&self, row: &Row, schema: &TableSchema) -> Result<String> {
         // Try to use primary key
         for column in &schema.columns {
             if column.primary_key {
-                if let Some(value) = row.values.get(&column.name) {
-                    return Ok(format!("{}:{}", table_name, self.sql_value_to_string(value)));
+                if let Some(value) = row.get(&column.name) {
+                    return Ok(format!("{}{}", schema.key_prefix(), encode_key_value(value)));
                 }
             }
         }
         
         // Fallback to UUID if no primary key
-        Ok(format!("{}:{}", table_name, uuid::Uuid::new_v4()))
+        Ok(format!("{}{}", schema.key_prefix(), uuid::Uuid::new_v4()))
     }
 
-    fn sql_value_to_string(&self, value: &SqlValue) -> String {
-        match value {
-            SqlValue::Integer(i) => i.to_string(),
-            SqlValue::Varchar(s) => s.clone(),
-            SqlValue::Decimal(d) => d.to_string(),
-            SqlValue::Boolean(b) => b.to_string(),
-            SqlValue::Timestamp(t) => t.to_rfc3339(),
-            SqlValue::Null => "null".to_string(),
+    fn filter_rows(&self, rows: Vec<Row>, where_clause: &Expr) -> Result<Vec<Row>> {
+        let mut filtered = Vec::with_capacity(rows.len());
+        for row in rows {
+            if expr::matches_with(where_clause, &row, &self.functions)? {
+                filtered.push(row);
+            }
         }
+        Ok(filtered)
     }
 
-    fn filter_rows(&self, rows: Vec<Row>, _where_clause: &Expr) -> Result<Vec<Row>> {
-        // Simplified WHERE clause handling - just return all rows for now
-        // In a full implementation, this would parse and evaluate the WHERE expression
-        Ok(rows)
-    }
+    /// Sorts by each ORDER BY key in turn. NULLs come last in either
+    /// direction unless NULLS FIRST says otherwise. A key may carry a collation, e.g. `name COLLATE NOCASE`.
+    /// The rows were charged to `memory` when they were read; their keys
+    /// are charged as they're computed.
+    fn sort_rows(
+        &self,
+        rows: Vec<Row>,
+        order_by: &[sqlparser::ast::OrderByExpr],
+        memory: &mut MemoryBudget,
+    ) -> Result<Vec<Row>> {
+        let keys = order_by
+            .iter()
+            .map(|item| match &item.expr {
+                Expr::Collate { expr, collation } => Ok((expr.as_ref(), expr::Collation::from_name(collation)?)),
+                expr => Ok((expr, expr::Collation::Binary)),
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-    fn sort_rows(&self, rows: Vec<Row>, _order_by: &[sqlparser::ast::OrderByExpr]) -> Result<Vec<Row>> {
-        // Simplified ORDER BY handling - just return rows as-is for now
-        // In a full implementation, this would sort based on the ORDER BY clause
-        Ok(rows)
-    }
+        // Evaluate each row's sort keys once rather than per comparison
+        let mut keyed = Vec::with_capacity(rows.len());
+        for row in rows {
+            let values = keys
+                .iter()
+                .map(|(key, _)| expr::evaluate_with(key, &row, &self.functions))
+                .collect::<Result<Vec<_>>>()?;
+            memory.reserve(values.iter().map(memory::value_size).sum())?;
+            keyed.push((values, row));
+        }
 
-    fn format_select_results(&self, rows: &[Row], projection: &[SelectItem], schema: &TableSchema) -> Result<String> {
-        let mut result = String::new();
-        
-        // Determine which columns to show
-        let columns: Vec<String> = match projection.first() {
-            Some(SelectItem::Wildcard(..)) => {
-                schema.columns.iter().map(|c| c.name.clone()).collect()
-            }
-            _ => {
-                let mut cols = Vec::new();
-                for item in projection {
-                    match item {
-                        SelectItem::UnnamedExpr(Expr::Identifier(ident)) => {
-                            cols.push(ident.to_string());
+        let mut error = None;
+        keyed.sort_by(|(a, _), (b, _)| {
+            for (i, item) in order_by.iter().enumerate() {
+                let descending = item.asc == Some(false);
+                let nulls_first = item.nulls_first.unwrap_or(false);
+                let ordering = match (&a[i], &b[i]) {
+                    (SqlValue::Null, SqlValue::Null) => Ordering::Equal,
+                    (SqlValue::Null, _) => if nulls_first { Ordering::Less } else { Ordering::Greater },
+                    (_, SqlValue::Null) => if nulls_first { Ordering::Greater } else { Ordering::Less },
+                    (x, y) => match keys[i].1.compare(x, y) {
+                        Ok(ordering) => {
+                            let ordering = ordering.unwrap_or(Ordering::Equal);
+                            if descending { ordering.reverse() } else { ordering }
                         }
-                        _ => {
-                            // Handle other projection types as needed
-                            cols.push("*".to_string());
+                        Err(e) => {
+                            error.get_or_insert(e);
+                            Ordering::Equal
                         }
-                    }
+                    },
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
                 }
-                cols
             }
-        };
+            Ordering::Equal
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(keyed.into_iter().map(|(_, row)| row).collect()),
+        }
+    }
+
+    fn format_select_results(
+        &self,
+        rows: &[Row],
+        projection: &[SelectItem],
+        schema: &TableSchema,
+    ) -> Result<ResultSet> {
+        let (headers, values) = self.project_rows(rows, projection, schema)?;
+        Ok(ResultSet::new(headers, values))
+    }
+
+    /// Evaluates the select list against each row. A list of aggregate calls
+    /// instead collapses all rows into one.
+    fn project_rows(
+        &self,
+        rows: &[Row],
+        projection: &[SelectItem],
+        schema: &TableSchema,
+    ) -> Result<(Vec<String>, Vec<Vec<SqlValue>>)> {
+        // Determine which columns to show; plain column references are read
+        // from the row, anything else is evaluated per row
+        let mut columns: Vec<(String, Option<&Expr>)> = Vec::new();
+        for item in projection {
+            Self::check_columns_exist(item, schema)?;
+            functions::check_coalesce_types(item, schema)?;
+            match item {
+                SelectItem::Wildcard(..) | SelectItem::QualifiedWildcard(..) => {
+                    columns.extend(schema.columns.iter().map(|c| (c.name.clone(), None)));
+                }
+                SelectItem::UnnamedExpr(Expr::Identifier(ident)) => {
+                    columns.push((ident.value.clone(), None));
+                }
+                // Headed by the column alone, as a plain reference would be
+                SelectItem::UnnamedExpr(expr @ Expr::CompoundIdentifier(idents)) => {
+                    columns.push((idents[idents.len() - 1].value.clone(), Some(expr)));
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    columns.push(("?column?".to_string(), Some(expr)));
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    columns.push((alias.value.clone(), Some(expr)));
+                }
+            }
+        }
+        let headers = columns.iter().map(|(name, _)| name.clone()).collect();
 
-        // Header
-        result.push_str(&columns.join("\t"));
-        result.push('\n');
-        result.push_str(&"-".repeat(columns.len() * 10));
-        result.push('\n');
+        if columns.iter().any(|(_, expr)| expr.is_some_and(aggregate::is_aggregate)) {
+            let values = columns
+                .iter()
+                .map(|(name, expr)| match expr {
+                    Some(expr) if aggregate::is_aggregate(expr) => aggregate::evaluate(expr, rows, &self.functions),
+                    _ => Err(anyhow!("Column '{}' must be an aggregate when the query aggregates", name)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            return Ok((headers, vec![values]));
+        }
 
-        // Data rows
+        let mut values = Vec::with_capacity(rows.len());
         for row in rows {
-            let mut row_values = Vec::new();
-            for col in &columns {
-                let value = row.values.get(col)
-                    .map(|v| self.sql_value_to_string(v))
-                    .unwrap_or_else(|| "NULL".to_string());
-                row_values.push(value);
+            let mut row_values = Vec::with_capacity(columns.len());
+            for (col, expr) in &columns {
+                row_values.push(match expr {
+                    Some(expr) => expr::evaluate_with(expr, row, &self.functions)?,
+                    None => row.get(col).cloned().unwrap_or(SqlValue::Null),
+                });
             }
-            result.push_str(&row_values.join("\t"));
-            result.push('\n');
+            values.push(row_values);
         }
+        Ok((headers, values))
+    }
 
-        if rows.is_empty() {
-            result.push_str("(0 rows)\n");
-        } else {
-            result.push_str(&format!("({} rows)\n", rows.len()));
+    /// Rejects column references the table doesn't have, which would
+    /// otherwise read as NULL in every row.
+    fn check_columns_exist<V: Visit>(node: &V, schema: &TableSchema) -> Result<()> {
+        let flow = visit_expressions(node, |expr| {
+            let name = match expr {
+                Expr::Identifier(ident) => ident.value.clone(),
+                Expr::CompoundIdentifier(idents) => expr::qualified_name(idents),
+                _ => return ControlFlow::Continue(()),
+            };
+            match schema.columns.iter().any(|c| c.name == name) {
+                true => ControlFlow::Continue(()),
+                false => ControlFlow::Break(anyhow!("unknown column '{}'", name)),
+            }
+        });
+        match flow {
+            ControlFlow::Break(e) => Err(e),
+            ControlFlow::Continue(()) => Ok(()),
+        }
+    }
+}
+
+/// Visits the keys under `prefix` and their values in key order, as
+/// `storage` holds them with `overlay` made on top: a key it deletes is
+/// skipped, and one it inserts reads its value. Stops once `visit` returns
+/// false.
+fn for_each_visible<F>(storage: &BPlusTree, prefix: &str, overlay: &Overlay, mut visit: F) -> Result<()>
+where
+    F: FnMut(&str, &[u8]) -> Result<bool>,
+{
+    let mut written = overlay.iter().peekable();
+    let mut more = true;
+    storage.for_each_prefix_while(prefix, |key, data| {
+        let mut replaced = false;
+        while let Some((written_key, value)) = written.next_if(|(written_key, _)| written_key.as_str() <= key) {
+            replaced = written_key == key;
+            if let Some(value) = value {
+                more = visit(written_key, value)?;
+                if !more {
+                    return Ok(false);
+                }
+            }
+        }
+        if !replaced {
+            more = visit(key, data)?;
+        }
+        Ok(more)
+    })?;
+    for (key, value) in written {
+        if !more {
+            break;
+        }
+        if let Some(value) = value {
+            more = visit(key, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates a LIMIT or OFFSET expression, which may be any constant
+/// expression (including a bound parameter) yielding a non-negative integer.
+fn row_count(expr: &Expr, clause: &str) -> Result<usize> {
+    let row = Row::new();
+    let value = expr::evaluate(expr, &row)
+        .map_err(|e| anyhow!("{} must be a non-negative integer, got {}: {}", clause, expr, e))?;
+    match value {
+        SqlValue::Integer(n) if n >= 0 => Ok(n as usize),
+        other => Err(anyhow!("{} must be a non-negative integer, got {}", clause, output::value_sql(&other))),
+    }
+}
+
+
+/// The parts of an INSERT the engine acts on, taken out of sqlparser's
+/// `Statement::Insert` in one place since its shape changes between
+/// sqlparser versions. `source` is `None` for `DEFAULT VALUES`.
+struct InsertParts<'a> {
+    table_name: &'a ObjectName,
+    columns: &'a [Ident],
+    source: Option<&'a Query>,
+    returning: Option<&'a [SelectItem]>,
+}
+
+impl<'a> InsertParts<'a> {
+    /// Refuses the INSERT clauses the engine doesn't implement rather than
+    /// ignoring them.
+    fn from_statement(statement: &'a Statement) -> Result<Self> {
+        match statement {
+            Statement::Insert { or: Some(action), .. } => Err(anyhow!("INSERT OR {} is not supported", action)),
+            Statement::Insert { on: Some(_), .. } => {
+                Err(anyhow!("INSERT ... ON CONFLICT and ON DUPLICATE KEY UPDATE are not supported"))
+            }
+            Statement::Insert { partitioned: Some(_), .. } | Statement::Insert { overwrite: true, .. } => {
+                Err(anyhow!("Partitioned and overwriting INSERTs are not supported"))
+            }
+            Statement::Insert { table_name, columns, source, returning, .. } => Ok(Self {
+                table_name,
+                columns,
+                source: Some(source),
+                returning: returning.as_deref(),
+            }),
+            _ => Err(anyhow!("Expected an INSERT statement, got: {}", statement)),
+        }
+    }
+}
+
+/// One statement of a batch passed to `execute_in`.
+enum Batched {
+    Statement(Box<Statement>),
+    DefaultValuesInsert((ObjectName, Option<Vec<SelectItem>>)),
+}
+
+/// Parses the tokens of one statement of a batch. The parser doesn't know
+/// DEFAULT VALUES or a bare `DELETE ... RETURNING`, so those are tried
+/// first.
+fn parse_batched(tokens: &[Token]) -> Result<Vec<Batched>> {
+    if let Some(insert) = parse_default_values_insert(tokens.to_vec()) {
+        return Ok(vec![Batched::DefaultValuesInsert(insert?)]);
+    }
+    if let Some(delete) = parse_bare_returning_delete(tokens.to_vec()) {
+        return Ok(vec![Batched::Statement(Box::new(delete?))]);
+    }
+    let statements = Parser::new(&GenericDialect {})
+        .with_tokens(tokens.to_vec())
+        .parse_statements()
+        .map_err(|e| anyhow!("Parse error: {}", e))?;
+    Ok(statements.into_iter().map(|statement| Batched::Statement(Box::new(statement))).collect())
+}
+
+/// Recognises `INSERT INTO <table> DEFAULT VALUES [RETURNING ...]`, which
+/// the parser rejects. `None` if `sql` isn't one.
+fn parse_default_values_insert(tokens: Vec<Token>) -> Option<Result<(ObjectName, Option<Vec<SelectItem>>)>> {
+    let mut parser = Parser::new(&GenericDialect {}).with_tokens(tokens);
+    if !parser.parse_keywords(&[Keyword::INSERT, Keyword::INTO]) {
+        return None;
+    }
+    let table_name = parser.parse_object_name().ok()?;
+    if !parser.parse_keywords(&[Keyword::DEFAULT, Keyword::VALUES]) {
+        return None;
+    }
+    let mut rest = || -> std::result::Result<_, ParserError> {
+        let returning = match parser.parse_keyword(Keyword::RETURNING) {
+            true => Some(parser.parse_comma_separated(Parser::parse_select_item)?),
+            false => None,
+        };
+        let _ = parser.consume_token(&Token::SemiColon);
+        parser.expect_token(&Token::EOF)?;
+        Ok(returning)
+    };
+    Some(rest().map(|returning| (table_name, returning)).map_err(|e| anyhow!("Parse error: {}", e)))
+}
+
+/// Recognises `DELETE FROM <table> RETURNING ...` with no WHERE, which
+/// the parser reads as the table aliased `RETURNING` and then fails on.
+/// `None` if `sql` isn't one.
+fn parse_bare_returning_delete(tokens: Vec<Token>) -> Option<Result<Statement>> {
+    let mut parser = Parser::new(&GenericDialect {}).with_tokens(tokens);
+    if !parser.parse_keywords(&[Keyword::DELETE, Keyword::FROM]) {
+        return None;
+    }
+    let name = parser.parse_object_name().ok()?;
+    if !parser.parse_keyword(Keyword::RETURNING) {
+        return None;
+    }
+    let mut rest = || -> std::result::Result<_, ParserError> {
+        let returning = parser.parse_comma_separated(Parser::parse_select_item)?;
+        let _ = parser.consume_token(&Token::SemiColon);
+        parser.expect_token(&Token::EOF)?;
+        Ok(returning)
+    };
+    let delete = |returning| Statement::Delete {
+        tables: vec![],
+        from: vec![TableWithJoins {
+            relation: TableFactor::Table {
+                name,
+                alias: None,
+                args: None,
+                with_hints: vec![],
+                version: None,
+                partitions: vec![],
+            },
+            joins: vec![],
+        }],
+        using: None,
+        selection: None,
+        returning: Some(returning),
+        order_by: vec![],
+        limit: None,
+    };
+    Some(rest().map(delete).map_err(|e| anyhow!("Parse error: {}", e)))
+}
+
+fn parse_generated_expr(sql: &str) -> Result<Expr> {
+    Parser::new(&GenericDialect {})
+        .try_with_sql(sql)
+        .and_then(|mut parser| parser.parse_expr())
+        .map_err(|e| anyhow!("Invalid generated column expression '{}': {}", sql, e))
+}
+
+/// The value `column`'s DEFAULT (its SQL text `default`) gives a row
+/// inserted at `now`, converted as an inserted value would be.
+fn default_value(
+    column: &Column,
+    default: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    session: &Session,
+) -> Result<SqlValue> {
+    let expr = Parser::new(&GenericDialect {})
+        .try_with_sql(default)
+        .and_then(|mut parser| parser.parse_expr())
+        .map_err(|e| anyhow!("Invalid DEFAULT for column '{}': {}", column.name, e))?;
+    let value = match &expr {
+        Expr::Function(function) if function.name.to_string().eq_ignore_ascii_case("CURRENT_TIMESTAMP") => {
+            if !matches!(column.data_type, SqlDataType::Timestamp) {
+                return Err(anyhow!(
+                    "CURRENT_TIMESTAMP can only be the DEFAULT of a TIMESTAMP column, not '{}'",
+                    column.name
+                ));
+            }
+            SqlValue::Timestamp(now)
+        }
+        Expr::Value(_) => expr::evaluate(&expr, &Row::new())?,
+        Expr::UnaryOp { expr: operand, .. } if matches!(**operand, Expr::Value(_)) => {
+            expr::evaluate(&expr, &Row::new())?
+        }
+        _ => {
+            return Err(anyhow!(
+                "DEFAULT for column '{}' must be a literal or CURRENT_TIMESTAMP, got {}",
+                column.name,
+                default
+            ))
+        }
+    };
+    insert_value(value, column, session)
+}
+
+/// Whether a join condition is equalities joined by AND, the only kind
+/// joins support so far.
+fn is_equality(condition: &Expr) -> bool {
+    match condition {
+        Expr::BinaryOp { op: BinaryOperator::Eq, .. } => true,
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => is_equality(left) && is_equality(right),
+        Expr::Nested(inner) => is_equality(inner),
+        _ => false,
+    }
+}
+
+fn references_column(expr: &Expr, column: &str) -> bool {
+    visit_expressions(expr, |e| match e {
+        Expr::Identifier(ident) if ident.value == column => ControlFlow::Break(()),
+        _ => ControlFlow::Continue(()),
+    })
+    .is_break()
+}
+
+fn rename_identifier(expr: &mut Expr, from: &str, to: &str) {
+    let _ = visit_expressions_mut(expr, |e| {
+        if let Expr::Identifier(ident) = e {
+            if ident.value == from {
+                ident.value = to.to_string();
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+}
+
+/// Fills in the row's generated columns from its ordinary ones.
+fn compute_generated_columns(row: &mut Row, schema: &TableSchema, functions: &FunctionRegistry) -> Result<()> {
+    for column in &schema.columns {
+        let value = match (&column.generated_expr, &column.generated) {
+            (Some(parsed), _) => expr::evaluate_with(parsed, row, functions)?,
+            (None, Some(generated)) => expr::evaluate_with(&parse_generated_expr(generated)?, row, functions)?,
+            (None, None) => continue,
+        };
+        row.set(column.name.clone(), value);
+    }
+    Ok(())
+}
+
+/// Brings a row written under an older schema version in line with
+/// `schema`: values of columns the schema no longer has are dropped, and
+/// values whose column changed type are converted. A value that can't be
+/// converted is an error naming the row, rather than being guessed at.
+/// Columns added since the row was written are left absent and read as NULL.
+fn conform_row(key: &str, mut row: Row, schema: &TableSchema) -> Result<Row> {
+    if row.schema_version == schema.version {
+        return Ok(row);
+    }
+
+    let version = row.schema_version;
+    let dropped: Vec<String> = row
+        .iter()
+        .map(|(name, _)| name)
+        .filter(|name| !schema.columns.iter().any(|c| c.name == **name && c.added_in <= version))
+        .cloned()
+        .collect();
+    for name in dropped {
+        row.remove(&name);
+    }
+    for column in &schema.columns {
+        if let Some(value) = row.get(&column.name) {
+            let converted = conform_value(value, &column.data_type).ok_or_else(|| {
+                anyhow!(
+                    "Row '{}' (schema v{}) has value {:?} for column '{}', which can't be read as {:?}",
+                    key,
+                    row.schema_version,
+                    value,
+                    column.name,
+                    column.data_type
+                )
+            })?;
+            row.set(column.name.clone(), converted);
+        }
+    }
+    row.schema_version = schema.version;
+    Ok(row)
+}
+
+/// Checks one stored row against its table's schema, failing with the
+/// first problem found.
+fn check_row(key: &str, data: &[u8], schema: &TableSchema) -> Result<()> {
+    let row = codec::decode_row(data).map_err(|e| anyhow!("Row '{}' can't be decoded: {}", key, e))?;
+    if row.schema_version > schema.version {
+        return Err(anyhow!(
+            "Row '{}' claims schema v{}, newer than the table's v{}",
+            key,
+            row.schema_version,
+            schema.version
+        ));
+    }
+    let row = conform_row(key, row, schema)?;
+
+    for (name, value) in row.iter() {
+        let column = schema
+            .columns
+            .iter()
+            .find(|c| c.name == *name)
+            .ok_or_else(|| anyhow!("Row '{}' has a value for unknown column '{}'", key, name))?;
+        if !fits_type(value, &column.data_type) {
+            return Err(anyhow!("Row '{}' has value {:?} for {:?} column '{}'", key, value, column.data_type, name));
+        }
+    }
+    if let Some(pk) = schema.columns.iter().find(|c| c.primary_key) {
+        if let Some(value) = row.get(&pk.name) {
+            let expected = format!("{}{}", schema.key_prefix(), encode_key_value(value));
+            if key != expected {
+                return Err(anyhow!("Row '{}' is stored under the wrong key, its primary key gives '{}'", key, expected));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks a value given for `column` in an INSERT against the column's
+/// type. Integers widen to decimals and strings are read as timestamps;
+/// any other mismatch is an error, as is a string longer than a VARCHAR
+/// column allows, unless the session's `StringOverflow` says to cut it to
+/// length. Decimals are fitted to their column by `decimal_value`.
+fn insert_value(value: SqlValue, column: &Column, session: &Session) -> Result<SqlValue> {
+    let mismatch = |value: &SqlValue| {
+        let shown = match value {
+            SqlValue::Integer(i) => i.to_string(),
+            SqlValue::Decimal(d) => d.to_string(),
+            SqlValue::Boolean(b) => b.to_string(),
+            SqlValue::Varchar(s) => format!("'{}'", s),
+            SqlValue::Timestamp(t) => format!("'{}'", t.to_rfc3339()),
+            SqlValue::Null => "NULL".to_string(),
+        };
+        anyhow!("Cannot insert {} into {} column '{}'", shown, ddl::type_sql(&column.data_type), column.name)
+    };
+    match (value, &column.data_type) {
+        (SqlValue::Varchar(s), SqlDataType::Varchar(length)) => match s.char_indices().nth(*length as usize) {
+            None => Ok(SqlValue::Varchar(s)),
+            Some((end, _)) if session.string_overflow() == StringOverflow::Truncate => {
+                Ok(SqlValue::Varchar(s[..end].to_string()))
+            }
+            Some(_) => Err(anyhow!(
+                "Value for column '{}' is {} characters, longer than VARCHAR({})",
+                column.name,
+                s.chars().count(),
+                length
+            )),
+        },
+        (SqlValue::Varchar(s), SqlDataType::Timestamp) => match expr::parse_timestamp(&s) {
+            Some(t) => Ok(SqlValue::Timestamp(t)),
+            None => Err(mismatch(&SqlValue::Varchar(s))),
+        },
+        (SqlValue::Integer(i), SqlDataType::Decimal(precision, scale)) => {
+            decimal_value(i as f64, *precision, *scale, column, session.scale_overflow())
+        }
+        (SqlValue::Decimal(d), SqlDataType::Decimal(precision, scale)) => {
+            decimal_value(d, *precision, *scale, column, session.scale_overflow())
+        }
+        (value @ SqlValue::Null, _)
+        | (value @ SqlValue::Integer(_), SqlDataType::Integer)
+        | (value @ SqlValue::Boolean(_), SqlDataType::Boolean)
+        | (value @ SqlValue::Timestamp(_), SqlDataType::Timestamp) => Ok(value),
+        (value, _) => Err(mismatch(&value)),
+    }
+}
+
+/// Fits `value` to a `DECIMAL(precision, scale)` column: extra decimal
+/// places are rounded off or rejected, as `overflow` says, and a value
+/// with more than `precision - scale` integer digits is rejected.
+fn decimal_value(value: f64, precision: u8, scale: u8, column: &Column, overflow: ScaleOverflow) -> Result<SqlValue> {
+    let rounded = match round_half_away(value, scale) {
+        Some(_) if overflow == ScaleOverflow::Error => {
+            return Err(anyhow!(
+                "Value {} for column '{}' has more than {} decimal places",
+                value,
+                column.name,
+                scale
+            ));
+        }
+        Some(rounded) => rounded,
+        None => value,
+    };
+    if rounded.abs() >= 10f64.powi(precision as i32 - scale as i32) {
+        return Err(anyhow!(
+            "Value {} for column '{}' has too many integer digits for DECIMAL({}, {})",
+            value,
+            column.name,
+            precision,
+            scale
+        ));
+    }
+    Ok(SqlValue::Decimal(rounded))
+}
+
+/// `value` rounded to `scale` decimal places, halves away from zero, or
+/// `None` if it has no more places than that. The digits rounded are the
+/// shortest decimal text reading back as `value`, which is the literal as
+/// written, not the binary fraction nearest it: 1.005 is held as
+/// 1.00499999999999989..., but rounds to 1.01.
+fn round_half_away(value: f64, scale: u8) -> Option<f64> {
+    let text = value.abs().to_string();
+    let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+    let scale = scale as usize;
+    if fraction.len() <= scale {
+        return None;
+    }
+    let mut digits = format!("{}{}", whole, &fraction[..scale]).into_bytes();
+    if fraction.as_bytes()[scale] >= b'5' {
+        // Carry the one through any trailing nines
+        let carried = digits.iter().rposition(|&d| d != b'9');
+        let start = carried.map_or(0, |i| i + 1);
+        digits[start..].fill(b'0');
+        match carried {
+            Some(i) => digits[i] += 1,
+            None => digits.insert(0, b'1'),
+        }
+    }
+    if scale > 0 {
+        digits.insert(digits.len() - scale, b'.');
+    }
+    let rounded: f64 = String::from_utf8(digits).ok()?.parse().ok()?;
+    Some(rounded.copysign(value))
+}
+
+fn conform_value(value: &SqlValue, data_type: &SqlDataType) -> Option<SqlValue> {
+    match (value, data_type) {
+        (SqlValue::Null, _)
+        | (SqlValue::Integer(_), SqlDataType::Integer)
+        | (SqlValue::Varchar(_), SqlDataType::Varchar(_))
+        | (SqlValue::Decimal(_), SqlDataType::Decimal(..))
+        | (SqlValue::Boolean(_), SqlDataType::Boolean)
+        | (SqlValue::Timestamp(_), SqlDataType::Timestamp) => Some(value.clone()),
+        (SqlValue::Integer(i), SqlDataType::Decimal(..)) => Some(SqlValue::Decimal(*i as f64)),
+        (SqlValue::Decimal(d), SqlDataType::Integer)
+            if d.fract() == 0.0 && *d >= i64::MIN as f64 && *d <= i64::MAX as f64 =>
+        {
+            Some(SqlValue::Integer(*d as i64))
+        }
+        (SqlValue::Varchar(s), SqlDataType::Integer) => s.trim().parse().ok().map(SqlValue::Integer),
+        (SqlValue::Varchar(s), SqlDataType::Decimal(..)) => s.trim().parse().ok().map(SqlValue::Decimal),
+        (SqlValue::Integer(i), SqlDataType::Varchar(_)) => Some(SqlValue::Varchar(i.to_string())),
+        (SqlValue::Decimal(d), SqlDataType::Varchar(_)) => Some(SqlValue::Varchar(d.to_string())),
+        (SqlValue::Boolean(b), SqlDataType::Varchar(_)) => Some(SqlValue::Varchar(b.to_string())),
+        (SqlValue::Timestamp(t), SqlDataType::Varchar(_)) => Some(SqlValue::Varchar(t.to_rfc3339())),
+        _ => None,
+    }
+}
+
+/// Reads the `shards` option of a CREATE TABLE, defaulting to 1.
+fn shard_count(options: &[SqlOption]) -> Result<u32> {
+    let mut count = 1;
+    for option in options {
+        match (option.name.value.to_ascii_lowercase().as_str(), &option.value) {
+            ("shards", Value::Number(n, _)) => {
+                count = n
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| anyhow!("shards must be a positive integer, got {}", n))?;
+            }
+            ("shards", other) => return Err(anyhow!("shards must be a positive integer, got {}", other)),
+            (other, _) => return Err(anyhow!("Unsupported table option '{}'", other)),
         }
+    }
+    Ok(count)
+}
 
-        Ok(result)
+/// Encodes a primary-key value for use in a storage key. Keys are compared
+/// as strings, so integers are written as the 16 hex digits of the value
+/// with its sign bit flipped: fixed width and ordered numerically, with
+/// negatives first (e.g. 1 -> `8000000000000001`). Other types use their
+/// display form. Changing this requires bumping `KEY_FORMAT_VERSION`.
+pub fn encode_key_value(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Integer(i) => format!("{:016x}", (*i as u64) ^ (1 << 63)),
+        SqlValue::Varchar(s) => s.clone(),
+        SqlValue::Decimal(d) => d.to_string(),
+        SqlValue::Boolean(b) => b.to_string(),
+        SqlValue::Timestamp(t) => t.to_rfc3339(),
+        SqlValue::Null => "null".to_string(),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::bptree::BPlusTree;
+    use crate::clock::{Clock, MockClock};
+    use crate::sql::session::Cancellation;
+    use crate::storage::bptree::{self, BPlusTree};
+    use crate::storage::codec::{self, RowFormat};
+    use crate::storage::shard;
     use crate::txn::wal::WriteAheadLog;
     use tempfile::TempDir;
 
@@ -478,4 +2827,1840 @@ mod tests {
         assert!(result.is_ok());
         assert!(result.unwrap().contains("Alice"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_insert_returning_generated_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY AUTO_INCREMENT, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (name) VALUES ('Alice')").await.unwrap();
+
+        let result = engine.execute("INSERT INTO users (name) VALUES ('Bob') RETURNING id").await.unwrap();
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines[0], "id");
+        assert_eq!(lines[2], "2");
+        assert!(result.contains("(1 rows)"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_returning_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Alice'), (2, 'Bob')").await.unwrap();
+
+        let result = engine.execute("DELETE FROM users RETURNING *").await.unwrap();
+        assert!(result.contains("Alice"));
+        assert!(result.contains("Bob"));
+        assert!(result.contains("(2 rows)"));
+
+        engine.execute("INSERT INTO users (id, name) VALUES (3, 'Carol')").await.unwrap();
+        let result = engine.execute("DELETE FROM users AS u WHERE id = 3 RETURNING name;").await.unwrap();
+        assert!(result.contains("Carol"));
+
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("(0 rows)"));
+    }
+
+    #[tokio::test]
+    async fn test_prepare_and_execute() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("PREPARE add_user AS INSERT INTO users (id, name) VALUES ($1, $2)").await.unwrap();
+        engine.execute("EXECUTE add_user (1, 'O''Brien')").await.unwrap();
+        assert!(engine.execute("EXECUTE add_user (2)").await.is_err());
+        let err = engine.execute("EXECUTE add_user ('x', 'Carol')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Parameter $1 is VARCHAR but column 'id' is INTEGER");
+
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("O'Brien"));
+
+        engine.execute("DEALLOCATE add_user").await.unwrap();
+        assert!(engine.execute("EXECUTE add_user (3, 'Carol')").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_projection_arithmetic_overflow() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        let err = engine.execute("SELECT 9223372036854775807 + 1").await.unwrap_err();
+        assert_eq!(err.to_string(), "integer overflow in expression");
+
+        engine.execute("CREATE TABLE items (id INTEGER PRIMARY KEY, qty INTEGER)").await.unwrap();
+        engine.execute("INSERT INTO items (id, qty) VALUES (1, 9223372036854775807)").await.unwrap();
+        assert!(engine.execute("SELECT qty * 2 FROM items").await.is_err());
+
+        let result = engine.execute("SELECT id + 1 AS next, qty - 7 FROM items").await.unwrap();
+        assert!(result.starts_with("next\t?column?"));
+        assert!(result.contains("2\t9223372036854775800"));
+    }
+
+    #[tokio::test]
+    async fn test_integer_keys_scan_in_numeric_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY)").await.unwrap();
+        for id in [100, 10, -5, 2, 1] {
+            engine.execute(&format!("INSERT INTO t (id) VALUES ({})", id)).await.unwrap();
+        }
+
+        let keys = engine.storage.read().await.scan_prefix("1:").unwrap();
+        let expected: Vec<String> = [-5, 1, 2, 10, 100]
+            .iter()
+            .map(|id| format!("1:{}", encode_key_value(&SqlValue::Integer(*id))))
+            .collect();
+        assert_eq!(keys, expected);
+
+        let result = engine.execute("SELECT id FROM t").await.unwrap();
+        let ids: Vec<&str> = result.lines().skip(2).take(5).collect();
+        assert_eq!(ids, vec!["-5", "1", "2", "10", "100"]);
+    }
+
+    #[tokio::test]
+    async fn test_where_compares_two_columns() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE products (id INTEGER PRIMARY KEY, price DECIMAL, discount INTEGER)").await.unwrap();
+        engine.execute("INSERT INTO products (id, price, discount) VALUES (1, 10.5, 2), (2, 3.0, 5), (3, 8.0, 8)").await.unwrap();
+
+        let result = engine.execute("SELECT id FROM products WHERE discount < price").await.unwrap();
+        let ids: Vec<&str> = result.lines().skip(2).collect();
+        assert_eq!(ids, vec!["1", "(1 rows)"]);
+    }
+
+    #[tokio::test]
+    async fn test_databases_isolate_same_named_tables() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        let mut first = Session::new();
+        let mut second = Session::new();
+        engine.execute_in(&mut first, "CREATE DATABASE a").await.unwrap();
+        engine.execute_in(&mut first, "CREATE DATABASE b").await.unwrap();
+        assert!(engine.execute_in(&mut first, "CREATE DATABASE a").await.is_err());
+        assert!(engine.execute_in(&mut first, "USE missing").await.is_err());
+
+        engine.execute_in(&mut first, "USE a").await.unwrap();
+        engine.execute_in(&mut second, "USE b").await.unwrap();
+        assert_eq!(first.database(), "a");
+        for (session, name) in [(&mut first, "Alice"), (&mut second, "Bob")] {
+            engine.execute_in(session, "CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+            engine.execute_in(session, &format!("INSERT INTO users (id, name) VALUES (1, '{}')", name)).await.unwrap();
+        }
+
+        let result = engine.execute_in(&mut first, "SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Alice") && !result.contains("Bob"));
+        let result = engine.execute_in(&mut second, "SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Bob") && !result.contains("Alice"));
+
+        // Qualified names reach across databases; the default database is untouched
+        let result = engine.execute_in(&mut first, "SELECT name FROM b.users").await.unwrap();
+        assert!(result.contains("Bob"));
+        assert!(engine.execute("SELECT * FROM users").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_select_unknown_column() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Alice')").await.unwrap();
+
+        let err = engine.execute("SELECT nmae FROM users").await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown column 'nmae'");
+        assert!(engine.execute("SELECT id + bogus AS x FROM users").await.is_err());
+
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Alice"));
+    }
+
+    #[tokio::test]
+    async fn test_create_table_rejects_invalid_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        let err = engine.execute("CREATE TABLE \"a:b\" (id INTEGER PRIMARY KEY)").await.unwrap_err();
+        assert!(err.to_string().contains("must not contain ':'"), "{}", err);
+
+        let err = engine.execute("CREATE TABLE t (\"\" INTEGER)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid column name: must not be empty");
+
+        // Nothing was created by the rejected statements
+        assert!(engine.execute("SELECT * FROM t").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_insert_value_count_must_match_columns() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100), age INTEGER)").await.unwrap();
+
+        let err = engine.execute("INSERT INTO users (id, name) VALUES (1)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Too few values provided: expected 2, got 1");
+        let err = engine.execute("INSERT INTO users (id) VALUES (1, 'Alice')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Too many values provided: expected 1, got 2");
+        let err = engine.execute("INSERT INTO users VALUES (1, 'Alice')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Too few values provided: expected 3, got 2");
+
+        // A bad row rejects the whole statement
+        assert!(engine.execute("INSERT INTO users (id, name) VALUES (1, 'Alice'), (2)").await.is_err());
+        assert!(engine.execute("SELECT * FROM users").await.unwrap().contains("(0 rows)"));
+
+        // Omitting a column from the list is how it's left unset
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Alice')").await.unwrap();
+        engine.execute("INSERT INTO users VALUES (2, 'Bob', 30)").await.unwrap();
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("Alice\tNULL") && result.contains("Bob\t30"));
+    }
+
+    #[tokio::test]
+    async fn test_generated_columns() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY, a INT, b INT, total INT GENERATED ALWAYS AS (a + b))").await.unwrap();
+        engine.execute("INSERT INTO t (id, a, b) VALUES (1, 2, 3)").await.unwrap();
+        engine.execute("INSERT INTO t VALUES (2, 10, 20)").await.unwrap();
+
+        let result = engine.execute("SELECT id, total FROM t").await.unwrap();
+        assert!(result.contains("1\t5\n") && result.contains("2\t30\n"), "{}", result);
+
+        let err = engine.execute("INSERT INTO t (id, a, b, total) VALUES (3, 1, 1, 99)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot write to generated column 'total'");
+
+        assert!(engine.execute("CREATE TABLE bad (a INT, c INT GENERATED ALWAYS AS (a + missing))").await.is_err());
+
+        // A schema read back from the WAL has its expression parsed again
+        let stored = bincode::serialize(&engine.schemas.read().await["t"]).unwrap();
+        let schema: TableSchema = bincode::deserialize(&stored).unwrap();
+        assert!(schema.columns[3].generated_expr.is_none());
+        let mut catalog = Catalog::new();
+        catalog.schemas.insert("t".to_string(), schema);
+        engine.install_catalog(catalog).await;
+        assert!(engine.schemas.read().await["t"].columns[3].generated_expr.is_some());
+        engine.execute("INSERT INTO t (id, a, b) VALUES (3, 1, 1)").await.unwrap();
+        assert!(engine.execute("SELECT total FROM t WHERE id = 3").await.unwrap().contains("\n2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rows_read_under_altered_schema() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'alice')").await.unwrap();
+        engine.execute("ALTER TABLE users ADD COLUMN email VARCHAR(100)").await.unwrap();
+        engine.execute("INSERT INTO users (id, name, email) VALUES (2, 'bob', 'bob@example.com')").await.unwrap();
+
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("1\talice\tNULL\n"), "{}", result);
+        assert!(result.contains("2\tbob\tbob@example.com\n"), "{}", result);
+        assert_eq!(engine.schemas.read().await["users"].version, 2);
+
+        let err = engine.execute("ALTER TABLE users ADD COLUMN email VARCHAR(10)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'email' already exists");
+        // Nothing to add, so no new version and nothing logged
+        let logged = engine.wal.read().await.entry_count();
+        engine.execute("ALTER TABLE users ADD COLUMN IF NOT EXISTS email VARCHAR(10)").await.unwrap();
+        engine.execute("ALTER TABLE users DROP COLUMN IF EXISTS missing").await.unwrap();
+        assert_eq!(engine.schemas.read().await["users"].version, 2);
+        assert_eq!(engine.wal.read().await.entry_count(), logged);
+
+        // A row from an older version holding a dropped column and a value
+        // whose column type has since changed
+        let old = Row {
+            values: HashMap::from([
+                ("id".to_string(), SqlValue::Decimal(3.0)),
+                ("name".to_string(), SqlValue::Integer(7)),
+                ("legacy".to_string(), SqlValue::Boolean(true)),
+            ]),
+            schema_version: 1,
+        };
+        engine.storage.write().await.insert("1:3".to_string(), codec::encode_row(&old, RowFormat::Bincode).unwrap()).unwrap();
+        let schema = engine.schemas.read().await["users"].clone();
+        let rows = engine.load_table_rows(&schema).await.unwrap();
+        let row = rows.iter().find(|r| matches!(r.get("id"), Some(SqlValue::Integer(3)))).unwrap();
+        assert!(matches!(row.get("name"), Some(SqlValue::Varchar(s)) if s == "7"));
+        assert!(row.get("legacy").is_none());
+        assert_eq!(row.schema_version, schema.version);
+
+        let bad = Row { values: HashMap::from([("id".to_string(), SqlValue::Varchar("x".into()))]), schema_version: 1 };
+        engine.storage.write().await.insert("1:4".to_string(), codec::encode_row(&bad, RowFormat::Json).unwrap()).unwrap();
+        let err = engine.execute("SELECT * FROM users").await.unwrap_err();
+        assert!(err.to_string().contains("column 'id'"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_execute_statement_matches_sql_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = |name: &str| {
+            let path = temp_dir.path().join(name);
+            async move {
+                let wal = WriteAheadLog::new(path.to_str().unwrap()).await.unwrap();
+                SqlEngine::new(BPlusTree::new(), wal)
+            }
+        };
+        let (by_sql, by_ast) = (engine("sql.wal").await, engine("ast.wal").await);
+        let parse = |sql: &str| Parser::parse_sql(&GenericDialect {}, sql).unwrap().remove(0);
+
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))",
+            "INSERT INTO users (id, name) VALUES (1, 'alice'), (2, 'bob')",
+            "SELECT name FROM users WHERE id = 1",
+        ] {
+            let expected = by_sql.execute(sql).await.unwrap();
+            assert_eq!(by_ast.execute_statement(&parse(sql)).await.unwrap(), expected);
+        }
+
+        // Callers can rewrite the AST before running it
+        let mut select = parse("SELECT name FROM users WHERE id = 1");
+        let _ = sqlparser::ast::visit_expressions_mut(&mut select, |expr| {
+            if let Expr::Value(Value::Number(n, _)) = expr {
+                *n = "2".to_string();
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        let result = by_ast.execute_statement(&select).await.unwrap();
+        assert!(result.contains("bob") && !result.contains("alice"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_select_formatting_without_rows_or_columns() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE tags (label VARCHAR(20))").await.unwrap();
+        let result = engine.execute("SELECT * FROM tags").await.unwrap();
+        assert_eq!(result, "label\n-----\n(0 rows)\n");
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'bartholomew')").await.unwrap();
+        let result = engine.execute("SELECT id, name FROM users").await.unwrap();
+        assert_eq!(result, "id\tname\n-------------------\n1\tbartholomew\n(1 rows)\n");
+
+        engine.execute("CREATE TABLE nothing ()").await.unwrap();
+        assert_eq!(engine.execute("SELECT * FROM nothing").await.unwrap(), "(0 rows)\n");
+    }
+
+    #[tokio::test]
+    async fn test_where_subqueries() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'alice'), (2, 'bob'), (3, 'carol')").await.unwrap();
+        engine.execute("CREATE TABLE orders (id INTEGER PRIMARY KEY, user_id INTEGER)").await.unwrap();
+        engine.execute("INSERT INTO orders (id, user_id) VALUES (10, 1), (11, 3), (12, 3)").await.unwrap();
+        engine.execute("CREATE TABLE products (id INTEGER PRIMARY KEY, price DECIMAL(10,2))").await.unwrap();
+        engine.execute("INSERT INTO products (id, price) VALUES (1, 5.0), (2, 10.0), (3, 30.0)").await.unwrap();
+
+        let result = engine.execute("SELECT name FROM users WHERE id IN (SELECT user_id FROM orders) ORDER BY id").await.unwrap();
+        assert_eq!(result, "name\n-----\nalice\ncarol\n(2 rows)\n");
+        let result = engine.execute("SELECT name FROM users WHERE id NOT IN (SELECT user_id FROM orders)").await.unwrap();
+        assert!(result.contains("bob\n(1 rows)"), "{}", result);
+
+        // The average is 15
+        let result = engine.execute("SELECT id FROM products WHERE price > (SELECT AVG(price) FROM products)").await.unwrap();
+        assert_eq!(result, "id\n--\n3\n(1 rows)\n");
+
+        let err = engine.execute("SELECT id FROM products WHERE price > (SELECT price FROM products)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Scalar subquery returned more than one row");
+        let err = engine.execute("SELECT id FROM users WHERE id IN (SELECT id, user_id FROM orders)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Subquery must return exactly one column, got 2");
+    }
+
+    #[tokio::test]
+    async fn test_limit_and_offset_expressions() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("INSERT INTO t (id) VALUES (1), (2), (3), (4), (5)").await.unwrap();
+
+        engine.execute("PREPARE page AS SELECT id FROM t ORDER BY id LIMIT ? OFFSET ?").await.unwrap();
+        let result = engine.execute("EXECUTE page (2, 1)").await.unwrap();
+        assert_eq!(result, "id\n--\n2\n3\n(2 rows)\n");
+
+        let result = engine.execute("SELECT id FROM t ORDER BY id LIMIT 1 + 2").await.unwrap();
+        assert!(result.ends_with("1\n2\n3\n(3 rows)\n"), "{}", result);
+
+        let err = engine.execute("SELECT id FROM t LIMIT -1").await.unwrap_err();
+        assert_eq!(err.to_string(), "LIMIT must be a non-negative integer, got -1");
+        let err = engine.execute("SELECT id FROM t LIMIT 99999999999999999999").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "LIMIT must be a non-negative integer, got 99999999999999999999: number too large to fit in target type"
+        );
+        assert!(engine.execute("EXECUTE page (1, 'x')").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limit_with_offset() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("INSERT INTO t (id) VALUES (1), (2), (3), (4), (5)").await.unwrap();
+
+        let result = engine.execute("SELECT id FROM t ORDER BY id LIMIT 2 OFFSET 1").await.unwrap();
+        assert_eq!(result, "id\n--\n2\n3\n(2 rows)\n");
+        let result = engine.execute("SELECT id FROM t ORDER BY id OFFSET 3").await.unwrap();
+        assert_eq!(result, "id\n--\n4\n5\n(2 rows)\n");
+
+        // An offset past the end leaves nothing, with or without a limit
+        let result = engine.execute("SELECT id FROM t ORDER BY id LIMIT 2 OFFSET 10").await.unwrap();
+        assert!(result.ends_with("(0 rows)\n"), "{}", result);
+        let result = engine.execute("SELECT id FROM t OFFSET 5").await.unwrap();
+        assert!(result.ends_with("(0 rows)\n"), "{}", result);
+
+        let err = engine.execute("SELECT id FROM t LIMIT 2 OFFSET 'two'").await.unwrap_err();
+        assert_eq!(err.to_string(), "OFFSET must be a non-negative integer, got 'two'");
+    }
+
+    #[tokio::test]
+    async fn test_drop_table_releases_all_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        for i in 0..1000 {
+            let name = format!("t{}", i);
+            engine.execute(&format!("CREATE TABLE {} (id INTEGER PRIMARY KEY AUTO_INCREMENT)", name)).await.unwrap();
+            engine.execute(&format!("INSERT INTO {} (id) VALUES (NULL)", name)).await.unwrap();
+            engine.execute(&format!("DROP TABLE {}", name)).await.unwrap();
+        }
+
+        assert!(engine.schemas.read().await.is_empty());
+        assert!(engine.sequences.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plan_cache_shared_by_shape_and_invalidated_by_ddl() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'alice'), (2, 'bob')").await.unwrap();
+
+        let result = engine.execute("SELECT name FROM users WHERE id = 1").await.unwrap();
+        assert!(result.contains("alice") && !result.contains("bob"), "{}", result);
+        let result = engine.execute("SELECT name FROM users WHERE id = 2").await.unwrap();
+        assert!(result.contains("bob") && !result.contains("alice"), "{}", result);
+        let result = engine.execute("SELECT name FROM users WHERE id = 3").await.unwrap();
+        assert!(result.ends_with("(0 rows)\n"), "{}", result);
+        {
+            let plans = engine.plans.read().await;
+            assert_eq!((plans.len(), plans.misses(), plans.hits()), (1, 1, 2));
+            let plan = plans.plans().next().unwrap();
+            assert_eq!(plan.access, AccessPath::PrimaryKey { column: "id".to_string() });
+        }
+
+        engine.execute("ALTER TABLE users ADD COLUMN email VARCHAR(100)").await.unwrap();
+        assert!(engine.plans.read().await.is_empty());
+        let result = engine.execute("SELECT * FROM users WHERE id = 1").await.unwrap();
+        assert!(result.contains("1\talice\tNULL"), "{}", result);
+        assert_eq!(engine.plans.read().await.misses(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_result_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE big (id INTEGER PRIMARY KEY)").await.unwrap();
+        let values: Vec<String> = (0..500).map(|i| format!("({})", i)).collect();
+        engine.execute(&format!("INSERT INTO big (id) VALUES {}", values.join(", "))).await.unwrap();
+
+        let mut session = Session::with_result_limit(crate::sql::session::ResultLimit {
+            max_rows: Some(10),
+            mode: LimitMode::Truncate,
+        });
+        let result = engine.execute_in(&mut session, "SELECT id FROM big").await.unwrap();
+        assert!(result.ends_with("(10 rows)\nWarning: result truncated to 10 rows (max_result_rows)\n"), "{}", result);
+
+        // The scan stops once it has the rows wanted, filtered rows not
+        // counted; an aggregate still reads every row
+        let query = |sql| match Parser::parse_sql(&GenericDialect {}, sql).unwrap().remove(0) {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let mut stats = QueryStats::collecting();
+        let odd = query("SELECT id FROM big WHERE id % 2 = 1");
+        let (_, rows) = engine.query_rows(&session, &odd, Some(11), &mut stats).await.unwrap();
+        assert_eq!(rows.len(), 11);
+        assert_eq!(stats.rows_scanned, 22);
+        let mut stats = QueryStats::collecting();
+        engine.query_rows(&session, &query("SELECT COUNT(*) FROM big"), Some(11), &mut stats).await.unwrap();
+        assert_eq!(stats.rows_scanned, 500);
+
+        // An explicit LIMIT is the caller's choice and isn't capped
+        let result = engine.execute_in(&mut session, "SELECT id FROM big LIMIT 20").await.unwrap();
+        assert!(result.ends_with("(20 rows)\n"), "{}", result);
+
+        engine.execute_in(&mut session, "SET result_limit_mode = 'error'").await.unwrap();
+        let err = engine.execute_in(&mut session, "SELECT id FROM big").await.unwrap_err();
+        assert!(err.to_string().contains("more than 10 rows"), "{}", err);
+
+        engine.execute_in(&mut session, "SET max_result_rows = 0").await.unwrap();
+        let result = engine.execute_in(&mut session, "SELECT id FROM big").await.unwrap();
+        assert!(result.ends_with("(500 rows)\n"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_table_shard_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE events (id INTEGER PRIMARY KEY) WITH (shards = 4)").await.unwrap();
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        let key = SqlValue::Integer(42);
+        let expected = shard::shard_for_key(encode_key_value(&key).as_bytes(), 4);
+        assert_eq!(engine.shard_for("events", &key).await.unwrap(), expected);
+        assert_eq!(engine.shard_for("users", &key).await.unwrap(), 0);
+
+        for bad in ["WITH (shards = 0)", "WITH (shards = 'x')", "WITH (colour = 1)"] {
+            assert!(engine.execute(&format!("CREATE TABLE t (id INTEGER) {}", bad)).await.is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_column() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine
+            .execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100), nickname VARCHAR(100) NULL, qty INTEGER, double_qty INTEGER GENERATED ALWAYS AS (qty * 2))")
+            .await
+            .unwrap();
+        engine.execute("INSERT INTO users (id, name, nickname, qty) VALUES (1, 'alice', 'al', 3)").await.unwrap();
+
+        engine.execute("ALTER TABLE users DROP COLUMN nickname").await.unwrap();
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.starts_with("id\tname\tqty\tdouble_qty\n"), "{}", result);
+        assert!(result.contains("1\talice\t3\t6\n"), "{}", result);
+        assert!(engine.execute("SELECT nickname FROM users").await.is_err());
+
+        // The drop is logged with the new schema, so replay reconstructs it
+        let logged = match &engine.wal.read().await.get_entries().last().unwrap().operation {
+            WalOperation::AlterTable(schema) => schema.columns.iter().map(|c| c.name.clone()).collect::<Vec<_>>(),
+            other => panic!("expected AlterTable, got {:?}", other),
+        };
+        assert_eq!(logged, vec!["id", "name", "qty", "double_qty"]);
+
+        let err = engine.execute("ALTER TABLE users DROP COLUMN id").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot drop primary key column 'id'");
+        let err = engine.execute("ALTER TABLE users DROP COLUMN qty").await.unwrap_err();
+        assert!(err.to_string().contains("double_qty depend on it"), "{}", err);
+        engine.execute("ALTER TABLE users DROP COLUMN qty CASCADE").await.unwrap();
+        engine.execute("ALTER TABLE users DROP COLUMN IF EXISTS qty").await.unwrap();
+
+        // A re-added column doesn't resurrect the old values left in storage
+        engine.execute("ALTER TABLE users ADD COLUMN nickname VARCHAR(100)").await.unwrap();
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("1\talice\tNULL\n"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_rename_table_and_column() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY AUTO_INCREMENT, name VARCHAR(100), qty INTEGER, double_qty INTEGER GENERATED ALWAYS AS (qty * 2))").await.unwrap();
+        engine.execute("CREATE TABLE orders (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("INSERT INTO users (name, qty) VALUES ('alice', 1), ('bob', 2)").await.unwrap();
+
+        // Rows are keyed by table id, so the rename writes no keys
+        let writes = engine.storage.read().await.operation_count();
+        let result = engine.execute("ALTER TABLE users RENAME TO people").await.unwrap();
+        assert_eq!(result, "Table 'users' renamed to 'people'");
+        assert_eq!(engine.storage.read().await.operation_count(), writes);
+        let result = engine.execute("SELECT id, name FROM people").await.unwrap();
+        assert!(result.contains("1\talice\n") && result.contains("2\tbob\n"), "{}", result);
+        assert!(engine.execute("SELECT * FROM users").await.is_err());
+        // The auto-increment sequence moves with the table
+        engine.execute("INSERT INTO people (name, qty) VALUES ('carol', 3)").await.unwrap();
+        assert!(engine.execute("SELECT name FROM people WHERE id = 3").await.unwrap().contains("carol"));
+
+        engine.execute("ALTER TABLE people RENAME COLUMN name TO full_name").await.unwrap();
+        engine.execute("ALTER TABLE people RENAME COLUMN qty TO quantity").await.unwrap();
+        let result = engine.execute("SELECT full_name, quantity, double_qty FROM people WHERE id = 1").await.unwrap();
+        assert!(result.contains("alice\t1\t2"), "{}", result);
+        assert!(engine.execute("SELECT name FROM people").await.is_err());
+        let schema = engine.schemas.read().await["people"].clone();
+        assert_eq!(schema.columns[3].generated.as_deref(), Some("quantity * 2"));
+        engine.execute("INSERT INTO people (full_name, quantity) VALUES ('dave', 4)").await.unwrap();
+        assert!(engine.execute("SELECT double_qty FROM people WHERE id = 4").await.unwrap().contains("8"));
+
+        let err = engine.execute("ALTER TABLE people RENAME TO orders").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'orders' already exists");
+        let err = engine.execute("ALTER TABLE people RENAME COLUMN id TO full_name").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'full_name' already exists");
+        assert!(engine.execute("ALTER TABLE people RENAME COLUMN name TO other").await.is_err());
+
+        // Replaying the log onto an empty tree reproduces the moved and
+        // rewritten rows
+        let mut replayed = BPlusTree::new();
+        for entry in engine.wal.read().await.get_entries() {
+            replayed.apply_wal_entry(entry, RowFormat::default()).unwrap();
+        }
+        let key = format!("1:{}", encode_key_value(&SqlValue::Integer(2)));
+        let row = codec::decode_row(&replayed.get(&key).unwrap().unwrap()).unwrap();
+        assert!(matches!(row.get("full_name"), Some(SqlValue::Varchar(name)) if name == "bob"));
+        assert!(row.get("name").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_primary_key_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada')").await.unwrap();
+
+        let entries = engine.wal.read().await.entry_count();
+        let copies = bptree::value_copies();
+        let err = engine.execute("INSERT INTO users (id, name) VALUES (1, 'Grace')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate primary key id = 1 in table 'users'");
+        // Checked by key alone, and refused before it was logged
+        assert_eq!(bptree::value_copies(), copies);
+        assert_eq!(engine.wal.read().await.entry_count(), entries);
+
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Ada") && !result.contains("Grace"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_insert_values_checked_against_column_types() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute(
+                "CREATE TABLE items (id INTEGER PRIMARY KEY, code VARCHAR(4), price DECIMAL(8, 2), \
+                 active BOOLEAN, added TIMESTAMP)",
+            )
+            .await
+            .unwrap();
+
+        for (sql, expected) in [
+            ("(1, 'abc', 'cheap', NULL, NULL)", "Cannot insert 'cheap' into DECIMAL(8, 2) column 'price'"),
+            ("('1', NULL, NULL, NULL, NULL)", "Cannot insert '1' into INTEGER column 'id'"),
+            ("(1.5, NULL, NULL, NULL, NULL)", "Cannot insert 1.5 into INTEGER column 'id'"),
+            ("(1, 42, NULL, NULL, NULL)", "Cannot insert 42 into VARCHAR(4) column 'code'"),
+            ("(1, NULL, TRUE, NULL, NULL)", "Cannot insert true into DECIMAL(8, 2) column 'price'"),
+            ("(1, NULL, NULL, 1, NULL)", "Cannot insert 1 into BOOLEAN column 'active'"),
+            ("(1, NULL, NULL, NULL, 'soon')", "Cannot insert 'soon' into TIMESTAMP column 'added'"),
+            ("(1, 'abcde', NULL, NULL, NULL)", "Value for column 'code' is 5 characters, longer than VARCHAR(4)"),
+        ] {
+            let err = engine.execute(&format!("INSERT INTO items VALUES {}", sql)).await.unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", sql);
+        }
+        let err = engine.execute("INSERT INTO items (id, colour) VALUES (1, 'red')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'colour' does not exist");
+
+        // Integers widen to decimals, and strings are read as timestamps
+        engine.execute("INSERT INTO items VALUES (1, 'ab', 3, FALSE, '2024-05-01')").await.unwrap();
+        let result = engine.execute("SELECT price, added FROM items WHERE id = 1").await.unwrap();
+        assert!(result.contains("3\t2024-05-01T00:00:00+00:00"), "{}", result);
+
+        // Characters are counted, not bytes; truncating is opt-in per session
+        engine.execute("INSERT INTO items (id, code) VALUES (2, 'ñññ')").await.unwrap();
+        let mut session = Session::new();
+        engine.execute_in(&mut session, "SET string_overflow = 'truncate'").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO items (id, code) VALUES (3, 'ñbcdef')").await.unwrap();
+        let result = engine.execute("SELECT code FROM items WHERE id = 3").await.unwrap();
+        assert!(result.contains("\nñbcd\n"), "{}", result);
+        let err = engine.execute_in(&mut session, "SET string_overflow = 'wrap'").await.unwrap_err();
+        assert_eq!(err.to_string(), "string_overflow must be 'error' or 'truncate', got 'wrap'");
+    }
+
+    #[tokio::test]
+    async fn test_decimal_scale_and_precision() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE prices (id INTEGER PRIMARY KEY, amount DECIMAL(5, 2))").await.unwrap();
+
+        // Extra places are rounded off by default, from the digits written,
+        // though 1.005 and 2.675 fall just below the half in binary
+        let values = "(1, 1.239), (2, -0.125), (3, 999.994), (5, 1.005), (6, -2.675), (7, 9.995)";
+        engine.execute(&format!("INSERT INTO prices VALUES {}", values)).await.unwrap();
+        let result = engine.execute("SELECT amount FROM prices ORDER BY id").await.unwrap();
+        assert!(result.contains("\n1.24\n-0.13\n999.99\n1.01\n-2.68\n10\n"), "{}", result);
+
+        // Only three integer digits fit, also once rounded, and integers count too
+        for (sql, expected) in [
+            ("(4, 1000.5)", "Value 1000.5 for column 'amount' has too many integer digits for DECIMAL(5, 2)"),
+            ("(4, 999.999)", "Value 999.999 for column 'amount' has too many integer digits for DECIMAL(5, 2)"),
+            ("(4, -1000)", "Value -1000 for column 'amount' has too many integer digits for DECIMAL(5, 2)"),
+        ] {
+            let err = engine.execute(&format!("INSERT INTO prices VALUES {}", sql)).await.unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", sql);
+        }
+
+        let mut session = Session::new();
+        engine.execute_in(&mut session, "SET scale_overflow = 'error'").await.unwrap();
+        let err = engine.execute_in(&mut session, "INSERT INTO prices VALUES (4, 1.239)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Value 1.239 for column 'amount' has more than 2 decimal places");
+        engine.execute_in(&mut session, "INSERT INTO prices VALUES (4, 1.5)").await.unwrap();
+        let err = engine.execute_in(&mut session, "SET scale_overflow = 'floor'").await.unwrap_err();
+        assert_eq!(err.to_string(), "scale_overflow must be 'round' or 'error', got 'floor'");
+    }
+
+    #[tokio::test]
+    async fn test_not_null_columns_enforced() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute("CREATE TABLE users (id INTEGER PRIMARY KEY, email VARCHAR(100) NOT NULL, age INTEGER)")
+            .await
+            .unwrap();
+
+        let err = engine.execute("INSERT INTO users (id, email) VALUES (1, NULL)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'email' cannot be NULL");
+        let err = engine.execute("INSERT INTO users (id, age) VALUES (1, 30)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'email' is NOT NULL and must be given a value");
+        let err = engine.execute("INSERT INTO users (id, email) VALUES (1, 'a@x'), (2, NULL)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Row 2: Column 'email' cannot be NULL");
+        let err = engine.execute("INSERT INTO users (email) VALUES ('a@x')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Primary key column 'id' cannot be NULL");
+        assert!(engine.execute("SELECT * FROM users").await.unwrap().contains("(0 rows)"));
+
+        // Columns are nullable unless declared NOT NULL or part of the key
+        engine.execute("INSERT INTO users (id, email) VALUES (1, 'a@x')").await.unwrap();
+        let result = engine.execute("SELECT * FROM users WHERE age IS NULL").await.unwrap();
+        assert!(result.contains("1\ta@x\tNULL\n(1 rows)"), "{}", result);
+
+        let err = engine.execute("ALTER TABLE users ADD COLUMN name VARCHAR(100) NOT NULL").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot add NOT NULL column 'name' to an existing table");
+        let err = engine.execute("CREATE TABLE t (a INTEGER NULL NOT NULL)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'a' cannot be both NULL and NOT NULL");
+    }
+
+    #[tokio::test]
+    async fn test_primary_key_is_not_null() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100) NULL)").await.unwrap();
+
+        let schema = engine.schemas.read().await["users"].clone();
+        assert!(schema.columns[0].primary_key && !schema.columns[0].nullable);
+        for sql in ["INSERT INTO users (id, name) VALUES (NULL, 'Ada')", "INSERT INTO users (name) VALUES ('Ada')"] {
+            let err = engine.execute(sql).await.unwrap_err();
+            assert_eq!(err.to_string(), "Primary key column 'id' cannot be NULL", "{}", sql);
+        }
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("(0 rows)"), "{}", result);
+
+        let err = engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY NULL)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Primary key column 'id' cannot be NULL");
+    }
+
+    #[tokio::test]
+    async fn test_registered_function_callable_from_select() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let mut engine = SqlEngine::new(storage, wal);
+        engine.register_function("double", Arity::Exact(1), |args| match &args[0] {
+            SqlValue::Integer(i) => Ok(SqlValue::Integer(i * 2)),
+            other => Err(anyhow!("DOUBLE expects an integer, got {:?}", other)),
+        });
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+
+        let result = engine
+            .execute("SELECT DOUBLE(id) AS twice, UPPER(name) AS loud FROM users WHERE DOUBLE(id) > 2")
+            .await
+            .unwrap();
+        assert!(result.contains("4\tGRACE"), "{}", result);
+        assert!(result.contains("(1 rows)"), "{}", result);
+
+        let err = engine.execute("SELECT DOUBLE(id, 2) FROM users").await.unwrap_err();
+        assert_eq!(err.to_string(), "DOUBLE expects 1 argument, got 2");
+        let err = engine.execute("SELECT TRIPLE(id) FROM users").await.unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function 'triple'");
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_and_nullif() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute("CREATE TABLE contacts (id INTEGER PRIMARY KEY, mobile VARCHAR(9), home VARCHAR(9), work VARCHAR(9))")
+            .await
+            .unwrap();
+        engine
+            .execute(
+                "INSERT INTO contacts VALUES (1, '555-1', '555-2', NULL), (2, NULL, '555-3', '555-4'), \
+                 (3, NULL, NULL, '555-5'), (4, NULL, NULL, NULL), (5, '555-6', '555-6', NULL)",
+            )
+            .await
+            .unwrap();
+
+        // The first non-NULL column, in argument order
+        let result = engine
+            .execute("SELECT id, COALESCE(mobile, home, work) AS phone FROM contacts ORDER BY id")
+            .await
+            .unwrap();
+        assert!(result.contains("\n1\t555-1\n2\t555-3\n3\t555-5\n4\tNULL\n5\t555-6\n"), "{}", result);
+        let result = engine.execute("SELECT id FROM contacts WHERE COALESCE(mobile, work) = '555-4'").await.unwrap();
+        assert!(result.contains("\n2\n") && result.contains("(1 rows)"), "{}", result);
+
+        // NULLIF hides a home number that repeats the mobile one
+        let result = engine
+            .execute("SELECT id, NULLIF(home, mobile) AS other FROM contacts WHERE id IN (1, 5) ORDER BY id")
+            .await
+            .unwrap();
+        assert!(result.contains("\n1\t555-2\n5\tNULL\n"), "{}", result);
+        let result =
+            engine.execute("SELECT id FROM contacts WHERE NULLIF(home, mobile) IS NULL ORDER BY id").await.unwrap();
+        assert!(result.contains("\n3\n4\n5\n"), "{}", result);
+
+        // Argument types are checked from the schema and literals, so the
+        // query fails whatever the rows hold, even with none
+        for sql in [
+            "SELECT COALESCE(mobile, id) FROM contacts WHERE id = 1",
+            "SELECT COALESCE(mobile, id) FROM contacts WHERE id = 4",
+            "SELECT id FROM contacts WHERE COALESCE(mobile, 0) = 1 AND id = 99",
+            "DELETE FROM contacts WHERE COALESCE(home, (7)) = 7",
+        ] {
+            let err = engine.execute(sql).await.unwrap_err();
+            assert!(err.to_string().starts_with("COALESCE arguments have incompatible types: VARCHAR and "), "{}", sql);
+        }
+        let result = engine.execute("SELECT COALESCE(work, 'none') AS work FROM contacts WHERE id = 1").await.unwrap();
+        assert!(result.contains("\nnone\n"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_insert_default_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE events (id INTEGER PRIMARY KEY AUTO_INCREMENT, note VARCHAR(100))").await.unwrap();
+
+        let result = engine.execute("INSERT INTO events (id, note) VALUES (5, 'boot')").await.unwrap();
+        assert_eq!(result, "1 row(s) inserted");
+        let result = engine.execute("INSERT INTO events DEFAULT VALUES").await.unwrap();
+        assert_eq!(result, "1 row(s) inserted");
+        let result = engine.execute("insert into events default values returning id;").await.unwrap();
+        assert!(result.contains("\n7\n"), "{}", result);
+        let result = engine.execute("SELECT * FROM events WHERE id = 6").await.unwrap();
+        assert!(result.contains("6\tNULL"), "{}", result);
+
+        let err = engine.execute("INSERT INTO events DEFAULT VALUES extra").await.unwrap_err();
+        assert!(err.to_string().starts_with("Parse error:"), "{}", err);
+        let err = engine.execute("INSERT INTO missing DEFAULT VALUES").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'missing' does not exist");
+        // A table whose key has no default can't take a row of defaults
+        engine.execute("CREATE TABLE tags (name VARCHAR(100) PRIMARY KEY)").await.unwrap();
+        let err = engine.execute("INSERT INTO tags DEFAULT VALUES").await.unwrap_err();
+        assert_eq!(err.to_string(), "Primary key column 'name' cannot be NULL");
+        let err = engine.execute("INSERT INTO tags (name) VALUES ('a') ON CONFLICT DO NOTHING").await.unwrap_err();
+        assert_eq!(err.to_string(), "INSERT ... ON CONFLICT and ON DUPLICATE KEY UPDATE are not supported");
+    }
+
+    #[tokio::test]
+    async fn test_column_defaults() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let mut wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let clock = MockClock::new();
+        wal.set_clock(clock.clone());
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute(
+                "CREATE TABLE tasks (id INTEGER PRIMARY KEY, priority INTEGER NOT NULL DEFAULT 3, \
+                 status VARCHAR(10) DEFAULT 'open', created TIMESTAMP DEFAULT CURRENT_TIMESTAMP)",
+            )
+            .await
+            .unwrap();
+
+        let first = clock.utc_now();
+        engine.execute("INSERT INTO tasks (id) VALUES (1), (2)").await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        // A value given, even NULL, overrides the default
+        engine.execute("INSERT INTO tasks (id, priority, status) VALUES (3, 1, NULL)").await.unwrap();
+
+        let result = engine.execute("SELECT id, priority, status FROM tasks ORDER BY id").await.unwrap();
+        assert!(result.ends_with("1\t3\topen\n2\t3\topen\n3\t1\tNULL\n(3 rows)\n"), "{}", result);
+        let sql = format!("SELECT id FROM tasks WHERE created = '{}' ORDER BY id", first.to_rfc3339());
+        let result = engine.execute(&sql).await.unwrap();
+        assert!(result.ends_with("1\n2\n(2 rows)\n"), "{}", result);
+        let later = first + chrono::Duration::seconds(60);
+        let sql = format!("SELECT id FROM tasks WHERE created = '{}'", later.to_rfc3339());
+        assert!(engine.execute(&sql).await.unwrap().ends_with("3\n(1 rows)\n"));
+
+        let ddl = engine.execute("SHOW CREATE TABLE tasks").await.unwrap();
+        assert!(ddl.contains("\"priority\" INTEGER NOT NULL DEFAULT 3, "), "{}", ddl);
+        assert!(ddl.contains("\"status\" VARCHAR(10) DEFAULT 'open', "), "{}", ddl);
+        // Folded like any unquoted word
+        assert!(ddl.contains("\"created\" TIMESTAMP DEFAULT current_timestamp"), "{}", ddl);
+
+        let create = |column: &str| format!("CREATE TABLE bad (id INTEGER PRIMARY KEY, {})", column);
+        let err = engine.execute(&create("n INTEGER DEFAULT 'x'")).await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot insert 'x' into INTEGER column 'n'");
+        let err = engine.execute(&create("n INTEGER DEFAULT CURRENT_TIMESTAMP")).await.unwrap_err();
+        assert_eq!(err.to_string(), "CURRENT_TIMESTAMP can only be the DEFAULT of a TIMESTAMP column, not 'n'");
+        let err = engine.execute(&create("n INTEGER DEFAULT id")).await.unwrap_err();
+        assert_eq!(err.to_string(), "DEFAULT for column 'n' must be a literal or CURRENT_TIMESTAMP, got id");
+        let err = engine.execute(&create("n INTEGER NOT NULL DEFAULT NULL")).await.unwrap_err();
+        assert_eq!(err.to_string(), "NOT NULL column 'n' cannot default to NULL");
+        let err = engine.execute("ALTER TABLE tasks ADD COLUMN owner VARCHAR(10) DEFAULT 'me'").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot add column 'owner' with a DEFAULT to an existing table");
+    }
+
+    #[tokio::test]
+    async fn test_where_filters_rows() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100), age INTEGER NULL)").await.unwrap();
+        engine
+            .execute("INSERT INTO users (id, name, age) VALUES (1, 'Ada', 36), (2, 'Grace', 45), (3, 'Alan', NULL), (4, 'Edsger', 72)")
+            .await
+            .unwrap();
+
+        let count = |sql: &'static str| {
+            let engine = engine.clone();
+            async move { engine.execute(sql).await.unwrap().lines().last().unwrap().to_string() }
+        };
+        assert_eq!(count("SELECT * FROM users WHERE id = 1").await, "(1 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE id != 1").await, "(3 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE age >= 45").await, "(2 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE age < 45 OR name = 'Alan'").await, "(2 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE NOT (age > 40) AND id <= 2").await, "(1 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE age IS NULL").await, "(1 rows)");
+        assert_eq!(count("SELECT * FROM users WHERE age IS NOT NULL").await, "(3 rows)");
+
+        let err = engine.execute("SELECT * FROM users WHERE age > 'old'").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot compare type INTEGER with type VARCHAR");
+        let err = engine.execute("SELECT * FROM users WHERE name = TRUE").await.unwrap_err();
+        assert_eq!(err.to_string(), "Cannot compare type VARCHAR with type BOOLEAN");
+    }
+
+    #[tokio::test]
+    async fn test_order_by_keys_directions_and_nulls() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE people (id INTEGER PRIMARY KEY, name VARCHAR(100), team VARCHAR(10) NULL, age INTEGER NULL)").await.unwrap();
+        engine
+            .execute(
+                "INSERT INTO people (id, name, team, age) VALUES \
+                 (1, 'Dana', 'red', 30), (2, 'Ari', 'blue', NULL), (3, 'Cy', 'red', 25), (4, 'Bo', NULL, 41), (5, 'Eli', 'blue', 30)",
+            )
+            .await
+            .unwrap();
+
+        let names = |result: String| -> Vec<String> {
+            result.lines().skip(2).filter(|l| !l.starts_with('(')).map(String::from).collect()
+        };
+        let result = engine.execute("SELECT name FROM people ORDER BY name DESC").await.unwrap();
+        assert_eq!(names(result), vec!["Eli", "Dana", "Cy", "Bo", "Ari"]);
+        // NULL comes last in either direction
+        let result = engine.execute("SELECT name FROM people ORDER BY age").await.unwrap();
+        assert_eq!(names(result), vec!["Cy", "Dana", "Eli", "Bo", "Ari"]);
+        let result = engine.execute("SELECT name FROM people ORDER BY age DESC").await.unwrap();
+        assert_eq!(names(result), vec!["Bo", "Dana", "Eli", "Cy", "Ari"]);
+        let result = engine.execute("SELECT name FROM people ORDER BY age DESC NULLS FIRST").await.unwrap();
+        assert_eq!(names(result), vec!["Ari", "Bo", "Dana", "Eli", "Cy"]);
+        let result = engine.execute("SELECT name FROM people ORDER BY age NULLS FIRST").await.unwrap();
+        assert_eq!(names(result), vec!["Ari", "Cy", "Dana", "Eli", "Bo"]);
+
+        let result = engine.execute("SELECT name FROM people ORDER BY team, age DESC, name").await.unwrap();
+        assert_eq!(names(result), vec!["Eli", "Ari", "Dana", "Cy", "Bo"]);
+        let result = engine.execute("SELECT name FROM people ORDER BY age, name DESC").await.unwrap();
+        assert_eq!(names(result), vec!["Cy", "Eli", "Dana", "Bo", "Ari"]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_with_and_without_where() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100), age INTEGER)").await.unwrap();
+        engine
+            .execute("INSERT INTO users (id, name, age) VALUES (1, 'Ada', 36), (2, 'Grace', 45), (3, 'Alan', 41), (4, 'Edsger', 72)")
+            .await
+            .unwrap();
+
+        let result = engine.execute("DELETE FROM users WHERE age > 40 AND name != 'Alan'").await.unwrap();
+        assert_eq!(result, "2 row(s) deleted");
+        let result = engine.execute("SELECT name FROM users ORDER BY id").await.unwrap();
+        assert_eq!(result.lines().skip(2).take(2).collect::<Vec<_>>(), vec!["Ada", "Alan"]);
+        assert!(result.contains("(2 rows)"), "{}", result);
+
+        let result = engine.execute("DELETE FROM users WHERE id = 99").await.unwrap();
+        assert_eq!(result, "0 row(s) deleted");
+
+        // Each deleted row is logged, and a DELETE matching none logs nothing
+        let result = engine.execute("DELETE FROM users").await.unwrap();
+        assert_eq!(result, "2 row(s) deleted");
+        let result = engine.execute("SELECT * FROM users").await.unwrap();
+        assert!(result.contains("(0 rows)"), "{}", result);
+        let wal = engine.wal.read().await;
+        let deletes = wal.get_entries().iter().filter(|e| matches!(e.operation, WalOperation::Delete { .. })).count();
+        assert_eq!(deletes, 4);
+    }
+
+    #[tokio::test]
+    async fn test_show_create_table_round_trips() {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = |name: &str| {
+            let wal_path = temp_dir.path().join(name);
+            async move {
+                let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+                SqlEngine::new(BPlusTree::new(), wal)
+            }
+        };
+        let original = engine("original.wal").await;
+        original
+            .execute(
+                "CREATE TABLE \"order items\" (id INTEGER PRIMARY KEY AUTO_INCREMENT, sku CHAR(12) NOT NULL, \
+                 note VARCHAR NULL, price DECIMAL(8, 3), qty INTEGER NOT NULL, gift BOOLEAN, shipped TIMESTAMP, \
+                 total DECIMAL(10, 2) GENERATED ALWAYS AS (price * qty)) WITH (shards = 3)",
+            )
+            .await
+            .unwrap();
+
+        let ddl = original.execute("SHOW CREATE TABLE \"order items\"").await.unwrap();
+        assert_eq!(
+            ddl,
+            "CREATE TABLE \"order items\" (\"id\" INTEGER PRIMARY KEY AUTO_INCREMENT, \"sku\" VARCHAR(12) NOT NULL, \
+             \"note\" VARCHAR(255) NULL, \"price\" DECIMAL(8, 3), \"qty\" INTEGER NOT NULL, \"gift\" BOOLEAN, \
+             \"shipped\" TIMESTAMP, \"total\" DECIMAL(10, 2) GENERATED ALWAYS AS (price * qty)) WITH (shards = 3);"
+        );
+
+        let recreated = engine("recreated.wal").await;
+        recreated.execute(&ddl).await.unwrap();
+        let describe = |engine: &SqlEngine| {
+            let schemas = engine.schemas.try_read().unwrap();
+            let schema = &schemas["order items"];
+            format!("{:?} {}", schema.columns, schema.shard_count)
+        };
+        assert_eq!(describe(&recreated), describe(&original));
+        assert_eq!(recreated.execute("SHOW CREATE TABLE \"order items\"").await.unwrap(), ddl);
+
+        let err = original.execute("SHOW CREATE TABLE missing").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'missing' does not exist");
+    }
+
+    #[tokio::test]
+    async fn test_aggregates() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute("CREATE TABLE orders (id INTEGER PRIMARY KEY, customer VARCHAR(20), qty INTEGER, price DECIMAL)")
+            .await
+            .unwrap();
+        let single = |sql: &'static str| {
+            let engine = &engine;
+            async move {
+                let result = engine.execute(sql).await.unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                assert_eq!(lines.len(), 4, "{}", result);
+                lines[2].to_string()
+            }
+        };
+
+        assert_eq!(single("SELECT COUNT(*) FROM orders").await, "0");
+        let empty = single("SELECT COUNT(qty), SUM(qty), AVG(qty), MIN(qty), MAX(qty) FROM orders").await;
+        assert_eq!(empty, "0\tNULL\tNULL\tNULL\tNULL");
+
+        engine
+            .execute(
+                "INSERT INTO orders VALUES (1, 'Cy', 2, 4.5), (2, 'Ada', 5, 10), (3, 'Bo', NULL, 0.25), \
+                 (4, 'Ada', 1, NULL)",
+            )
+            .await
+            .unwrap();
+        assert_eq!(single("SELECT COUNT(*) FROM orders").await, "4");
+        assert_eq!(single("SELECT COUNT(qty) FROM orders").await, "3");
+        assert_eq!(single("SELECT SUM(qty) FROM orders").await, "8");
+        assert_eq!(single("SELECT SUM(price) FROM orders").await, "14.75");
+        assert_eq!(single("SELECT SUM(qty * price) FROM orders").await, "59");
+        assert_eq!(single("SELECT AVG(price) FROM orders").await, "4.916666666666667");
+        assert_eq!(single("SELECT MIN(qty), MAX(qty) FROM orders").await, "1\t5");
+        assert_eq!(single("SELECT MIN(customer), MAX(customer) FROM orders").await, "Ada\tCy");
+        assert_eq!(single("SELECT MAX(price) FROM orders WHERE customer = 'Ada'").await, "10");
+        assert_eq!(single("SELECT COUNT(*) AS n, SUM(qty) AS total FROM orders WHERE id > 1").await, "3\t6");
+
+        let err = engine.execute("SELECT SUM(customer) FROM orders").await.unwrap_err();
+        assert_eq!(err.to_string(), "SUM expects numeric values, got Varchar(\"Cy\")");
+        let err = engine.execute("SELECT AVG(customer) FROM orders").await.unwrap_err();
+        assert_eq!(err.to_string(), "AVG expects numeric values, got Varchar(\"Cy\")");
+        let err = engine.execute("SELECT customer, COUNT(*) FROM orders").await.unwrap_err();
+        assert_eq!(err.to_string(), "Column 'customer' must be an aggregate when the query aggregates");
+    }
+
+    #[tokio::test]
+    async fn test_order_by_memory_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE events (id INTEGER PRIMARY KEY, body VARCHAR(100))").await.unwrap();
+        let values: Vec<String> = (0..2000).map(|i| format!("({}, 'event number {}')", i, i)).collect();
+        engine.execute(&format!("INSERT INTO events VALUES {}", values.join(", "))).await.unwrap();
+
+        let mut session = Session::new().with_memory_limit(Some(64 * 1024));
+        let err = engine.execute_in(&mut session, "SELECT * FROM events ORDER BY body DESC").await.unwrap_err();
+        assert_eq!(err.to_string(), "query exceeded memory limit (64 kB)");
+
+        // Rows are charged as they're read and joined, so buffering the whole
+        // table goes over without a sort too
+        for sql in ["SELECT COUNT(*) FROM events", "SELECT COUNT(*) FROM events a JOIN events b ON a.id = b.id"] {
+            let err = engine.execute_in(&mut session, sql).await.unwrap_err();
+            assert_eq!(err.to_string(), "query exceeded memory limit (64 kB)");
+        }
+
+        // Rows WHERE rules out aren't buffered, and a sort that fits runs
+        let result = engine
+            .execute_in(&mut session, "SELECT id FROM events WHERE id < 10 ORDER BY id DESC LIMIT 1")
+            .await
+            .unwrap();
+        assert!(result.contains("\n9\n"), "{}", result);
+        let result = engine.execute("SELECT id FROM events ORDER BY body DESC LIMIT 1").await.unwrap();
+        assert!(result.contains("\n999\n"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_identifier_case_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let mut engine = SqlEngine::new(storage, wal);
+
+        // The default folds unquoted names, so `Name` is created as `name`
+        engine.execute("CREATE TABLE People (id INTEGER PRIMARY KEY, Name VARCHAR(50))").await.unwrap();
+        engine.execute("INSERT INTO PEOPLE (ID, NAME) VALUES (1, 'Ada')").await.unwrap();
+        for sql in ["SELECT name FROM people", "SELECT NAME FROM People", "SELECT \"name\" FROM \"people\""] {
+            assert!(engine.execute(sql).await.unwrap().contains("Ada"), "{}", sql);
+        }
+        let err = engine.execute("SELECT \"Name\" FROM people").await.unwrap_err();
+        assert!(err.to_string().contains("Name"), "{}", err);
+
+        // Folding quoted names too makes every spelling the same column
+        engine.set_identifier_case(IdentifierCase::Insensitive);
+        engine.execute("CREATE TABLE Pets (id INTEGER PRIMARY KEY, Name VARCHAR(50))").await.unwrap();
+        engine.execute("INSERT INTO pets (id, \"Name\") VALUES (1, 'Rex')").await.unwrap();
+        for sql in ["SELECT name FROM pets", "SELECT NAME FROM PETS", "SELECT \"Name\" FROM \"Pets\""] {
+            assert!(engine.execute(sql).await.unwrap().contains("Rex"), "{}", sql);
+        }
+
+        // Preserving keeps names as typed, so only the exact spelling matches
+        engine.set_identifier_case(IdentifierCase::Preserve);
+        engine.execute("CREATE TABLE Toys (id INTEGER PRIMARY KEY, Name VARCHAR(50))").await.unwrap();
+        engine.execute("INSERT INTO Toys (id, Name) VALUES (1, 'Ball')").await.unwrap();
+        for sql in ["SELECT Name FROM Toys", "SELECT \"Name\" FROM \"Toys\""] {
+            assert!(engine.execute(sql).await.unwrap().contains("Ball"), "{}", sql);
+        }
+        assert!(engine.execute("SELECT name FROM Toys").await.is_err());
+        assert!(engine.execute("SELECT Name FROM toys").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inner_join() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE authors (id INTEGER PRIMARY KEY, name VARCHAR(50))").await.unwrap();
+        engine
+            .execute("CREATE TABLE books (id INTEGER PRIMARY KEY, author_id INTEGER, title VARCHAR(50))")
+            .await
+            .unwrap();
+        engine.execute("INSERT INTO authors (id, name) VALUES (1, 'Le Guin'), (2, 'Herbert')").await.unwrap();
+        engine
+            .execute("INSERT INTO books (id, author_id, title) VALUES (1, 1, 'Earthsea'), (2, 2, 'Dune'), (3, 9, 'Ex')")
+            .await
+            .unwrap();
+
+        let sql = "SELECT authors.name, books.title FROM authors JOIN books ON authors.id = books.author_id \
+                   ORDER BY books.id";
+        let result = engine.execute(sql).await.unwrap();
+        assert!(result.contains("(2 rows)"), "{}", result);
+        assert!(result.starts_with("name\ttitle\n"), "{}", result);
+        assert!(result.find("Le Guin\tEarthsea").unwrap() < result.find("Herbert\tDune").unwrap(), "{}", result);
+
+        // Aliases qualify columns in place of the table name
+        let result = engine
+            .execute("SELECT b.title FROM authors a INNER JOIN books b ON a.id = b.author_id WHERE a.name = 'Herbert'")
+            .await
+            .unwrap();
+        assert!(result.contains("Dune") && result.contains("(1 rows)"), "{}", result);
+
+        let err = engine.execute("SELECT * FROM authors JOIN books ON authors.id > books.author_id").await.unwrap_err();
+        assert!(err.to_string().contains("Unsupported join condition"), "{}", err);
+        let err = engine.execute("SELECT * FROM authors JOIN books ON authors.id = books.writer").await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown column 'books.writer'");
+        let err = engine.execute("SELECT * FROM authors JOIN authors ON authors.id = authors.id").await.unwrap_err();
+        assert!(err.to_string().contains("specified more than once"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_select_from_inline_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        let sql = "SELECT * FROM (VALUES (1, 'a'), (2, 'b'), (3, NULL)) AS t(id, name) WHERE id > 1 ORDER BY id DESC";
+        let result = engine.execute(sql).await.unwrap();
+        assert_eq!(result, "id\tname\n------------\n3\tNULL\n2\tb\n(2 rows)\n");
+        // Columns the alias doesn't name are numbered
+        let sql = "SELECT column2 FROM (VALUES (1, 2.5), (2, 3)) AS t(id) WHERE id = 2";
+        let result = engine.execute(sql).await.unwrap();
+        assert!(result.ends_with("3\n(1 rows)\n"), "{}", result);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(50))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        let sql = "SELECT users.name, r.role FROM users JOIN (VALUES (2, 'admin')) AS r(user_id, role) \
+                   ON users.id = r.user_id";
+        let result = engine.execute(sql).await.unwrap();
+        assert!(result.ends_with("Grace\tadmin\n(1 rows)\n"), "{}", result);
+
+        let err = engine.execute("SELECT * FROM (VALUES (1, 'a'), (2)) AS t(id, name)").await.unwrap_err();
+        assert_eq!(err.to_string(), "VALUES lists must all be the same length, got 2 and 1");
+        let err = engine.execute("SELECT * FROM (VALUES (1), ('a')) AS t(id)").await.unwrap_err();
+        assert_eq!(err.to_string(), "VALUES column 'id' mixes INTEGER and VARCHAR");
+        let err = engine.execute("SELECT * FROM (VALUES (1)) AS t(id, name)").await.unwrap_err();
+        assert_eq!(err.to_string(), "VALUES list has 1 columns, but 2 names were given");
+        let err = engine.execute("SELECT * FROM (SELECT id FROM users) AS u").await.unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported subquery in FROM; only a VALUES list is supported");
+    }
+
+    #[tokio::test]
+    async fn test_drop_table() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(50))").await.unwrap();
+        engine.execute("CREATE TABLE orders (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        engine.execute("INSERT INTO orders (id) VALUES (1)").await.unwrap();
+
+        assert_eq!(engine.execute("DROP TABLE users").await.unwrap(), "Table 'users' dropped");
+        let err = engine.execute("SELECT * FROM users").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'users' does not exist");
+        assert!(engine.storage.read().await.scan_prefix("1:").unwrap().is_empty());
+        assert!(engine.execute("SELECT * FROM orders").await.unwrap().contains("(1 rows)"));
+
+        let err = engine.execute("DROP TABLE users").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'users' does not exist");
+        let result = engine.execute("DROP TABLE IF EXISTS users").await.unwrap();
+        assert_eq!(result, "Table 'users' does not exist, skipping");
+        // A missing table fails the whole statement
+        assert!(engine.execute("DROP TABLE orders, users").await.is_err());
+        assert!(engine.execute("SELECT * FROM orders").await.is_ok());
+
+        // The name can be reused, starting empty
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        assert!(engine.execute("SELECT * FROM users").await.unwrap().contains("(0 rows)"));
+    }
+
+    #[tokio::test]
+    async fn test_statement_batches() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        let result = engine
+            .execute(
+                "CREATE TABLE notes (id INTEGER PRIMARY KEY AUTO_INCREMENT, body VARCHAR(50));
+                 INSERT INTO notes (id, body) VALUES (1, 'a; b'), (2, 'c');
+                 INSERT INTO notes DEFAULT VALUES;",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.lines().count(), 3, "{}", result);
+        let result = engine.execute("SELECT body FROM notes ORDER BY id").await.unwrap();
+        assert!(result.contains("a; b\nc\nNULL\n(3 rows)"), "{}", result);
+
+        // Later statements see the session changes of earlier ones
+        let mut session = Session::new();
+        let result = engine
+            .execute_in(&mut session, "CREATE DATABASE other; USE other; CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .await
+            .unwrap();
+        assert!(result.contains("Using database 'other'"), "{}", result);
+        assert_eq!(session.database(), "other");
+        assert!(engine.execute("SELECT * FROM other.t").await.is_ok());
+
+        // A syntax error anywhere runs nothing; a failing statement stops
+        // the batch, keeping what ran before it
+        assert!(engine.execute("INSERT INTO notes (id) VALUES (10); SELEC 1").await.is_err());
+        let batch = "INSERT INTO notes (id) VALUES (11); INSERT INTO missing (id) VALUES (1); \
+                     INSERT INTO notes (id) VALUES (12)";
+        let err = engine.execute(batch).await.unwrap_err();
+        assert_eq!(err.to_string(), "Statement 2 of 3 failed: Table 'missing' does not exist");
+        let result = engine.execute("SELECT id FROM notes WHERE id >= 10").await.unwrap();
+        assert!(result.contains("11\n(1 rows)"), "{}", result);
+
+        assert_eq!(engine.execute(" ; ;").await.unwrap(), "No statement to execute");
+    }
+
+    #[tokio::test]
+    async fn test_multi_row_insert_is_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada')").await.unwrap();
+
+        let entries = engine.wal.read().await.entry_count();
+        let err = engine
+            .execute("INSERT INTO users (id, name) VALUES (2, 'Grace'), (3, 'Edsger'), (1, 'Alan')")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Row 3: Duplicate primary key id = 1 in table 'users'");
+        let err = engine.execute("INSERT INTO users (id, name) VALUES (4, 'Barbara'), (4, 'Niklaus')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Row 2: Duplicate primary key id = 4 in table 'users'");
+        let err = engine.execute("INSERT INTO users (id, name) VALUES (5, 'Donald'), (6)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Row 2: Too few values provided: expected 2, got 1");
+        assert_eq!(engine.wal.read().await.entry_count(), entries);
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("(1 rows)"), "{}", result);
+
+        // The rows are logged as one transaction, so replay applies all or none
+        engine.execute("INSERT INTO users (id, name) VALUES (2, 'Grace'), (3, 'Edsger')").await.unwrap();
+        let logged = engine.wal.write().await.replay().await.unwrap();
+        let operations: Vec<_> = logged[entries..].iter().map(|e| (e.operation.clone(), e.tx_id)).collect();
+        let tx_id = logged[entries].seq;
+        assert!(matches!(operations[0], (WalOperation::Begin(id), None) if id == tx_id));
+        assert!(matches!(operations[1], (WalOperation::Insert { .. }, Some(id)) if id == tx_id));
+        assert!(matches!(operations[2], (WalOperation::Insert { .. }, Some(id)) if id == tx_id));
+        assert!(matches!(operations[3], (WalOperation::Commit(id), None) if id == tx_id));
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Grace") && result.contains("Edsger"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_explain_analyze_reports_scan_costs() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, age INTEGER)").await.unwrap();
+        let values: Vec<String> = (0..2000).map(|i| format!("({}, {})", i, i % 500)).collect();
+        engine.execute(&format!("INSERT INTO users (id, age) VALUES {}", values.join(", "))).await.unwrap();
+
+        let stat = |report: &str, name: &str| -> usize {
+            let line = report.lines().find(|line| line.starts_with(name)).unwrap_or_else(|| panic!("{}", report));
+            line[name.len()..].trim().parse().unwrap()
+        };
+
+        let lookup = engine.execute("EXPLAIN ANALYZE SELECT * FROM users WHERE id = 42").await.unwrap();
+        assert!(lookup.starts_with("plan\n"), "{}", lookup);
+        assert!(lookup.contains("Primary key lookup on users (id)"), "{}", lookup);
+        assert_eq!(stat(&lookup, "Rows scanned:"), 1);
+        assert_eq!(stat(&lookup, "Rows returned:"), 1);
+        assert!(lookup.contains("Execution time:"), "{}", lookup);
+
+        let scan = engine.execute("EXPLAIN ANALYZE SELECT * FROM users WHERE age = 7").await.unwrap();
+        assert!(scan.contains("Full scan on users"), "{}", scan);
+        assert_eq!(stat(&scan, "Rows scanned:"), 2000);
+        assert_eq!(stat(&scan, "Rows returned:"), 4);
+        assert!(stat(&scan, "Nodes visited:") > stat(&lookup, "Nodes visited:"), "{}\n{}", lookup, scan);
+
+        // An IN list on the key is fetched in one pass, duplicates once
+        let sql = "EXPLAIN ANALYZE SELECT * FROM users WHERE id IN (44, 42, 43, 42, 5000)";
+        let batch = engine.execute(sql).await.unwrap();
+        assert!(batch.contains("Primary key lookup of 4 keys on users (id)"), "{}", batch);
+        assert_eq!(stat(&batch, "Rows scanned:"), 3);
+        assert_eq!(stat(&batch, "Rows returned:"), 3);
+        assert!(stat(&batch, "Nodes visited:") < 3 * stat(&lookup, "Nodes visited:"), "{}\n{}", lookup, batch);
+        let result = engine.execute("SELECT id FROM users WHERE id IN (44, 42, 43, 42)").await.unwrap();
+        assert_eq!(result.lines().skip(2).take(3).collect::<Vec<_>>(), vec!["42", "43", "44"]);
+
+        assert!(engine.execute("EXPLAIN SELECT * FROM users").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_information_schema_views() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute(
+                "CREATE TABLE products (id INTEGER PRIMARY KEY, name VARCHAR(40) NULL, price DECIMAL(10, 2), \
+                 total DECIMAL(10, 2) GENERATED ALWAYS AS (price * 2))",
+            )
+            .await
+            .unwrap();
+        engine.execute("CREATE DATABASE shop").await.unwrap();
+        engine.execute("CREATE TABLE shop.orders (id INTEGER PRIMARY KEY)").await.unwrap();
+
+        let result = engine
+            .execute(
+                "SELECT column_name, ordinal_position, data_type, is_nullable, character_maximum_length, \
+                 numeric_precision, numeric_scale, is_generated FROM information_schema.columns \
+                 WHERE table_name = 'products'",
+            )
+            .await
+            .unwrap();
+        let rows: Vec<&str> = result.lines().skip(2).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "id\t1\tbigint\tNO\tNULL\t64\t0\tNEVER",
+                "name\t2\tcharacter varying\tYES\t40\tNULL\tNULL\tNEVER",
+                "price\t3\tnumeric\tYES\tNULL\t10\t2\tNEVER",
+                "total\t4\tnumeric\tYES\tNULL\t10\t2\tALWAYS",
+                "(4 rows)",
+            ]
+        );
+
+        let result = engine
+            .execute("SELECT table_schema, table_name, table_type FROM INFORMATION_SCHEMA.TABLES ORDER BY table_name")
+            .await
+            .unwrap();
+        assert!(result.contains("shop\torders\tBASE TABLE\n"), "{}", result);
+        assert!(result.contains("default\tproducts\tBASE TABLE\n"), "{}", result);
+        assert!(result.ends_with("(2 rows)\n"), "{}", result);
+
+        assert!(engine.execute("SELECT * FROM information_schema.routines").await.is_err());
+        assert!(engine.execute("CREATE DATABASE information_schema").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_count_primary_key_range_from_subtree_counts() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE events (id INTEGER PRIMARY KEY, kind VARCHAR(10))").await.unwrap();
+        engine.execute("CREATE TABLE other (id INTEGER PRIMARY KEY)").await.unwrap();
+        for batch in 0..6 {
+            let values: Vec<String> = (batch * 500..(batch + 1) * 500).map(|i| format!("({}, 'k{}')", i, i % 7)).collect();
+            engine.execute(&format!("INSERT INTO events (id, kind) VALUES {}", values.join(", "))).await.unwrap();
+        }
+        engine.execute("INSERT INTO other (id) VALUES (150)").await.unwrap();
+
+        let visits = bptree::node_visits();
+        let result = engine.execute("SELECT COUNT(*) AS n FROM events WHERE id BETWEEN 100 AND 2099").await.unwrap();
+        assert_eq!(result, "n\n----\n2000\n(1 rows)\n");
+        let counted = bptree::node_visits() - visits;
+        assert!(counted < 10, "counting visited {} nodes", counted);
+
+        // Queries that need more than the count scan, and agree
+        let visits = bptree::node_visits();
+        let result = engine.execute("SELECT COUNT(kind) FROM events WHERE id BETWEEN 100 AND 2099").await.unwrap();
+        assert!(result.contains("\n2000\n"), "{}", result);
+        assert!(bptree::node_visits() - visits > counted);
+        let result = engine.execute("SELECT COUNT(*) FROM events WHERE id BETWEEN 99.5 AND 2099").await.unwrap();
+        assert!(result.contains("\n2000\n"), "{}", result);
+
+        for (sql, expected) in [
+            ("SELECT COUNT(*) FROM events WHERE id BETWEEN -5 AND 2", "3"),
+            ("SELECT COUNT(*) FROM events WHERE id BETWEEN 2990 AND 9000", "10"),
+            ("SELECT COUNT(*) FROM events WHERE id BETWEEN 10 AND 5", "0"),
+            ("SELECT COUNT(*) FROM other WHERE id BETWEEN 0 AND 1000", "1"),
+        ] {
+            let result = engine.execute(sql).await.unwrap();
+            assert!(result.contains(&format!("\n{}\n", expected)), "{}: {}", sql, result);
+        }
+    }
+
+    // /dev/full fails every write with ENOSPC
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_full_wal_disk_rejects_writes_before_applying() {
+        use crate::txn::WalError;
+
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'Ada')").await.unwrap();
+
+        *engine.wal.write().await = WriteAheadLog::new("/dev/full").await.unwrap();
+        let operations = engine.storage.read().await.operation_count();
+
+        let err = engine.execute("INSERT INTO users (id, name) VALUES (2, 'Grace')").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<WalError>(), Some(WalError::DiskFull)), "{}", err);
+        assert!(engine.wal.read().await.is_read_only());
+        assert_eq!(engine.storage.read().await.operation_count(), operations);
+
+        // Later writes are refused up front; reads carry on
+        let err = engine.execute("CREATE TABLE orders (id INTEGER PRIMARY KEY)").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<WalError>(), Some(WalError::DiskFull)), "{}", err);
+        assert!(engine.execute("SELECT * FROM orders").await.is_err());
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Ada") && result.contains("(1 rows)"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_order_by_collate_nocase() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users (id, name) VALUES (1, 'alice'), (2, 'Zoe'), (3, 'bob'), (4, 'Alice')").await.unwrap();
+
+        let names = |result: String| -> Vec<String> {
+            result.lines().skip(2).filter(|l| !l.starts_with('(')).map(String::from).collect()
+        };
+
+        let result = engine.execute("SELECT name FROM users ORDER BY name").await.unwrap();
+        assert_eq!(names(result), vec!["Alice", "Zoe", "alice", "bob"]);
+
+        let result = engine.execute("SELECT name FROM users ORDER BY name COLLATE NOCASE").await.unwrap();
+        assert_eq!(names(result), vec!["Alice", "alice", "bob", "Zoe"]);
+
+        let result = engine.execute("SELECT name FROM users ORDER BY name COLLATE nocase DESC").await.unwrap();
+        assert_eq!(names(result), vec!["Zoe", "bob", "alice", "Alice"]);
+
+        assert!(engine.execute("SELECT name FROM users ORDER BY name COLLATE klingon").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_that_fails_logs_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY, name VARCHAR(10))").await.unwrap();
+        engine.execute("INSERT INTO t (id, name) VALUES (1, 'a')").await.unwrap();
+        engine.storage.write().await.insert("1:zz".to_string(), b"not a row".to_vec()).unwrap();
+
+        let logged = engine.wal.read().await.entry_count();
+        assert!(engine.execute("ALTER TABLE t RENAME COLUMN name TO label").await.is_err());
+        assert_eq!(engine.wal.read().await.entry_count(), logged);
+        assert!(engine.schemas.read().await["t"].columns.iter().any(|c| c.name == "name"));
+        let key = format!("1:{}", encode_key_value(&SqlValue::Integer(1)));
+        let row = codec::decode_row(&engine.storage.read().await.get(&key).unwrap().unwrap()).unwrap();
+        assert!(row.get("name").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_statements_write_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE t (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("INSERT INTO t (id) VALUES (1), (2)").await.unwrap();
+
+        let logged = engine.wal.read().await.entry_count();
+
+        let cancellation = Cancellation::new();
+        let mut session = Session::new().with_cancellation(cancellation.clone());
+        cancellation.cancel();
+        for sql in ["INSERT INTO t (id) VALUES (3)", "DELETE FROM t", "SELECT * FROM t"] {
+            let err = engine.execute_in(&mut session, sql).await.unwrap_err();
+            assert_eq!(err.to_string(), "Query cancelled");
+        }
+
+        // A statement timeout stops it the same way
+        let timed_out = Cancellation::new().with_timeout(Some(Duration::ZERO));
+        let mut session = Session::new().with_cancellation(timed_out);
+        let err = engine.execute_in(&mut session, "INSERT INTO t (id) VALUES (3)").await.unwrap_err();
+        assert_eq!(err.to_string(), "Statement timed out after 0 ms (statement_timeout)");
+
+        assert_eq!(engine.wal.read().await.entry_count(), logged);
+        assert!(engine.execute("SELECT * FROM t").await.unwrap().contains("(2 rows)"));
+    }
+
+    #[tokio::test]
+    async fn test_transactions_commit_and_roll_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users VALUES (1, 'Ada')").await.unwrap();
+        let entries = engine.wal.read().await.entry_count();
+
+        // A rolled-back insert and delete never reach the WAL or storage
+        let mut session = Session::new();
+        engine.execute_in(&mut session, "BEGIN").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO users VALUES (2, 'Grace')").await.unwrap();
+        engine.execute_in(&mut session, "DELETE FROM users WHERE id = 1").await.unwrap();
+        let err = engine.execute_in(&mut session, "INSERT INTO users VALUES (2, 'Linus')").await.unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate primary key id = 2 in table 'users'");
+        let err = engine.execute_in(&mut session, "CREATE TABLE orders (id INTEGER PRIMARY KEY)").await.unwrap_err();
+        assert!(err.to_string().contains("inside a transaction"), "{}", err);
+        let result = engine.execute("SELECT id FROM users").await.unwrap();
+        assert!(result.contains("\n1\n") && result.contains("(1 rows)"), "{}", result);
+        engine.execute_in(&mut session, "ROLLBACK").await.unwrap();
+        assert_eq!(session.transaction(), None);
+        let result = engine.execute("SELECT id FROM users").await.unwrap();
+        assert!(result.contains("\n1\n") && result.contains("(1 rows)"), "{}", result);
+        assert_eq!(engine.wal.read().await.entry_count(), entries);
+
+        // A committed batch lands as a whole
+        engine.execute_in(&mut session, "BEGIN").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO users VALUES (2, 'Grace')").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO users VALUES (3, 'Linus'), (4, 'Barbara')").await.unwrap();
+        engine.execute_in(&mut session, "DELETE FROM users WHERE id = 1").await.unwrap();
+        let result = engine.execute_in(&mut session, "COMMIT").await.unwrap();
+        assert!(result.ends_with("committed with 4 write(s)"), "{}", result);
+        let result = engine.execute("SELECT id FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("\n2\n3\n4\n") && result.contains("(3 rows)"), "{}", result);
+
+        let err = engine.execute_in(&mut session, "COMMIT").await.unwrap_err();
+        assert_eq!(err.to_string(), "No transaction is open");
+        assert!(engine.execute_in(&mut session, "ROLLBACK").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_reads_its_own_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+        engine.execute("INSERT INTO users VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+
+        // A row inserted and deleted in one transaction is gone at COMMIT
+        let mut session = Session::new();
+        for sql in ["BEGIN", "INSERT INTO users VALUES (5, 'Linus')", "DELETE FROM users WHERE id = 5", "COMMIT"] {
+            engine.execute_in(&mut session, sql).await.unwrap();
+        }
+        let result = engine.execute("SELECT id FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("\n1\n2\n") && result.contains("(2 rows)"), "{}", result);
+
+        // Scans, key lookups and range counts see the transaction's writes,
+        // and only the transaction does
+        engine.execute_in(&mut session, "BEGIN").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO users VALUES (5, 'Linus'), (3, 'Barbara')").await.unwrap();
+        let result = engine.execute_in(&mut session, "DELETE FROM users WHERE id = 2").await.unwrap();
+        assert_eq!(result, "1 row(s) deleted");
+        let result = engine.execute_in(&mut session, "SELECT id FROM users").await.unwrap();
+        assert!(result.contains("\n1\n3\n5\n") && result.contains("(3 rows)"), "{}", result);
+        let result = engine.execute_in(&mut session, "SELECT name FROM users WHERE id IN (2, 5)").await.unwrap();
+        assert!(result.contains("\nLinus\n") && result.contains("(1 rows)"), "{}", result);
+        let count = "SELECT COUNT(*) FROM users WHERE id BETWEEN 1 AND 5";
+        let result = engine.execute_in(&mut session, count).await.unwrap();
+        assert!(result.contains("\n3\n"), "{}", result);
+        let result = engine.execute("SELECT id FROM users").await.unwrap();
+        assert!(result.contains("\n1\n2\n") && result.contains("(2 rows)"), "{}", result);
+        let result = engine.execute_in(&mut session, "DELETE FROM users WHERE id = 2").await.unwrap();
+        assert_eq!(result, "0 row(s) deleted");
+        engine.execute_in(&mut session, "COMMIT").await.unwrap();
+        let result = engine.execute("SELECT id FROM users").await.unwrap();
+        assert!(result.contains("\n1\n3\n5\n") && result.contains("(3 rows)"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_limits_are_configurable() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let mut engine = SqlEngine::new(storage, wal);
+        engine.set_transaction_limits(TransactionLimits { max_open: 1, idle_timeout: Duration::from_secs(60) });
+
+        let (mut first, mut second) = (Session::new(), Session::new());
+        engine.execute_in(&mut first, "BEGIN").await.unwrap();
+        let err = engine.execute_in(&mut second, "BEGIN").await.unwrap_err();
+        assert_eq!(err.to_string(), "Too many open transactions (max 1)");
+        engine.execute_in(&mut first, "COMMIT").await.unwrap();
+        engine.execute_in(&mut second, "BEGIN").await.unwrap();
+        engine.execute_in(&mut second, "ROLLBACK").await.unwrap();
+
+        // A one-shot call can't leave its transaction open
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY)").await.unwrap();
+        engine.execute("BEGIN; INSERT INTO users VALUES (1)").await.unwrap();
+        engine.execute("BEGIN").await.unwrap();
+        engine.execute_in(&mut first, "BEGIN").await.unwrap();
+        assert!(engine.execute("SELECT id FROM users").await.unwrap().contains("(0 rows)"));
+    }
+
+    #[tokio::test]
+    async fn test_commit_fails_on_a_key_inserted_meanwhile() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))").await.unwrap();
+
+        let mut session = Session::new();
+        engine.execute_in(&mut session, "BEGIN").await.unwrap();
+        engine.execute_in(&mut session, "INSERT INTO users VALUES (1, 'Ada'), (2, 'Grace')").await.unwrap();
+        engine.execute("INSERT INTO users VALUES (2, 'Linus')").await.unwrap();
+        let err = engine.execute_in(&mut session, "COMMIT").await.unwrap_err();
+        assert!(err.to_string().contains("inserted by another session"), "{}", err);
+
+        // None of the transaction's rows were applied, and the session can
+        // start another
+        let result = engine.execute("SELECT name FROM users").await.unwrap();
+        assert!(result.contains("Linus") && result.contains("(1 rows)"), "{}", result);
+        engine.execute_in(&mut session, "BEGIN").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prepared_insert_runs_with_different_parameters() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_path = temp_dir.path().join("test.wal");
+        let storage = BPlusTree::new();
+        let wal = WriteAheadLog::new(wal_path.to_str().unwrap()).await.unwrap();
+        let engine = SqlEngine::new(storage, wal);
+        engine
+            .execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100), score DECIMAL(5, 2))")
+            .await
+            .unwrap();
+
+        let insert = engine.prepare("INSERT INTO users (id, name, score) VALUES (?, ?, ?)").await.unwrap();
+        assert_eq!(insert.parameter_count(), 3);
+        insert.execute(&[1.into(), "O'Brien".into(), 9.5.into()]).await.unwrap();
+        insert.execute(&[2.into(), "Grace".into(), 7.into()]).await.unwrap();
+        let result = engine.execute("SELECT id, name, score FROM users ORDER BY id").await.unwrap();
+        assert!(result.contains("\n1\tO'Brien\t9.5\n2\tGrace\t7\n"), "{}", result);
+
+        // Parameters are checked for count, and against the column they fill
+        let err = insert.execute(&[3.into(), "Ada".into()]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Expected 3 parameter(s), got 2");
+        let err = insert.execute(&["3".into(), "Ada".into(), SqlValue::Null]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Parameter $1 is VARCHAR but column 'id' is INTEGER");
+
+        let select = engine.prepare("SELECT name FROM users WHERE score > $1 AND id <> $2").await.unwrap();
+        let result = select.execute(&[5.into(), 1.into()]).await.unwrap();
+        assert!(result.contains("Grace") && result.contains("(1 rows)"), "{}", result);
+        let err = select.execute(&[true.into(), 1.into()]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Parameter $1 is BOOLEAN but column 'score' is DECIMAL(5, 2)");
+
+        // A qualified column is checked in the table its qualifier names
+        engine.execute("CREATE TABLE teams (id INTEGER PRIMARY KEY, score VARCHAR(10))").await.unwrap();
+        engine.execute("INSERT INTO teams (id, score) VALUES (2, 'gold')").await.unwrap();
+        let join = engine
+            .prepare("SELECT u.name FROM users u JOIN teams t ON u.id = t.id WHERE t.score = ?")
+            .await
+            .unwrap();
+        let result = join.execute(&["gold".into()]).await.unwrap();
+        assert!(result.contains("Grace") && result.contains("(1 rows)"), "{}", result);
+        let err = join.execute(&[5.into()]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Parameter $1 is INTEGER but column 'score' is VARCHAR(10)");
+
+        let err = engine.prepare("SELECT 1; SELECT 2").await.unwrap_err();
+        assert_eq!(err.to_string(), "Expected one statement to prepare, got 2");
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+
+/// Escapes a value for the tab-separated text result format, so a field
+/// never contains the tab or newline that delimit fields and rows. A
+/// backslash introduces `\\`, `\t`, `\n`, `\r` or `\xNN` for any other
+/// control character; everything else is passed through.
+pub fn escape_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 || c == '\x7f' => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_field`.
+pub fn unescape_field(field: &str) -> Result<String> {
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => value.push('\\'),
+            Some('t') => value.push('\t'),
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let code = u8::from_str_radix(&hex, 16).map_err(|_| anyhow!("Invalid escape '\\x{}'", hex))?;
+                value.push(code as char);
+            }
+            Some(other) => return Err(anyhow!("Invalid escape '\\{}'", other)),
+            None => return Err(anyhow!("Field ends with a lone backslash")),
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let value = "tab\there\nnew line\r\\ back\x00\x1b ünïcode";
+        let escaped = escape_field(value);
+        assert_eq!(escaped, "tab\\there\\nnew line\\r\\\\ back\\x00\\x1b ünïcode");
+        assert!(!escaped.contains(['\t', '\n']));
+        assert_eq!(unescape_field(&escaped).unwrap(), value);
+        assert!(unescape_field("bad\\q").is_err());
+    }
+}
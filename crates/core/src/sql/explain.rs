@@ -0,0 +1,46 @@
+use crate::sql::engine::SqlValue;
+use std::time::Duration;
+
+/// What running a query cost, gathered for `EXPLAIN ANALYZE`. Reads from
+/// subqueries add to the same totals. The default discards what it's
+/// given, so ordinary queries pay nothing for it.
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    collecting: bool,
+    /// How each table or view read was answered, in the order they ran.
+    pub access: Vec<String>,
+    /// Stored rows decoded. Rows answered from subtree counts aren't read.
+    pub rows_scanned: usize,
+    /// Tree nodes the reads visited.
+    pub nodes_visited: usize,
+}
+
+impl QueryStats {
+    /// Stats that keep what they're given.
+    pub fn collecting() -> Self {
+        Self { collecting: true, ..Self::default() }
+    }
+
+    /// Records one read of a table or view, described by `access`.
+    pub fn record(&mut self, access: impl FnOnce() -> String, rows_scanned: usize, nodes_visited: usize) {
+        if !self.collecting {
+            return;
+        }
+        self.access.push(access());
+        self.rows_scanned += rows_scanned;
+        self.nodes_visited += nodes_visited;
+    }
+
+    /// The `EXPLAIN ANALYZE` report: one line per read, then the totals.
+    pub fn report(&self, rows_returned: usize, elapsed: Duration) -> Vec<Vec<SqlValue>> {
+        let mut lines = self.access.clone();
+        if lines.is_empty() {
+            lines.push("No table access".to_string());
+        }
+        lines.push(format!("Rows scanned: {}", self.rows_scanned));
+        lines.push(format!("Rows returned: {}", rows_returned));
+        lines.push(format!("Nodes visited: {}", self.nodes_visited));
+        lines.push(format!("Execution time: {:.3} ms", elapsed.as_secs_f64() * 1000.0));
+        lines.into_iter().map(|line| vec![SqlValue::Varchar(line)]).collect()
+    }
+}
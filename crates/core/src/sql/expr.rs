@@ -0,0 +1,591 @@
+use crate::sql::engine::{Row, SqlValue};
+use crate::sql::functions::FunctionRegistry;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, FunctionArgExpr, Ident, ObjectName, UnaryOperator, Value};
+use std::cmp::Ordering;
+
+/// Evaluates a scalar expression against a row. Columns missing from the
+/// row evaluate to NULL, matching how omitted nullable columns are stored.
+/// Function calls resolve against the built-ins only.
+pub fn evaluate(expr: &Expr, row: &Row) -> Result<SqlValue> {
+    evaluate_with(expr, row, FunctionRegistry::builtins())
+}
+
+/// Like `evaluate`, with function calls resolved against `functions`.
+pub fn evaluate_with(expr: &Expr, row: &Row, functions: &FunctionRegistry) -> Result<SqlValue> {
+    let evaluate = |expr: &Expr, row: &Row| evaluate_with(expr, row, functions);
+    match expr {
+        Expr::Value(value) => literal_to_sql_value(value),
+        Expr::Identifier(ident) => Ok(row.get(&ident.value).cloned().unwrap_or(SqlValue::Null)),
+        Expr::CompoundIdentifier(idents) => {
+            Ok(row.get(&qualified_name(idents)).cloned().unwrap_or(SqlValue::Null))
+        }
+        Expr::Nested(inner) => evaluate(inner, row),
+        Expr::UnaryOp { op: UnaryOperator::Not, expr } => {
+            let value = evaluate(expr, row)?;
+            let truth = Ternary::from_value(&value).ok_or_else(|| anyhow!("Cannot apply NOT to {:?}", value))?;
+            Ok((!truth).into_value())
+        }
+        Expr::UnaryOp { op, expr } => {
+            let value = evaluate(expr, row)?;
+            match (op, value) {
+                (_, SqlValue::Null) => Ok(SqlValue::Null),
+                (UnaryOperator::Plus, value @ (SqlValue::Integer(_) | SqlValue::Decimal(_))) => Ok(value),
+                (UnaryOperator::Minus, SqlValue::Integer(i)) => i
+                    .checked_neg()
+                    .map(SqlValue::Integer)
+                    .ok_or_else(overflow),
+                (UnaryOperator::Minus, SqlValue::Decimal(d)) => Ok(SqlValue::Decimal(-d)),
+                (op, value) => Err(anyhow!("Cannot apply {} to {:?}", op, value)),
+            }
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let left = evaluate(left, row)?;
+            let right = evaluate(right, row)?;
+            match op {
+                BinaryOperator::And | BinaryOperator::Or => logical(op, left, right),
+                BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq => comparison(op, &left, &right),
+                _ => arithmetic(op, left, right),
+            }
+        }
+        Expr::InList { expr, list, negated } => {
+            let value = evaluate(expr, row)?;
+            // A NULL on either side makes a miss unknown rather than false
+            let mut result = Ternary::False;
+            for item in list {
+                result = result.or(comparison_truth(&BinaryOperator::Eq, &value, &evaluate(item, row)?)?);
+                if result == Ternary::True {
+                    break;
+                }
+            }
+            Ok(if *negated { !result } else { result }.into_value())
+        }
+        Expr::Between { expr, negated, low, high } => {
+            let value = evaluate(expr, row)?;
+            let above_low = comparison_truth(&BinaryOperator::GtEq, &value, &evaluate(low, row)?)?;
+            let below_high = comparison_truth(&BinaryOperator::LtEq, &value, &evaluate(high, row)?)?;
+            let result = above_low.and(below_high);
+            Ok(if *negated { !result } else { result }.into_value())
+        }
+        Expr::IsNull(expr) => Ok(SqlValue::Boolean(matches!(evaluate(expr, row)?, SqlValue::Null))),
+        Expr::IsNotNull(expr) => Ok(SqlValue::Boolean(!matches!(evaluate(expr, row)?, SqlValue::Null))),
+        // Arguments are evaluated left to right only until one isn't NULL,
+        // so those after it can't fail the row. Their types are checked
+        // before any row is read, by `functions::check_coalesce_types`
+        Expr::Function(function) if function.name.to_string().eq_ignore_ascii_case("COALESCE") => {
+            if function.args.is_empty() {
+                return Err(anyhow!("COALESCE expects at least 1 argument, got 0"));
+            }
+            for arg in &function.args {
+                let value = match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => evaluate(arg, row)?,
+                    _ => return Err(anyhow!("Unsupported argument to {}: {}", function.name, arg)),
+                };
+                if !matches!(value, SqlValue::Null) {
+                    return Ok(value);
+                }
+            }
+            Ok(SqlValue::Null)
+        }
+        Expr::Function(function) => {
+            let args = function
+                .args
+                .iter()
+                .map(|arg| match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => evaluate(arg, row),
+                    _ => Err(anyhow!("Unsupported argument to {}: {}", function.name, arg)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            functions.call(&function.name.to_string(), &args)
+        }
+        _ => Err(anyhow!("Unsupported expression: {}", expr)),
+    }
+}
+
+/// The name a qualified column reference like `users.id` reads from a
+/// joined row.
+pub fn qualified_name(idents: &[Ident]) -> String {
+    idents.iter().map(|ident| ident.value.as_str()).collect::<Vec<_>>().join(".")
+}
+
+/// SQL's three truth values. Comparing with NULL gives `Unknown`, which
+/// AND, OR and NOT carry through by the standard truth tables: `Unknown`
+/// AND `False` is `False`, but `Unknown` AND `True` stays `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ternary {
+    True,
+    False,
+    Unknown,
+}
+
+impl Ternary {
+    /// Reads a boolean or NULL; other values have no truth value.
+    pub fn from_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Boolean(b) => Some(Self::from(*b)),
+            SqlValue::Null => Some(Self::Unknown),
+            _ => None,
+        }
+    }
+
+    /// The value an expression yields: a boolean, or NULL for `Unknown`.
+    pub fn into_value(self) -> SqlValue {
+        match self {
+            Self::True => SqlValue::Boolean(true),
+            Self::False => SqlValue::Boolean(false),
+            Self::Unknown => SqlValue::Null,
+        }
+    }
+
+    pub fn and(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::False, _) | (_, Self::False) => Self::False,
+            (Self::True, Self::True) => Self::True,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn or(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::True, _) | (_, Self::True) => Self::True,
+            (Self::False, Self::False) => Self::False,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl std::ops::Not for Ternary {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        match self {
+            Self::True => Self::False,
+            Self::False => Self::True,
+            Self::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl From<bool> for Ternary {
+    fn from(b: bool) -> Self {
+        if b {
+            Self::True
+        } else {
+            Self::False
+        }
+    }
+}
+
+/// Evaluates a predicate against a row to its truth value.
+pub fn truth(predicate: &Expr, row: &Row) -> Result<Ternary> {
+    truth_with(predicate, row, FunctionRegistry::builtins())
+}
+
+/// Like `truth`, with function calls resolved against `functions`.
+pub fn truth_with(predicate: &Expr, row: &Row, functions: &FunctionRegistry) -> Result<Ternary> {
+    let value = evaluate_with(predicate, row, functions)?;
+    Ternary::from_value(&value).ok_or_else(|| anyhow!("Predicate must evaluate to a boolean, got {:?}", value))
+}
+
+/// Whether a row satisfies a predicate: only `True` does, so a WHERE that
+/// is unknown for a row leaves it out.
+pub fn matches(predicate: &Expr, row: &Row) -> Result<bool> {
+    matches_with(predicate, row, FunctionRegistry::builtins())
+}
+
+/// Like `matches`, with function calls resolved against `functions`.
+pub fn matches_with(predicate: &Expr, row: &Row, functions: &FunctionRegistry) -> Result<bool> {
+    Ok(truth_with(predicate, row, functions)? == Ternary::True)
+}
+
+/// Compares two values. Either side may come from a column or a literal.
+/// Only values of comparable types compare: integers and decimals with
+/// each other, numerically; strings with strings; booleans with booleans;
+/// and timestamps with timestamps or with strings that parse as one, see
+/// `parse_timestamp`. Any other pairing is an error naming both types,
+/// rather than a guess; NULL compares with anything, as unknown.
+pub fn compare(left: &SqlValue, right: &SqlValue) -> Result<Option<Ordering>> {
+    let ordering = match (left, right) {
+        (SqlValue::Null, _) | (_, SqlValue::Null) => return Ok(None),
+        (SqlValue::Integer(a), SqlValue::Integer(b)) => a.cmp(b),
+        (SqlValue::Varchar(a), SqlValue::Varchar(b)) => a.cmp(b),
+        (SqlValue::Boolean(a), SqlValue::Boolean(b)) => a.cmp(b),
+        (SqlValue::Timestamp(a), SqlValue::Timestamp(b)) => a.cmp(b),
+        (SqlValue::Timestamp(a), SqlValue::Varchar(b)) => a.cmp(&timestamp_operand(b)?),
+        (SqlValue::Varchar(a), SqlValue::Timestamp(b)) => timestamp_operand(a)?.cmp(b),
+        (a, b) => match (as_f64(a), as_f64(b)) {
+            (Some(x), Some(y)) => match x.partial_cmp(&y) {
+                Some(ordering) => ordering,
+                None => return Ok(None),
+            },
+            _ => return Err(anyhow!("Cannot compare type {} with type {}", type_name(a), type_name(b))),
+        },
+    };
+    Ok(Some(ordering))
+}
+
+/// The SQL type of a value, as named in errors.
+pub fn type_name(value: &SqlValue) -> &'static str {
+    match value {
+        SqlValue::Integer(_) => "INTEGER",
+        SqlValue::Varchar(_) => "VARCHAR",
+        SqlValue::Decimal(_) => "DECIMAL",
+        SqlValue::Boolean(_) => "BOOLEAN",
+        SqlValue::Timestamp(_) => "TIMESTAMP",
+        SqlValue::Null => "NULL",
+    }
+}
+
+/// How strings compare when sorting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collation {
+    /// Byte order, so uppercase sorts before lowercase. The default.
+    Binary,
+    /// Ignores ASCII case; strings equal apart from case fall back to
+    /// binary order so the result is deterministic.
+    NoCase,
+}
+
+impl Collation {
+    pub fn from_name(name: &ObjectName) -> Result<Self> {
+        match name.to_string().to_ascii_uppercase().as_str() {
+            "BINARY" => Ok(Self::Binary),
+            "NOCASE" => Ok(Self::NoCase),
+            _ => Err(anyhow!("Unknown collation '{}'", name)),
+        }
+    }
+
+    pub fn compare(self, left: &SqlValue, right: &SqlValue) -> Result<Option<Ordering>> {
+        match (self, left, right) {
+            (Self::NoCase, SqlValue::Varchar(a), SqlValue::Varchar(b)) => {
+                Ok(Some(a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()).then_with(|| a.cmp(b))))
+            }
+            _ => compare(left, right),
+        }
+    }
+}
+
+fn comparison(op: &BinaryOperator, left: &SqlValue, right: &SqlValue) -> Result<SqlValue> {
+    comparison_truth(op, left, right).map(Ternary::into_value)
+}
+
+/// A comparison's truth value; `Unknown` if either side is NULL.
+fn comparison_truth(op: &BinaryOperator, left: &SqlValue, right: &SqlValue) -> Result<Ternary> {
+    let ordering = match compare(left, right)? {
+        Some(ordering) => ordering,
+        None => return Ok(Ternary::Unknown),
+    };
+    let result = match op {
+        BinaryOperator::Eq => ordering == Ordering::Equal,
+        BinaryOperator::NotEq => ordering != Ordering::Equal,
+        BinaryOperator::Lt => ordering == Ordering::Less,
+        BinaryOperator::LtEq => ordering != Ordering::Greater,
+        BinaryOperator::Gt => ordering == Ordering::Greater,
+        BinaryOperator::GtEq => ordering != Ordering::Less,
+        _ => return Err(anyhow!("Unsupported operator: {}", op)),
+    };
+    Ok(Ternary::from(result))
+}
+
+/// Three-valued AND/OR over booleans and NULLs, see `Ternary`.
+fn logical(op: &BinaryOperator, left: SqlValue, right: SqlValue) -> Result<SqlValue> {
+    let truth = |value: SqlValue| Ternary::from_value(&value).ok_or_else(|| anyhow!("Cannot apply {} to {:?}", op, value));
+    let (left, right) = (truth(left)?, truth(right)?);
+    let result = match op {
+        BinaryOperator::And => left.and(right),
+        _ => left.or(right),
+    };
+    Ok(result.into_value())
+}
+
+pub fn literal_to_sql_value(value: &Value) -> Result<SqlValue> {
+    match value {
+        Value::Number(n, _) => {
+            if n.contains(['.', 'e', 'E']) {
+                Ok(SqlValue::Decimal(n.parse()?))
+            } else {
+                Ok(SqlValue::Integer(n.parse()?))
+            }
+        }
+        Value::SingleQuotedString(s) => Ok(SqlValue::Varchar(s.clone())),
+        Value::Boolean(b) => Ok(SqlValue::Boolean(*b)),
+        Value::Null => Ok(SqlValue::Null),
+        _ => Err(anyhow!("Unsupported value type: {:?}", value)),
+    }
+}
+
+/// Integer arithmetic is checked: overflow is an error rather than wrapping
+/// (release) or panicking (debug). Mixing integers and decimals yields a
+/// decimal. Any NULL operand yields NULL.
+fn arithmetic(op: &BinaryOperator, left: SqlValue, right: SqlValue) -> Result<SqlValue> {
+    match (left, right) {
+        (SqlValue::Null, _) | (_, SqlValue::Null) => Ok(SqlValue::Null),
+        (SqlValue::Integer(a), SqlValue::Integer(b)) => {
+            let result = match op {
+                BinaryOperator::Plus => a.checked_add(b),
+                BinaryOperator::Minus => a.checked_sub(b),
+                BinaryOperator::Multiply => a.checked_mul(b),
+                BinaryOperator::Divide | BinaryOperator::Modulo if b == 0 => {
+                    return Err(anyhow!("Division by zero"))
+                }
+                BinaryOperator::Divide => a.checked_div(b),
+                BinaryOperator::Modulo => a.checked_rem(b),
+                _ => return Err(anyhow!("Unsupported operator: {}", op)),
+            };
+            result.map(SqlValue::Integer).ok_or_else(overflow)
+        }
+        (a, b) => {
+            let (a, b) = match (as_f64(&a), as_f64(&b)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Err(anyhow!("Cannot apply {} to {:?} and {:?}", op, a, b)),
+            };
+            let result = match op {
+                BinaryOperator::Plus => a + b,
+                BinaryOperator::Minus => a - b,
+                BinaryOperator::Multiply => a * b,
+                BinaryOperator::Divide | BinaryOperator::Modulo if b == 0.0 => {
+                    return Err(anyhow!("Division by zero"))
+                }
+                BinaryOperator::Divide => a / b,
+                BinaryOperator::Modulo => a % b,
+                _ => return Err(anyhow!("Unsupported operator: {}", op)),
+            };
+            Ok(SqlValue::Decimal(result))
+        }
+    }
+}
+
+fn as_f64(value: &SqlValue) -> Option<f64> {
+    match value {
+        SqlValue::Integer(i) => Some(*i as f64),
+        SqlValue::Decimal(d) => Some(*d),
+        _ => None,
+    }
+}
+
+/// Reads a timestamp written as RFC 3339 (`2024-01-01T09:30:00Z`, any
+/// offset) or as a date alone (`2024-01-01`), which means midnight UTC.
+pub fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+fn timestamp_operand(s: &str) -> Result<DateTime<Utc>> {
+    parse_timestamp(s)
+        .ok_or_else(|| anyhow!("Cannot compare '{}' with a timestamp: expected RFC 3339 or YYYY-MM-DD", s))
+}
+
+fn overflow() -> anyhow::Error {
+    anyhow!("integer overflow in expression")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+    use std::collections::HashMap;
+
+    fn eval(sql: &str) -> Result<SqlValue> {
+        let expr = Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+        evaluate(&expr, &Row { values: HashMap::new(), schema_version: 0 })
+    }
+
+    #[test]
+    fn test_coalesce_evaluates_until_a_value() {
+        assert!(matches!(eval("COALESCE(NULL, 2, 3)").unwrap(), SqlValue::Integer(2)));
+        assert!(matches!(eval("coalesce(NULL, NULL)").unwrap(), SqlValue::Null));
+        // Arguments after the first value are never evaluated
+        assert!(matches!(eval("COALESCE(1, 1 / 0)").unwrap(), SqlValue::Integer(1)));
+        assert!(eval("COALESCE(NULL, 1 / 0)").is_err());
+        assert_eq!(eval("COALESCE()").unwrap_err().to_string(), "COALESCE expects at least 1 argument, got 0");
+    }
+
+    #[test]
+    fn test_checked_integer_arithmetic() {
+        assert!(matches!(eval("2 + 3 * 4").unwrap(), SqlValue::Integer(14)));
+        assert!(matches!(eval("7 - 10").unwrap(), SqlValue::Integer(-3)));
+        assert!(matches!(eval("1 + 0.5").unwrap(), SqlValue::Decimal(d) if d == 1.5));
+
+        for sql in ["9223372036854775807 + 1", "-9223372036854775807 - 2", "9223372036854775807 * 2"] {
+            let err = eval(sql).unwrap_err();
+            assert_eq!(err.to_string(), "integer overflow in expression");
+        }
+        assert!(eval("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_compare_integer_and_decimal_columns() {
+        let expr = Parser::new(&GenericDialect {})
+            .try_with_sql("discount < price")
+            .unwrap()
+            .parse_expr()
+            .unwrap();
+        let row = |discount: SqlValue, price: SqlValue| Row {
+            values: HashMap::from([("discount".to_string(), discount), ("price".to_string(), price)]),
+            schema_version: 0,
+        };
+
+        assert!(matches(&expr, &row(SqlValue::Integer(5), SqlValue::Decimal(9.5))).unwrap());
+        assert!(!matches(&expr, &row(SqlValue::Decimal(10.0), SqlValue::Integer(10))).unwrap());
+        assert!(!matches(&expr, &row(SqlValue::Null, SqlValue::Integer(4))).unwrap());
+        assert!(matches(&expr, &row(SqlValue::Boolean(true), SqlValue::Integer(4))).is_err());
+    }
+
+    #[test]
+    fn test_comparability_matrix() {
+        let created = "2024-03-01T12:00:00Z".parse().unwrap();
+        let comparable = [
+            (SqlValue::Integer(3), SqlValue::Integer(4), Ordering::Less),
+            (SqlValue::Integer(4), SqlValue::Decimal(3.5), Ordering::Greater),
+            (SqlValue::Decimal(2.0), SqlValue::Integer(2), Ordering::Equal),
+            (SqlValue::Varchar("abc".into()), SqlValue::Varchar("abd".into()), Ordering::Less),
+            (SqlValue::Boolean(false), SqlValue::Boolean(true), Ordering::Less),
+            (SqlValue::Timestamp(created), SqlValue::Timestamp(created), Ordering::Equal),
+            (SqlValue::Timestamp(created), SqlValue::Varchar("2024-01-01".into()), Ordering::Greater),
+            (SqlValue::Varchar("2024-03-01T12:00:00Z".into()), SqlValue::Timestamp(created), Ordering::Equal),
+        ];
+        for (left, right, expected) in comparable {
+            assert_eq!(compare(&left, &right).unwrap(), Some(expected), "{:?} vs {:?}", left, right);
+        }
+        assert_eq!(compare(&SqlValue::Null, &SqlValue::Boolean(true)).unwrap(), None);
+
+        let incomparable = [
+            (SqlValue::Varchar("3".into()), SqlValue::Integer(3), "VARCHAR with type INTEGER"),
+            (SqlValue::Decimal(1.0), SqlValue::Varchar("1.0".into()), "DECIMAL with type VARCHAR"),
+            (SqlValue::Varchar("true".into()), SqlValue::Boolean(true), "VARCHAR with type BOOLEAN"),
+            (SqlValue::Boolean(true), SqlValue::Integer(1), "BOOLEAN with type INTEGER"),
+            (SqlValue::Decimal(0.0), SqlValue::Boolean(false), "DECIMAL with type BOOLEAN"),
+            (SqlValue::Timestamp(created), SqlValue::Integer(1709294400), "TIMESTAMP with type INTEGER"),
+            (SqlValue::Decimal(1.5), SqlValue::Timestamp(created), "DECIMAL with type TIMESTAMP"),
+            (SqlValue::Boolean(true), SqlValue::Timestamp(created), "BOOLEAN with type TIMESTAMP"),
+        ];
+        for (left, right, types) in incomparable {
+            let err = compare(&left, &right).unwrap_err();
+            assert_eq!(err.to_string(), format!("Cannot compare type {}", types));
+        }
+    }
+
+    #[test]
+    fn test_compare_timestamps_with_string_literals() {
+        let created = "2024-03-01T12:00:00Z".parse().unwrap();
+        let row = Row {
+            values: HashMap::from([("created".to_string(), SqlValue::Timestamp(created))]),
+            schema_version: 0,
+        };
+        let matches = |sql: &str| {
+            let expr = Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+            matches(&expr, &row)
+        };
+
+        assert!(matches("created > '2024-01-01'").unwrap());
+        assert!(!matches("created < '2024-03-01'").unwrap());
+        assert!(matches("'2024-03-02' > created").unwrap());
+        assert!(matches("created = '2024-03-01T12:00:00Z'").unwrap());
+        assert!(matches("created = '2024-03-01T14:00:00+02:00'").unwrap());
+        assert!(matches("created < '2024-03-01T12:00:00.001Z'").unwrap());
+        assert!(matches("created BETWEEN '2024-03-01' AND '2024-03-02'").unwrap());
+
+        let err = matches("created > 'last tuesday'").unwrap_err();
+        assert_eq!(err.to_string(), "Cannot compare 'last tuesday' with a timestamp: expected RFC 3339 or YYYY-MM-DD");
+        assert!(matches("created > '2024-13-01'").is_err());
+    }
+
+    #[test]
+    fn test_between() {
+        assert!(matches!(eval("5 BETWEEN 1 AND 5").unwrap(), SqlValue::Boolean(true)));
+        assert!(matches!(eval("6 BETWEEN 1 AND 5").unwrap(), SqlValue::Boolean(false)));
+        assert!(matches!(eval("6 NOT BETWEEN 1 AND 5").unwrap(), SqlValue::Boolean(true)));
+        assert!(matches!(eval("'b' BETWEEN 'a' AND 'c'").unwrap(), SqlValue::Boolean(true)));
+        assert!(matches!(eval("3 BETWEEN NULL AND 5").unwrap(), SqlValue::Null));
+        // Out of range on the known side decides it despite the NULL
+        assert!(matches!(eval("9 BETWEEN NULL AND 5").unwrap(), SqlValue::Boolean(false)));
+    }
+
+    #[test]
+    fn test_three_valued_logic() {
+        use Ternary::{False, True, Unknown};
+        assert_eq!(Unknown.or(True), True);
+        assert_eq!(Unknown.or(False), Unknown);
+        assert_eq!(Unknown.and(False), False);
+        assert_eq!(Unknown.and(True), Unknown);
+        assert_eq!(!Unknown, Unknown);
+        assert_eq!(!True, False);
+
+        let truth = |sql: &str| {
+            let expr = Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+            truth(&expr, &Row { values: HashMap::new(), schema_version: 0 }).unwrap()
+        };
+        assert_eq!(truth("NULL OR TRUE"), True);
+        assert_eq!(truth("NULL AND FALSE"), False);
+        assert_eq!(truth("NULL AND TRUE"), Unknown);
+        assert_eq!(truth("NOT NULL"), Unknown);
+        assert_eq!(truth("NOT (1 = 2)"), True);
+        assert_eq!(truth("1 NOT IN (2, NULL)"), Unknown);
+        assert!(eval("NOT 1").is_err());
+
+        // Only TRUE keeps a row; unknown is left out like false
+        let null_column = Row { values: HashMap::from([("age".to_string(), SqlValue::Null)]), schema_version: 0 };
+        for sql in ["NOT NULL", "age = 30", "age = NULL", "NOT (age = 30)", "NULL AND FALSE"] {
+            let expr = Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+            assert!(!matches(&expr, &null_column).unwrap(), "{}", sql);
+        }
+        let expr = Parser::new(&GenericDialect {}).try_with_sql("age = 30 OR TRUE").unwrap().parse_expr().unwrap();
+        assert!(matches(&expr, &null_column).unwrap());
+        for (sql, expected) in [("age IS NULL", true), ("age IS NOT NULL", false), ("NOT (age IS NULL)", false)] {
+            let expr = Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+            assert_eq!(matches(&expr, &null_column).unwrap(), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_not_over_parenthesized_predicates() {
+        use Ternary::{False, True, Unknown};
+        let parse = |sql: &str| Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap();
+        let empty = Row { values: HashMap::new(), schema_version: 0 };
+        assert_eq!(truth(&parse("NOT (TRUE)"), &empty).unwrap(), False);
+        assert_eq!(truth(&parse("NOT (NULL = 1)"), &empty).unwrap(), Unknown);
+        assert!(!matches(&parse("NOT (NULL = 1)"), &empty).unwrap());
+        assert_eq!(truth(&parse("NOT ((NOT (FALSE)))"), &empty).unwrap(), False);
+
+        // NOT over a parenthesized AND agrees with its De Morgan expansion
+        // for every mix of values and NULLs
+        let negated = parse("NOT (a = 1 AND b = 2)");
+        let expanded = parse("a <> 1 OR b <> 2");
+        let nested = parse("NOT ((a = 1 OR b = 2) AND NOT (a = 2 OR (b = 1)))");
+        let values = [SqlValue::Integer(1), SqlValue::Integer(2), SqlValue::Null];
+        for a in &values {
+            for b in &values {
+                let row = Row {
+                    values: HashMap::from([("a".to_string(), a.clone()), ("b".to_string(), b.clone())]),
+                    schema_version: 0,
+                };
+                let inner = |sql: &str| truth(&parse(sql), &row).unwrap();
+                assert_eq!(truth(&negated, &row).unwrap(), truth(&expanded, &row).unwrap(), "a={:?} b={:?}", a, b);
+                let either = inner("a = 1").or(inner("b = 2"));
+                let neither = !inner("a = 2").or(inner("b = 1"));
+                assert_eq!(truth(&nested, &row).unwrap(), !either.and(neither), "a={:?} b={:?}", a, b);
+            }
+        }
+
+        // A known false conjunct decides the AND despite the NULL, so
+        // its negation keeps the row; otherwise the NULL leaves it unknown
+        let row = |a: SqlValue| Row {
+            values: HashMap::from([("a".to_string(), a), ("b".to_string(), SqlValue::Null)]),
+            schema_version: 0,
+        };
+        assert_eq!(truth(&negated, &row(SqlValue::Integer(2))).unwrap(), True);
+        assert_eq!(truth(&negated, &row(SqlValue::Integer(1))).unwrap(), Unknown);
+        assert!(!matches(&negated, &row(SqlValue::Integer(1))).unwrap());
+    }
+}
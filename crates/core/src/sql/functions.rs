@@ -0,0 +1,229 @@
+use crate::sql::engine::{SqlDataType, SqlValue, TableSchema};
+use crate::sql::expr;
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{visit_expressions, Expr, FunctionArg, FunctionArgExpr, Visit};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, OnceLock};
+
+/// The body of a scalar function: its evaluated arguments in, one value out.
+pub type ScalarFunction = Arc<dyn Fn(&[SqlValue]) -> Result<SqlValue> + Send + Sync>;
+
+/// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, n) = match self {
+            Arity::Exact(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        write!(f, "{}{} argument{}", prefix, n, if *n == 1 { "" } else { "s" })
+    }
+}
+
+#[derive(Clone)]
+struct Registered {
+    arity: Arity,
+    function: ScalarFunction,
+}
+
+/// Scalar functions callable from expressions, by case-insensitive name.
+/// `new` starts with the built-ins; an embedding application adds its own
+/// with `register`, which replaces any function of the same name. COALESCE
+/// isn't one: it evaluates its arguments only until one isn't NULL, so
+/// `expr::evaluate_with` handles it itself.
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Registered>,
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.functions.keys().collect();
+        names.sort();
+        f.debug_struct("FunctionRegistry").field("functions", &names).finish()
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self { functions: HashMap::new() };
+        registry.register("UPPER", Arity::Exact(1), |args| text(&args[0], |s| s.to_uppercase()));
+        registry.register("LOWER", Arity::Exact(1), |args| text(&args[0], |s| s.to_lowercase()));
+        registry.register("LENGTH", Arity::Exact(1), |args| match &args[0] {
+            SqlValue::Null => Ok(SqlValue::Null),
+            SqlValue::Varchar(s) => Ok(SqlValue::Integer(s.chars().count() as i64)),
+            other => Err(anyhow!("LENGTH expects a string, got {:?}", other)),
+        });
+        registry.register("ABS", Arity::Exact(1), |args| match &args[0] {
+            SqlValue::Null => Ok(SqlValue::Null),
+            SqlValue::Integer(i) => {
+                i.checked_abs().map(SqlValue::Integer).ok_or_else(|| anyhow!("integer overflow in expression"))
+            }
+            SqlValue::Decimal(d) => Ok(SqlValue::Decimal(d.abs())),
+            other => Err(anyhow!("ABS expects a number, got {:?}", other)),
+        });
+        registry.register("NULLIF", Arity::Exact(2), |args| {
+            match expr::compare(&args[0], &args[1])? {
+                Some(std::cmp::Ordering::Equal) => Ok(SqlValue::Null),
+                _ => Ok(args[0].clone()),
+            }
+        });
+        registry
+    }
+
+    /// The built-in functions alone, for evaluation outside an engine.
+    pub fn builtins() -> &'static FunctionRegistry {
+        static BUILTINS: OnceLock<FunctionRegistry> = OnceLock::new();
+        BUILTINS.get_or_init(FunctionRegistry::new)
+    }
+
+    pub fn register<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&[SqlValue]) -> Result<SqlValue> + Send + Sync + 'static,
+    {
+        self.functions.insert(name.to_ascii_uppercase(), Registered { arity, function: Arc::new(function) });
+    }
+
+    /// Calls `name` with already evaluated arguments, checking their count.
+    pub fn call(&self, name: &str, args: &[SqlValue]) -> Result<SqlValue> {
+        let registered = self
+            .functions
+            .get(&name.to_ascii_uppercase())
+            .ok_or_else(|| anyhow!("Unknown function '{}'", name))?;
+        if !registered.arity.accepts(args.len()) {
+            return Err(anyhow!("{} expects {}, got {}", name.to_ascii_uppercase(), registered.arity, args.len()));
+        }
+        (registered.function)(args)
+    }
+}
+
+/// Rejects a COALESCE in `node` whose arguments can't stand in for each
+/// other: they must be all numbers, or all of one other type. Only the
+/// arguments typed before any row is read count, columns of `schema` and
+/// literals, so whether a query fails doesn't depend on the rows it reads.
+pub fn check_coalesce_types<V: Visit>(node: &V, schema: &TableSchema) -> Result<()> {
+    let flow = visit_expressions(node, |expr| {
+        let function = match expr {
+            Expr::Function(function) if function.name.to_string().eq_ignore_ascii_case("COALESCE") => function,
+            _ => return ControlFlow::Continue(()),
+        };
+        let mut first = None;
+        for arg in &function.args {
+            let arg_type = match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => static_type(arg, schema),
+                _ => None,
+            };
+            match (first, arg_type) {
+                (None, arg_type) => first = arg_type,
+                (Some(first), Some(arg_type)) if !compatible(first, arg_type) => {
+                    return ControlFlow::Break(anyhow!(
+                        "COALESCE arguments have incompatible types: {} and {}",
+                        first,
+                        arg_type
+                    ));
+                }
+                _ => {}
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    match flow {
+        ControlFlow::Break(e) => Err(e),
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+/// The type of `expr`, as `expr::type_name` names it, if it is a column of
+/// `schema` or a literal other than NULL.
+fn static_type(expr: &Expr, schema: &TableSchema) -> Option<&'static str> {
+    let column = |name: &str| {
+        let column = schema.columns.iter().find(|c| c.name == name)?;
+        Some(match column.data_type {
+            SqlDataType::Integer => "INTEGER",
+            SqlDataType::Varchar(_) => "VARCHAR",
+            SqlDataType::Decimal(..) => "DECIMAL",
+            SqlDataType::Boolean => "BOOLEAN",
+            SqlDataType::Timestamp => "TIMESTAMP",
+        })
+    };
+    match expr {
+        Expr::Nested(inner) => static_type(inner, schema),
+        Expr::Identifier(ident) => column(&ident.value),
+        Expr::CompoundIdentifier(idents) => column(&expr::qualified_name(idents)),
+        Expr::Value(value) => match expr::literal_to_sql_value(value).ok()? {
+            SqlValue::Null => None,
+            value => Some(expr::type_name(&value)),
+        },
+        _ => None,
+    }
+}
+
+/// Whether values of two types can stand in for each other: both numbers,
+/// or both the same other type.
+fn compatible(a: &str, b: &str) -> bool {
+    let numeric = |t: &str| t == "INTEGER" || t == "DECIMAL";
+    a == b || numeric(a) && numeric(b)
+}
+
+fn text(value: &SqlValue, f: impl FnOnce(&str) -> String) -> Result<SqlValue> {
+    match value {
+        SqlValue::Null => Ok(SqlValue::Null),
+        SqlValue::Varchar(s) => Ok(SqlValue::Varchar(f(s))),
+        other => Err(anyhow!("Expected a string, got {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins_and_arity() {
+        let functions = FunctionRegistry::builtins();
+        let upper = functions.call("upper", &[SqlValue::Varchar("ab".into())]).unwrap();
+        assert!(matches!(upper, SqlValue::Varchar(s) if s == "AB"));
+        let length = functions.call("LENGTH", &[SqlValue::Varchar("héllo".into())]).unwrap();
+        assert!(matches!(length, SqlValue::Integer(5)));
+        assert!(matches!(functions.call("ABS", &[SqlValue::Null]).unwrap(), SqlValue::Null));
+
+        let err = functions.call("upper", &[]).unwrap_err();
+        assert_eq!(err.to_string(), "UPPER expects 1 argument, got 0");
+        let err = functions.call("NULLIF", &[SqlValue::Null]).unwrap_err();
+        assert_eq!(err.to_string(), "NULLIF expects 2 arguments, got 1");
+        assert_eq!(functions.call("nope", &[]).unwrap_err().to_string(), "Unknown function 'nope'");
+    }
+
+    #[test]
+    fn test_nullif() {
+        let functions = FunctionRegistry::builtins();
+        let call = |name: &str, args: &[SqlValue]| functions.call(name, args);
+        assert!(matches!(call("NULLIF", &[SqlValue::Integer(3), SqlValue::Decimal(3.0)]).unwrap(), SqlValue::Null));
+        assert!(matches!(call("NULLIF", &[SqlValue::Integer(3), SqlValue::Integer(4)]).unwrap(), SqlValue::Integer(3)));
+        // NULL never equals anything, so it comes back as itself
+        assert!(matches!(call("NULLIF", &[SqlValue::Null, SqlValue::Null]).unwrap(), SqlValue::Null));
+        assert!(matches!(call("NULLIF", &[SqlValue::Integer(3), SqlValue::Null]).unwrap(), SqlValue::Integer(3)));
+        assert!(call("NULLIF", &[SqlValue::Integer(3), SqlValue::Boolean(true)]).is_err());
+    }
+}
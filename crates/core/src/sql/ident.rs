@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{Ident, ObjectName};
+use sqlparser::tokenizer::Token;
+use std::str::FromStr;
+
+/// Longest database, table or column name accepted.
+pub const MAX_IDENTIFIER_LEN: usize = 64;
+
+/// Characters that identifiers may not contain: `:` separates the table
+/// name from the primary key in storage keys, and `.` separates a database
+/// from a table in qualified names.
+const RESERVED_CHARS: [char; 2] = [':', '.'];
+
+/// Checks a name being created against the identifier rules: not empty, at
+/// most `MAX_IDENTIFIER_LEN` characters, and no reserved or control
+/// characters. `kind` names the object in the error, e.g. "table".
+pub fn validate(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("Invalid {} name: must not be empty", kind));
+    }
+    if name.chars().count() > MAX_IDENTIFIER_LEN {
+        return Err(anyhow!(
+            "Invalid {} name '{}': longer than {} characters",
+            kind,
+            name,
+            MAX_IDENTIFIER_LEN
+        ));
+    }
+    if let Some(c) = name.chars().find(|c| RESERVED_CHARS.contains(c) || c.is_control()) {
+        return Err(anyhow!("Invalid {} name '{}': must not contain {:?}", kind, name, c));
+    }
+    Ok(())
+}
+
+/// How table, column and database names are normalised as statements are
+/// parsed, so a name is created and looked up the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdentifierCase {
+    /// Unquoted names fold to lower case and quoted ones keep theirs, as
+    /// in PostgreSQL: `Name` and `NAME` are `name`, `"Name"` is distinct.
+    #[default]
+    Lower,
+    /// Names keep the case they were typed in, quoted or not.
+    Preserve,
+    /// Every name folds to lower case, quoted or not, so names never differ
+    /// by case alone. Quoting still allows keywords and spaces.
+    Insensitive,
+}
+
+impl IdentifierCase {
+    /// Applies the policy to a statement's tokens before they are parsed.
+    /// Keywords are matched regardless of case, so folding them is harmless.
+    pub fn fold(self, tokens: &mut [Token]) {
+        for token in tokens {
+            if let Token::Word(word) = token {
+                if self.folds(word.quote_style) {
+                    word.value = word.value.to_lowercase();
+                }
+            }
+        }
+    }
+
+    /// Applies the policy to a name built outside the parser.
+    pub fn fold_name(self, name: &ObjectName) -> ObjectName {
+        ObjectName(
+            name.0
+                .iter()
+                .map(|ident| match self.folds(ident.quote_style) {
+                    true => Ident { value: ident.value.to_lowercase(), quote_style: ident.quote_style },
+                    false => ident.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    fn folds(self, quote_style: Option<char>) -> bool {
+        match self {
+            IdentifierCase::Lower => quote_style.is_none(),
+            IdentifierCase::Preserve => false,
+            IdentifierCase::Insensitive => true,
+        }
+    }
+}
+
+impl FromStr for IdentifierCase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "lower" => Ok(IdentifierCase::Lower),
+            "preserve" => Ok(IdentifierCase::Preserve),
+            "insensitive" => Ok(IdentifierCase::Insensitive),
+            _ => Err(anyhow!("Unknown identifier case '{}', expected lower, preserve or insensitive", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("table", "users").is_ok());
+        assert!(validate("table", "order items").is_ok());
+        assert!(validate("table", "a:b").is_err());
+        assert!(validate("table", "a.b").is_err());
+        assert!(validate("column", "").is_err());
+        assert!(validate("column", &"x".repeat(MAX_IDENTIFIER_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::sql::engine::{Column, Row, SqlDataType, SqlValue, TableSchema};
+use crate::sql::session::DEFAULT_DATABASE;
+use sqlparser::ast::ObjectName;
+use std::collections::HashMap;
+
+/// Name of the read-only database holding the views. It can't be created.
+pub const DATABASE: &str = "information_schema";
+
+/// Catalog every table belongs to, reported as `table_catalog`.
+const CATALOG: &str = "wundradb";
+
+/// A read-only view of the schema catalog, queried like a table but
+/// computed from the catalog on each read rather than stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    /// One row per table.
+    Tables,
+    /// One row per column of every table.
+    Columns,
+}
+
+impl View {
+    /// The view `name` refers to, if it is `information_schema.<view>`.
+    /// Matched case-insensitively, as tools vary in how they spell it.
+    pub fn resolve(name: &ObjectName) -> Option<Self> {
+        match name.0.as_slice() {
+            [database, view] if database.value.eq_ignore_ascii_case(DATABASE) => {
+                match view.value.to_ascii_lowercase().as_str() {
+                    "tables" => Some(Self::Tables),
+                    "columns" => Some(Self::Columns),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The view's columns, for projection and `SELECT *`.
+    pub fn schema(self) -> TableSchema {
+        let columns = match self {
+            Self::Tables => vec![
+                text_column("table_catalog"),
+                text_column("table_schema"),
+                text_column("table_name"),
+                text_column("table_type"),
+            ],
+            Self::Columns => vec![
+                text_column("table_catalog"),
+                text_column("table_schema"),
+                text_column("table_name"),
+                text_column("column_name"),
+                integer_column("ordinal_position"),
+                text_column("column_default"),
+                text_column("is_nullable"),
+                text_column("data_type"),
+                integer_column("character_maximum_length"),
+                integer_column("numeric_precision"),
+                integer_column("numeric_scale"),
+                text_column("is_generated"),
+                text_column("generation_expression"),
+            ],
+        };
+        let name = match self {
+            Self::Tables => "tables",
+            Self::Columns => "columns",
+        };
+        TableSchema { name: format!("{}.{}", DATABASE, name), columns, version: 1, shard_count: 1, id: 0 }
+    }
+
+    /// The view's rows for the tables in `schemas`, ordered by database,
+    /// table and column position.
+    pub fn rows(self, schemas: &HashMap<String, TableSchema>) -> Vec<Row> {
+        let mut tables: Vec<(&str, &str, &TableSchema)> = schemas
+            .iter()
+            .map(|(name, schema)| match name.split_once('.') {
+                Some((database, table)) => (database, table, schema),
+                None => (DEFAULT_DATABASE, name.as_str(), schema),
+            })
+            .collect();
+        tables.sort_by_key(|&(database, table, _)| (database, table));
+
+        let mut rows = Vec::new();
+        for (database, table, schema) in tables {
+            let identity = [
+                ("table_catalog", text(CATALOG)),
+                ("table_schema", text(database)),
+                ("table_name", text(table)),
+            ];
+            match self {
+                Self::Tables => rows.push(row(identity.into_iter().chain([("table_type", text("BASE TABLE"))]))),
+                Self::Columns => {
+                    for (position, column) in schema.columns.iter().enumerate() {
+                        let (length, precision, scale) = type_sizes(&column.data_type);
+                        let generation = column.generated.as_deref().map_or(SqlValue::Null, text);
+                        let default = column.default.as_deref().map_or(SqlValue::Null, text);
+                        rows.push(row(identity.clone().into_iter().chain([
+                            ("column_name", text(&column.name)),
+                            ("ordinal_position", SqlValue::Integer(position as i64 + 1)),
+                            ("column_default", default),
+                            ("is_nullable", text(if column.accepts_null() { "YES" } else { "NO" })),
+                            ("data_type", text(type_name(&column.data_type))),
+                            ("character_maximum_length", length),
+                            ("numeric_precision", precision),
+                            ("numeric_scale", scale),
+                            ("is_generated", text(if column.generated.is_some() { "ALWAYS" } else { "NEVER" })),
+                            ("generation_expression", generation),
+                        ])));
+                    }
+                }
+            }
+        }
+        rows
+    }
+}
+
+/// The standard name of a column type.
+fn type_name(data_type: &SqlDataType) -> &'static str {
+    match data_type {
+        SqlDataType::Integer => "bigint",
+        SqlDataType::Varchar(_) => "character varying",
+        SqlDataType::Boolean => "boolean",
+        SqlDataType::Decimal(..) => "numeric",
+        SqlDataType::Timestamp => "timestamp with time zone",
+    }
+}
+
+/// `character_maximum_length`, `numeric_precision` and `numeric_scale`;
+/// NULL where they don't apply to the type.
+fn type_sizes(data_type: &SqlDataType) -> (SqlValue, SqlValue, SqlValue) {
+    match data_type {
+        SqlDataType::Varchar(length) => (SqlValue::Integer(*length as i64), SqlValue::Null, SqlValue::Null),
+        // Integers are stored as 64-bit values
+        SqlDataType::Integer => (SqlValue::Null, SqlValue::Integer(64), SqlValue::Integer(0)),
+        SqlDataType::Decimal(precision, scale) => {
+            (SqlValue::Null, SqlValue::Integer(*precision as i64), SqlValue::Integer(*scale as i64))
+        }
+        SqlDataType::Boolean | SqlDataType::Timestamp => (SqlValue::Null, SqlValue::Null, SqlValue::Null),
+    }
+}
+
+fn text(value: &str) -> SqlValue {
+    SqlValue::Varchar(value.to_string())
+}
+
+fn row<'a>(values: impl IntoIterator<Item = (&'a str, SqlValue)>) -> Row {
+    let mut row = Row { schema_version: 1, ..Row::new() };
+    for (name, value) in values {
+        row.set(name, value);
+    }
+    row
+}
+
+fn text_column(name: &str) -> Column {
+    view_column(name, SqlDataType::Varchar(u32::MAX))
+}
+
+fn integer_column(name: &str) -> Column {
+    view_column(name, SqlDataType::Integer)
+}
+
+fn view_column(name: &str, data_type: SqlDataType) -> Column {
+    Column {
+        name: name.to_string(),
+        data_type,
+        nullable: true,
+        primary_key: false,
+        auto_increment: false,
+        generated: None,
+        generated_expr: None,
+        default: None,
+        added_in: 1,
+        not_null: false,
+    }
+}
@@ -0,0 +1,73 @@
+use crate::sql::engine::{Row, SqlValue};
+use anyhow::{anyhow, Result};
+use std::mem::size_of;
+
+/// Memory held by one query's buffered rows: those its scans keep, its
+/// joins produce and ORDER BY's sort keys. Each reserves what it buffers,
+/// estimated with `row_size`, and the query fails once the total passes
+/// the limit rather than growing until the server runs out.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes, or an unlimited one that only counts.
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    pub fn reserve(&mut self, bytes: usize) -> Result<()> {
+        self.used += bytes;
+        match self.limit {
+            Some(limit) if self.used > limit => {
+                Err(anyhow!("query exceeded memory limit ({} kB)", limit.div_ceil(1024)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes reserved so far.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+/// Approximate bytes a row takes in memory: its values and column names.
+/// Ignores the map's spare capacity, so it is a lower bound.
+pub fn row_size(row: &Row) -> usize {
+    size_of::<Row>()
+        + row.iter().map(|(name, value)| size_of::<String>() + name.len() + value_size(value)).sum::<usize>()
+}
+
+pub fn value_size(value: &SqlValue) -> usize {
+    size_of::<SqlValue>()
+        + match value {
+            SqlValue::Varchar(s) => s.len(),
+            _ => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_budget_fails_past_its_limit() {
+        let values = HashMap::from([("name".to_string(), SqlValue::Varchar("x".repeat(100)))]);
+        let row = Row { values, schema_version: 1 };
+        assert!(row_size(&row) > 100);
+
+        let mut budget = MemoryBudget::new(Some(2048));
+        budget.reserve(2000).unwrap();
+        budget.reserve(48).unwrap();
+        let err = budget.reserve(1).unwrap_err();
+        assert_eq!(err.to_string(), "query exceeded memory limit (2 kB)");
+
+        let mut unlimited = MemoryBudget::new(None);
+        unlimited.reserve(usize::MAX / 2).unwrap();
+        assert_eq!(unlimited.used(), usize::MAX / 2);
+    }
+}
@@ -1 +1,18 @@
-pub mod engine;
\ No newline at end of file
+pub mod aggregate;
+pub mod catalog;
+pub mod ddl;
+pub mod engine;
+pub mod escape;
+pub mod explain;
+pub mod expr;
+pub mod functions;
+pub mod ident;
+pub mod information_schema;
+pub mod memory;
+pub mod output;
+pub mod plan;
+pub mod prepared;
+pub mod row;
+pub mod session;
+pub mod subquery;
+pub mod values;
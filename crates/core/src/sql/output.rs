@@ -0,0 +1,137 @@
+use crate::sql::engine::SqlValue;
+use crate::sql::escape::escape_field;
+
+/// What one statement returned, before it is rendered as text. Clients
+/// that frame results themselves read the typed values from here.
+#[derive(Debug, Clone)]
+pub enum Output {
+    Rows(ResultSet),
+    /// The number of rows a write changed, with the message that reports
+    /// it, such as `2 row(s) inserted`.
+    Affected { rows: u64, message: String },
+    /// Anything else, such as the table a CREATE TABLE created.
+    Message(String),
+}
+
+impl Output {
+    /// The output as the engine's text results show it.
+    pub fn render(&self) -> String {
+        match self {
+            Output::Rows(set) => set.render(),
+            Output::Affected { message, .. } | Output::Message(message) => message.clone(),
+        }
+    }
+
+    /// The rows a result set holds or a write changed; 0 for a message.
+    pub fn row_count(&self) -> u64 {
+        match self {
+            Output::Rows(set) => set.rows.len() as u64,
+            Output::Affected { rows, .. } => *rows,
+            Output::Message(_) => 0,
+        }
+    }
+}
+
+/// A result set: its column names, and each row's values in that order.
+#[derive(Debug, Clone, Default)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
+    /// Shown after the rows, as when they were cut to `max_result_rows`.
+    pub warning: Option<String>,
+    /// Rendered as a header line and a value line with no separator, the
+    /// way constant selects like `SELECT 1` print.
+    pub compact: bool,
+}
+
+impl ResultSet {
+    pub fn new(columns: Vec<String>, rows: Vec<Vec<SqlValue>>) -> Self {
+        Self { columns, rows, ..Self::default() }
+    }
+
+    /// Tab-separated lines under a header and a dashed separator as wide
+    /// as the widest line, then the row count and any warning. Fields are
+    /// escaped with `escape_field` so values can't break the layout.
+    pub fn render(&self) -> String {
+        let header = self.columns.iter().map(|h| escape_field(h)).collect::<Vec<_>>().join("\t");
+        if self.compact {
+            let values: Vec<String> =
+                self.rows.first().into_iter().flatten().map(|v| escape_field(&value_text(v))).collect();
+            return format!("{}\n{}\n(1 row)\n", header, values.join("\t"));
+        }
+
+        let mut result = String::new();
+        // Render every line first so the separator can match the widest one
+        let lines: Vec<String> = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| match value {
+                        SqlValue::Null => "NULL".to_string(),
+                        value => escape_field(&value_text(value)),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\t")
+            })
+            .collect();
+
+        // With no columns there is nothing to head; only the count is shown
+        if !self.columns.is_empty() {
+            let width = lines.iter().chain(std::iter::once(&header)).map(|l| rendered_width(l)).max().unwrap_or(0);
+            result.push_str(&header);
+            result.push('\n');
+            result.push_str(&"-".repeat(width));
+            result.push('\n');
+            for line in &lines {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+
+        result.push_str(&format!("({} rows)\n", self.rows.len()));
+        result.push_str(self.warning.as_deref().unwrap_or(""));
+        result
+    }
+}
+
+/// The outputs of a batch of statements, rendered one after another.
+pub fn render(outputs: &[Output]) -> String {
+    let mut combined = String::new();
+    for output in outputs {
+        if !combined.is_empty() && !combined.ends_with('\n') {
+            combined.push('\n');
+        }
+        combined.push_str(&output.render());
+    }
+    combined
+}
+
+/// `value` as text, unescaped, with NULL as `null`.
+pub fn value_text(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Integer(i) => i.to_string(),
+        SqlValue::Varchar(s) => s.clone(),
+        SqlValue::Decimal(d) => d.to_string(),
+        SqlValue::Boolean(b) => b.to_string(),
+        SqlValue::Timestamp(t) => t.to_rfc3339(),
+        SqlValue::Null => "null".to_string(),
+    }
+}
+
+/// `value` as a SQL literal: strings and timestamps quoted, with quotes
+/// doubled, and NULL as `NULL`.
+pub fn value_sql(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Varchar(s) => format!("'{}'", s.replace('\'', "''")),
+        SqlValue::Timestamp(t) => format!("'{}'", t.to_rfc3339()),
+        SqlValue::Null => "NULL".to_string(),
+        value => value_text(value),
+    }
+}
+
+/// Width of a result line on a terminal, with tabs expanded to the next
+/// multiple of eight columns.
+fn rendered_width(line: &str) -> usize {
+    line.chars().fold(0, |width, c| if c == '\t' { (width / 8 + 1) * 8 } else { width + 1 })
+}
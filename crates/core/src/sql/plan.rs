@@ -0,0 +1,316 @@
+use crate::sql::engine::TableSchema;
+use sqlparser::ast::{visit_expressions, visit_expressions_mut, BinaryOperator, Expr, Query, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::ControlFlow;
+
+/// How a SELECT reads its table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessPath {
+    /// Read every row under the table's key prefix.
+    FullScan,
+    /// The WHERE clause pins the primary key with `column = <constant>` or
+    /// `column IN (<constants>)`, so the matching rows are fetched by key.
+    PrimaryKey { column: String },
+    /// The WHERE clause is exactly `column BETWEEN <constant> AND
+    /// <constant>` on the primary key, so `COUNT(*)` is answered from the
+    /// tree's subtree counts. Other projections still scan.
+    PrimaryKeyRange { column: String },
+}
+
+/// The access path chosen for a statement shape. Plans never depend on
+/// literal values, so one plan serves every query of the same shape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub table: String,
+    pub access: AccessPath,
+}
+
+/// Chooses how to read `schema`'s table for a query filtered by `selection`.
+pub fn plan_select(schema: &TableSchema, selection: Option<&Expr>) -> Plan {
+    let mut keys = schema.columns.iter().filter(|c| c.primary_key);
+    let access = match (keys.next(), keys.next(), selection) {
+        (Some(key), None, Some(selection)) if primary_key_values(selection, &key.name).is_some() => {
+            AccessPath::PrimaryKey { column: key.name.clone() }
+        }
+        (Some(key), None, Some(selection)) if primary_key_range(selection, &key.name).is_some() => {
+            AccessPath::PrimaryKeyRange { column: key.name.clone() }
+        }
+        _ => AccessPath::FullScan,
+    };
+    Plan { table: schema.name.clone(), access }
+}
+
+/// Finds a top-level `column = <constant>` conjunct (either way round) in
+/// `selection` and returns the constant side.
+pub fn primary_key_value<'a>(selection: &'a Expr, column: &str) -> Option<&'a Expr> {
+    match selection {
+        Expr::Nested(inner) => primary_key_value(inner, column),
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            primary_key_value(left, column).or_else(|| primary_key_value(right, column))
+        }
+        Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => match (&**left, &**right) {
+            (Expr::Identifier(ident), value) | (value, Expr::Identifier(ident))
+                if ident.value == column && is_constant(value) =>
+            {
+                Some(value)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Like `primary_key_value`, but also accepts a top-level
+/// `column IN (<constants>)` conjunct, returning every listed constant.
+pub fn primary_key_values<'a>(selection: &'a Expr, column: &str) -> Option<Vec<&'a Expr>> {
+    match selection {
+        Expr::Nested(inner) => primary_key_values(inner, column),
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            primary_key_values(left, column).or_else(|| primary_key_values(right, column))
+        }
+        Expr::InList { expr, list, negated: false } => match &**expr {
+            Expr::Identifier(ident) if ident.value == column && list.iter().all(is_constant) => {
+                Some(list.iter().collect())
+            }
+            _ => None,
+        },
+        _ => primary_key_value(selection, column).map(|value| vec![value]),
+    }
+}
+
+/// If `selection` is just `column BETWEEN <constant> AND <constant>`,
+/// returns the two bounds.
+pub fn primary_key_range<'a>(selection: &'a Expr, column: &str) -> Option<(&'a Expr, &'a Expr)> {
+    match selection {
+        Expr::Nested(inner) => primary_key_range(inner, column),
+        Expr::Between { expr, negated: false, low, high } => match &**expr {
+            Expr::Identifier(ident) if ident.value == column && is_constant(low) && is_constant(high) => {
+                Some((&**low, &**high))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `expr` evaluates the same for every row: it references no
+/// columns and runs no subqueries.
+fn is_constant(expr: &Expr) -> bool {
+    visit_expressions(expr, |e| match e {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) | Expr::Subquery(_) | Expr::InSubquery { .. } => {
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    })
+    .is_continue()
+}
+
+/// The query with every literal replaced by `?`, so queries differing only
+/// in their values share a cache entry.
+pub fn normalize(query: &Query) -> String {
+    let mut query = query.clone();
+    let _ = visit_expressions_mut(&mut query, |expr| {
+        if let Expr::Value(value) = expr {
+            *value = Value::Placeholder("?".to_string());
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    query.to_string()
+}
+
+/// Plans a `PlanCache` holds by default before evicting.
+pub const DEFAULT_PLAN_CACHE_CAPACITY: usize = 1024;
+
+/// Plans keyed by resolved table and normalized statement shape. Entries
+/// for a table are dropped whenever its schema changes, and once the cache
+/// is full, inserting a plan evicts the least recently used one.
+#[derive(Debug)]
+pub struct PlanCache {
+    /// Each plan, with the tick it was last used at.
+    plans: HashMap<(String, String), (Plan, u64)>,
+    /// The keys of `plans` by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, (String, String)>,
+    tick: u64,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for PlanCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_PLAN_CACHE_CAPACITY)
+    }
+}
+
+impl PlanCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache holding at most `capacity` plans; 0 caches none.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { plans: HashMap::new(), recency: BTreeMap::new(), tick: 0, capacity, hits: 0, misses: 0 }
+    }
+
+    pub fn get(&mut self, table: &str, shape: &str) -> Option<Plan> {
+        let key = (table.to_string(), shape.to_string());
+        match self.plans.get_mut(&key) {
+            Some((plan, used)) => {
+                self.hits += 1;
+                self.recency.remove(used);
+                self.tick += 1;
+                *used = self.tick;
+                self.recency.insert(self.tick, key);
+                Some(plan.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, shape: String, plan: Plan) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (plan.table.clone(), shape);
+        match self.plans.get(&key) {
+            Some((_, used)) => {
+                self.recency.remove(used);
+            }
+            None if self.plans.len() >= self.capacity => {
+                if let Some((_, oldest)) = self.recency.pop_first() {
+                    self.plans.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.plans.insert(key, (plan, self.tick));
+    }
+
+    pub fn invalidate_table(&mut self, table: &str) {
+        self.plans.retain(|(t, _), _| t != table);
+        self.recency.retain(|_, (t, _)| t != table);
+    }
+
+    pub fn len(&self) -> usize {
+        self.plans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plans.is_empty()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn plans(&self) -> impl Iterator<Item = &Plan> {
+        self.plans.values().map(|(plan, _)| plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::ast::{SetExpr, Statement};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn query(sql: &str) -> Query {
+        match Parser::parse_sql(&GenericDialect {}, sql).unwrap().remove(0) {
+            Statement::Query(query) => *query,
+            other => panic!("not a query: {}", other),
+        }
+    }
+
+    fn selection(query: &Query) -> Option<&Expr> {
+        match &*query.body {
+            SetExpr::Select(select) => select.selection.as_ref(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_normalize_ignores_literals() {
+        let a = normalize(&query("SELECT name FROM users WHERE id = 1 LIMIT 5"));
+        let b = normalize(&query("SELECT name FROM users WHERE id = 42 LIMIT 10"));
+        assert_eq!(a, b);
+        assert_ne!(a, normalize(&query("SELECT name FROM users WHERE age = 1 LIMIT 5")));
+    }
+
+    #[test]
+    fn test_primary_key_value() {
+        let q = query("SELECT * FROM t WHERE name = 'x' AND (1 + 1 = id)");
+        assert_eq!(primary_key_value(selection(&q).unwrap(), "id").unwrap().to_string(), "1 + 1");
+
+        for sql in ["SELECT * FROM t WHERE id = other", "SELECT * FROM t WHERE id = 1 OR id = 2", "SELECT * FROM t WHERE id > 1"] {
+            let q = query(sql);
+            assert!(primary_key_value(selection(&q).unwrap(), "id").is_none(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_primary_key_values() {
+        let q = query("SELECT * FROM t WHERE name = 'x' AND id IN (3, 1 + 1)");
+        let values = primary_key_values(selection(&q).unwrap(), "id").unwrap();
+        assert_eq!(values.iter().map(|v| v.to_string()).collect::<Vec<_>>(), vec!["3", "1 + 1"]);
+        let q = query("SELECT * FROM t WHERE id = 7");
+        assert_eq!(primary_key_values(selection(&q).unwrap(), "id").unwrap().len(), 1);
+
+        for sql in [
+            "SELECT * FROM t WHERE id NOT IN (1, 2)",
+            "SELECT * FROM t WHERE id IN (1, other)",
+            "SELECT * FROM t WHERE id IN (1) OR id = 2",
+        ] {
+            let q = query(sql);
+            assert!(primary_key_values(selection(&q).unwrap(), "id").is_none(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_primary_key_range() {
+        let q = query("SELECT COUNT(*) FROM t WHERE (id BETWEEN 1 AND 2 * 5)");
+        let (low, high) = primary_key_range(selection(&q).unwrap(), "id").unwrap();
+        assert_eq!((low.to_string(), high.to_string()), ("1".to_string(), "2 * 5".to_string()));
+
+        for sql in [
+            "SELECT * FROM t WHERE id NOT BETWEEN 1 AND 2",
+            "SELECT * FROM t WHERE id BETWEEN 1 AND other",
+            "SELECT * FROM t WHERE name BETWEEN 'a' AND 'b'",
+            "SELECT * FROM t WHERE id BETWEEN 1 AND 2 AND name = 'x'",
+        ] {
+            let q = query(sql);
+            assert!(primary_key_range(selection(&q).unwrap(), "id").is_none(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_plan_cache_evicts_least_recently_used() {
+        let plan = |table: &str| Plan { table: table.to_string(), access: AccessPath::FullScan };
+        let mut cache = PlanCache::with_capacity(2);
+        cache.insert("a".to_string(), plan("t"));
+        cache.insert("b".to_string(), plan("t"));
+        assert!(cache.get("t", "a").is_some());
+        cache.insert("c".to_string(), plan("u"));
+
+        // "b" was used least recently
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("t", "b").is_none());
+        assert!(cache.get("t", "a").is_some());
+
+        cache.invalidate_table("t");
+        assert_eq!(cache.len(), 1);
+        cache.insert("d".to_string(), plan("u"));
+        cache.insert("e".to_string(), plan("u"));
+        assert!(cache.get("u", "c").is_none());
+        assert_eq!(cache.len(), 2);
+    }
+}
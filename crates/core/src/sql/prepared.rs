@@ -0,0 +1,403 @@
+use crate::sql::engine::{Column, ExecutionResult, SqlDataType, SqlEngine, SqlValue, TableSchema};
+use crate::sql::row::fits_type;
+use crate::sql::session::Session;
+use crate::sql::{ddl, expr};
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{
+    visit_expressions, visit_expressions_mut, BinaryOperator, Expr, Ident, ObjectName, SetExpr, Statement,
+    TableFactor, TableWithJoins, UnaryOperator, Value,
+};
+use std::ops::ControlFlow;
+
+/// A statement parsed once by `SqlEngine::prepare`, then run with
+/// `execute` for each set of parameter values. Values are bound into the
+/// parsed statement, never spliced into SQL text.
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    engine: SqlEngine,
+    parameterized: Parameterized,
+}
+
+impl PreparedStatement {
+    pub(crate) fn new(engine: SqlEngine, parameterized: Parameterized) -> Self {
+        Self { engine, parameterized }
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.parameterized.parameter_count()
+    }
+
+    /// Runs the statement with `params` as `$1`, `$2`, ... (or its `?`s,
+    /// in order), in a fresh session.
+    pub async fn execute(&self, params: &[SqlValue]) -> Result<ExecutionResult> {
+        self.execute_in(&mut Session::new(), params).await
+    }
+
+    pub async fn execute_in(&self, session: &mut Session, params: &[SqlValue]) -> Result<ExecutionResult> {
+        let statement = self.parameterized.bind(params)?;
+        self.engine.execute_statement_in(session, &statement).await
+    }
+}
+
+/// A statement with its placeholders numbered, and for each parameter the
+/// column whose type it must have, when the statement makes that plain:
+/// what a `PreparedStatement` and a statement SQL `PREPARE` names both
+/// keep, so binding checks them alike.
+#[derive(Debug, Clone)]
+pub(crate) struct Parameterized {
+    statement: Statement,
+    columns: Vec<Option<Column>>,
+}
+
+impl Parameterized {
+    pub(crate) fn new(statement: Statement, columns: Vec<Option<Column>>) -> Self {
+        Self { statement, columns }
+    }
+
+    pub(crate) fn parameter_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// The statement with `params` in place of its placeholders, each
+    /// checked against its column's type first.
+    pub(crate) fn bind(&self, params: &[SqlValue]) -> Result<Statement> {
+        if params.len() != self.columns.len() {
+            return Err(anyhow!("Expected {} parameter(s), got {}", self.columns.len(), params.len()));
+        }
+        let mut values = Vec::with_capacity(params.len());
+        for (index, (param, column)) in params.iter().zip(&self.columns).enumerate() {
+            if let Some(column) = column.as_ref().filter(|column| !accepts(&column.data_type, param)) {
+                return Err(anyhow!(
+                    "Parameter ${} is {} but column '{}' is {}",
+                    index + 1,
+                    expr::type_name(param),
+                    column.name,
+                    ddl::type_sql(&column.data_type)
+                ));
+            }
+            values.push(literal(param).map_err(|e| anyhow!("Parameter ${}: {}", index + 1, e))?);
+        }
+        let mut statement = self.statement.clone();
+        bind_parameters(&mut statement, &values)?;
+        Ok(statement)
+    }
+}
+
+/// Whether a parameter can stand for a value of a `data_type` column: one
+/// of its type, NULL, an integer for a decimal, or a string that reads as
+/// a timestamp for a timestamp.
+fn accepts(data_type: &SqlDataType, value: &SqlValue) -> bool {
+    match (data_type, value) {
+        (SqlDataType::Decimal(..), SqlValue::Integer(_)) => true,
+        (SqlDataType::Timestamp, SqlValue::Varchar(s)) => expr::parse_timestamp(s).is_some(),
+        _ => fits_type(value, data_type),
+    }
+}
+
+/// The literal that evaluates back to `value`.
+fn literal(value: &SqlValue) -> Result<Value> {
+    Ok(match value {
+        SqlValue::Integer(i) => Value::Number(i.to_string(), false),
+        // Debug keeps the decimal point, so 2.0 doesn't read back as an integer
+        SqlValue::Decimal(d) if d.is_finite() => Value::Number(format!("{:?}", d), false),
+        SqlValue::Decimal(d) => return Err(anyhow!("{} can't be written as a literal", d)),
+        SqlValue::Varchar(s) => Value::SingleQuotedString(s.clone()),
+        SqlValue::Boolean(b) => Value::Boolean(*b),
+        SqlValue::Timestamp(t) => Value::SingleQuotedString(t.to_rfc3339()),
+        SqlValue::Null => Value::Null,
+    })
+}
+
+/// Number of parameters a statement expects: the highest `$n` referenced,
+/// or the number of `?` placeholders, whichever is larger.
+pub fn count_parameters(statement: &Statement) -> usize {
+    let mut positional = 0;
+    let mut numbered = 0;
+    let _ = visit_expressions(statement, |expr| {
+        if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+            match placeholder_number(placeholder) {
+                Some(n) => numbered = numbered.max(n),
+                None => positional += 1,
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    positional.max(numbered)
+}
+
+/// Replaces the `$n` and `?` placeholders in `statement` with `params`.
+/// `$n` refers to the n-th parameter; each `?` takes the next one in order.
+pub fn bind_parameters(statement: &mut Statement, params: &[Value]) -> Result<()> {
+    let expected = count_parameters(statement);
+    if params.len() != expected {
+        return Err(anyhow!("Expected {} parameter(s), got {}", expected, params.len()));
+    }
+
+    let mut next_positional = 0;
+    let flow = visit_expressions_mut(statement, |expr| {
+        if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+            let index = match placeholder_number(placeholder) {
+                Some(n) => n - 1,
+                None => {
+                    next_positional += 1;
+                    next_positional - 1
+                }
+            };
+            match params.get(index) {
+                Some(value) => *expr = Expr::Value(value.clone()),
+                None => return ControlFlow::Break(anyhow!("No value bound for placeholder {}", placeholder)),
+            }
+        }
+        ControlFlow::Continue(())
+    });
+
+    match flow {
+        ControlFlow::Break(e) => Err(e),
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+/// Rewrites each `?` as the `$n` it binds to under `bind_parameters`, so
+/// every parameter is known by its number.
+pub fn number_placeholders(statement: &mut Statement) {
+    let mut next_positional = 0;
+    let _ = visit_expressions_mut(statement, |expr| {
+        if let Expr::Value(Value::Placeholder(placeholder)) = expr {
+            if placeholder_number(placeholder).is_none() {
+                next_positional += 1;
+                *placeholder = format!("${}", next_positional);
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+}
+
+/// The tables a statement's parameters may refer to the columns of, with
+/// any alias: the one an INSERT writes first, then those a DELETE or
+/// SELECT reads, joined ones included.
+pub fn statement_tables(statement: &Statement) -> Vec<(&ObjectName, Option<&Ident>)> {
+    let from: &[TableWithJoins] = match statement {
+        Statement::Insert { table_name, .. } => return vec![(table_name, None)],
+        Statement::Delete { from, .. } => from,
+        Statement::Query(query) => match query.body.as_ref() {
+            SetExpr::Select(select) => &select.from,
+            _ => &[],
+        },
+        _ => &[],
+    };
+    from.iter()
+        .flat_map(|table| std::iter::once(&table.relation).chain(table.joins.iter().map(|join| &join.relation)))
+        .filter_map(|relation| match relation {
+            TableFactor::Table { name, alias, .. } => Some((name, alias.as_ref().map(|alias| &alias.name))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The column `reference`, as `parameter_columns` gives it, names among
+/// `tables`, each the names a statement refers to a table by and its
+/// schema: a qualified reference is looked up in the table its qualifier
+/// names, an unqualified one in the only table with such a column. `None`
+/// when that doesn't settle it.
+pub fn resolve_column<'a>(reference: &str, tables: &'a [(Vec<String>, TableSchema)]) -> Option<&'a Column> {
+    let find = |schema: &'a TableSchema, name: &str| schema.columns.iter().find(|c| c.name == name);
+    match reference.rsplit_once('.') {
+        Some((qualifier, name)) => {
+            let (_, schema) = tables.iter().find(|(names, _)| names.iter().any(|n| n == qualifier))?;
+            find(schema, name)
+        }
+        None => {
+            let mut found = tables.iter().filter_map(|(_, schema)| find(schema, reference));
+            match (found.next(), found.next()) {
+                (Some(column), None) => Some(column),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// For each parameter `$n` of a statement numbered by
+/// `number_placeholders`, at index n - 1, the column it stands for a value
+/// of, qualified as the statement writes it: the one an INSERT puts it in
+/// (`ordinary_columns` being those an INSERT without a column list fills),
+/// or one it is compared with, tested BETWEEN or listed IN against.
+pub fn parameter_columns(statement: &Statement, ordinary_columns: &[String]) -> Vec<Option<String>> {
+    let mut columns = vec![None; count_parameters(statement)];
+    let mut found = |expr: &Expr, column: &str| {
+        if let Some(slot) = parameter_index(expr).and_then(|index| columns.get_mut(index)) {
+            slot.get_or_insert_with(|| column.to_string());
+        }
+    };
+
+    if let Statement::Insert { columns: named, source, .. } = statement {
+        if let SetExpr::Values(values) = source.body.as_ref() {
+            let names: Vec<&str> = match named.is_empty() {
+                true => ordinary_columns.iter().map(String::as_str).collect(),
+                false => named.iter().map(|ident| ident.value.as_str()).collect(),
+            };
+            for row in &values.rows {
+                for (expr, name) in row.iter().zip(&names) {
+                    found(expr, name);
+                }
+            }
+        }
+    }
+    let _ = visit_expressions(statement, |expr| {
+        match expr {
+            Expr::BinaryOp { left, op, right } if is_comparison(op) => {
+                if let Some(column) = column_name(left) {
+                    found(right, &column);
+                }
+                if let Some(column) = column_name(right) {
+                    found(left, &column);
+                }
+            }
+            Expr::Between { expr, low, high, .. } => {
+                if let Some(column) = column_name(expr) {
+                    found(low, &column);
+                    found(high, &column);
+                }
+            }
+            Expr::InList { expr, list, .. } => {
+                if let Some(column) = column_name(expr) {
+                    list.iter().for_each(|item| found(item, &column));
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    columns
+}
+
+fn is_comparison(op: &BinaryOperator) -> bool {
+    matches!(
+        op,
+        BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq
+    )
+}
+
+fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(ident.value.clone()),
+        Expr::CompoundIdentifier(idents) => Some(expr::qualified_name(idents)),
+        _ => None,
+    }
+}
+
+/// Zero-based index of the `$n` parameter `expr` is, if it is one.
+fn parameter_index(expr: &Expr) -> Option<usize> {
+    match expr {
+        Expr::Value(Value::Placeholder(placeholder)) => placeholder_number(placeholder).map(|n| n - 1),
+        _ => None,
+    }
+}
+
+/// Converts an `EXECUTE` argument into a literal value. Only literals (and
+/// negated numbers) are accepted, so arguments can never inject SQL.
+pub fn parameter_value(expr: &Expr) -> Result<Value> {
+    match expr {
+        Expr::Value(Value::Placeholder(_)) => Err(anyhow!("Parameters must be literal values")),
+        Expr::Value(value) => Ok(value.clone()),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => match expr.as_ref() {
+            Expr::Value(Value::Number(n, long)) => Ok(Value::Number(format!("-{}", n), *long)),
+            _ => Err(anyhow!("Parameters must be literal values")),
+        },
+        _ => Err(anyhow!("Parameters must be literal values")),
+    }
+}
+
+fn placeholder_number(placeholder: &str) -> Option<usize> {
+    placeholder.strip_prefix('$').and_then(|n| n.parse().ok()).filter(|n| *n > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn parse(sql: &str) -> Statement {
+        Parser::parse_sql(&GenericDialect {}, sql).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_bind_numbered_and_positional() {
+        let mut statement = parse("SELECT * FROM users WHERE id = $2 AND name = $1");
+        assert_eq!(count_parameters(&statement), 2);
+        bind_parameters(
+            &mut statement,
+            &[Value::SingleQuotedString("Alice".into()), Value::Number("7".into(), false)],
+        )
+        .unwrap();
+        assert_eq!(statement.to_string(), "SELECT * FROM users WHERE id = 7 AND name = 'Alice'");
+
+        let mut statement = parse("INSERT INTO users (id, name) VALUES (?, ?)");
+        bind_parameters(
+            &mut statement,
+            &[Value::Number("1".into(), false), Value::SingleQuotedString("Bob".into())],
+        )
+        .unwrap();
+        assert_eq!(statement.to_string(), "INSERT INTO users (id, name) VALUES (1, 'Bob')");
+    }
+
+    #[test]
+    fn test_parameter_columns() {
+        let mut statement = parse("INSERT INTO users VALUES (?, ?), (?, 'x')");
+        number_placeholders(&mut statement);
+        assert_eq!(statement.to_string(), "INSERT INTO users VALUES ($1, $2), ($3, 'x')");
+        let ordinary = ["id".to_string(), "name".to_string()];
+        let columns = parameter_columns(&statement, &ordinary);
+        assert_eq!(columns, [Some("id".to_string()), Some("name".to_string()), Some("id".to_string())]);
+
+        let statement = parse("SELECT * FROM users u WHERE $1 < u.age AND name IN ($2, 'x') AND id BETWEEN $3 AND $4");
+        let tables = statement_tables(&statement);
+        assert_eq!(tables.len(), 1);
+        assert_eq!((tables[0].0.to_string(), tables[0].1.unwrap().value.as_str()), ("users".to_string(), "u"));
+        let columns: Vec<_> = parameter_columns(&statement, &[]).into_iter().map(Option::unwrap).collect();
+        assert_eq!(columns, ["u.age", "name", "id", "id"]);
+    }
+
+    #[test]
+    fn test_resolve_column() {
+        let column = |name: &str, data_type: SqlDataType| Column {
+            name: name.to_string(),
+            data_type,
+            nullable: true,
+            primary_key: false,
+            auto_increment: false,
+            generated: None,
+            generated_expr: None,
+            default: None,
+            added_in: 1,
+            not_null: false,
+        };
+        let schema =
+            |name: &str, columns| TableSchema { name: name.to_string(), columns, version: 1, shard_count: 1, id: 0 };
+        let users = schema("users", vec![column("id", SqlDataType::Integer), column("name", SqlDataType::Varchar(20))]);
+        let teams = schema("teams", vec![column("id", SqlDataType::Integer), column("rank", SqlDataType::Boolean)]);
+        let tables = [(vec!["u".to_string()], users), (vec!["teams".to_string()], teams)];
+        assert!(matches!(resolve_column("u.name", &tables).unwrap().data_type, SqlDataType::Varchar(20)));
+        assert!(matches!(resolve_column("teams.id", &tables).unwrap().data_type, SqlDataType::Integer));
+        assert_eq!(resolve_column("rank", &tables).unwrap().name, "rank");
+        // Ambiguous, or in no table named so
+        assert!(resolve_column("id", &tables).is_none());
+        assert!(resolve_column("users.name", &tables).is_none());
+        assert!(resolve_column("u.rank", &tables).is_none());
+
+        let mut statement = parse("SELECT UPPER(?) FROM users");
+        number_placeholders(&mut statement);
+        assert_eq!(parameter_columns(&statement, &[]), [None]);
+    }
+
+    #[test]
+    fn test_bind_wrong_arity() {
+        let mut statement = parse("SELECT * FROM users WHERE id = $1");
+        assert!(bind_parameters(&mut statement, &[]).is_err());
+    }
+}
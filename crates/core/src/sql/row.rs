@@ -0,0 +1,304 @@
+use crate::sql::engine::{Column, SqlDataType, SqlValue, TableSchema};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One row's values by column name. A column with no value reads as NULL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Row {
+    /// Prefer `get` and `set`; the map stays public for existing callers.
+    pub values: HashMap<String, SqlValue>,
+    /// `TableSchema::version` the row was written under; 0 for rows that
+    /// aren't stored.
+    pub schema_version: u32,
+}
+
+impl Row {
+    /// An empty row, belonging to no stored table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a row of `schema`'s table, checking each value as it is set.
+    pub fn builder(schema: &TableSchema) -> RowBuilder<'_> {
+        RowBuilder { schema, row: Row { values: HashMap::new(), schema_version: schema.version } }
+    }
+
+    pub fn get(&self, column: &str) -> Option<&SqlValue> {
+        self.values.get(column)
+    }
+
+    /// Sets `column` to `value`, returning the value it replaces.
+    pub fn set(&mut self, column: impl Into<String>, value: impl Into<SqlValue>) -> Option<SqlValue> {
+        self.values.insert(column.into(), value.into())
+    }
+
+    pub fn remove(&mut self, column: &str) -> Option<SqlValue> {
+        self.values.remove(column)
+    }
+
+    /// The value of `column` as a `T`: `None` if it is missing or NULL, and
+    /// an error if it holds another type.
+    pub fn get_typed<T: FromSqlValue>(&self, column: &str) -> Result<Option<T>> {
+        match self.get(column) {
+            None | Some(SqlValue::Null) => Ok(None),
+            Some(value) => T::from_sql_value(value)
+                .map(Some)
+                .ok_or_else(|| anyhow!("Column '{}' holds {:?}, not {}", column, value, T::TYPE_NAME)),
+        }
+    }
+
+    /// Column names and values, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &SqlValue)> {
+        self.values.iter()
+    }
+}
+
+/// Builds a row of one table. `set` rejects columns the table doesn't
+/// have, generated columns and values of the wrong type; `build` rejects
+/// NOT NULL columns left NULL.
+#[derive(Debug)]
+pub struct RowBuilder<'a> {
+    schema: &'a TableSchema,
+    row: Row,
+}
+
+impl RowBuilder<'_> {
+    pub fn set(mut self, column: &str, value: impl Into<SqlValue>) -> Result<Self> {
+        let value = value.into();
+        let column = self.column(column)?;
+        if column.generated.is_some() {
+            return Err(anyhow!("Cannot write to generated column '{}'", column.name));
+        }
+        if !fits_type(&value, &column.data_type) {
+            return Err(anyhow!("Value {:?} doesn't fit {:?} column '{}'", value, column.data_type, column.name));
+        }
+        let name = column.name.clone();
+        self.row.set(name, value);
+        Ok(self)
+    }
+
+    /// The row so far, for the engine to fill in auto-increment and
+    /// generated columns before `build`.
+    pub(crate) fn row_mut(&mut self) -> &mut Row {
+        &mut self.row
+    }
+
+    pub fn build(self) -> Result<Row> {
+        for column in self.schema.columns.iter().filter(|c| !c.accepts_null()) {
+            match self.row.get(&column.name) {
+                None | Some(SqlValue::Null) if column.primary_key => {
+                    return Err(anyhow!("Primary key column '{}' cannot be NULL", column.name));
+                }
+                Some(SqlValue::Null) => return Err(anyhow!("Column '{}' cannot be NULL", column.name)),
+                // The builder doesn't apply DEFAULTs; the engine sets them for
+                // columns an INSERT leaves out, so a NOT NULL column still
+                // missing here had none
+                None => return Err(anyhow!("Column '{}' is NOT NULL and must be given a value", column.name)),
+                Some(_) => {}
+            }
+        }
+        Ok(self.row)
+    }
+
+    fn column(&self, name: &str) -> Result<&Column> {
+        self.schema
+            .columns
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| anyhow!("Column '{}' does not exist", name))
+    }
+}
+
+/// Whether `value` can be stored in a column of `data_type`. NULL fits
+/// any column; nullability is checked separately.
+pub(crate) fn fits_type(value: &SqlValue, data_type: &SqlDataType) -> bool {
+    matches!(
+        (value, data_type),
+        (SqlValue::Null, _)
+            | (SqlValue::Integer(_), SqlDataType::Integer)
+            | (SqlValue::Varchar(_), SqlDataType::Varchar(_))
+            | (SqlValue::Decimal(_), SqlDataType::Decimal(..))
+            | (SqlValue::Boolean(_), SqlDataType::Boolean)
+            | (SqlValue::Timestamp(_), SqlDataType::Timestamp)
+    )
+}
+
+/// Rust types a non-NULL `SqlValue` can be read as with `Row::get_typed`.
+pub trait FromSqlValue: Sized {
+    /// The SQL type named in errors.
+    const TYPE_NAME: &'static str;
+
+    /// `None` if `value` holds another type.
+    fn from_sql_value(value: &SqlValue) -> Option<Self>;
+}
+
+impl FromSqlValue for i64 {
+    const TYPE_NAME: &'static str = "INTEGER";
+
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for String {
+    const TYPE_NAME: &'static str = "VARCHAR";
+
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Varchar(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for f64 {
+    const TYPE_NAME: &'static str = "DECIMAL";
+
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Decimal(d) => Some(*d),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for bool {
+    const TYPE_NAME: &'static str = "BOOLEAN";
+
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for DateTime<Utc> {
+    const TYPE_NAME: &'static str = "TIMESTAMP";
+
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Timestamp(t) => Some(*t),
+            _ => None,
+        }
+    }
+}
+
+impl From<i64> for SqlValue {
+    fn from(n: i64) -> Self {
+        SqlValue::Integer(n)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(s: &str) -> Self {
+        SqlValue::Varchar(s.to_string())
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(s: String) -> Self {
+        SqlValue::Varchar(s)
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(d: f64) -> Self {
+        SqlValue::Decimal(d)
+    }
+}
+
+impl From<bool> for SqlValue {
+    fn from(b: bool) -> Self {
+        SqlValue::Boolean(b)
+    }
+}
+
+impl From<DateTime<Utc>> for SqlValue {
+    fn from(t: DateTime<Utc>) -> Self {
+        SqlValue::Timestamp(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> TableSchema {
+        let column = |name: &str, data_type: SqlDataType, nullable: bool| Column {
+            name: name.to_string(),
+            data_type,
+            nullable,
+            primary_key: name == "id",
+            auto_increment: false,
+            generated: if name == "shout" { Some("UPPER(name)".to_string()) } else { None },
+            generated_expr: None,
+            default: None,
+            added_in: 1,
+            not_null: !nullable,
+        };
+        TableSchema {
+            name: "users".to_string(),
+            columns: vec![
+                column("id", SqlDataType::Integer, false),
+                column("name", SqlDataType::Varchar(20), false),
+                column("score", SqlDataType::Decimal(5, 2), true),
+                column("shout", SqlDataType::Varchar(20), true),
+            ],
+            version: 3,
+            shard_count: 1,
+            id: 1,
+        }
+    }
+
+    #[test]
+    fn test_builder_validates_against_the_schema() {
+        let schema = users();
+        let row = Row::builder(&schema).set("id", 1).unwrap().set("name", "Ada").unwrap().build().unwrap();
+        assert_eq!(row.schema_version, 3);
+        assert!(matches!(row.get("name"), Some(SqlValue::Varchar(name)) if name == "Ada"));
+        assert!(row.get("score").is_none());
+
+        let err = Row::builder(&schema).set("age", 30).unwrap_err();
+        assert_eq!(err.to_string(), "Column 'age' does not exist");
+        let err = Row::builder(&schema).set("shout", "ADA").unwrap_err();
+        assert_eq!(err.to_string(), "Cannot write to generated column 'shout'");
+        let err = Row::builder(&schema).set("id", "one").unwrap_err();
+        assert!(err.to_string().contains("doesn't fit Integer column 'id'"), "{}", err);
+
+        let err = Row::builder(&schema).set("name", "Ada").unwrap().build().unwrap_err();
+        assert_eq!(err.to_string(), "Primary key column 'id' cannot be NULL");
+        let err = Row::builder(&schema).set("id", 1).unwrap().set("name", SqlValue::Null).unwrap().build().unwrap_err();
+        assert_eq!(err.to_string(), "Column 'name' cannot be NULL");
+        let err = Row::builder(&schema).set("id", 1).unwrap().build().unwrap_err();
+        assert_eq!(err.to_string(), "Column 'name' is NOT NULL and must be given a value");
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        let mut row = Row::new();
+        row.set("id", 7);
+        row.set("name", "Grace");
+        row.set("score", 9.5);
+        row.set("active", true);
+        row.set("deleted", SqlValue::Null);
+
+        assert_eq!(row.get_typed::<i64>("id").unwrap(), Some(7));
+        assert_eq!(row.get_typed::<String>("name").unwrap(), Some("Grace".to_string()));
+        assert_eq!(row.get_typed::<f64>("score").unwrap(), Some(9.5));
+        assert_eq!(row.get_typed::<bool>("active").unwrap(), Some(true));
+        assert_eq!(row.get_typed::<bool>("deleted").unwrap(), None);
+        assert_eq!(row.get_typed::<i64>("missing").unwrap(), None);
+        let err = row.get_typed::<i64>("name").unwrap_err();
+        assert_eq!(err.to_string(), "Column 'name' holds Varchar(\"Grace\"), not INTEGER");
+
+        assert!(matches!(row.set("id", 8), Some(SqlValue::Integer(7))));
+        assert!(matches!(row.remove("id"), Some(SqlValue::Integer(8))));
+        assert_eq!(row.iter().count(), 4);
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Database that sessions start in. Its tables keep unqualified names, so
+/// data written before namespaces existed stays readable.
+pub const DEFAULT_DATABASE: &str = "default";
+
+/// What happens when a query without a LIMIT would return more rows than
+/// `ResultLimit::max_rows`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Return the first `max_rows` rows, followed by a warning.
+    #[default]
+    Truncate,
+    /// Fail the query.
+    Error,
+}
+
+/// Cap on the rows a single SELECT may return, guarding against
+/// accidentally fetching a huge table. Queries with an explicit LIMIT are
+/// exempt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResultLimit {
+    pub max_rows: Option<usize>,
+    pub mode: LimitMode,
+}
+
+/// What an INSERT does with a string longer than its `VARCHAR(n)` column.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StringOverflow {
+    /// Reject the statement.
+    #[default]
+    Error,
+    /// Store the first `n` characters.
+    Truncate,
+}
+
+/// What an INSERT does with a number having more decimal places than its
+/// `DECIMAL(p, s)` column's scale `s`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScaleOverflow {
+    /// Round to `s` places, halves away from zero.
+    #[default]
+    Round,
+    /// Reject the statement.
+    Error,
+}
+
+/// How long a statement may run before it is cancelled. `timeout` starts
+/// at the server's default and `SET statement_timeout` changes it, but
+/// never past `ceiling`, which the server fixes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementTimeout {
+    pub timeout: Option<Duration>,
+    pub ceiling: Option<Duration>,
+}
+
+impl StatementTimeout {
+    /// The limit that applies: the timeout, capped at the ceiling.
+    pub fn limit(&self) -> Option<Duration> {
+        match (self.timeout, self.ceiling) {
+            (Some(timeout), Some(ceiling)) => Some(timeout.min(ceiling)),
+            (timeout, None) => timeout,
+            (None, ceiling) => ceiling,
+        }
+    }
+}
+
+/// Asks a running statement to stop, when `KILL` sets it from another
+/// connection or its statement timeout runs out. The engine checks it
+/// between statements and rows, never while writing, so a cancelled
+/// statement has either logged nothing or finished applying all it
+/// logged. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    /// When the statement times out, and the timeout that set it.
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The cancellation, also triggered once `timeout` from now has
+    /// passed; `None` sets no deadline.
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { deadline: timeout.map(|timeout| (Instant::now() + timeout, timeout)), ..self }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Fails once the statement has been cancelled or timed out.
+    pub fn check(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(anyhow!("Query cancelled"));
+        }
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => {
+                Err(anyhow!("Statement timed out after {} ms (statement_timeout)", timeout.as_millis()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Per-connection state that outlives a single statement.
+#[derive(Debug, Clone)]
+pub struct Session {
+    database: String,
+    result_limit: ResultLimit,
+    statement_timeout: StatementTimeout,
+    string_overflow: StringOverflow,
+    scale_overflow: ScaleOverflow,
+    memory_limit: Option<usize>,
+    admin: bool,
+    /// Id of the transaction opened with `BEGIN`, if one is open.
+    transaction: Option<u64>,
+    cancellation: Cancellation,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            database: DEFAULT_DATABASE.to_string(),
+            result_limit: ResultLimit::default(),
+            statement_timeout: StatementTimeout::default(),
+            string_overflow: StringOverflow::default(),
+            scale_overflow: ScaleOverflow::default(),
+            memory_limit: None,
+            admin: false,
+            transaction: None,
+            cancellation: Cancellation::new(),
+        }
+    }
+
+    /// A session starting with `limit`, which `SET max_result_rows` and
+    /// `SET result_limit_mode` can then override.
+    pub fn with_result_limit(limit: ResultLimit) -> Self {
+        Self { result_limit: limit, ..Self::new() }
+    }
+
+    pub fn result_limit(&self) -> ResultLimit {
+        self.result_limit
+    }
+
+    pub(crate) fn set_result_limit(&mut self, limit: ResultLimit) {
+        self.result_limit = limit;
+    }
+
+    /// The session with `timeout`, whose `timeout` field
+    /// `SET statement_timeout` can then override up to its ceiling.
+    pub fn with_statement_timeout(self, timeout: StatementTimeout) -> Self {
+        Self { statement_timeout: timeout, ..self }
+    }
+
+    pub fn statement_timeout(&self) -> StatementTimeout {
+        self.statement_timeout
+    }
+
+    pub(crate) fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.statement_timeout.timeout = timeout;
+    }
+
+    /// The session with the rows each query buffers limited to `bytes` of
+    /// memory, see `MemoryBudget`; `None` leaves them unlimited.
+    pub fn with_memory_limit(self, bytes: Option<usize>) -> Self {
+        Self { memory_limit: bytes, ..self }
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// The session, allowed to run admin commands or not. There are no
+    /// user accounts, so whoever creates the session decides.
+    pub fn with_admin(self, admin: bool) -> Self {
+        Self { admin, ..self }
+    }
+
+    /// Whether admin commands such as `SHOW TREE STRUCTURE` may run.
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// The transaction the session's writes are buffered in, from `BEGIN`
+    /// until `COMMIT` or `ROLLBACK`.
+    pub fn transaction(&self) -> Option<u64> {
+        self.transaction
+    }
+
+    pub(crate) fn set_transaction(&mut self, transaction: Option<u64>) {
+        self.transaction = transaction;
+    }
+
+    /// The session, with its statements stopped once `cancellation` is.
+    pub fn with_cancellation(self, cancellation: Cancellation) -> Self {
+        Self { cancellation, ..self }
+    }
+
+    /// Fails once the running statement has been cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        self.cancellation.check()
+    }
+
+    /// Set with `SET string_overflow = 'error' | 'truncate'`.
+    pub fn string_overflow(&self) -> StringOverflow {
+        self.string_overflow
+    }
+
+    pub(crate) fn set_string_overflow(&mut self, overflow: StringOverflow) {
+        self.string_overflow = overflow;
+    }
+
+    /// Set with `SET scale_overflow = 'round' | 'error'`.
+    pub fn scale_overflow(&self) -> ScaleOverflow {
+        self.scale_overflow
+    }
+
+    pub(crate) fn set_scale_overflow(&mut self, overflow: ScaleOverflow) {
+        self.scale_overflow = overflow;
+    }
+
+    /// The database unqualified table names resolve in.
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    pub(crate) fn set_database(&mut self, database: String) {
+        self.database = database;
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
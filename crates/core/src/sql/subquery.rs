@@ -0,0 +1,155 @@
+use crate::sql::engine::SqlValue;
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{Expr, Query, Value, Visit, VisitMut, Visitor, VisitorMut};
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
+
+/// How a subquery's result is used by the expression containing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubqueryKind {
+    /// `(SELECT ...)` used as a value: one column, at most one row.
+    Scalar,
+    /// `x [NOT] IN (SELECT ...)`: one column, any number of rows.
+    In,
+}
+
+/// Returns the subqueries in `expr` in visiting order, skipping any nested
+/// inside another subquery; running the outer query resolves those.
+pub fn collect(expr: &Expr) -> Vec<(SubqueryKind, Query)> {
+    let mut collector = Collector { depth: 0, found: Vec::new() };
+    let _ = Visit::visit(expr, &mut collector);
+    collector.found
+}
+
+/// Turns the rows a subquery produced into the literal expression that
+/// replaces it. Only uncorrelated subqueries are supported, so the result
+/// is the same for every outer row.
+pub fn materialize(kind: SubqueryKind, columns: usize, rows: Vec<Vec<SqlValue>>) -> Result<Vec<Expr>> {
+    if columns != 1 {
+        return Err(anyhow!("Subquery must return exactly one column, got {}", columns));
+    }
+    let mut values = rows.into_iter().map(|mut row| literal(row.remove(0)));
+    match kind {
+        SubqueryKind::Scalar if values.len() > 1 => Err(anyhow!("Scalar subquery returned more than one row")),
+        // No rows reads as NULL
+        SubqueryKind::Scalar => Ok(vec![values.next().unwrap_or(Expr::Value(Value::Null))]),
+        SubqueryKind::In => Ok(values.collect()),
+    }
+}
+
+/// Replaces the subqueries `collect` returned with their materialized
+/// results, given in the same order.
+pub fn substitute(expr: &mut Expr, results: Vec<Vec<Expr>>) {
+    let _ = VisitMut::visit(expr, &mut Substituter { results: results.into() });
+}
+
+fn literal(value: SqlValue) -> Expr {
+    Expr::Value(match value {
+        SqlValue::Integer(i) => Value::Number(i.to_string(), false),
+        SqlValue::Decimal(d) => Value::Number(format!("{:?}", d), false),
+        SqlValue::Varchar(s) => Value::SingleQuotedString(s),
+        SqlValue::Boolean(b) => Value::Boolean(b),
+        SqlValue::Timestamp(t) => Value::SingleQuotedString(t.to_rfc3339()),
+        SqlValue::Null => Value::Null,
+    })
+}
+
+struct Collector {
+    depth: usize,
+    found: Vec<(SubqueryKind, Query)>,
+}
+
+impl Visitor for Collector {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Subquery(query) => {
+                if self.depth == 0 {
+                    self.found.push((SubqueryKind::Scalar, (**query).clone()));
+                }
+                self.depth += 1;
+            }
+            Expr::InSubquery { expr: needle, subquery, .. } => {
+                if self.depth == 0 {
+                    self.found.push((SubqueryKind::In, (**subquery).clone()));
+                    // The left-hand side stays in the rewritten expression,
+                    // so its subqueries are outermost too
+                    self.found.extend(collect(needle));
+                }
+                self.depth += 1;
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if matches!(expr, Expr::Subquery(_) | Expr::InSubquery { .. }) {
+            self.depth -= 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Replaces subqueries before their children are visited, so nested
+/// subqueries disappear with their parent rather than being matched
+/// against the wrong result.
+struct Substituter {
+    results: VecDeque<Vec<Expr>>,
+}
+
+impl VisitorMut for Substituter {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Subquery(_) => {
+                if let Some(mut result) = self.results.pop_front() {
+                    *expr = result.remove(0);
+                }
+            }
+            Expr::InSubquery { expr: needle, negated, .. } => {
+                if let Some(list) = self.results.pop_front() {
+                    *expr = Expr::InList { expr: needle.clone(), list, negated: *negated };
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn parse(sql: &str) -> Expr {
+        Parser::new(&GenericDialect {}).try_with_sql(sql).unwrap().parse_expr().unwrap()
+    }
+
+    #[test]
+    fn test_collect_and_substitute_outermost_subqueries() {
+        let mut expr = parse("(SELECT 7) IN (SELECT x FROM t WHERE x > (SELECT 1)) AND (SELECT y FROM u) < b");
+        let found = collect(&expr);
+        let kinds: Vec<SubqueryKind> = found.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![SubqueryKind::In, SubqueryKind::Scalar, SubqueryKind::Scalar]);
+        assert_eq!(found[1].1.to_string(), "SELECT 7");
+
+        let in_list = materialize(SubqueryKind::In, 1, vec![vec![SqlValue::Integer(1)], vec![SqlValue::Integer(2)]]).unwrap();
+        let seven = materialize(SubqueryKind::Scalar, 1, vec![vec![SqlValue::Integer(7)]]).unwrap();
+        let scalar = materialize(SubqueryKind::Scalar, 1, vec![vec![SqlValue::Decimal(2.5)]]).unwrap();
+        substitute(&mut expr, vec![in_list, seven, scalar]);
+        assert_eq!(expr.to_string(), "7 IN (1, 2) AND 2.5 < b");
+    }
+
+    #[test]
+    fn test_scalar_subquery_shape() {
+        let two_rows = vec![vec![SqlValue::Integer(1)], vec![SqlValue::Integer(2)]];
+        assert!(materialize(SubqueryKind::Scalar, 1, two_rows).is_err());
+        assert!(materialize(SubqueryKind::Scalar, 2, vec![]).is_err());
+        assert_eq!(materialize(SubqueryKind::Scalar, 1, vec![]).unwrap(), vec![Expr::Value(Value::Null)]);
+    }
+}
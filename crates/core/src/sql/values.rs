@@ -0,0 +1,112 @@
+use crate::sql::engine::{Column, Row, SqlDataType, SqlValue, TableSchema};
+use crate::sql::expr;
+use crate::sql::functions::FunctionRegistry;
+use anyhow::{anyhow, Result};
+use sqlparser::ast::{Query, SetExpr, TableAlias};
+
+/// Name of an inline table given without an alias.
+const UNALIASED: &str = "values";
+
+/// The inline table `(VALUES (1, 'a'), (2, 'b')) AS t(id, name)` in a FROM
+/// clause, as a schema and its rows. Columns are named by the alias's
+/// column list, and any it leaves out `column1`, `column2` and so on, as in
+/// PostgreSQL. Each value is a constant expression, and a column's type is
+/// that of its non-NULL values.
+pub fn table(
+    subquery: &Query,
+    alias: Option<&TableAlias>,
+    functions: &FunctionRegistry,
+) -> Result<(TableSchema, Vec<Row>)> {
+    let plain = subquery.order_by.is_empty() && subquery.limit.is_none() && subquery.offset.is_none();
+    let values = match &*subquery.body {
+        SetExpr::Values(values) if plain => values,
+        _ => return Err(anyhow!("Unsupported subquery in FROM; only a VALUES list is supported")),
+    };
+    let width = values.rows.first().map_or(0, Vec::len);
+    if let Some(row) = values.rows.iter().find(|row| row.len() != width) {
+        return Err(anyhow!("VALUES lists must all be the same length, got {} and {}", width, row.len()));
+    }
+
+    let given = alias.map_or(&[][..], |alias| &alias.columns);
+    if given.len() > width {
+        return Err(anyhow!("VALUES list has {} columns, but {} names were given", width, given.len()));
+    }
+    let names: Vec<String> = (0..width)
+        .map(|i| given.get(i).map_or_else(|| format!("column{}", i + 1), |name| name.value.clone()))
+        .collect();
+    if let Some((i, name)) = names.iter().enumerate().find(|(i, name)| names[..*i].contains(name)) {
+        return Err(anyhow!("Column name '{}' given more than once (column {})", name, i + 1));
+    }
+
+    let empty = Row::new();
+    let rows = values
+        .rows
+        .iter()
+        .map(|row| row.iter().map(|value| expr::evaluate_with(value, &empty, functions)).collect())
+        .collect::<Result<Vec<Vec<SqlValue>>>>()?;
+    let columns = names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let data_type = column_type(&name, rows.iter().map(|row| &row[i]))?;
+            Ok(Column {
+                name,
+                data_type,
+                nullable: true,
+                primary_key: false,
+                auto_increment: false,
+                generated: None,
+                generated_expr: None,
+                default: None,
+                added_in: 1,
+                not_null: false,
+            })
+        })
+        .collect::<Result<Vec<Column>>>()?;
+
+    let rows = rows
+        .into_iter()
+        .map(|values| {
+            let mut row = Row::new();
+            for (column, value) in columns.iter().zip(values) {
+                row.set(column.name.clone(), value);
+            }
+            row
+        })
+        .collect();
+    let name = alias.map_or(UNALIASED.to_string(), |alias| alias.name.value.clone());
+    Ok((TableSchema { name, columns, version: 1, shard_count: 1, id: 0 }, rows))
+}
+
+/// The type of the column `name` holding `values`. Integers and decimals
+/// together are DECIMAL; any other mix is an error, and a column of NULLs
+/// alone is VARCHAR.
+fn column_type<'a>(name: &str, values: impl Iterator<Item = &'a SqlValue>) -> Result<SqlDataType> {
+    let numeric = |value: &SqlValue| matches!(value, SqlValue::Integer(_) | SqlValue::Decimal(_));
+    let mut first: Option<&SqlValue> = None;
+    let mut decimal = false;
+    for value in values.filter(|value| !matches!(value, SqlValue::Null)) {
+        decimal |= matches!(value, SqlValue::Decimal(_));
+        match first {
+            None => first = Some(value),
+            Some(first) if numeric(first) && numeric(value) => {}
+            Some(first) if expr::type_name(first) == expr::type_name(value) => {}
+            Some(first) => {
+                return Err(anyhow!(
+                    "VALUES column '{}' mixes {} and {}",
+                    name,
+                    expr::type_name(first),
+                    expr::type_name(value)
+                ))
+            }
+        }
+    }
+    Ok(match first {
+        // The values are kept as given, so the precision is nominal
+        _ if decimal => SqlDataType::Decimal(38, 10),
+        Some(SqlValue::Integer(_)) => SqlDataType::Integer,
+        Some(SqlValue::Boolean(_)) => SqlDataType::Boolean,
+        Some(SqlValue::Timestamp(_)) => SqlDataType::Timestamp,
+        _ => SqlDataType::Varchar(u32::MAX),
+    })
+}
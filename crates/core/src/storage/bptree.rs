@@ -1,13 +1,31 @@
+use crate::storage::codec::RowFormat;
 use crate::txn::wal::WalEntry;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
 use std::path::Path;
 
 const NODE_SIZE: usize = 256;
 
+/// Version of the storage key encoding (see `sql::engine::encode_key_value`)
+/// and of the row value framing (see `storage::codec`). Snapshots record it
+/// so data written with an older encoding is detected on load instead of
+/// being scanned in the wrong order or misread.
+pub const KEY_FORMAT_VERSION: u32 = 4;
+
+/// The last key format that prefixed row keys with the table name rather
+/// than its id. Such snapshots still load, and are converted by
+/// `migrate_to_table_ids` once the catalog is known.
+pub const NAME_PREFIXED_KEY_FORMAT: u32 = 3;
+
+/// Mutations after which a checkpoint is due, unless configured otherwise.
+pub const DEFAULT_CHECKPOINT_THRESHOLD: usize = 1000;
+
+/// Values larger than this many bytes are stored out-of-line by default.
+pub const DEFAULT_OVERFLOW_THRESHOLD: usize = 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BPlusTree {
     root: Option<NodeId>,
@@ -15,19 +33,82 @@ pub struct BPlusTree {
     next_node_id: NodeId,
     leaf_head: Option<NodeId>,
     operation_count: usize,
+    /// Sequence number of the last WAL entry reflected in this tree.
+    applied_seq: u64,
+    key_format: u32,
+    /// Out-of-line storage for large values, referenced from leaves by id so
+    /// leaves stay small and scans over keys touch less data.
+    overflow: BTreeMap<OverflowId, Value>,
+    next_overflow_id: OverflowId,
+    overflow_threshold: usize,
 }
 
 type NodeId = u64;
+type OverflowId = u64;
 type Key = String;
 type Value = Vec<u8>;
 
+/// Whether an insert added a key, and the split it caused, if any.
+type Insertion = (bool, Option<(Key, NodeId)>);
+
+thread_local! {
+    static NODE_VISITS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+thread_local! {
+    static VALUE_COPIES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Counts a node read by a lookup or scan, so callers can tell how much a
+/// read touched.
+fn record_node_visit() {
+    NODE_VISITS.with(|visits| visits.set(visits.get() + 1));
+}
+
+/// Number of nodes read by lookups, counts and prefix scans on this
+/// thread. Only differences are meaningful: take one before and after a
+/// read, with no `.await` in between.
+pub fn node_visits() -> usize {
+    NODE_VISITS.with(|visits| visits.get())
+}
+
+/// Number of stored values copied out of trees on this thread.
+#[cfg(test)]
+pub fn value_copies() -> usize {
+    VALUE_COPIES.with(|copies| copies.get())
+}
+
+/// A leaf slot: either the value itself or a pointer into `overflow`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum StoredValue {
+    Inline(Value),
+    Overflow(OverflowId),
+}
+
+/// Size and shape counters for the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub keys: usize,
+    pub inline_values: usize,
+    pub overflow_values: usize,
+    pub overflow_bytes: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Node {
     id: NodeId,
     is_leaf: bool,
     keys: Vec<Key>,
-    values: Vec<Value>,
+    values: Vec<StoredValue>,
     children: Vec<NodeId>,
+    /// Keys in each child's subtree, parallel to `children`, so a range can
+    /// be counted without visiting its leaves. Rebuilt on load rather than
+    /// stored in snapshots.
+    #[serde(skip)]
+    counts: Vec<usize>,
     next_leaf: Option<NodeId>,
     prev_leaf: Option<NodeId>,
 }
@@ -40,6 +121,7 @@ impl Node {
             keys: Vec::new(),
             values: Vec::new(),
             children: Vec::new(),
+            counts: Vec::new(),
             next_leaf: None,
             prev_leaf: None,
         }
@@ -52,6 +134,24 @@ impl Node {
     fn find_key_index(&self, key: &str) -> usize {
         self.keys.binary_search_by(|k| k.as_str().cmp(key)).unwrap_or_else(|i| i)
     }
+
+    /// Index of the child subtree that may contain `key`. Separator keys are
+    /// copies of the first key in the right subtree, so equal keys go right.
+    fn find_child_index(&self, key: &str) -> usize {
+        self.keys.partition_point(|k| k.as_str() <= key)
+    }
+
+    /// Checks an internal node has a child on either side of each key, and
+    /// a count per child.
+    fn check_internal_shape(&self) -> Result<()> {
+        if self.children.len() != self.keys.len() + 1 {
+            return Err(anyhow!("Internal node {} has {} keys but {} children", self.id, self.keys.len(), self.children.len()));
+        }
+        if self.counts.len() != self.children.len() {
+            return Err(anyhow!("Internal node {} has {} children but {} counts", self.id, self.children.len(), self.counts.len()));
+        }
+        Ok(())
+    }
 }
 
 impl BPlusTree {
@@ -62,10 +162,25 @@ impl BPlusTree {
             next_node_id: 1,
             leaf_head: None,
             operation_count: 0,
+            applied_seq: 0,
+            key_format: KEY_FORMAT_VERSION,
+            overflow: BTreeMap::new(),
+            next_overflow_id: 1,
+            overflow_threshold: DEFAULT_OVERFLOW_THRESHOLD,
+        }
+    }
+
+    /// Creates a tree that stores values larger than `threshold` bytes
+    /// out-of-line.
+    pub fn with_overflow_threshold(threshold: usize) -> Self {
+        Self {
+            overflow_threshold: threshold,
+            ..Self::new()
         }
     }
 
     pub fn insert(&mut self, key: Key, value: Value) -> Result<()> {
+        let value = self.store_value(value);
         if self.root.is_none() {
             // Create root node
             let root_id = self.allocate_node_id();
@@ -78,9 +193,18 @@ impl BPlusTree {
             self.leaf_head = Some(root_id);
         } else {
             let root_id = self.root.unwrap();
-            let new_root = self.insert_recursive(root_id, key, value)?;
-            if let Some(new_root_id) = new_root {
+            if let (_, Some((promote_key, sibling_id))) = self.insert_recursive(root_id, key, value, true)? {
+                // Root was split, grow the tree by one level
+                let new_root_id = self.allocate_node_id();
+                let mut new_root = Node::new(new_root_id, false);
+                new_root.keys.push(promote_key);
+                new_root.children.push(root_id);
+                new_root.children.push(sibling_id);
+                new_root.counts = vec![self.subtree_count(root_id), self.subtree_count(sibling_id)];
+
+                self.nodes.insert(new_root_id, new_root);
                 self.root = Some(new_root_id);
+                self.debug_check_shape(new_root_id);
             }
         }
         
@@ -88,24 +212,42 @@ impl BPlusTree {
         Ok(())
     }
 
-    fn insert_recursive(&mut self, node_id: NodeId, key: Key, value: Value) -> Result<Option<NodeId>> {
-        let node = self.nodes.get(&node_id).unwrap().clone();
+    /// Inserts into the subtree rooted at `node_id`. Returns whether the key
+    /// is new, rather than an overwrite, and if the node had to be split,
+    /// the separator key and the id of the new right sibling so the caller
+    /// can link it into the parent. `rightmost` says whether the subtree is
+    /// the last one on its level, the only place ascending keys land.
+    fn insert_recursive(
+        &mut self,
+        node_id: NodeId,
+        key: Key,
+        value: StoredValue,
+        rightmost: bool,
+    ) -> Result<Insertion> {
+        let node = self.nodes.get(&node_id).unwrap();
         
         if node.is_leaf {
-            self.insert_into_leaf(node_id, key, value)
+            self.insert_into_leaf(node_id, key, value, rightmost)
         } else {
-            self.insert_into_internal(node_id, key, value)
+            self.insert_into_internal(node_id, key, value, rightmost)
         }
     }
 
-    fn insert_into_leaf(&mut self, node_id: NodeId, key: Key, value: Value) -> Result<Option<NodeId>> {
+    fn insert_into_leaf(
+        &mut self,
+        node_id: NodeId,
+        key: Key,
+        value: StoredValue,
+        rightmost: bool,
+    ) -> Result<Insertion> {
         let node = self.nodes.get_mut(&node_id).unwrap();
         let index = node.find_key_index(&key);
         
         if index < node.keys.len() && node.keys[index] == key {
             // Update existing key
-            node.values[index] = value;
-            return Ok(None);
+            let old = std::mem::replace(&mut node.values[index], value);
+            self.release_value(old);
+            return Ok((false, None));
         }
         
         // Insert new key-value pair
@@ -113,34 +255,47 @@ impl BPlusTree {
         node.values.insert(index, value);
         
         if node.is_full() {
-            self.split_leaf(node_id)
+            let appended = rightmost && index + 1 == node.keys.len();
+            Ok((true, Some(self.split_leaf(node_id, appended))))
         } else {
-            Ok(None)
+            Ok((true, None))
         }
     }
 
-    fn insert_into_internal(&mut self, node_id: NodeId, key: Key, value: Value) -> Result<Option<NodeId>> {
-        let node = self.nodes.get(&node_id).unwrap().clone();
-        let index = node.find_key_index(&key);
-        
-        let child_id = if index < node.children.len() {
-            node.children[index]
-        } else {
-            return Err(anyhow!("Invalid child index"));
-        };
+    fn insert_into_internal(
+        &mut self,
+        node_id: NodeId,
+        key: Key,
+        value: StoredValue,
+        rightmost: bool,
+    ) -> Result<Insertion> {
+        let node = self.nodes.get(&node_id).unwrap();
+        // A misshapen node would send the key to the wrong child, or none
+        node.check_internal_shape().map_err(|e| anyhow!("Cannot insert '{}': {}", key, e))?;
+        let index = node.find_child_index(&key);
+        let child_id = node.children[index];
+        let rightmost = rightmost && index + 1 == node.children.len();
         
-        let new_child = self.insert_recursive(child_id, key, value)?;
+        let (added, split) = self.insert_recursive(child_id, key, value, rightmost)?;
+        if added {
+            self.nodes.get_mut(&node_id).unwrap().counts[index] += 1;
+        }
         
-        if let Some(new_child_id) = new_child {
-            self.insert_child(node_id, new_child_id)
+        if let Some((promote_key, new_child_id)) = split {
+            Ok((added, self.insert_child(node_id, promote_key, new_child_id, rightmost)?))
         } else {
-            Ok(None)
+            Ok((added, None))
         }
     }
 
-    fn split_leaf(&mut self, node_id: NodeId) -> Result<Option<NodeId>> {
+    /// Splits a full leaf. An even split suits random inserts, but under
+    /// ascending keys (auto-increment ids) the left half would never be
+    /// written again and stay half empty. So when the key that filled the
+    /// rightmost leaf was appended at its end, only that key moves to the
+    /// new leaf. Elsewhere a key at a leaf's end is just a random insert.
+    fn split_leaf(&mut self, node_id: NodeId, appended: bool) -> (Key, NodeId) {
         let node = self.nodes.get(&node_id).unwrap().clone();
-        let mid = node.keys.len() / 2;
+        let mid = if appended { node.keys.len() - 1 } else { node.keys.len() / 2 };
         
         // Create new leaf node
         let new_node_id = self.allocate_node_id();
@@ -169,41 +324,54 @@ impl BPlusTree {
         let promote_key = new_node.keys[0].clone();
         self.nodes.insert(new_node_id, new_node);
         
-        // If this is the root, create new root
-        if Some(node_id) == self.root {
-            let new_root_id = self.allocate_node_id();
-            let mut new_root = Node::new(new_root_id, false);
-            new_root.keys.push(promote_key);
-            new_root.children.push(node_id);
-            new_root.children.push(new_node_id);
-            
-            self.nodes.insert(new_root_id, new_root);
-            Ok(Some(new_root_id))
-        } else {
-            Ok(Some(new_node_id))
-        }
+        (promote_key, new_node_id)
     }
 
-    fn insert_child(&mut self, parent_id: NodeId, child_id: NodeId) -> Result<Option<NodeId>> {
-        let child = self.nodes.get(&child_id).unwrap().clone();
-        let promote_key = child.keys[0].clone();
-        
+    /// Links `child_id`, just split off the child at the separator's
+    /// position, into the parent, splitting the parent in turn if full.
+    fn insert_child(
+        &mut self,
+        parent_id: NodeId,
+        promote_key: Key,
+        child_id: NodeId,
+        rightmost: bool,
+    ) -> Result<Option<(Key, NodeId)>> {
+        let moved = self.subtree_count(child_id);
         let parent = self.nodes.get_mut(&parent_id).unwrap();
-        let index = parent.find_key_index(&promote_key);
+        let index = parent.find_child_index(&promote_key);
         
         parent.keys.insert(index, promote_key);
         parent.children.insert(index + 1, child_id);
+        // The new child's keys all came from its left sibling
+        parent.counts[index] -= moved;
+        parent.counts.insert(index + 1, moved);
         
         if parent.is_full() {
-            self.split_internal(parent_id)
+            let appended = rightmost && index + 1 == parent.keys.len();
+            Ok(Some(self.split_internal(parent_id, appended)))
         } else {
+            self.debug_check_shape(parent_id);
             Ok(None)
         }
     }
 
-    fn split_internal(&mut self, node_id: NodeId) -> Result<Option<NodeId>> {
+    /// Panics, in debug builds, if an insert left internal node `node_id`
+    /// misshapen, naming the node, so the bug surfaces where it happened
+    /// rather than on some later insert.
+    fn debug_check_shape(&self, node_id: NodeId) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.nodes[&node_id].check_internal_shape() {
+                panic!("B+tree insert broke an invariant: {}", e);
+            }
+        }
+    }
+
+    /// Splits a full internal node, biased right like `split_leaf` when the
+    /// new separator was appended to the rightmost node of its level. The
+    /// new node keeps one key and two children.
+    fn split_internal(&mut self, node_id: NodeId, appended: bool) -> (Key, NodeId) {
         let node = self.nodes.get(&node_id).unwrap().clone();
-        let mid = node.keys.len() / 2;
+        let mid = if appended { node.keys.len() - 2 } else { node.keys.len() / 2 };
         
         // Create new internal node
         let new_node_id = self.allocate_node_id();
@@ -212,6 +380,7 @@ impl BPlusTree {
         // Move second half to new node
         new_node.keys = node.keys[mid + 1..].to_vec();
         new_node.children = node.children[mid + 1..].to_vec();
+        new_node.counts = node.counts[mid + 1..].to_vec();
         
         let promote_key = node.keys[mid].clone();
         
@@ -219,22 +388,13 @@ impl BPlusTree {
         let old_node = self.nodes.get_mut(&node_id).unwrap();
         old_node.keys.truncate(mid);
         old_node.children.truncate(mid + 1);
+        old_node.counts.truncate(mid + 1);
         
         self.nodes.insert(new_node_id, new_node);
+        self.debug_check_shape(node_id);
+        self.debug_check_shape(new_node_id);
         
-        // If this is the root, create new root
-        if Some(node_id) == self.root {
-            let new_root_id = self.allocate_node_id();
-            let mut new_root = Node::new(new_root_id, false);
-            new_root.keys.push(promote_key);
-            new_root.children.push(node_id);
-            new_root.children.push(new_node_id);
-            
-            self.nodes.insert(new_root_id, new_root);
-            Ok(Some(new_root_id))
-        } else {
-            Ok(Some(new_node_id))
-        }
+        (promote_key, new_node_id)
     }
 
     pub fn get(&self, key: &str) -> Result<Option<Value>> {
@@ -245,18 +405,80 @@ impl BPlusTree {
         }
     }
 
+    /// Looks up several keys at once, returning their values in the order
+    /// the keys were given. Keys are visited in sorted order along the leaf
+    /// chain: one in the current or next leaf costs no descent from the
+    /// root, so a batch of nearby keys costs about as much as one `get`.
+    pub fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<Value>>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+
+        let mut values = vec![None; keys.len()];
+        let mut current = None;
+        for index in order {
+            let key = keys[index].as_str();
+            current = match current.and_then(|leaf_id| self.leaf_along_chain(leaf_id, key)) {
+                Some(leaf_id) => Some(leaf_id),
+                None => {
+                    let leaf_id = self.find_leaf_for_prefix(key)?;
+                    if leaf_id.is_some() {
+                        record_node_visit();
+                    }
+                    leaf_id
+                }
+            };
+            let leaf = match current {
+                Some(leaf_id) => &self.nodes[&leaf_id],
+                None => continue,
+            };
+            if let Ok(position) = leaf.keys.binary_search_by(|k| k.as_str().cmp(key)) {
+                values[index] = Some(self.load_value(&leaf.values[position])?);
+            }
+        }
+        Ok(values)
+    }
+
+    /// The leaf that would hold `key` if it's `leaf_id` or the one after it,
+    /// given `key` sorts at or after everything before `leaf_id`.
+    fn leaf_along_chain(&self, leaf_id: NodeId, key: &str) -> Option<NodeId> {
+        // A leaf's keys run up to the first key of the leaf after it
+        let holds = |leaf_id: NodeId| match self.nodes[&leaf_id].next_leaf {
+            Some(next_id) => self.nodes[&next_id].keys.first().map(|first| key < first.as_str()),
+            None => Some(true),
+        };
+        if holds(leaf_id)? {
+            return Some(leaf_id);
+        }
+        let next_id = self.nodes[&leaf_id].next_leaf?;
+        if holds(next_id)? {
+            record_node_visit();
+            return Some(next_id);
+        }
+        None
+    }
+
+    /// Whether `key` is present. Unlike `get`, the value isn't copied, so
+    /// existence checks stay cheap for large rows.
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        match self.find_leaf_for_prefix(key)? {
+            Some(leaf_id) => Ok(self.nodes[&leaf_id].keys.binary_search_by(|k| k.as_str().cmp(key)).is_ok()),
+            None => Ok(false),
+        }
+    }
+
     fn get_recursive(&self, node_id: NodeId, key: &str) -> Result<Option<Value>> {
+        record_node_visit();
         let node = self.nodes.get(&node_id).unwrap();
         
         if node.is_leaf {
             let index = node.find_key_index(key);
             if index < node.keys.len() && node.keys[index] == key {
-                Ok(Some(node.values[index].clone()))
+                self.load_value(&node.values[index]).map(Some)
             } else {
                 Ok(None)
             }
         } else {
-            let index = node.find_key_index(key);
+            let index = node.find_child_index(key);
             let child_id = if index < node.children.len() {
                 node.children[index]
             } else {
@@ -266,6 +488,205 @@ impl BPlusTree {
         }
     }
 
+    /// Removes `key` from the tree, returning its value if it was present.
+    /// Underflowed leaves are left in place; the leaf chain stays intact so
+    /// scans keep working.
+    pub fn remove(&mut self, key: &str) -> Result<Option<Value>> {
+        let mut leaf_id = match self.root {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        // The internal nodes passed through, and which child was taken, so
+        // their counts can be decremented
+        let mut path = Vec::new();
+        while !self.nodes[&leaf_id].is_leaf {
+            let node = &self.nodes[&leaf_id];
+            let index = node.find_child_index(key);
+            path.push((leaf_id, index));
+            leaf_id = *node.children.get(index).ok_or_else(|| anyhow!("Invalid child index"))?;
+        }
+
+        let node = self.nodes.get_mut(&leaf_id).unwrap();
+        let index = node.find_key_index(key);
+        if index < node.keys.len() && node.keys[index] == key {
+            node.keys.remove(index);
+            let stored = node.values.remove(index);
+            let value = self.load_value(&stored)?;
+            self.release_value(stored);
+            for (node_id, index) in path {
+                self.nodes.get_mut(&node_id).unwrap().counts[index] -= 1;
+            }
+            self.operation_count += 1;
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn store_value(&mut self, value: Value) -> StoredValue {
+        if value.len() <= self.overflow_threshold {
+            return StoredValue::Inline(value);
+        }
+        let id = self.next_overflow_id;
+        self.next_overflow_id += 1;
+        self.overflow.insert(id, value);
+        StoredValue::Overflow(id)
+    }
+
+    fn load_value(&self, stored: &StoredValue) -> Result<Value> {
+        #[cfg(test)]
+        VALUE_COPIES.with(|copies| copies.set(copies.get() + 1));
+        self.value_bytes(stored).map(<[u8]>::to_vec)
+    }
+
+    fn value_bytes<'a>(&'a self, stored: &'a StoredValue) -> Result<&'a [u8]> {
+        match stored {
+            StoredValue::Inline(value) => Ok(value),
+            StoredValue::Overflow(id) => self
+                .overflow
+                .get(id)
+                .map(Vec::as_slice)
+                .ok_or_else(|| anyhow!("Overflow value {} is missing", id)),
+        }
+    }
+
+    /// Reclaims the out-of-line storage of a value that is no longer referenced.
+    fn release_value(&mut self, stored: StoredValue) {
+        if let StoredValue::Overflow(id) = stored {
+            self.overflow.remove(&id);
+        }
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            nodes: self.nodes.len(),
+            overflow_bytes: self.overflow.values().map(Vec::len).sum(),
+            ..TreeStats::default()
+        };
+        for node in self.nodes.values().filter(|n| n.is_leaf) {
+            stats.leaves += 1;
+            stats.keys += node.keys.len();
+            for value in &node.values {
+                match value {
+                    StoredValue::Inline(_) => stats.inline_values += 1,
+                    StoredValue::Overflow(_) => stats.overflow_values += 1,
+                }
+            }
+        }
+        stats
+    }
+
+    /// A text rendering of the tree for debugging: each node reached from
+    /// the root, indented by depth, with whether it is a leaf, its key
+    /// count and its first and last key; then the leaf chain as followed
+    /// from its head. Damage is shown rather than reported as an error, so
+    /// a broken tree can still be inspected.
+    pub fn render_structure(&self) -> String {
+        let mut lines = vec![format!("root: {}", self.root.map_or("none".to_string(), |id| id.to_string()))];
+        let mut pending: Vec<(NodeId, usize)> = self.root.into_iter().map(|id| (id, 0)).collect();
+        let mut visited = HashSet::new();
+        while let Some((node_id, depth)) = pending.pop() {
+            let indent = "  ".repeat(depth);
+            let node = match self.nodes.get(&node_id) {
+                Some(node) if visited.insert(node_id) => node,
+                Some(_) => {
+                    lines.push(format!("{}node {}: already shown, referenced again", indent, node_id));
+                    continue;
+                }
+                None => {
+                    lines.push(format!("{}node {}: missing", indent, node_id));
+                    continue;
+                }
+            };
+            let range = match (node.keys.first(), node.keys.last()) {
+                (Some(first), Some(last)) => format!(", {:?}..{:?}", first, last),
+                _ => String::new(),
+            };
+            if node.is_leaf {
+                let link = |id: Option<NodeId>| id.map_or("-".to_string(), |id| id.to_string());
+                lines.push(format!(
+                    "{}leaf {}: {} keys{}, prev {}, next {}",
+                    indent,
+                    node_id,
+                    node.keys.len(),
+                    range,
+                    link(node.prev_leaf),
+                    link(node.next_leaf)
+                ));
+            } else {
+                let (keys, children) = (node.keys.len(), &node.children);
+                lines.push(format!("{}internal {}: {} keys{}, children {:?}", indent, node_id, keys, range, children));
+                pending.extend(node.children.iter().rev().map(|&child| (child, depth + 1)));
+            }
+        }
+
+        let mut chain = Vec::new();
+        let mut current = self.leaf_head;
+        while let Some(node_id) = current {
+            if chain.len() > self.nodes.len() {
+                chain.push("... (cycle)".to_string());
+                break;
+            }
+            chain.push(node_id.to_string());
+            current = self.nodes.get(&node_id).and_then(|node| node.next_leaf);
+        }
+        lines.push(format!("leaf chain: {}", if chain.is_empty() { "empty".to_string() } else { chain.join(" -> ") }));
+        lines.join("\n")
+    }
+
+    /// Number of keys `k` with `start <= k <= end`. Descends to both ends
+    /// and sums the subtree counts between them, so it visits O(height)
+    /// nodes however many keys match.
+    pub fn count_range(&self, start: &str, end: &str) -> usize {
+        if start > end {
+            return 0;
+        }
+        self.rank(end, true) - self.rank(start, false)
+    }
+
+    /// Number of keys below `key`, or up to and including it if `inclusive`.
+    fn rank(&self, key: &str, inclusive: bool) -> usize {
+        let mut rank = 0;
+        let mut current = self.root;
+        while let Some(node_id) = current {
+            record_node_visit();
+            let node = &self.nodes[&node_id];
+            if node.is_leaf {
+                rank += node.keys.partition_point(|k| k.as_str() < key || (inclusive && k == key));
+                break;
+            }
+            // Every key in an earlier child is below the separator before
+            // this one, which is at most `key`
+            let index = node.find_child_index(key);
+            rank += node.counts[..index].iter().sum::<usize>();
+            current = Some(node.children[index]);
+        }
+        rank
+    }
+
+    /// Keys in the subtree rooted at `node_id`.
+    fn subtree_count(&self, node_id: NodeId) -> usize {
+        let node = &self.nodes[&node_id];
+        if node.is_leaf {
+            node.keys.len()
+        } else {
+            node.counts.iter().sum()
+        }
+    }
+
+    /// Recomputes every internal node's child counts, which snapshots don't
+    /// store, returning the subtree's key count.
+    fn rebuild_counts(&mut self, node_id: NodeId) -> usize {
+        let node = &self.nodes[&node_id];
+        if node.is_leaf {
+            return node.keys.len();
+        }
+        let counts: Vec<usize> = node.children.clone().into_iter().map(|child| self.rebuild_counts(child)).collect();
+        let total = counts.iter().sum();
+        self.nodes.get_mut(&node_id).unwrap().counts = counts;
+        total
+    }
+
     pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<Key>> {
         let mut results = Vec::new();
         
@@ -291,6 +712,86 @@ impl BPlusTree {
         Ok(results)
     }
 
+    /// Visits every entry whose key starts with `prefix`, in key order. Keys
+    /// and values are borrowed from the tree, so callers that decode values
+    /// avoid the copies `scan_prefix` followed by `get` would make.
+    pub fn for_each_prefix<F>(&self, prefix: &str, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8]) -> Result<()>,
+    {
+        self.for_each_prefix_while(prefix, |key, value| visit(key, value).map(|()| true))
+    }
+
+    /// Like `for_each_prefix`, but stops as soon as `visit` returns false,
+    /// without reading the entries after it.
+    pub fn for_each_prefix_while<F>(&self, prefix: &str, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8]) -> Result<bool>,
+    {
+        let mut current = self.find_leaf_for_prefix(prefix)?;
+        while let Some(node_id) = current {
+            record_node_visit();
+            let node = self.nodes.get(&node_id).unwrap();
+            for (key, value) in node.keys.iter().zip(&node.values) {
+                if key.starts_with(prefix) {
+                    if !visit(key, self.value_bytes(value)?)? {
+                        return Ok(());
+                    }
+                } else if key.as_str() > prefix {
+                    return Ok(());
+                }
+            }
+            current = node.next_leaf;
+        }
+        Ok(())
+    }
+
+    /// Moves every entry under the `from` key prefix to the same suffix
+    /// under `to`, returning how many moved.
+    pub fn rename_prefix(&mut self, from: &str, to: &str) -> Result<usize> {
+        let mut entries = Vec::new();
+        self.for_each_prefix(from, |key, value| {
+            entries.push((key.to_string(), value.to_vec()));
+            Ok(())
+        })?;
+        for (key, value) in &entries {
+            self.remove(key)?;
+            self.insert(format!("{}{}", to, &key[from.len()..]), value.clone())?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Removes every entry under `prefix`, returning how many there were.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let mut keys = Vec::new();
+        self.for_each_prefix(prefix, |key, _| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
+        for key in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Replaces the value of every entry under `prefix` with
+    /// `rewrite(value)`, returning how many were rewritten.
+    pub fn rewrite_prefix<F>(&mut self, prefix: &str, mut rewrite: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]) -> Result<Vec<u8>>,
+    {
+        let mut entries = Vec::new();
+        self.for_each_prefix(prefix, |key, value| {
+            entries.push((key.to_string(), rewrite(value)?));
+            Ok(())
+        })?;
+        let count = entries.len();
+        for (key, value) in entries {
+            self.insert(key, value)?;
+        }
+        Ok(count)
+    }
+
     fn find_leaf_for_prefix(&self, prefix: &str) -> Result<Option<NodeId>> {
         if let Some(root_id) = self.root {
             self.find_leaf_recursive(root_id, prefix)
@@ -305,7 +806,9 @@ impl BPlusTree {
         if node.is_leaf {
             Ok(Some(node_id))
         } else {
-            let index = node.find_key_index(key);
+            // The leaf is counted by whoever reads it
+            record_node_visit();
+            let index = node.find_child_index(key);
             let child_id = if index < node.children.len() {
                 node.children[index]
             } else {
@@ -315,47 +818,298 @@ impl BPlusTree {
         }
     }
 
+    /// Writes the snapshot to a temporary file beside `path`, syncs it, then
+    /// renames it over `path`, so a crash mid-write leaves the previous
+    /// snapshot intact.
     pub fn save_to_disk(&self, path: &str) -> Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, self)?;
+        let temp_path = snapshot_temp_path(path);
+        let file = File::create(&temp_path)?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        crate::storage::fsync::sync_parent_dir(path)?;
         Ok(())
     }
 
     pub fn load_from_disk(&mut self, path: &str) -> Result<()> {
+        // A leftover temp file is a snapshot that was never completed
+        let temp_path = snapshot_temp_path(path);
+        if Path::new(&temp_path).exists() {
+            tracing::warn!("Removing incomplete snapshot {}", temp_path);
+            std::fs::remove_file(&temp_path)?;
+        }
+
         if !Path::new(path).exists() {
             return Err(anyhow!("Storage file does not exist"));
         }
         
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        let loaded: BPlusTree = bincode::deserialize_from(reader)?;
+        let mut loaded: BPlusTree = bincode::deserialize_from(reader)?;
+        if loaded.key_format != KEY_FORMAT_VERSION && loaded.key_format != NAME_PREFIXED_KEY_FORMAT {
+            return Err(anyhow!(
+                "Storage uses key format v{}, expected v{}; the data must be migrated",
+                loaded.key_format,
+                KEY_FORMAT_VERSION
+            ));
+        }
+        loaded.repair_id_counters();
+        if let Some(root_id) = loaded.root {
+            loaded.rebuild_counts(root_id);
+        }
         
         *self = loaded;
         Ok(())
     }
 
-    pub fn apply_wal_entry(&mut self, entry: &WalEntry) -> Result<()> {
+    /// Moves the id counters past every id in use. A snapshot taken with a
+    /// stale counter would otherwise hand out the id of a live node (or
+    /// overflow value) and overwrite it.
+    fn repair_id_counters(&mut self) {
+        if let Some(&max) = self.nodes.keys().next_back() {
+            if self.next_node_id <= max {
+                tracing::warn!("Snapshot next_node_id {} is not past node {}; repairing", self.next_node_id, max);
+                self.next_node_id = max + 1;
+            }
+        }
+        if let Some(&max) = self.overflow.keys().next_back() {
+            if self.next_overflow_id <= max {
+                tracing::warn!("Snapshot next_overflow_id {} is not past value {}; repairing", self.next_overflow_id, max);
+                self.next_overflow_id = max + 1;
+            }
+        }
+    }
+
+    /// Applies a replayed WAL entry, writing rows in `format`, the format
+    /// the database is configured to write new rows in.
+    pub fn apply_wal_entry(&mut self, entry: &WalEntry, format: RowFormat) -> Result<()> {
+        self.mark_applied(entry.seq);
         match &entry.operation {
             crate::txn::wal::WalOperation::Insert { key, row, .. } => {
-                let serialized_row = bincode::serialize(row)?;
+                let serialized_row = crate::storage::codec::encode_row(row, format)?;
                 self.insert(key.clone(), serialized_row)?;
             }
-            crate::txn::wal::WalOperation::CreateTable(_) => {
-                // Table creation doesn't affect storage directly
+            crate::txn::wal::WalOperation::Delete { key, .. } => {
+                self.remove(key)?;
+            }
+            crate::txn::wal::WalOperation::RenameTable { from, to } => {
+                // Keys carry the table id, except in trees still awaiting
+                // migration
+                if self.key_format == NAME_PREFIXED_KEY_FORMAT {
+                    self.rename_prefix(&format!("{}:", from), &format!("{}:", to))?;
+                }
+            }
+            crate::txn::wal::WalOperation::RenameColumn { schema, from, to } => {
+                let prefix = if self.key_format == NAME_PREFIXED_KEY_FORMAT {
+                    format!("{}:", schema.name)
+                } else {
+                    schema.key_prefix()
+                };
+                self.rewrite_prefix(&prefix, |bytes| crate::storage::codec::rename_column(bytes, from, to))?;
+            }
+            crate::txn::wal::WalOperation::DropTable { name, id } => {
+                let prefix = if self.key_format == NAME_PREFIXED_KEY_FORMAT {
+                    format!("{}:", name)
+                } else {
+                    format!("{}:", id)
+                };
+                self.remove_prefix(&prefix)?;
+            }
+            crate::txn::wal::WalOperation::CreateTable(_)
+            | crate::txn::wal::WalOperation::AlterTable(_)
+            | crate::txn::wal::WalOperation::CreateDatabase { .. } => {
+                // Catalog changes don't affect storage directly
+            }
+            crate::txn::wal::WalOperation::Begin(_) | crate::txn::wal::WalOperation::Commit(_) => {
+                // Replay groups a transaction's writes by these markers
             }
         }
         Ok(())
     }
 
+    pub fn key_format(&self) -> u32 {
+        self.key_format
+    }
+
+    /// Marks a new, empty tree as keyed by table name, for replaying a WAL
+    /// written before table ids into, after which `migrate_to_table_ids`
+    /// re-keys it. A tree that holds anything keeps its format.
+    pub fn expect_name_prefixed_keys(&mut self) {
+        if self.root.is_none() {
+            self.key_format = NAME_PREFIXED_KEY_FORMAT;
+        }
+    }
+
+    /// Re-keys a name-prefixed tree by table id, given each table's name
+    /// and id. Every old key is removed before any new one is written, so a
+    /// table name that looks like another table's id can't collide.
+    /// Returns the number of rows moved; a current tree is left alone.
+    pub fn migrate_to_table_ids(&mut self, tables: &[(String, u64)]) -> Result<usize> {
+        if self.key_format != NAME_PREFIXED_KEY_FORMAT {
+            return Ok(0);
+        }
+        let mut moved = Vec::new();
+        for (name, id) in tables {
+            let prefix = format!("{}:", name);
+            self.for_each_prefix(&prefix, |key, value| {
+                moved.push((key.to_string(), format!("{}:{}", id, &key[prefix.len()..]), value.to_vec()));
+                Ok(())
+            })?;
+        }
+        for (old_key, _, _) in &moved {
+            self.remove(old_key)?;
+        }
+        for (_, new_key, value) in &moved {
+            self.insert(new_key.clone(), value.clone())?;
+        }
+        self.key_format = KEY_FORMAT_VERSION;
+        tracing::info!("Migrated {} rows to table-id keys", moved.len());
+        Ok(moved.len())
+    }
+
+    pub fn applied_seq(&self) -> u64 {
+        self.applied_seq
+    }
+
+    /// Records that the WAL entry `seq` has been applied to the tree.
+    pub fn mark_applied(&mut self, seq: u64) {
+        self.applied_seq = self.applied_seq.max(seq);
+    }
+
+    /// Checks the structural invariants of the tree: every referenced node
+    /// exists, keys are strictly sorted and within their parent's separator
+    /// bounds, internal nodes have one more child than keys, and the leaf
+    /// chain visits every leaf in key order.
+    pub fn verify(&self) -> Result<()> {
+        let root_id = match self.root {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let mut leaves = Vec::new();
+        self.verify_node(root_id, None, None, "", &mut leaves)?;
+
+        let mut chain = Vec::new();
+        let mut current = self.leaf_head;
+        while let Some(node_id) = current {
+            if chain.len() > self.nodes.len() {
+                return Err(anyhow!("Leaf chain contains a cycle"));
+            }
+            chain.push(node_id);
+            current = self
+                .nodes
+                .get(&node_id)
+                .ok_or_else(|| anyhow!("Leaf chain references missing node {}", node_id))?
+                .next_leaf;
+        }
+
+        if chain != leaves {
+            return Err(anyhow!("Leaf chain {:?} does not match tree order {:?}", chain, leaves));
+        }
+        Ok(())
+    }
+
+    /// Like `verify`, but only descends into subtrees that can hold keys
+    /// starting with `prefix`, so checking one table doesn't walk the
+    /// whole tree. The leaf chain is checked between the leaves visited.
+    pub fn verify_prefix(&self, prefix: &str) -> Result<()> {
+        let root_id = match self.root {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let mut leaves = Vec::new();
+        self.verify_node(root_id, None, None, prefix, &mut leaves)?;
+
+        for pair in leaves.windows(2) {
+            let next = self.nodes[&pair[0]].next_leaf;
+            if next != Some(pair[1]) {
+                return Err(anyhow!("Leaf {} links to {:?} instead of the next leaf {}", pair[0], next, pair[1]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the subtree at `node_id`, skipping children whose separator
+    /// bounds rule out keys starting with `prefix`. Returns the subtree's
+    /// key count, taking skipped children's counts on trust.
+    fn verify_node(
+        &self,
+        node_id: NodeId,
+        lower: Option<&str>,
+        upper: Option<&str>,
+        prefix: &str,
+        leaves: &mut Vec<NodeId>,
+    ) -> Result<usize> {
+        let node = self
+            .nodes
+            .get(&node_id)
+            .ok_or_else(|| anyhow!("Node {} is referenced but missing", node_id))?;
+
+        if node.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("Node {} keys are not strictly sorted", node_id));
+        }
+        if let (Some(lower), Some(first)) = (lower, node.keys.first()) {
+            if first.as_str() < lower {
+                return Err(anyhow!("Node {} key '{}' is below separator '{}'", node_id, first, lower));
+            }
+        }
+        if let (Some(upper), Some(last)) = (upper, node.keys.last()) {
+            if last.as_str() >= upper {
+                return Err(anyhow!("Node {} key '{}' is not below separator '{}'", node_id, last, upper));
+            }
+        }
+
+        if node.is_leaf {
+            if node.values.len() != node.keys.len() {
+                return Err(anyhow!("Leaf {} has {} keys but {} values", node_id, node.keys.len(), node.values.len()));
+            }
+            for value in &node.values {
+                if let StoredValue::Overflow(id) = value {
+                    if !self.overflow.contains_key(id) {
+                        return Err(anyhow!("Leaf {} references missing overflow value {}", node_id, id));
+                    }
+                }
+            }
+            leaves.push(node_id);
+            return Ok(node.keys.len());
+        }
+
+        node.check_internal_shape()?;
+        for (i, child_id) in node.children.iter().enumerate() {
+            let child_lower = if i == 0 { lower } else { Some(node.keys[i - 1].as_str()) };
+            let child_upper = if i == node.keys.len() { upper } else { Some(node.keys[i].as_str()) };
+            let below = child_upper.is_some_and(|upper| upper <= prefix);
+            let above = child_lower.is_some_and(|lower| lower > prefix && !lower.starts_with(prefix));
+            if below || above {
+                continue;
+            }
+            let count = self.verify_node(*child_id, child_lower, child_upper, prefix, leaves)?;
+            if count != node.counts[i] {
+                return Err(anyhow!("Internal node {} counts {} keys under child {}, which has {}", node_id, node.counts[i], child_id, count));
+            }
+        }
+        Ok(node.counts.iter().sum())
+    }
+
     fn allocate_node_id(&mut self) -> NodeId {
         let id = self.next_node_id;
         self.next_node_id += 1;
         id
     }
 
-    pub fn should_checkpoint(&self) -> bool {
-        self.operation_count >= 1000
+    /// Whether `threshold` mutations have built up since the last
+    /// checkpoint. Reads don't count: they add nothing to the WAL a
+    /// checkpoint would reclaim.
+    pub fn should_checkpoint(&self, threshold: usize) -> bool {
+        self.operation_count >= threshold
+    }
+
+    /// Mutations since the last checkpoint: inserts, overwrites of an
+    /// existing key and removals of a present key.
+    pub fn operation_count(&self) -> usize {
+        self.operation_count
     }
 
     pub fn reset_operation_count(&mut self) {
@@ -369,10 +1123,14 @@ impl Default for BPlusTree {
     }
 }
 
+fn snapshot_temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
 
     #[test]
     fn test_insert_and_get() {
@@ -414,6 +1172,277 @@ mod tests {
         assert_eq!(new_tree.get("key1").unwrap(), Some(b"value1".to_vec()));
     }
 
+    #[test]
+    fn test_interrupted_save_keeps_previous_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("storage.db");
+        let path = path.to_str().unwrap();
+
+        let mut tree = BPlusTree::new();
+        tree.insert("key1".to_string(), b"value1".to_vec()).unwrap();
+        tree.save_to_disk(path).unwrap();
+
+        // A crash partway through the next save leaves only a truncated temp file
+        std::fs::write(snapshot_temp_path(path), b"\x01\x02").unwrap();
+
+        let mut loaded = BPlusTree::new();
+        loaded.load_from_disk(path).unwrap();
+        assert_eq!(loaded.get("key1").unwrap(), Some(b"value1".to_vec()));
+        assert!(!Path::new(&snapshot_temp_path(path)).exists());
+    }
+
+    #[test]
+    fn test_load_rejects_old_key_format() {
+        let mut tree = BPlusTree::new();
+        tree.insert("key1".to_string(), b"value1".to_vec()).unwrap();
+        tree.key_format = NAME_PREFIXED_KEY_FORMAT - 1;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        tree.save_to_disk(temp_file.path().to_str().unwrap()).unwrap();
+
+        let mut loaded = BPlusTree::new();
+        let err = loaded.load_from_disk(temp_file.path().to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("key format"));
+    }
+
+    #[test]
+    fn test_operation_count_tracks_mutations() {
+        let mut tree = BPlusTree::new();
+        for i in 0..6 {
+            tree.insert(format!("k{}", i), vec![i]).unwrap();
+        }
+        tree.insert("k0".to_string(), vec![9]).unwrap();
+        tree.remove("k1").unwrap();
+        tree.remove("k2").unwrap();
+        // Neither reads nor removing an absent key change anything
+        tree.get("k3").unwrap();
+        tree.scan_prefix("k").unwrap();
+        tree.remove("missing").unwrap();
+
+        assert_eq!(tree.operation_count(), 9);
+        assert!(tree.should_checkpoint(9));
+        assert!(!tree.should_checkpoint(10));
+        tree.reset_operation_count();
+        assert!(!tree.should_checkpoint(1));
+    }
+
+    #[test]
+    fn test_migrate_to_table_ids() {
+        let mut tree = BPlusTree::new();
+        tree.key_format = NAME_PREFIXED_KEY_FORMAT;
+        tree.insert("users:1".to_string(), b"alice".to_vec()).unwrap();
+        tree.insert("users:2".to_string(), b"bob".to_vec()).unwrap();
+        // A table whose name is another table's id
+        tree.insert("2:1".to_string(), b"odd".to_vec()).unwrap();
+
+        let temp_file = NamedTempFile::new().unwrap();
+        tree.save_to_disk(temp_file.path().to_str().unwrap()).unwrap();
+        let mut loaded = BPlusTree::new();
+        loaded.load_from_disk(temp_file.path().to_str().unwrap()).unwrap();
+
+        let tables = [("users".to_string(), 2), ("2".to_string(), 1)];
+        assert_eq!(loaded.migrate_to_table_ids(&tables).unwrap(), 3);
+        assert_eq!(loaded.key_format(), KEY_FORMAT_VERSION);
+        assert_eq!(loaded.scan_prefix("").unwrap(), vec!["1:1", "2:1", "2:2"]);
+        assert_eq!(loaded.get("1:1").unwrap(), Some(b"odd".to_vec()));
+        assert_eq!(loaded.get("2:2").unwrap(), Some(b"bob".to_vec()));
+        assert_eq!(loaded.migrate_to_table_ids(&tables).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_load_repairs_stale_node_id_counter() {
+        let mut tree = BPlusTree::with_overflow_threshold(8);
+        for i in 0..1000 {
+            tree.insert(format!("k:{:04}", i), vec![i as u8; 16]).unwrap();
+        }
+        tree.next_node_id = 1;
+        tree.next_overflow_id = 0;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        tree.save_to_disk(temp_file.path().to_str().unwrap()).unwrap();
+        let mut loaded = BPlusTree::new();
+        loaded.load_from_disk(temp_file.path().to_str().unwrap()).unwrap();
+
+        for i in 1000..2000 {
+            loaded.insert(format!("k:{:04}", i), vec![i as u8; 16]).unwrap();
+        }
+        loaded.verify().unwrap();
+        for i in 0..2000 {
+            assert_eq!(loaded.get(&format!("k:{:04}", i)).unwrap(), Some(vec![i as u8; 16]));
+        }
+    }
+
+    fn leaf_fill_factor(tree: &BPlusTree) -> f64 {
+        let stats = tree.stats();
+        stats.keys as f64 / (stats.leaves * (NODE_SIZE - 1)) as f64
+    }
+
+    #[test]
+    fn test_sequential_inserts_fill_leaves() {
+        let mut tree = BPlusTree::new();
+        for i in 0..20_000 {
+            tree.insert(format!("t:{:08}", i), vec![0]).unwrap();
+        }
+        tree.verify().unwrap();
+        assert!(leaf_fill_factor(&tree) > 0.95, "fill factor {}", leaf_fill_factor(&tree));
+
+        // Random inserts still split evenly
+        let mut tree = BPlusTree::new();
+        let mut x: u64 = 42;
+        for _ in 0..20_000 {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            tree.insert(format!("t:{:016x}", x), vec![0]).unwrap();
+        }
+        tree.verify().unwrap();
+        let fill = leaf_fill_factor(&tree);
+        assert!(fill > 0.5 && fill < 0.95, "fill factor {}", fill);
+    }
+
+    #[test]
+    fn test_only_the_rightmost_leaf_splits_right_biased() {
+        let mut tree = BPlusTree::new();
+        for i in 0..NODE_SIZE {
+            tree.insert(format!("t:{:04}", i), vec![0]).unwrap();
+        }
+        // Lands at the end of the now full left leaf, which isn't an append
+        tree.insert(format!("t:{:04}5", NODE_SIZE - 2), vec![0]).unwrap();
+        tree.verify().unwrap();
+
+        let sparse = tree
+            .nodes
+            .values()
+            .filter(|node| node.is_leaf && node.next_leaf.is_some() && node.keys.len() < NODE_SIZE / 2)
+            .count();
+        assert_eq!(sparse, 0);
+    }
+
+    #[test]
+    fn test_for_each_prefix() {
+        let mut tree = BPlusTree::with_overflow_threshold(8);
+        for i in 0..50 {
+            tree.insert(format!("a:{:02}", i), vec![i as u8]).unwrap();
+            tree.insert(format!("b:{:02}", i), vec![i as u8; 16]).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        tree.for_each_prefix("b:", |key, value| {
+            seen.push((key.to_string(), value.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen.len(), 50);
+        assert_eq!(seen[7], ("b:07".to_string(), vec![7u8; 16]));
+        assert!(seen.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_large_values_stored_out_of_line() {
+        let mut tree = BPlusTree::with_overflow_threshold(64);
+        let large = vec![7u8; 4096];
+        tree.insert("small".to_string(), b"tiny".to_vec()).unwrap();
+        tree.insert("large".to_string(), large.clone()).unwrap();
+
+        assert_eq!(tree.get("small").unwrap(), Some(b"tiny".to_vec()));
+        assert_eq!(tree.get("large").unwrap(), Some(large.clone()));
+
+        let stats = tree.stats();
+        assert_eq!(stats.inline_values, 1);
+        assert_eq!(stats.overflow_values, 1);
+        assert_eq!(stats.overflow_bytes, 4096);
+
+        // Removing the key reclaims its overflow value
+        assert_eq!(tree.remove("large").unwrap(), Some(large));
+        assert_eq!(tree.stats().overflow_values, 0);
+        assert_eq!(tree.stats().overflow_bytes, 0);
+        tree.verify().unwrap();
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = BPlusTree::new();
+        tree.insert("key1".to_string(), b"value1".to_vec()).unwrap();
+        tree.insert("key2".to_string(), b"value2".to_vec()).unwrap();
+
+        assert_eq!(tree.remove("key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(tree.get("key1").unwrap(), None);
+        assert_eq!(tree.get("key2").unwrap(), Some(b"value2".to_vec()));
+    }
+
+    #[test]
+    fn test_remove_missing_and_reinsert() {
+        let mut tree = BPlusTree::new();
+        assert_eq!(tree.remove("key1").unwrap(), None);
+        tree.insert("key1".to_string(), b"value1".to_vec()).unwrap();
+        assert_eq!(tree.remove("key0").unwrap(), None);
+        assert_eq!(tree.remove("key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(tree.remove("key1").unwrap(), None);
+
+        tree.insert("key1".to_string(), b"again".to_vec()).unwrap();
+        assert_eq!(tree.get("key1").unwrap(), Some(b"again".to_vec()));
+        assert_eq!(tree.scan_prefix("key").unwrap(), vec!["key1".to_string()]);
+    }
+
+    #[test]
+    fn test_remove_across_leaves_keeps_scans_ordered() {
+        let mut tree = BPlusTree::new();
+        for i in 0..1000 {
+            tree.insert(format!("key{:04}", i), format!("value{}", i).into_bytes()).unwrap();
+        }
+        for i in (1..1000).step_by(2) {
+            assert_eq!(tree.remove(&format!("key{:04}", i)).unwrap(), Some(format!("value{}", i).into_bytes()));
+        }
+        // A run long enough to empty whole leaves; the odd keys are already gone
+        for i in 400..600 {
+            let expected = (i % 2 == 0).then(|| format!("value{}", i).into_bytes());
+            assert_eq!(tree.remove(&format!("key{:04}", i)).unwrap(), expected);
+        }
+        tree.verify().unwrap();
+
+        let expected: Vec<String> =
+            (0..1000).filter(|i| i % 2 == 0 && !(400..600).contains(i)).map(|i| format!("key{:04}", i)).collect();
+        assert_eq!(tree.scan_prefix("key").unwrap(), expected);
+        assert_eq!(tree.count_range("key0000", "key9999"), expected.len());
+        assert_eq!(tree.get("key0402").unwrap(), None);
+        assert_eq!(tree.get("key0602").unwrap(), Some(b"value602".to_vec()));
+
+        for i in (400..600).step_by(2) {
+            tree.insert(format!("key{:04}", i), b"back".to_vec()).unwrap();
+        }
+        tree.verify().unwrap();
+        assert_eq!(tree.get("key0500").unwrap(), Some(b"back".to_vec()));
+        assert_eq!(tree.scan_prefix("key05").unwrap().len(), 50);
+    }
+
+    #[test]
+    fn test_multi_get_walks_the_leaf_chain() {
+        let mut tree = BPlusTree::new();
+        assert_eq!(tree.multi_get(&["k1".to_string()]).unwrap(), vec![None]);
+        for i in 0..2000 {
+            tree.insert(format!("key{:04}", i), format!("value{}", i).into_bytes()).unwrap();
+        }
+
+        // Unsorted, with a repeat and keys that aren't there
+        let keys: Vec<String> = ["key0150", "key0003", "absent", "key1999", "key0150", "key0004x", "zzz"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        let expected: Vec<_> = keys.iter().map(|key| tree.get(key).unwrap()).collect();
+        assert_eq!(tree.multi_get(&keys).unwrap(), expected);
+        assert_eq!(expected[0], Some(b"value150".to_vec()));
+
+        let batch: Vec<String> = (1000..1100).map(|i| format!("key{:04}", i)).collect();
+        let visits = node_visits();
+        for key in &batch {
+            tree.get(key).unwrap();
+        }
+        let separate = node_visits() - visits;
+        let visits = node_visits();
+        let values = tree.multi_get(&batch).unwrap();
+        let batched = node_visits() - visits;
+        assert!(values.iter().all(Option::is_some));
+        assert!(batched * 4 < separate, "multi_get visited {} nodes, separate gets {}", batched, separate);
+    }
+
     #[test]
     fn test_many_inserts() {
         let mut tree = BPlusTree::new();
@@ -431,5 +1460,143 @@ mod tests {
             let expected_value = format!("value{}", i).into_bytes();
             assert_eq!(tree.get(&key).unwrap(), Some(expected_value));
         }
+        tree.verify().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_verify_detects_broken_leaf_chain() {
+        let mut tree = BPlusTree::new();
+        for i in 0..600 {
+            tree.insert(format!("key{:04}", i), vec![]).unwrap();
+        }
+        tree.verify().unwrap();
+
+        let head = tree.leaf_head.unwrap();
+        tree.nodes.get_mut(&head).unwrap().next_leaf = None;
+        assert!(tree.verify().is_err());
+    }
+
+    #[test]
+    fn test_render_structure() {
+        assert_eq!(BPlusTree::new().render_structure(), "root: none\nleaf chain: empty");
+
+        let mut tree = BPlusTree::new();
+        for i in 0..600 {
+            tree.insert(format!("key{:04}", i), vec![]).unwrap();
+        }
+        let rendering = tree.render_structure();
+        let lines: Vec<&str> = rendering.lines().collect();
+        assert_eq!(lines[0], format!("root: {}", tree.root.unwrap()));
+        assert!(lines[1].starts_with(&format!("internal {}: ", tree.root.unwrap())), "{}", rendering);
+
+        // Every leaf is shown once, one level down, in key order, and the
+        // chain links them in that same order
+        let leaves: Vec<NodeId> = tree.nodes.values().filter(|node| node.is_leaf).map(|node| node.id).collect();
+        let nodes = &lines[1..lines.len() - 1];
+        let shown: Vec<&str> = nodes.iter().filter(|line| line.trim_start().starts_with("leaf ")).copied().collect();
+        assert_eq!(shown.len(), leaves.len());
+        assert!(leaves.len() > 2);
+        assert!(shown.iter().all(|line| line.starts_with("  leaf ")));
+        assert!(shown[0].contains("\"key0000\"..") && shown[0].contains("prev -"), "{}", shown[0]);
+        assert!(shown[shown.len() - 1].contains("..\"key0599\"") && shown[shown.len() - 1].ends_with("next -"));
+        let ids: Vec<&str> = shown.iter().map(|line| line.trim_start()[5..].split(':').next().unwrap()).collect();
+        assert_eq!(lines[lines.len() - 1], format!("leaf chain: {}", ids.join(" -> ")));
+
+        // A dangling link shows up rather than failing the rendering
+        let head = tree.leaf_head.unwrap();
+        tree.nodes.get_mut(&head).unwrap().next_leaf = Some(9999);
+        assert!(tree.render_structure().ends_with(&format!("leaf chain: {} -> 9999", head)));
+    }
+
+    #[test]
+    fn test_verify_prefix_checks_only_its_range() {
+        let mut tree = BPlusTree::new();
+        for i in 0..600 {
+            tree.insert(format!("a:{:04}", i), vec![]).unwrap();
+            tree.insert(format!("b:{:04}", i), vec![]).unwrap();
+        }
+        tree.verify_prefix("a:").unwrap();
+
+        // Break the link between the first two leaves, both under "a:"
+        let head = tree.leaf_head.unwrap();
+        tree.nodes.get_mut(&head).unwrap().next_leaf = None;
+        assert!(tree.verify_prefix("a:").is_err());
+        tree.verify_prefix("b:").unwrap();
+        assert!(tree.verify().is_err());
+    }
+
+    #[test]
+    fn test_count_range_tracks_inserts_and_removes() {
+        let key = |i: usize| format!("k{:05}", i);
+        let mut tree = BPlusTree::new();
+        // Shuffled, so nodes split in the middle as well as at the end
+        for i in 0..5000 {
+            tree.insert(key(i * 7919 % 5000), vec![]).unwrap();
+        }
+        for i in (0..5000).step_by(3) {
+            tree.remove(&key(i)).unwrap();
+        }
+        // Overwrites and misses change no counts
+        tree.insert(key(1), vec![1]).unwrap();
+        tree.remove(&key(3)).unwrap();
+        tree.verify().unwrap();
+
+        let expected = |a: usize, b: usize| (a..=b).filter(|i| i % 3 != 0).count();
+        let ranges = [(0, 4999), (10, 20), (1234, 3456), (4990, 4999), (7, 7), (9, 8)];
+        for (a, b) in ranges {
+            assert_eq!(tree.count_range(&key(a), &key(b)), expected(a, b), "{}..={}", a, b);
+        }
+        assert_eq!(tree.count_range("a", "z"), expected(0, 4999));
+
+        // Counts aren't in the snapshot; loading rebuilds them
+        let temp_file = NamedTempFile::new().unwrap();
+        tree.save_to_disk(temp_file.path().to_str().unwrap()).unwrap();
+        let mut loaded = BPlusTree::new();
+        loaded.load_from_disk(temp_file.path().to_str().unwrap()).unwrap();
+        loaded.verify().unwrap();
+        for (a, b) in ranges {
+            assert_eq!(loaded.count_range(&key(a), &key(b)), expected(a, b), "{}..={}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_contains_key_copies_no_value() {
+        let mut tree = BPlusTree::with_overflow_threshold(16);
+        assert!(!tree.contains_key("k1").unwrap());
+        for i in 0..1000 {
+            tree.insert(format!("k{}", i), vec![7; 64]).unwrap();
+        }
+        tree.remove("k500").unwrap();
+
+        let copies = value_copies();
+        assert!(tree.contains_key("k1").unwrap());
+        assert!(tree.contains_key("k999").unwrap());
+        assert!(!tree.contains_key("k500").unwrap());
+        assert!(!tree.contains_key("k").unwrap());
+        assert!(!tree.contains_key("zzz").unwrap());
+        assert_eq!(value_copies(), copies);
+
+        tree.get("k1").unwrap();
+        assert_eq!(value_copies(), copies + 1);
+    }
+
+    #[test]
+    fn test_misshapen_internal_node_is_reported() {
+        let mut tree = BPlusTree::new();
+        for i in 0..1000 {
+            tree.insert(format!("k{:04}", i), vec![1]).unwrap();
+        }
+        let root_id = tree.root.unwrap();
+        let root = tree.nodes.get_mut(&root_id).unwrap();
+        assert!(!root.is_leaf);
+        // Lose the last child, as a buggy split might
+        root.children.pop();
+        root.counts.pop();
+        let keys = root.keys.len();
+
+        let expected = format!("Internal node {} has {} keys but {} children", root_id, keys, keys);
+        let err = tree.insert("k9999".to_string(), vec![1]).unwrap_err();
+        assert_eq!(err.to_string(), format!("Cannot insert 'k9999': {}", expected));
+        assert_eq!(tree.verify().unwrap_err().to_string(), expected);
+    }
+}
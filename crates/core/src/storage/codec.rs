@@ -0,0 +1,188 @@
+use crate::sql::engine::Row;
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+/// First byte of every stored row, naming the encoding (and its version)
+/// of the bytes after it. Decoding goes by this byte rather than the
+/// configured format, so rows written under another setting stay readable.
+/// An incompatible change to an encoding gets a new tag.
+const TAG_BINCODE_V1: u8 = 1;
+const TAG_JSON_V1: u8 = 2;
+
+/// Encoding used for newly written rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RowFormat {
+    /// Compact, but reads fields by position, so `Row` and `SqlValue` may
+    /// only grow by appending.
+    #[default]
+    Bincode,
+    /// Larger, but self-describing: fields and variants are named.
+    Json,
+}
+
+impl RowFormat {
+    fn codec(self) -> &'static dyn RowCodec {
+        match self {
+            RowFormat::Bincode => &BincodeCodec,
+            RowFormat::Json => &JsonCodec,
+        }
+    }
+}
+
+impl FromStr for RowFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bincode" => Ok(RowFormat::Bincode),
+            "json" => Ok(RowFormat::Json),
+            _ => Err(anyhow!("Unknown row format '{}', expected bincode or json", s)),
+        }
+    }
+}
+
+/// One way of turning a row into bytes and back.
+pub trait RowCodec: Sync {
+    fn tag(&self) -> u8;
+    fn encode(&self, row: &Row) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<Row>;
+}
+
+struct BincodeCodec;
+
+impl RowCodec for BincodeCodec {
+    fn tag(&self) -> u8 {
+        TAG_BINCODE_V1
+    }
+
+    fn encode(&self, row: &Row) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(row)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Row> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+struct JsonCodec;
+
+impl RowCodec for JsonCodec {
+    fn tag(&self) -> u8 {
+        TAG_JSON_V1
+    }
+
+    fn encode(&self, row: &Row) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(row)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Row> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Encodes `row` for storage, prefixed with its format tag.
+pub fn encode_row(row: &Row, format: RowFormat) -> Result<Vec<u8>> {
+    let codec = format.codec();
+    let mut bytes = vec![codec.tag()];
+    bytes.extend(codec.encode(row)?);
+    Ok(bytes)
+}
+
+/// Decodes a stored row in whichever format it was written.
+pub fn decode_row(bytes: &[u8]) -> Result<Row> {
+    match bytes.split_first() {
+        Some((&TAG_BINCODE_V1, body)) => BincodeCodec.decode(body),
+        Some((&TAG_JSON_V1, body)) => JsonCodec.decode(body),
+        Some((tag, _)) => Err(anyhow!("Unknown row format tag {}", tag)),
+        None => Err(anyhow!("Stored row is empty")),
+    }
+}
+
+/// Moves a stored row's `from` value to `to`, keeping the row's format. A
+/// stale `to` value, left by a dropped column of that name, is discarded.
+pub fn rename_column(bytes: &[u8], from: &str, to: &str) -> Result<Vec<u8>> {
+    let codec = match bytes.first() {
+        Some(&TAG_BINCODE_V1) => RowFormat::Bincode.codec(),
+        Some(&TAG_JSON_V1) => RowFormat::Json.codec(),
+        _ => return Err(anyhow!("Unknown row format in stored row")),
+    };
+    let mut row = codec.decode(&bytes[1..])?;
+    row.remove(to);
+    if let Some(value) = row.remove(from) {
+        row.set(to, value);
+    }
+    let mut renamed = vec![codec.tag()];
+    renamed.extend(codec.encode(&row)?);
+    Ok(renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::engine::SqlValue;
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    fn row() -> Row {
+        Row {
+            values: HashMap::from([
+                ("id".to_string(), SqlValue::Integer(7)),
+                ("name".to_string(), SqlValue::Varchar("Ada".to_string())),
+                ("score".to_string(), SqlValue::Decimal(1.5)),
+                ("active".to_string(), SqlValue::Boolean(true)),
+                ("note".to_string(), SqlValue::Null),
+            ]),
+            schema_version: 3,
+        }
+    }
+
+    #[test]
+    fn test_round_trip_each_format() {
+        for format in [RowFormat::Bincode, RowFormat::Json] {
+            let decoded = decode_row(&encode_row(&row(), format).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", sorted(&decoded)), format!("{:?}", sorted(&row())), "{:?}", format);
+            assert_eq!(decoded.schema_version, 3);
+        }
+        assert!(decode_row(&[9, 1, 2]).is_err());
+        assert_eq!("JSON".parse::<RowFormat>().unwrap(), RowFormat::Json);
+    }
+
+    /// `SqlValue` and `Row` as they were before later variants were added.
+    #[derive(Serialize)]
+    enum OldSqlValue {
+        Integer(i64),
+        Varchar(String),
+    }
+
+    #[derive(Serialize)]
+    struct OldRow {
+        values: HashMap<String, OldSqlValue>,
+        schema_version: u32,
+    }
+
+    #[test]
+    fn test_reads_rows_written_before_variants_were_added() {
+        let old = OldRow {
+            values: HashMap::from([
+                ("id".to_string(), OldSqlValue::Integer(1)),
+                ("name".to_string(), OldSqlValue::Varchar("Ada".to_string())),
+            ]),
+            schema_version: 1,
+        };
+        let encoded = [
+            [vec![TAG_BINCODE_V1], bincode::serialize(&old).unwrap()].concat(),
+            [vec![TAG_JSON_V1], serde_json::to_vec(&old).unwrap()].concat(),
+        ];
+        for bytes in encoded {
+            let row = decode_row(&bytes).unwrap();
+            assert!(matches!(row.get("id"), Some(SqlValue::Integer(1))));
+            assert!(matches!(row.get("name"), Some(SqlValue::Varchar(name)) if name == "Ada"));
+        }
+    }
+
+    fn sorted(row: &Row) -> Vec<(String, String)> {
+        let mut values: Vec<_> = row.iter().map(|(k, v)| (k.clone(), format!("{:?}", v))).collect();
+        values.sort();
+        values
+    }
+}
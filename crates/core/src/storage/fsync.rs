@@ -0,0 +1,54 @@
+use anyhow::Result;
+use std::fs::File;
+use std::path::Path;
+
+#[cfg(test)]
+thread_local! {
+    static DIR_SYNCS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Makes the entries of directory `path` durable: files created, renamed
+/// or removed in it survive a crash once this returns. A file's contents
+/// need their own `sync_all` first; this only covers its name.
+pub fn sync_dir(path: impl AsRef<Path>) -> Result<()> {
+    #[cfg(test)]
+    DIR_SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+
+    // Directories can't be opened as files on Windows, where the rename
+    // itself is durable once it returns
+    #[cfg(unix)]
+    File::open(path.as_ref())?.sync_all()?;
+    Ok(())
+}
+
+/// Syncs the directory containing `file`.
+pub fn sync_parent_dir(file: impl AsRef<Path>) -> Result<()> {
+    match file.as_ref().parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+        _ => sync_dir("."),
+    }
+}
+
+/// Number of `sync_dir` calls made on this thread.
+#[cfg(test)]
+pub fn dir_syncs() -> usize {
+    DIR_SYNCS.with(|syncs| syncs.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sync_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("data.db");
+        std::fs::write(&file, b"x").unwrap();
+
+        let before = dir_syncs();
+        sync_parent_dir(&file).unwrap();
+        assert_eq!(dir_syncs(), before + 1);
+        assert!(sync_dir(temp_dir.path().join("missing")).is_err());
+    }
+}
@@ -1,3 +1,6 @@
 pub mod bptree;
+pub mod codec;
+pub mod fsync;
+pub mod shard;
 
 pub use bptree::BPlusTree;
\ No newline at end of file
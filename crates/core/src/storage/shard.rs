@@ -0,0 +1,63 @@
+/// Maps `key` to one of `num_shards` shards.
+///
+/// Uses jump consistent hashing (Lamping & Veach), so the assignment is
+/// stable across processes and releases, and growing from `n` to `n + 1`
+/// shards moves only the keys that land on the new shard, about `1 / (n + 1)`
+/// of them.
+pub fn shard_for_key(key: &[u8], num_shards: u32) -> u32 {
+    assert!(num_shards > 0, "a table has at least one shard");
+    let mut hash = fnv1a(key);
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < num_shards as i64 {
+        bucket = next;
+        hash = hash.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((hash >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u32
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, its output is fixed, which shard
+/// assignment depends on.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..10_000).map(|i| format!("users:{}", i))
+    }
+
+    #[test]
+    fn test_shard_assignment_is_stable() {
+        for key in keys().take(100) {
+            let shard = shard_for_key(key.as_bytes(), 3);
+            assert!(shard < 3);
+            assert_eq!(shard_for_key(key.as_bytes(), 3), shard);
+        }
+        assert!(keys().all(|key| shard_for_key(key.as_bytes(), 1) == 0));
+        // Pinned so a change to the hash, which would reshuffle stored
+        // data, can't go unnoticed
+        let pinned: Vec<u32> = ["a", "b", "users:42"].iter().map(|k| shard_for_key(k.as_bytes(), 8)).collect();
+        assert_eq!(pinned, vec![2, 3, 0]);
+    }
+
+    #[test]
+    fn test_growing_shards_moves_few_keys() {
+        let mut moved = 0;
+        for key in keys() {
+            let before = shard_for_key(key.as_bytes(), 3);
+            let after = shard_for_key(key.as_bytes(), 4);
+            if before != after {
+                // Keys only ever move to the new shard
+                assert_eq!(after, 3, "{}", key);
+                moved += 1;
+            }
+        }
+        // About a quarter should move; a modulo hash would move three quarters
+        assert!((2_000..3_000).contains(&moved), "{} of 10000 keys moved", moved);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::sql::engine::{encode_key_value, Column, Row, SqlDataType, SqlValue, TableSchema};
+use crate::txn::wal::{WalEntry, WalOperation};
+use anyhow::{anyhow, Result};
+use bincode::de::read::SliceReader;
+use bincode::Options;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Version of the layout of a WAL record's body. Each names the field it
+/// added, or what changed; a record has the fields of its layout and of
+/// every one before.
+pub type Layout = u8;
+
+/// As the first release wrote them.
+pub const BASELINE: Layout = 0;
+/// `Column::auto_increment`.
+pub const AUTO_INCREMENT: Layout = 1;
+/// `WalEntry::seq`.
+pub const SEQ: Layout = 2;
+/// `Column::generated`.
+pub const GENERATED: Layout = 3;
+/// `TableSchema::version` and `Row::schema_version`.
+pub const SCHEMA_VERSION: Layout = 4;
+/// `TableSchema::shard_count`.
+pub const SHARD_COUNT: Layout = 5;
+/// `Column::added_in`.
+pub const ADDED_IN: Layout = 6;
+/// `TableSchema::id`. Row keys were prefixed with the table name before.
+pub const TABLE_ID: Layout = 7;
+/// `WalEntry::tx_id`.
+pub const TX_ID: Layout = 8;
+/// `Column::default`.
+pub const DEFAULT: Layout = 9;
+/// `Column::not_null`. NOT NULL wasn't enforced before, so older columns
+/// read as accepting NULL unless they are keys.
+pub const NOT_NULL: Layout = 10;
+/// No new field: table and column names are folded to lower case from
+/// here on, and were kept as typed before. See `Upgrade::unfolded`.
+pub const FOLDED_NAMES: Layout = 11;
+/// The layout records are written in.
+pub const CURRENT: Layout = FOLDED_NAMES;
+
+/// The body of a record of `entry`: its layout, then the entry.
+pub fn encode(entry: &WalEntry) -> Result<Vec<u8>> {
+    let mut body = vec![CURRENT];
+    bincode::serialize_into(&mut body, entry)?;
+    Ok(body)
+}
+
+/// Decodes a body that starts with its layout.
+pub fn decode_versioned(body: &[u8]) -> Result<(WalEntry, Layout)> {
+    let (&layout, rest) = body.split_first().ok_or_else(|| anyhow!("WAL record has no layout"))?;
+    if layout > CURRENT {
+        return Err(anyhow!("WAL record has layout {}, newer than the {} this version reads", layout, CURRENT));
+    }
+    Ok((decode(rest, layout)?, layout))
+}
+
+/// Decodes a body written before records named their layout, trying each
+/// layout from the newest and taking the first that reads the whole body.
+pub fn decode_unversioned(body: &[u8]) -> Result<(WalEntry, Layout)> {
+    let mut newest_error = None;
+    for layout in (BASELINE..=CURRENT).rev() {
+        match decode(body, layout) {
+            Ok(entry) => return Ok((entry, layout)),
+            Err(e) => {
+                newest_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(newest_error.unwrap())
+}
+
+fn decode(body: &[u8], layout: Layout) -> Result<WalEntry> {
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+    let mut reader = Reader { de: bincode::Deserializer::from_slice(body, options), layout };
+    let entry = reader.entry()?;
+    if u8::deserialize(&mut reader.de).is_ok() {
+        return Err(anyhow!("WAL record is longer than a layout {} entry", layout));
+    }
+    Ok(entry)
+}
+
+/// Reads a body field by field in the order bincode wrote them, leaving
+/// out the fields its layout doesn't have. Reading from the slice, a
+/// length past its end fails instead of allocating.
+struct Reader<'a, O: Options> {
+    de: bincode::Deserializer<SliceReader<'a>, O>,
+    layout: Layout,
+}
+
+impl<'a, O: Options> Reader<'a, O> {
+    fn read<T: Deserialize<'a>>(&mut self) -> Result<T> {
+        Ok(T::deserialize(&mut self.de)?)
+    }
+
+    /// A field added in layout `since`, or `default` if the body is older.
+    fn field<T: Deserialize<'a>>(&mut self, since: Layout, default: T) -> Result<T> {
+        if self.layout >= since {
+            self.read()
+        } else {
+            Ok(default)
+        }
+    }
+
+    fn entry(&mut self) -> Result<WalEntry> {
+        Ok(WalEntry {
+            id: self.read()?,
+            seq: self.field(SEQ, 0)?,
+            timestamp: self.read()?,
+            operation: self.operation()?,
+            tx_id: self.field(TX_ID, None)?,
+        })
+    }
+
+    /// Variants are numbered in declaration order, which never changed.
+    fn operation(&mut self) -> Result<WalOperation> {
+        let variant: u32 = self.read()?;
+        Ok(match variant {
+            0 => WalOperation::CreateTable(self.schema()?),
+            1 => WalOperation::Insert { table: self.read()?, key: self.read()?, row: self.row()? },
+            2 => WalOperation::Delete { table: self.read()?, key: self.read()? },
+            3 => WalOperation::CreateDatabase { name: self.read()? },
+            4 => WalOperation::AlterTable(self.schema()?),
+            5 => WalOperation::RenameTable { from: self.read()?, to: self.read()? },
+            6 => WalOperation::RenameColumn { schema: self.schema()?, from: self.read()?, to: self.read()? },
+            7 => WalOperation::Begin(self.read()?),
+            8 => WalOperation::Commit(self.read()?),
+            9 => WalOperation::DropTable { name: self.read()?, id: self.read()? },
+            _ => return Err(anyhow!("Unknown WAL operation {}", variant)),
+        })
+    }
+
+    /// Fields an older layout lacks are left 0 where `Upgrade` fills them in.
+    fn schema(&mut self) -> Result<TableSchema> {
+        let name = self.read()?;
+        let count: u64 = self.read()?;
+        let mut columns = Vec::new();
+        for _ in 0..count {
+            columns.push(self.column()?);
+        }
+        Ok(TableSchema {
+            name,
+            columns,
+            version: self.field(SCHEMA_VERSION, 0)?,
+            shard_count: self.field(SHARD_COUNT, 1)?,
+            id: self.field(TABLE_ID, 0)?,
+        })
+    }
+
+    fn column(&mut self) -> Result<Column> {
+        Ok(Column {
+            name: self.read()?,
+            data_type: self.read()?,
+            nullable: self.read()?,
+            primary_key: self.read()?,
+            auto_increment: self.field(AUTO_INCREMENT, false)?,
+            generated: self.field(GENERATED, None)?,
+            generated_expr: None,
+            default: self.field(DEFAULT, None)?,
+            added_in: self.field(ADDED_IN, 0)?,
+            not_null: self.field(NOT_NULL, false)?,
+        })
+    }
+
+    fn row(&mut self) -> Result<Row> {
+        let values: HashMap<String, SqlValue> = self.read()?;
+        Ok(Row { values, schema_version: self.field(SCHEMA_VERSION, 0)? })
+    }
+}
+
+/// Brings entries decoded from older layouts up to date, filling in what
+/// those layouts didn't record the way the code writing them behaved:
+/// sequence numbers in log order, table ids in order of creation, schema
+/// versions and the version each column came in, and integer keys in the
+/// order-preserving encoding. Entries must be passed in log order.
+#[derive(Debug)]
+pub struct Upgrade {
+    next_seq: u64,
+    next_table_id: u64,
+    /// Each table as of the entries passed so far, by name.
+    tables: HashMap<String, TableSchema>,
+    name_keyed: bool,
+    unfolded: HashMap<u64, TableSchema>,
+}
+
+impl Default for Upgrade {
+    fn default() -> Self {
+        Self {
+            next_seq: 1,
+            next_table_id: 1,
+            tables: HashMap::new(),
+            name_keyed: false,
+            unfolded: HashMap::new(),
+        }
+    }
+}
+
+impl Upgrade {
+    /// Whether an entry passed writes a row under a key prefixed with its
+    /// table's name, as before table ids. Such rows are re-keyed by
+    /// `BPlusTree::migrate_to_table_ids`.
+    pub fn name_keyed(&self) -> bool {
+        self.name_keyed
+    }
+
+    /// Each table last written by an entry from before `FOLDED_NAMES`, by
+    /// id, as that entry left it: its name and columns are as typed, and
+    /// may need folding for the default `IdentifierCase` to find them.
+    pub fn unfolded(&self) -> &HashMap<u64, TableSchema> {
+        &self.unfolded
+    }
+
+    /// Upgrades `entry`, decoded from a body of `layout`.
+    pub fn apply(&mut self, entry: &mut WalEntry, layout: Layout) {
+        if layout < SEQ {
+            entry.seq = self.next_seq;
+        }
+        self.next_seq = self.next_seq.max(entry.seq + 1);
+
+        match &mut entry.operation {
+            WalOperation::CreateTable(schema) => {
+                if layout < TABLE_ID {
+                    // Created again under the same name, a table kept the
+                    // old one's rows
+                    schema.id = self.tables.get(&schema.name).map_or(self.next_table_id, |table| table.id);
+                }
+                if layout < SCHEMA_VERSION {
+                    schema.version = 1;
+                }
+                if layout < ADDED_IN {
+                    for column in &mut schema.columns {
+                        column.added_in = 1;
+                    }
+                }
+                self.observe(schema, layout);
+            }
+            WalOperation::AlterTable(schema) | WalOperation::RenameColumn { schema, .. } => {
+                // Both came after schema versions
+                let previous = self.tables.get(&schema.name);
+                if layout < TABLE_ID {
+                    schema.id = previous.map_or(self.next_table_id, |table| table.id);
+                }
+                if layout < ADDED_IN {
+                    for column in &mut schema.columns {
+                        column.added_in = previous
+                            .and_then(|table| table.columns.iter().find(|c| c.name == column.name))
+                            .map_or(schema.version, |c| c.added_in);
+                    }
+                }
+                self.observe(schema, layout);
+            }
+            WalOperation::RenameTable { from, to } => {
+                if let Some(mut table) = self.tables.remove(from.as_str()) {
+                    table.name = to.clone();
+                    self.observe(&table, layout);
+                }
+            }
+            WalOperation::DropTable { name, id } => {
+                self.tables.remove(name.as_str());
+                self.unfolded.remove(id);
+            }
+            WalOperation::Insert { table, key, row } => {
+                if layout < SCHEMA_VERSION {
+                    row.schema_version = self.tables.get(table.as_str()).map_or(1, |t| t.version);
+                }
+                self.upgrade_key(table, key, layout);
+            }
+            WalOperation::Delete { table, key } => self.upgrade_key(table, key, layout),
+            WalOperation::CreateDatabase { .. } | WalOperation::Begin(_) | WalOperation::Commit(_) => {}
+        }
+    }
+
+    fn observe(&mut self, schema: &TableSchema, layout: Layout) {
+        self.next_table_id = self.next_table_id.max(schema.id + 1);
+        self.tables.insert(schema.name.clone(), schema.clone());
+        // An entry written since names names the table as it is now
+        if layout < FOLDED_NAMES {
+            self.unfolded.insert(schema.id, schema.clone());
+        } else {
+            self.unfolded.remove(&schema.id);
+        }
+    }
+
+    /// Notes a key prefixed with the table name, and re-encodes an integer
+    /// key still written in decimal. Row entries of the layouts from schema
+    /// versions to table ids are alike, so the key's prefix tells them
+    /// apart.
+    fn upgrade_key(&mut self, table: &str, key: &mut String, layout: Layout) {
+        if layout >= TX_ID {
+            return;
+        }
+        let suffix = match key.strip_prefix(table).and_then(|rest| rest.strip_prefix(':')) {
+            Some(suffix) => suffix,
+            None => return,
+        };
+        self.name_keyed = true;
+
+        let integer_key = self
+            .tables
+            .get(table)
+            .and_then(|schema| schema.columns.iter().find(|c| c.primary_key))
+            .is_some_and(|column| matches!(column.data_type, SqlDataType::Integer));
+        let encoded = suffix.len() == 16 && suffix.bytes().all(|b| b.is_ascii_hexdigit());
+        if layout < SCHEMA_VERSION && integer_key && !encoded {
+            if let Ok(value) = suffix.parse::<i64>() {
+                *key = format!("{}:{}", table, encode_key_value(&SqlValue::Integer(value)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> TableSchema {
+        TableSchema {
+            name: "users".to_string(),
+            columns: vec![Column {
+                name: "id".to_string(),
+                data_type: SqlDataType::Integer,
+                nullable: false,
+                primary_key: true,
+                auto_increment: true,
+                generated: None,
+                generated_expr: None,
+                default: Some("7".to_string()),
+                added_in: 2,
+                not_null: true,
+            }],
+            version: 3,
+            shard_count: 4,
+            id: 5,
+        }
+    }
+
+    #[test]
+    fn test_current_layout_reads_every_operation_as_bincode_wrote_it() {
+        let mut row = Row::new();
+        row.set("id", 1i64);
+        row.schema_version = 3;
+        let operations = vec![
+            WalOperation::CreateTable(schema()),
+            WalOperation::Insert { table: "users".to_string(), key: "5:a".to_string(), row },
+            WalOperation::Delete { table: "users".to_string(), key: "5:a".to_string() },
+            WalOperation::CreateDatabase { name: "db".to_string() },
+            WalOperation::AlterTable(schema()),
+            WalOperation::RenameTable { from: "a".to_string(), to: "b".to_string() },
+            WalOperation::RenameColumn { schema: schema(), from: "a".to_string(), to: "b".to_string() },
+            WalOperation::Begin(8),
+            WalOperation::Commit(8),
+            WalOperation::DropTable { name: "users".to_string(), id: 5 },
+        ];
+        for operation in operations {
+            let mut entry = WalEntry::new(operation).in_transaction(8);
+            entry.seq = 9;
+            let body = encode(&entry).unwrap();
+            let (decoded, layout) = decode_versioned(&body).unwrap();
+            assert_eq!(layout, CURRENT);
+            assert_eq!(bincode::serialize(&decoded).unwrap(), body[1..]);
+            // As written before records named their layout
+            let (decoded, layout) = decode_unversioned(&body[1..]).unwrap();
+            assert_eq!(layout, CURRENT);
+            assert_eq!(bincode::serialize(&decoded).unwrap(), body[1..]);
+        }
+    }
+
+    #[test]
+    fn test_newer_layout_is_rejected() {
+        let mut body = encode(&WalEntry::new(WalOperation::Begin(1))).unwrap();
+        body[0] = CURRENT + 1;
+        let err = decode_versioned(&body).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
+
+    #[test]
+    fn test_upgrade_fills_in_what_older_layouts_lack() {
+        // A table, its altered schema and a row, as written before schema
+        // versions: no sequence numbers either, and decimal integer keys
+        let mut upgrade = Upgrade::default();
+        let mut created = schema();
+        created.version = 0;
+        created.id = 0;
+        created.columns[0].added_in = 0;
+        let mut create = WalEntry::new(WalOperation::CreateTable(created));
+        upgrade.apply(&mut create, AUTO_INCREMENT);
+        let mut insert = WalEntry::new(WalOperation::Insert {
+            table: "users".to_string(),
+            key: "users:-2".to_string(),
+            row: Row::new(),
+        });
+        upgrade.apply(&mut insert, AUTO_INCREMENT);
+
+        let WalOperation::CreateTable(created) = &create.operation else { unreachable!() };
+        assert_eq!((create.seq, created.id, created.version, created.columns[0].added_in), (1, 1, 1, 1));
+        let WalOperation::Insert { key, row, .. } = &insert.operation else { unreachable!() };
+        assert_eq!(insert.seq, 2);
+        assert_eq!(*key, format!("users:{}", encode_key_value(&SqlValue::Integer(-2))));
+        assert_eq!(row.schema_version, 1);
+        assert!(upgrade.name_keyed());
+
+        // A column added by ALTER TABLE comes in with the new version
+        let mut altered = schema();
+        altered.version = 2;
+        altered.id = 0;
+        altered.columns[0].added_in = 0;
+        let mut email = altered.columns[0].clone();
+        email.name = "email".to_string();
+        email.primary_key = false;
+        altered.columns.push(email);
+        let mut alter = WalEntry::new(WalOperation::AlterTable(altered));
+        upgrade.apply(&mut alter, SHARD_COUNT);
+        let WalOperation::AlterTable(altered) = &alter.operation else { unreachable!() };
+        assert_eq!(altered.id, 1);
+        assert_eq!(altered.columns.iter().map(|c| c.added_in).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
@@ -1,3 +1,11 @@
+pub(crate) mod layout;
+pub mod registry;
+pub mod transaction;
 pub mod wal;
 
-pub use wal::{WriteAheadLog, WalEntry, WalOperation};
\ No newline at end of file
+pub use registry::{TransactionError, TransactionLimits, TransactionRegistry};
+pub use transaction::{Mutation, Overlay, Transaction};
+pub use wal::{
+    ExpiredSegments, WalIter, WalRetention, WalVerification, WriteAheadLog, WalEntry, WalError, WalOperation,
+    DEFAULT_MAX_SEGMENT_BYTES,
+};
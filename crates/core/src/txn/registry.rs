@@ -0,0 +1,193 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Limits on open transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionLimits {
+    /// Transactions that may be open at once; `begin` fails beyond it.
+    pub max_open: usize,
+    /// How long a transaction may go unused before it is rolled back.
+    pub idle_timeout: Duration,
+}
+
+impl Default for TransactionLimits {
+    fn default() -> Self {
+        Self { max_open: 1024, idle_timeout: Duration::from_secs(300) }
+    }
+}
+
+/// Failures a client sees when its transaction can't go on.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TransactionError {
+    #[error("Transaction {0} aborted due to idle timeout")]
+    IdleTimeout(u64),
+    #[error("Too many open transactions (max {0})")]
+    TooManyOpen(usize),
+    #[error("Transaction {0} is not open")]
+    NotOpen(u64),
+}
+
+/// The open transactions and the state each has buffered, `T`. Every
+/// access refreshes a transaction's idle clock; one left idle past
+/// `TransactionLimits::idle_timeout` is rolled back by `reap_idle` (or on
+/// its next access), its state dropped, and its owner told so once.
+/// Times are passed in, so callers and tests control the clock.
+#[derive(Debug)]
+pub struct TransactionRegistry<T> {
+    limits: TransactionLimits,
+    next_id: u64,
+    open: HashMap<u64, OpenTransaction<T>>,
+    /// Rolled back for idling and not yet reported to their owner.
+    timed_out: HashSet<u64>,
+}
+
+#[derive(Debug)]
+struct OpenTransaction<T> {
+    state: T,
+    last_used: Instant,
+}
+
+impl<T> TransactionRegistry<T> {
+    pub fn new(limits: TransactionLimits) -> Self {
+        Self { limits, next_id: 1, open: HashMap::new(), timed_out: HashSet::new() }
+    }
+
+    pub fn limits(&self) -> TransactionLimits {
+        self.limits
+    }
+
+    /// Opens a transaction holding `state` and returns its id. Idle
+    /// transactions are reaped first, so they don't count toward the cap.
+    pub fn begin(&mut self, state: T, now: Instant) -> Result<u64> {
+        self.reap_idle(now);
+        if self.open.len() >= self.limits.max_open {
+            return Err(TransactionError::TooManyOpen(self.limits.max_open).into());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, OpenTransaction { state, last_used: now });
+        Ok(id)
+    }
+
+    /// The state of transaction `id`, marking it used at `now`.
+    pub fn get_mut(&mut self, id: u64, now: Instant) -> Result<&mut T> {
+        self.expire(id, now)?;
+        let transaction = self.open.get_mut(&id).ok_or(TransactionError::NotOpen(id))?;
+        transaction.last_used = now;
+        Ok(&mut transaction.state)
+    }
+
+    /// Closes transaction `id`, for commit or rollback, returning its state.
+    pub fn finish(&mut self, id: u64, now: Instant) -> Result<T> {
+        self.expire(id, now)?;
+        let transaction = self.open.remove(&id).ok_or(TransactionError::NotOpen(id))?;
+        Ok(transaction.state)
+    }
+
+    /// Drops transaction `id` without reporting anything, for an owner
+    /// that went away, such as a closed connection.
+    pub fn forget(&mut self, id: u64) {
+        self.open.remove(&id);
+        self.timed_out.remove(&id);
+    }
+
+    /// Rolls back every transaction idle for longer than the timeout,
+    /// dropping its state. Returns how many were rolled back.
+    pub fn reap_idle(&mut self, now: Instant) -> usize {
+        let timeout = self.limits.idle_timeout;
+        let idle: Vec<u64> = self
+            .open
+            .iter()
+            .filter(|(_, transaction)| now.saturating_duration_since(transaction.last_used) > timeout)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &idle {
+            tracing::info!("Rolling back transaction {} after {:?} idle", id, timeout);
+            self.open.remove(id);
+            self.timed_out.insert(*id);
+        }
+        idle.len()
+    }
+
+    /// Whether transaction `id` is still open, without touching its clock.
+    pub fn is_open(&self, id: u64) -> bool {
+        self.open.contains_key(&id)
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Fails with `IdleTimeout`, once, if `id` was or is now rolled back
+    /// for idling.
+    fn expire(&mut self, id: u64, now: Instant) -> Result<()> {
+        let idle = self
+            .open
+            .get(&id)
+            .is_some_and(|transaction| now.saturating_duration_since(transaction.last_used) > self.limits.idle_timeout);
+        if idle {
+            self.open.remove(&id);
+            self.timed_out.insert(id);
+        }
+        if self.timed_out.remove(&id) {
+            return Err(TransactionError::IdleTimeout(id).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(max_open: usize) -> TransactionRegistry<Vec<&'static str>> {
+        TransactionRegistry::new(TransactionLimits { max_open, idle_timeout: Duration::from_secs(60) })
+    }
+
+    fn error(result: Result<impl std::fmt::Debug>) -> TransactionError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn test_idle_transaction_is_rolled_back() {
+        let start = Instant::now();
+        let mut transactions = registry(10);
+        let idle = transactions.begin(vec![], start).unwrap();
+        let busy = transactions.begin(vec![], start).unwrap();
+        transactions.get_mut(idle, start).unwrap().push("insert 1");
+
+        // Use keeps a transaction alive past the timeout from its start
+        let later = start + Duration::from_secs(45);
+        transactions.get_mut(busy, later).unwrap().push("insert 2");
+        assert_eq!(transactions.reap_idle(start + Duration::from_secs(90)), 1);
+        assert_eq!(transactions.open_count(), 1);
+
+        let err = error(transactions.get_mut(idle, start + Duration::from_secs(91)));
+        assert_eq!(err, TransactionError::IdleTimeout(idle));
+        assert_eq!(err.to_string(), format!("Transaction {} aborted due to idle timeout", idle));
+        // Reported once; after that it is simply gone
+        assert_eq!(error(transactions.finish(idle, start)), TransactionError::NotOpen(idle));
+
+        // Expiry is also noticed on access, without a reap in between
+        let now = start + Duration::from_secs(200);
+        assert_eq!(error(transactions.finish(busy, now)), TransactionError::IdleTimeout(busy));
+        assert_eq!(transactions.open_count(), 0);
+    }
+
+    #[test]
+    fn test_max_open_transactions() {
+        let now = Instant::now();
+        let mut transactions = registry(2);
+        let first = transactions.begin(vec![], now).unwrap();
+        transactions.begin(vec![], now).unwrap();
+        let err = error(transactions.begin(vec![], now));
+        assert_eq!(err, TransactionError::TooManyOpen(2));
+
+        // Finishing one, or idle ones timing out, makes room
+        assert_eq!(transactions.finish(first, now).unwrap(), Vec::<&str>::new());
+        transactions.begin(vec![], now).unwrap();
+        transactions.begin(vec![], now + Duration::from_secs(61)).unwrap();
+        assert_eq!(transactions.open_count(), 1);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::storage::bptree::BPlusTree;
+use crate::txn::wal::WalOperation;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// What a transaction's writes leave of the keys they touch, by key: the
+/// value inserted, or `None` for a delete. See `Transaction::overlay`.
+pub type Overlay = BTreeMap<String, Option<Vec<u8>>>;
+
+/// A change to one storage key, held back until its transaction commits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    Insert { key: String, value: Vec<u8> },
+    Delete { key: String },
+}
+
+impl Mutation {
+    pub fn key(&self) -> &str {
+        match self {
+            Mutation::Insert { key, .. } | Mutation::Delete { key } => key,
+        }
+    }
+}
+
+/// The writes of an open transaction, in statement order: what it will
+/// log, and what it will then do to storage. Nothing reaches the WAL or
+/// storage before `COMMIT`, and `ROLLBACK` simply drops it.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    operations: Vec<WalOperation>,
+    mutations: Vec<Mutation>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers one write: `operation` is logged and `mutation` applied at
+    /// commit.
+    pub fn push(&mut self, operation: WalOperation, mutation: Mutation) {
+        self.operations.push(operation);
+        self.mutations.push(mutation);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn operations(&self) -> &[WalOperation] {
+        &self.operations
+    }
+
+    /// Whether `key` is present once the buffered writes are applied, if
+    /// any of them touches it; `None` leaves it to storage.
+    pub fn key_present(&self, key: &str) -> Option<bool> {
+        self.mutations
+            .iter()
+            .rev()
+            .find(|mutation| mutation.key() == key)
+            .map(|mutation| matches!(mutation, Mutation::Insert { .. }))
+    }
+
+    /// What the buffered writes leave of each key under `prefix` they touch,
+    /// in key order: its value if inserted, `None` if deleted. Reads in the
+    /// transaction see these in place of what storage holds.
+    pub fn overlay(&self, prefix: &str) -> Overlay {
+        let mut overlay = Overlay::new();
+        for mutation in self.mutations.iter().filter(|mutation| mutation.key().starts_with(prefix)) {
+            let value = match mutation {
+                Mutation::Insert { value, .. } => Some(value.clone()),
+                Mutation::Delete { .. } => None,
+            };
+            overlay.insert(mutation.key().to_string(), value);
+        }
+        overlay
+    }
+
+    /// Fails if another session has since stored a key this transaction
+    /// inserts, which applying it would overwrite.
+    pub fn check(&self, storage: &BPlusTree) -> Result<()> {
+        let mut present: HashMap<&str, bool> = HashMap::new();
+        for mutation in &self.mutations {
+            let key = mutation.key();
+            if let Mutation::Insert { .. } = mutation {
+                let taken = match present.get(key) {
+                    Some(&taken) => taken,
+                    None => storage.contains_key(key)?,
+                };
+                if taken {
+                    return Err(anyhow!("Row '{}' was inserted by another session during the transaction", key));
+                }
+            }
+            present.insert(key, matches!(mutation, Mutation::Insert { .. }));
+        }
+        Ok(())
+    }
+
+    /// Makes the buffered writes to `storage`, in order.
+    pub fn apply(&self, storage: &mut BPlusTree) -> Result<()> {
+        for mutation in &self.mutations {
+            match mutation {
+                Mutation::Insert { key, value } => storage.insert(key.clone(), value.clone())?,
+                Mutation::Delete { key } => {
+                    storage.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
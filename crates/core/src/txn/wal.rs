@@ -1,15 +1,138 @@
+use crate::clock::{SharedClock, SystemClock};
 use crate::sql::engine::{Row, TableSchema};
+use crate::txn::layout::{self, Upgrade};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use std::collections::HashMap;
+use tokio::fs::{File, OpenOptions};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
 use uuid::Uuid;
 
+#[cfg(test)]
+thread_local! {
+    static FILE_OPENS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Number of times a log on this thread opened its file for appending.
+#[cfg(test)]
+pub fn file_opens() -> usize {
+    FILE_OPENS.with(|opens| opens.get())
+}
+
+#[cfg(any(test, feature = "bench"))]
+thread_local! {
+    static FILE_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Number of reads replays on this thread issued against their files.
+/// Only differences are meaningful: take one before and after a replay.
+#[cfg(any(test, feature = "bench"))]
+pub fn file_reads() -> usize {
+    FILE_READS.with(|reads| reads.get())
+}
+
+#[cfg(test)]
+thread_local! {
+    static WRITE_ATTEMPTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static FAILING_WRITES: std::cell::RefCell<std::collections::VecDeque<std::io::ErrorKind>> =
+        const { std::cell::RefCell::new(std::collections::VecDeque::new()) };
+}
+
+/// Number of times an append on this thread tried to write its record.
+#[cfg(test)]
+pub fn write_attempts() -> usize {
+    WRITE_ATTEMPTS.with(|attempts| attempts.get())
+}
+
+/// Makes the next appends' writes on this thread fail with `kinds`, one
+/// write per kind, before anything reaches the file.
+#[cfg(test)]
+pub fn fail_next_writes(kinds: &[std::io::ErrorKind]) {
+    FAILING_WRITES.with(|failing| failing.borrow_mut().extend(kinds));
+}
+
+/// Attempts `append` makes at writing a record before giving up on an
+/// error that might go away by itself, such as an interrupted call.
+const MAX_WRITE_ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry of a write, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Bytes `replay` reads from the file at a time, unless changed with
+/// `WriteAheadLog::set_replay_buffer_size`.
+pub const DEFAULT_REPLAY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Size past which `append` moves on to a new segment file, unless changed
+/// with `WriteAheadLog::set_max_segment_bytes`.
+pub const DEFAULT_MAX_SEGMENT_BYTES: u64 = 64 << 20;
+
+/// Set in a record's length prefix when a CRC32 of the body follows it.
+/// Records written before checksums have it clear and are read unchecked,
+/// though not after a record with it set: that is a flipped bit.
+pub(crate) const CHECKSUMMED: u32 = 1 << 31;
+
+/// Set in a record's length prefix, with `CHECKSUMMED`, when the CRC32
+/// covers the length prefix too. Records checksummed before cover only
+/// their body.
+pub(crate) const PREFIX_CHECKSUMMED: u32 = 1 << 29;
+
+/// Set in a record's length prefix when the body starts with its layout,
+/// see `layout`. The layout of a record written before is found by trying
+/// each.
+pub(crate) const VERSIONED: u32 = 1 << 30;
+
+/// Failures callers are expected to tell apart from other I/O errors.
+#[derive(Debug, thiserror::Error)]
+pub enum WalError {
+    /// The disk holding the log is full. The write was not logged or
+    /// applied, and writes are rejected until space is freed and a
+    /// checkpoint succeeds; reads are unaffected.
+    #[error("Disk full: the write was rejected and the database is read-only until space is freed and CHECKPOINT succeeds")]
+    DiskFull,
+    /// A tailing reader's next entries were dropped by compaction before it
+    /// read them; it has to start over from a snapshot.
+    #[error("WAL entries from seq {0} were compacted away before they were read")]
+    Compacted(u64),
+    /// The record at this file offset doesn't match its checksum, as a torn
+    /// or corrupted write leaves it.
+    #[error("WAL record at offset {0} fails its checksum")]
+    ChecksumMismatch(u64),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalEntry {
     pub id: Uuid,
+    /// Monotonic sequence number assigned by `WriteAheadLog::append`.
+    /// Snapshots record the last sequence they contain, so recovery knows
+    /// which entries still need replaying.
+    pub seq: u64,
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub operation: WalOperation,
+    /// Transaction the write belongs to. Replay applies a transaction's
+    /// writes only once it reaches the matching `Commit`; untagged writes
+    /// apply on their own.
+    pub tx_id: Option<u64>,
+}
+
+impl WalEntry {
+    pub fn new(operation: WalOperation) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            seq: 0,
+            timestamp: chrono::Utc::now(),
+            operation,
+            tx_id: None,
+        }
+    }
+
+    /// Tags the entry as a write of transaction `tx_id`.
+    pub fn in_transaction(mut self, tx_id: u64) -> Self {
+        self.tx_id = Some(tx_id);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,103 +143,415 @@ pub enum WalOperation {
         key: String,
         row: Row,
     },
+    Delete {
+        table: String,
+        key: String,
+    },
+    CreateDatabase {
+        name: String,
+    },
+    /// The table's full schema after an ALTER TABLE.
+    AlterTable(TableSchema),
+    /// The table's rows move from the `from` key prefix to `to`.
+    RenameTable {
+        from: String,
+        to: String,
+    },
+    /// `schema` is the table after the rename; each stored row's `from`
+    /// value moves to `to`.
+    RenameColumn {
+        schema: TableSchema,
+        from: String,
+        to: String,
+    },
+    /// Opens transaction `tx_id`; its writes follow, tagged with the id.
+    Begin(u64),
+    /// Makes transaction `tx_id`'s writes take effect on replay.
+    Commit(u64),
+    /// The table and all its rows, found by table `id`, are removed.
+    DropTable {
+        name: String,
+        id: u64,
+    },
 }
 
+/// Not `Clone`: two copies would each cache their own `entries` while
+/// appending to the same file. Share one instance behind `Arc<RwLock<_>>`.
+///
+/// The log is a series of segment files, see `segment_path`. Appends go to
+/// the last one until it would grow past `max_segment_bytes`, then to a new
+/// one; `compact` and `truncate` fold the log back into the first.
 #[derive(Debug)]
 pub struct WriteAheadLog {
     path: String,
     entries: Vec<WalEntry>,
+    next_seq: u64,
+    /// Set when an append hits a full disk, cleared by a successful
+    /// `compact`, which shows the disk takes writes again.
+    read_only: bool,
+    /// Append handle, opened on first use and kept so an append costs no
+    /// `open`. Dropped whenever the active segment is replaced, emptied or
+    /// rotated away from.
+    file: Option<File>,
+    /// Stamps entries made by `entry`.
+    clock: SharedClock,
+    replay_buffer_size: usize,
+    /// Number of the segment appends go to, and its size.
+    segment: u32,
+    segment_bytes: u64,
+    max_segment_bytes: u64,
+    /// Whether the last replay found rows keyed by table name, as written
+    /// before table ids.
+    name_keyed: bool,
+    /// Tables the last replay found named before names were folded.
+    unfolded: HashMap<u64, TableSchema>,
 }
 
 impl WriteAheadLog {
     pub async fn new(path: &str) -> Result<Self> {
-        let wal = Self {
+        let mut wal = Self {
             path: path.to_string(),
             entries: Vec::new(),
+            next_seq: 1,
+            read_only: false,
+            file: None,
+            clock: SystemClock::shared(),
+            replay_buffer_size: DEFAULT_REPLAY_BUFFER_SIZE,
+            segment: 1,
+            segment_bytes: 0,
+            max_segment_bytes: DEFAULT_MAX_SEGMENT_BYTES,
+            name_keyed: false,
+            unfolded: HashMap::new(),
         };
         
         // Create WAL file if it doesn't exist
-        if !tokio::fs::metadata(&wal.path).await.is_ok() {
+        if tokio::fs::metadata(&wal.path).await.is_err() {
             tokio::fs::File::create(&wal.path).await?;
         }
+
+        // Carry on appending to the last segment
+        if let Some(&last) = segments(path).await?.last() {
+            wal.segment = last;
+            wal.segment_bytes = tokio::fs::metadata(segment_path(path, last)).await?.len();
+        }
         
         Ok(wal)
     }
 
-    pub async fn append(&mut self, entry: &WalEntry) -> Result<()> {
-        // Serialize entry
-        let serialized = bincode::serialize(entry)?;
-        let size = serialized.len() as u32;
-        
-        // Open file in append mode
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)
-            .await?;
-        
-        // Write size prefix followed by entry
-        file.write_all(&size.to_le_bytes()).await?;
-        file.write_all(&serialized).await?;
-        file.sync_all().await?;
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// Sets how many bytes `replay` reads at a time. An entry larger than
+    /// this is still read whole, into a buffer grown to fit it.
+    pub fn set_replay_buffer_size(&mut self, bytes: usize) {
+        self.replay_buffer_size = bytes.max(4);
+    }
+
+    /// Sets the size past which appends start a new segment. A single entry
+    /// larger than this still goes whole into a segment of its own.
+    pub fn set_max_segment_bytes(&mut self, bytes: u64) {
+        self.max_segment_bytes = bytes;
+    }
+
+    /// The time by the log's clock, which timestamps its entries.
+    pub fn utc_now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.utc_now()
+    }
+
+    /// A new entry for `operation`, timestamped by the log's clock.
+    pub fn entry(&self, operation: WalOperation) -> WalEntry {
+        WalEntry { timestamp: self.clock.utc_now(), ..WalEntry::new(operation) }
+    }
+
+    /// Appends an entry, stamping it with the next sequence number, which
+    /// is returned. A failed write leaves no part of the entry in the file.
+    /// A write failing with an error that may pass, such as EINTR or
+    /// EAGAIN, is retried with backoff up to `MAX_WRITE_ATTEMPTS` times;
+    /// any other error fails the append at once. If the disk is full the
+    /// log turns read-only: this and every later append fail with
+    /// `WalError::DiskFull` until `compact` succeeds.
+    pub async fn append(&mut self, entry: &WalEntry) -> Result<u64> {
+        if self.read_only {
+            return Err(WalError::DiskFull.into());
+        }
+
+        let mut entry = entry.clone();
+        entry.seq = self.next_seq;
+
+        let record = encode_record(&entry)?;
+        if self.segment_bytes > 0 && self.segment_bytes + record.len() as u64 > self.max_segment_bytes {
+            self.rotate().await?;
+        }
+        let mut attempt = 1;
+        let mut backoff = RETRY_BACKOFF;
+        while let Err(e) = self.write_record(&record).await {
+            if is_transient(&e) && attempt < MAX_WRITE_ATTEMPTS {
+                tracing::warn!(
+                    "WAL write failed ({}), retrying in {:?} (attempt {} of {})",
+                    e,
+                    backoff,
+                    attempt + 1,
+                    MAX_WRITE_ATTEMPTS
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                backoff *= 2;
+                continue;
+            }
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                tracing::error!("WAL disk is full, rejecting writes until a checkpoint succeeds");
+                self.read_only = true;
+                return Err(WalError::DiskFull.into());
+            }
+            return Err(e.into());
+        }
         
         // Add to in-memory cache
-        self.entries.push(entry.clone());
+        self.next_seq += 1;
+        let seq = entry.seq;
+        self.entries.push(entry);
         
+        Ok(seq)
+    }
+
+    /// Writes `record` at the end of the file and syncs it. On failure,
+    /// whatever part was written is cut off again, so replay doesn't find a
+    /// torn entry the caller was told failed.
+    async fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        #[cfg(test)]
+        {
+            WRITE_ATTEMPTS.with(|attempts| attempts.set(attempts.get() + 1));
+            if let Some(kind) = FAILING_WRITES.with(|failing| failing.borrow_mut().pop_front()) {
+                return Err(kind.into());
+            }
+        }
+        let file = self.append_handle().await?;
+        let len_before = file.metadata().await?.len();
+        let written = async {
+            file.write_all(record).await?;
+            // tokio finishes the write in the background; `flush` reports
+            // its error, which `sync_all` would drop
+            file.flush().await?;
+            file.sync_all().await
+        }
+        .await;
+        match written {
+            Ok(()) => self.segment_bytes = len_before + record.len() as u64,
+            Err(_) => {
+                if let Err(truncate_error) = file.set_len(len_before).await {
+                    tracing::error!("Failed to remove partial WAL entry: {}", truncate_error);
+                }
+                // Start the next write from a fresh handle
+                self.file = None;
+            }
+        }
+        written
+    }
+
+    /// Closes the active segment and starts the next one.
+    async fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        self.segment += 1;
+        self.segment_bytes = 0;
+        self.append_handle().await?;
+        let path = segment_path(&self.path, self.segment);
+        crate::storage::fsync::sync_parent_dir(&path)?;
+        tracing::info!("WAL rotated to segment {}", path);
         Ok(())
     }
 
+    /// The open append handle, opening the active segment if there is none.
+    async fn append_handle(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            #[cfg(test)]
+            FILE_OPENS.with(|opens| opens.set(opens.get() + 1));
+            let path = segment_path(&self.path, self.segment);
+            let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    /// Whether appends are being rejected because the disk filled up.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Reads the whole log back, every segment in order, caching its
+    /// entries. Each file is read a buffer at a time and every entry wholly
+    /// inside the buffer is decoded from it, so a log of many small entries
+    /// costs few reads. A record cut short at the end of the last segment,
+    /// as a crash mid-append leaves it, ends the log as a corrupt record
+    /// does; in an earlier segment, a length prefix cut short ends it and an
+    /// entry body cut short is an error. A record failing its checksum ends
+    /// the log: it and the rest of its segment
+    /// are moved to `<segment>.corrupt` and cut from the file, any later
+    /// segments are renamed to `<segment>.corrupt` too, so later appends
+    /// aren't stranded behind the bad record, and the entries before it are
+    /// returned. Entries written in an older layout are upgraded, see
+    /// `layout::Upgrade`; any numbered no later than one read already, as
+    /// segments `compact` didn't get to remove hold, are skipped.
     pub async fn replay(&mut self) -> Result<Vec<WalEntry>> {
         let mut entries = Vec::new();
+        let mut upgrade = Upgrade::default();
+        let segments = segments(&self.path).await?;
+        for (i, &segment) in segments.iter().enumerate() {
+            let path = segment_path(&self.path, segment);
+            let last = i + 1 == segments.len();
+            if let Some(offset) = self.replay_segment(&path, &mut entries, &mut upgrade, last).await? {
+                self.cut_corrupt_tail(&path, offset).await?;
+                for &later in &segments[i + 1..] {
+                    let later = segment_path(&self.path, later);
+                    tokio::fs::rename(&later, format!("{}.corrupt", later)).await?;
+                    tracing::warn!("Moved WAL segment {} after a corrupt record to {}.corrupt", later, later);
+                }
+                self.segment = segment;
+                self.segment_bytes = offset;
+                break;
+            }
+        }
+        
+        // Update in-memory cache
+        if let Some(last) = entries.last() {
+            self.next_seq = self.next_seq.max(last.seq + 1);
+        }
+        self.name_keyed = upgrade.name_keyed();
+        self.unfolded = upgrade.unfolded().clone();
+        self.entries = entries.clone();
         
-        // Check if file exists and has content
-        let metadata = match tokio::fs::metadata(&self.path).await {
+        Ok(entries)
+    }
+
+    /// Reads the segment at `path` onto `entries`, returning the offset of
+    /// the record failing its checksum, if one does, or, in the `last`
+    /// segment, of a record cut short.
+    async fn replay_segment(
+        &self,
+        path: &str,
+        entries: &mut Vec<WalEntry>,
+        upgrade: &mut Upgrade,
+        last: bool,
+    ) -> Result<Option<u64>> {
+        let metadata = match tokio::fs::metadata(path).await {
             Ok(metadata) => metadata,
-            Err(_) => return Ok(entries), // File doesn't exist, no entries to replay
+            Err(_) => return Ok(None), // File doesn't exist, no entries to replay
         };
         
         if metadata.len() == 0 {
-            return Ok(entries);
+            return Ok(None);
         }
         
-        // Read all entries from file
-        let file = tokio::fs::File::open(&self.path).await?;
-        let mut reader = BufReader::new(file);
-        
+        let mut file = File::open(path).await?;
+        let mut buffer = vec![0u8; self.replay_buffer_size];
+        // `buffer[..filled]` holds bytes read but not yet decoded, starting
+        // at an entry's length prefix
+        let mut filled = 0;
+        // File offset of `buffer[0]`
+        let mut consumed = 0u64;
+        let mut checksummed = false;
         loop {
-            // Read size prefix
-            let mut size_buf = [0u8; 4];
-            match reader.read_exact(&mut size_buf).await {
-                Ok(_) => {},
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e.into()),
+            let mut start = 0;
+            let mut needed = 4;
+            while filled - start >= 4 {
+                needed = record_len(buffer[start..start + 4].try_into().unwrap());
+                if filled - start < needed {
+                    break;
+                }
+                let offset = consumed + start as u64;
+                match decode_record(&buffer[start..start + needed], offset, &mut checksummed) {
+                    // Left by a compaction cut short, an entry numbered no
+                    // later than one read already repeats it
+                    Ok((entry, layout))
+                        if layout >= layout::SEQ && entries.last().is_some_and(|e| entry.seq <= e.seq) => {}
+                    Ok((mut entry, layout)) => {
+                        upgrade.apply(&mut entry, layout);
+                        entries.push(entry);
+                    }
+                    Err(e) if matches!(e.downcast_ref::<WalError>(), Some(WalError::ChecksumMismatch(_))) => {
+                        tracing::warn!("{} of {}; replaying the {} entries before it", e, path, entries.len());
+                        return Ok(Some(offset));
+                    }
+                    Err(e) => return Err(e),
+                }
+                start += needed;
+                needed = 4;
             }
-            
-            let size = u32::from_le_bytes(size_buf) as usize;
-            
-            // Read entry data
-            let mut entry_buf = vec![0u8; size];
-            reader.read_exact(&mut entry_buf).await?;
-            
-            // Deserialize entry
-            let entry: WalEntry = bincode::deserialize(&entry_buf)?;
-            entries.push(entry);
+            buffer.copy_within(start..filled, 0);
+            filled -= start;
+            consumed += start as u64;
+            if needed > buffer.len() {
+                // No bigger than the file, in case the prefix is garbage
+                buffer.resize(needed.min(metadata.len() as usize), 0);
+            }
+
+            #[cfg(any(test, feature = "bench"))]
+            FILE_READS.with(|reads| reads.set(reads.get() + 1));
+            let read = file.read(&mut buffer[filled..]).await?;
+            if read == 0 {
+                if filled > 0 && last {
+                    tracing::warn!(
+                        "WAL {} ends inside an entry, {} bytes in; replaying the {} entries before it",
+                        path,
+                        filled,
+                        entries.len()
+                    );
+                    return Ok(Some(consumed));
+                }
+                if filled >= 4 {
+                    return Err(anyhow::anyhow!(
+                        "WAL ends inside an entry: {} of its {} bytes are present",
+                        filled,
+                        needed
+                    ));
+                }
+                break;
+            }
+            filled += read;
         }
-        
-        // Update in-memory cache
-        self.entries = entries.clone();
-        
-        Ok(entries)
+        Ok(None)
+    }
+
+    /// Moves the segment at `path` from `offset` on to `<path>.corrupt`,
+    /// replacing any earlier one, and truncates the segment there.
+    async fn cut_corrupt_tail(&mut self, path: &str, offset: u64) -> Result<()> {
+        let data = tokio::fs::read(path).await?;
+        let corrupt_path = format!("{}.corrupt", path);
+        tokio::fs::write(&corrupt_path, &data[offset as usize..]).await?;
+        tracing::warn!("Moved {} bytes of WAL from offset {} to {}", data.len() as u64 - offset, offset, corrupt_path);
+        let file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(offset).await?;
+        file.sync_all().await?;
+        self.file = None;
+        Ok(())
+    }
+
+    /// Reads the whole log at `path`, every segment, without applying or
+    /// caching anything, checking that each entry's length prefix fits in
+    /// the file, that it matches its checksum and that its body
+    /// deserializes. Stops at the first bad entry, since nothing after a bad
+    /// length can be located; one outside the first segment has the
+    /// segment's file named in its problem. For diagnosing a log the
+    /// database won't start from; a missing file verifies as empty.
+    pub async fn verify(path: &str) -> Result<WalVerification> {
+        let mut verification = WalVerification::default();
+        for segment in segments(path).await? {
+            let file = segment_path(path, segment);
+            let data = tokio::fs::read(&file).await?;
+            verification.file_bytes += data.len() as u64;
+            if verification.first_bad.is_none() {
+                if let Some((offset, problem)) = verify_segment(&data, &mut verification.valid_entries) {
+                    let problem = if segment == 1 { problem } else { format!("in {}: {}", file, problem) };
+                    verification.first_bad = Some((offset, problem));
+                }
+            }
+        }
+        Ok(verification)
     }
 
     pub async fn sync(&mut self) -> Result<()> {
         // Force sync to disk
-        let file = OpenOptions::new()
-            .write(true)
-            .open(&self.path)
-            .await?;
-        
-        file.sync_all().await?;
+        self.append_handle().await?.sync_all().await?;
         Ok(())
     }
 
@@ -129,10 +564,165 @@ impl WriteAheadLog {
             .await?;
         
         file.sync_all().await?;
+        if self.remove_later_segments().await? {
+            crate::storage::fsync::sync_parent_dir(&self.path)?;
+        }
         self.entries.clear();
+        self.file = None;
+        self.segment = 1;
+        self.segment_bytes = 0;
+        Ok(())
+    }
+
+    /// Deletes every segment after the first, oldest first, so a crash part
+    /// way leaves the newest ones. Returns whether there were any.
+    async fn remove_later_segments(&mut self) -> Result<bool> {
+        let later: Vec<u32> = segments(&self.path).await?.into_iter().filter(|&segment| segment > 1).collect();
+        for &segment in &later {
+            tokio::fs::remove_file(segment_path(&self.path, segment)).await?;
+        }
+        Ok(!later.is_empty())
+    }
+
+    /// Current size of the log in bytes, over all its segments.
+    pub async fn size_bytes(&self) -> Result<u64> {
+        let mut bytes = 0;
+        for segment in segments(&self.path).await? {
+            bytes += tokio::fs::metadata(segment_path(&self.path, segment)).await?.len();
+        }
+        Ok(bytes)
+    }
+
+    /// Number of segment files the log is made of.
+    pub async fn segment_count(&self) -> Result<usize> {
+        Ok(segments(&self.path).await?.len())
+    }
+
+    /// Drops every entry a snapshot now covers, keeping only catalog
+    /// changes (databases and table schemas), which snapshots don't record.
+    /// The retained entries are written to a temporary path and renamed over
+    /// the first segment, so a crash leaves either the old or the new one,
+    /// and then the later segments, all covered, are deleted. Returns the
+    /// number of bytes reclaimed. Having written a file, it also lifts the
+    /// read-only mode a full disk causes.
+    pub async fn compact(&mut self) -> Result<u64> {
+        let before = self.size_bytes().await?;
+        let retained: Vec<WalEntry> =
+            self.entries.iter().filter(|entry| is_catalog(&entry.operation)).cloned().collect();
+
+        Self::write_log(&self.path, &retained).await?;
+        // Left behind by a crash here, they replay after the first segment:
+        // their entries numbered before its last are skipped as repeats,
+        // and the data operations after those are covered by the snapshot
+        if self.remove_later_segments().await? {
+            crate::storage::fsync::sync_parent_dir(&self.path)?;
+        }
+        // The held handle still points at the replaced file
+        self.file = None;
+        self.segment = 1;
+        self.segment_bytes = tokio::fs::metadata(&self.path).await?.len();
+
+        self.entries = retained;
+        self.read_only = false;
+        let after = self.size_bytes().await?;
+        Ok(before.saturating_sub(after))
+    }
+
+    /// Writes a log file at `path` holding `entries` as they are, sequence
+    /// numbers included. It is written to a temporary path and renamed over
+    /// `path`, so a crash leaves either the old file or the new one.
+    pub async fn write_log(path: &str, entries: &[WalEntry]) -> Result<()> {
+        let temp_path = format!("{}.compact", path);
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        for entry in entries {
+            file.write_all(&encode_record(entry)?).await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, path).await?;
+        crate::storage::fsync::sync_parent_dir(path)?;
         Ok(())
     }
 
+    /// The oldest segments outside `retention`'s window as of the log's
+    /// clock, if any: a run of them from the first, never including the
+    /// segment being appended to, and ending between transactions, so none
+    /// is split between what expires and what is kept.
+    pub async fn expired_segments(&self, retention: &WalRetention) -> Result<Option<ExpiredSegments>> {
+        let segments = segments(&self.path).await?;
+        let now = self.clock.utc_now();
+        let mut sealed = Vec::new();
+        let mut upgrade = Upgrade::default();
+        for &segment in &segments[..segments.len().saturating_sub(1)] {
+            let path = segment_path(&self.path, segment);
+            let mut entries = Vec::new();
+            if let Some(offset) = self.replay_segment(&path, &mut entries, &mut upgrade, false).await? {
+                return Err(anyhow::anyhow!("WAL segment {} is corrupt at offset {}", path, offset));
+            }
+            sealed.push((segment, tokio::fs::metadata(&path).await?.len(), entries));
+        }
+
+        let mut kept_bytes = match segments.last() {
+            Some(&active) => tokio::fs::metadata(segment_path(&self.path, active)).await?.len(),
+            None => 0,
+        };
+        // Segments from `expired` on are kept
+        let mut expired = 0;
+        for (i, (_, bytes, entries)) in sealed.iter().enumerate().rev() {
+            let too_old = match (retention.max_age, entries.last()) {
+                (Some(max_age), Some(newest)) => {
+                    now.signed_duration_since(newest.timestamp).to_std().unwrap_or_default() > max_age
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            let too_big = retention.max_bytes.is_some_and(|max_bytes| kept_bytes + bytes > max_bytes);
+            if too_old || too_big {
+                expired = i + 1;
+                break;
+            }
+            kept_bytes += bytes;
+        }
+        while expired > 0 && has_open_transaction(sealed[..expired].iter().flat_map(|(_, _, entries)| entries)) {
+            expired -= 1;
+        }
+        if expired == 0 {
+            return Ok(None);
+        }
+
+        let through = sealed[expired - 1].0;
+        let entries = sealed.into_iter().take(expired).flat_map(|(_, _, entries)| entries).collect();
+        Ok(Some(ExpiredSegments { through, entries }))
+    }
+
+    /// Removes the segments `expired` names, returning the bytes reclaimed.
+    /// Their catalog entries, which snapshots don't record, are kept in the
+    /// first segment, rewritten as `compact` does; the rest are deleted,
+    /// oldest first. Having written a file, it also lifts the read-only
+    /// mode a full disk causes.
+    pub async fn remove_expired(&mut self, expired: &ExpiredSegments) -> Result<u64> {
+        let before = self.size_bytes().await?;
+        let catalog: Vec<WalEntry> =
+            expired.entries.iter().filter(|entry| is_catalog(&entry.operation)).cloned().collect();
+        Self::write_log(&self.path, &catalog).await?;
+        for segment in segments(&self.path).await? {
+            if segment > 1 && segment <= expired.through {
+                tokio::fs::remove_file(segment_path(&self.path, segment)).await?;
+            }
+        }
+        crate::storage::fsync::sync_parent_dir(&self.path)?;
+        if self.segment == 1 {
+            self.file = None;
+            self.segment_bytes = tokio::fs::metadata(&self.path).await?.len();
+        }
+
+        if let Some(last) = expired.entries.last().map(|entry| entry.seq) {
+            self.entries.retain(|entry| entry.seq > last || is_catalog(&entry.operation));
+        }
+        self.read_only = false;
+        let after = self.size_bytes().await?;
+        Ok(before.saturating_sub(after))
+    }
+
     pub fn get_entries(&self) -> &[WalEntry] {
         &self.entries
     }
@@ -141,6 +731,31 @@ impl WriteAheadLog {
         self.entries.len()
     }
 
+    /// Whether the last `replay` found rows keyed by table name rather than
+    /// id, which a log from before table ids holds. They apply only to a
+    /// tree expecting such keys, see `BPlusTree::expect_name_prefixed_keys`.
+    pub fn has_name_keyed_entries(&self) -> bool {
+        self.name_keyed
+    }
+
+    /// The tables the last `replay` found named before names were folded
+    /// to lower case, by id, with the names they had then. See
+    /// `SqlEngine::fold_names`.
+    pub fn unfolded_names(&self) -> &HashMap<u64, TableSchema> {
+        &self.unfolded
+    }
+
+    /// Sequence number of the most recently appended entry, or 0.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Ensures future appends are numbered after `seq`. Needed after a
+    /// truncate, when the WAL is empty but a snapshot already covers `seq`.
+    pub fn advance_seq_past(&mut self, seq: u64) {
+        self.next_seq = self.next_seq.max(seq + 1);
+    }
+
     pub async fn checkpoint(&mut self) -> Result<()> {
         // Perform checkpoint - sync to disk and optionally truncate
         self.sync().await?;
@@ -154,6 +769,28 @@ impl WriteAheadLog {
         Ok(())
     }
 
+    /// Reads the log file from disk, rather than the in-memory cache,
+    /// yielding entries numbered `seq` or later. Independent of this log:
+    /// it opens the file itself, so it can run alongside appends.
+    pub fn iter_from(&self, seq: u64) -> WalIter {
+        Self::iter_file(&self.path, seq)
+    }
+
+    /// Like `iter_from`, for the log at `path`, without opening it.
+    pub fn iter_file(path: &str, seq: u64) -> WalIter {
+        WalIter {
+            path: path.to_string(),
+            next_seq: seq,
+            segment: 1,
+            offset: 0,
+            reader: None,
+            poll_interval: None,
+            reopened: false,
+            checksummed: false,
+            upgrade: Upgrade::default(),
+        }
+    }
+
     pub async fn get_entries_since(&self, timestamp: chrono::DateTime<chrono::Utc>) -> Vec<WalEntry> {
         self.entries
             .iter()
@@ -166,19 +803,384 @@ impl WriteAheadLog {
         self.entries
             .iter()
             .filter(|entry| match &entry.operation {
-                WalOperation::CreateTable(schema) => schema.name == table_name,
+                WalOperation::CreateTable(schema)
+                | WalOperation::AlterTable(schema)
+                | WalOperation::RenameColumn { schema, .. } => schema.name == table_name,
+                WalOperation::RenameTable { from, to } => from == table_name || to == table_name,
+                WalOperation::DropTable { name, .. } => name == table_name,
                 WalOperation::Insert { table, .. } => table == table_name,
+                WalOperation::Delete { table, .. } => table == table_name,
+                WalOperation::CreateDatabase { .. } | WalOperation::Begin(_) | WalOperation::Commit(_) => false,
             })
             .cloned()
             .collect()
     }
 }
 
-impl Clone for WriteAheadLog {
-    fn clone(&self) -> Self {
-        Self {
-            path: self.path.clone(),
-            entries: self.entries.clone(),
+/// Cursor over the entries of a log file, from `WriteAheadLog::iter_from`.
+/// Call `next` until it returns `None`, or, in follow mode, forever.
+#[derive(Debug)]
+pub struct WalIter {
+    path: String,
+    next_seq: u64,
+    /// Segment being read, and the offset in it of the next unread entry.
+    segment: u32,
+    offset: u64,
+    /// Whether an entry read from the segment had a checksum, see
+    /// `decode_record`.
+    checksummed: bool,
+    reader: Option<BufReader<File>>,
+    /// Set in follow mode: how long to wait before looking again at the end.
+    poll_interval: Option<Duration>,
+    /// Whether the file was replaced since the last entry was read.
+    reopened: bool,
+    upgrade: Upgrade,
+}
+
+impl WalIter {
+    /// At the end of the log, waits for more entries, checking every
+    /// `poll_interval`, instead of ending. A log replaced by compaction or
+    /// emptied by truncation is noticed by the segment being read having
+    /// shrunk below what was already read, or gone, and is read again from
+    /// the start.
+    pub fn follow(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = Some(poll_interval);
+        self
+    }
+
+    /// The next entry, or `None` at the end of the log when not following.
+    /// An entry only partly written yet counts as the end.
+    pub async fn next(&mut self) -> Result<Option<WalEntry>> {
+        loop {
+            match self.read_entry().await? {
+                Some(entry) if entry.seq < self.next_seq => continue,
+                Some(entry) => {
+                    if std::mem::take(&mut self.reopened) && entry.seq > self.next_seq {
+                        return Err(WalError::Compacted(self.next_seq).into());
+                    }
+                    self.next_seq = entry.seq + 1;
+                    return Ok(Some(entry));
+                }
+                None => {
+                    // Appends only move on once a segment is complete. The
+                    // next may not be numbered one on, once a retention
+                    // window has removed segments
+                    if let Some(next) = segments(&self.path).await?.into_iter().find(|&n| n > self.segment) {
+                        self.reader = None;
+                        self.segment = next;
+                        self.offset = 0;
+                        self.checksummed = false;
+                        continue;
+                    }
+                    let poll_interval = match self.poll_interval {
+                        Some(poll_interval) => poll_interval,
+                        None => return Ok(None),
+                    };
+                    let len = tokio::fs::metadata(segment_path(&self.path, self.segment)).await.ok().map(|m| m.len());
+                    let replaced = match len {
+                        Some(len) => len < self.offset,
+                        None => self.segment > 1 || self.offset > 0,
+                    };
+                    if replaced {
+                        self.reader = None;
+                        self.segment = 1;
+                        self.offset = 0;
+                        self.checksummed = false;
+                        self.reopened = true;
+                        self.upgrade = Upgrade::default();
+                        continue;
+                    }
+                    tokio::time::sleep(poll_interval).await;
+                }
+            }
+        }
+    }
+
+    /// Whether an entry read so far has a row keyed by table name, as
+    /// `WriteAheadLog::has_name_keyed_entries`.
+    pub fn has_name_keyed_entries(&self) -> bool {
+        self.upgrade.name_keyed()
+    }
+
+    /// Reads the entry at `offset`, or returns `None`, leaving `offset`
+    /// where it was, if the file ends first.
+    async fn read_entry(&mut self) -> Result<Option<WalEntry>> {
+        if self.reader.is_none() {
+            let mut file = match File::open(segment_path(&self.path, self.segment)).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            file.seek(SeekFrom::Start(self.offset)).await?;
+            self.reader = Some(BufReader::new(file));
+        }
+        let reader = self.reader.as_mut().unwrap();
+
+        let mut record = vec![0u8; 4];
+        let read = async {
+            reader.read_exact(&mut record).await?;
+            record.resize(record_len(record[..4].try_into().unwrap()), 0);
+            reader.read_exact(&mut record[4..]).await
+        }
+        .await;
+        match read {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                // Rewind past whatever part of the entry was read, so it is
+                // read whole once the writer finishes it
+                reader.seek(SeekFrom::Start(self.offset)).await?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let (mut entry, layout) = decode_record(&record, self.offset, &mut self.checksummed)?;
+        self.upgrade.apply(&mut entry, layout);
+        self.offset += record.len() as u64;
+        Ok(Some(entry))
+    }
+}
+
+/// Checks the records of one segment's `data`, counting the intact ones
+/// onto `valid_entries`, and returns the offset of the first bad one and
+/// what is wrong with it.
+fn verify_segment(data: &[u8], valid_entries: &mut usize) -> Option<(u64, String)> {
+    let mut offset = 0;
+    let mut checksummed = false;
+    while offset < data.len() {
+        let remaining = data.len() - offset;
+        let problem = if remaining < 4 {
+            format!("truncated length prefix ({} of 4 bytes)", remaining)
+        } else {
+            let size = record_len(data[offset..offset + 4].try_into().unwrap()) - 4;
+            if size > remaining - 4 {
+                format!("length prefix of {} bytes runs past the end of the file ({} bytes left)", size, remaining - 4)
+            } else {
+                match decode_record(&data[offset..offset + 4 + size], offset as u64, &mut checksummed) {
+                    Ok(_) => {
+                        *valid_entries += 1;
+                        offset += 4 + size;
+                        continue;
+                    }
+                    Err(e) => match e.downcast_ref::<WalError>() {
+                        Some(WalError::ChecksumMismatch(_)) => "checksum mismatch".to_string(),
+                        _ => format!("entry does not deserialize: {}", e),
+                    },
+                }
+            }
+        };
+        return Some((offset as u64, problem));
+    }
+    None
+}
+
+/// File holding segment `segment` of the log at `path`. The first segment
+/// is `path` itself, so a log that never rotated is the one file it always
+/// was; later ones are numbered before the extension, as in
+/// `wal.000002.log`.
+pub fn segment_path(path: &str, segment: u32) -> String {
+    if segment == 1 {
+        return path.to_string();
+    }
+    let (dir, prefix, suffix) = segment_name_parts(path);
+    dir.join(format!("{}{:06}{}", prefix, segment, suffix)).to_string_lossy().into_owned()
+}
+
+/// The directory of the log at `path`, and what comes before and after the
+/// number in its later segments' file names.
+fn segment_name_parts(path: &str) -> (&Path, String, String) {
+    let path = Path::new(path);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => (dir, format!("{}.", stem), format!(".{}", extension.to_string_lossy())),
+        None => (dir, format!("{}.", stem), String::new()),
+    }
+}
+
+/// Numbers of the segments of the log at `path` present on disk, in
+/// order.
+async fn segments(path: &str) -> Result<Vec<u32>> {
+    let mut segments = Vec::new();
+    if tokio::fs::metadata(path).await.is_ok() {
+        segments.push(1);
+    }
+    let (dir, prefix, suffix) = segment_name_parts(path);
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut listing = match tokio::fs::read_dir(dir).await {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(file) = listing.next_entry().await? {
+        let name = file.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix)?.strip_suffix(&suffix))
+            .filter(|digits| digits.len() >= 6 && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u32>().ok())
+            .filter(|&number| number > 1);
+        segments.extend(number);
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Whether `operation` changes the catalog (databases and table schemas),
+/// which snapshots don't record, so the WAL must keep it.
+pub(crate) fn is_catalog(operation: &WalOperation) -> bool {
+    matches!(
+        operation,
+        WalOperation::CreateTable(_)
+            | WalOperation::AlterTable(_)
+            | WalOperation::RenameTable { .. }
+            | WalOperation::RenameColumn { .. }
+            | WalOperation::DropTable { .. }
+            | WalOperation::CreateDatabase { .. }
+    )
+}
+
+/// Whether `entries` begin a transaction they don't commit.
+fn has_open_transaction<'a>(entries: impl Iterator<Item = &'a WalEntry>) -> bool {
+    let mut open = std::collections::HashSet::new();
+    for entry in entries {
+        match entry.operation {
+            WalOperation::Begin(tx_id) => open.insert(tx_id),
+            WalOperation::Commit(tx_id) => open.remove(&tx_id),
+            _ => false,
+        };
+    }
+    !open.is_empty()
+}
+
+/// Whether a failed write may succeed if simply tried again. A full disk or
+/// a permission error won't go away by itself, so neither counts.
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// The bytes stored for `entry`: a length prefix flagged `CHECKSUMMED`,
+/// `PREFIX_CHECKSUMMED` and `VERSIONED`, the body in the current layout,
+/// and the CRC32 of the prefix and body, all little-endian.
+fn encode_record(entry: &WalEntry) -> Result<Vec<u8>> {
+    let body = layout::encode(entry)?;
+    let mut record = Vec::with_capacity(body.len() + 8);
+    let flags = CHECKSUMMED | PREFIX_CHECKSUMMED | VERSIONED;
+    record.extend_from_slice(&(body.len() as u32 | flags).to_le_bytes());
+    record.extend_from_slice(&body);
+    let checksum = crc32(&record);
+    record.extend_from_slice(&checksum.to_le_bytes());
+    Ok(record)
+}
+
+/// Length of the whole record starting with length prefix `prefix`.
+fn record_len(prefix: [u8; 4]) -> usize {
+    let prefix = u32::from_le_bytes(prefix);
+    let body = (prefix & !(CHECKSUMMED | PREFIX_CHECKSUMMED | VERSIONED)) as usize;
+    if prefix & CHECKSUMMED != 0 {
+        4 + body + 4
+    } else {
+        4 + body
+    }
+}
+
+/// Decodes a whole record found at file `offset`, checking its checksum if
+/// it has one, into the entry as written and the layout it was written in.
+/// `checksummed` is whether an earlier record of the segment had one, and
+/// is set once one does; an unchecked record after that fails as corrupt.
+fn decode_record(record: &[u8], offset: u64, checksummed: &mut bool) -> Result<(WalEntry, layout::Layout)> {
+    let prefix = u32::from_le_bytes(record[..4].try_into().unwrap());
+    let mut body = &record[4..];
+    if prefix & CHECKSUMMED != 0 {
+        let (covered, checksum) = record.split_at(record.len() - 4);
+        let covered = if prefix & PREFIX_CHECKSUMMED != 0 { covered } else { &covered[4..] };
+        if crc32(covered) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(WalError::ChecksumMismatch(offset).into());
+        }
+        body = &body[..body.len() - 4];
+        *checksummed = true;
+    } else if *checksummed {
+        return Err(WalError::ChecksumMismatch(offset).into());
+    }
+    if prefix & VERSIONED != 0 {
+        layout::decode_versioned(body)
+    } else {
+        layout::decode_unversioned(body)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as zlib and PNG use), by table lookup.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |crc, &byte| TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// How much history a checkpoint leaves in the WAL, for point-in-time
+/// recovery and for tailing it. With neither limit set, the default, a
+/// checkpoint compacts the whole log. Otherwise segments are kept whole and
+/// removed oldest first once outside the window: when their newest entry
+/// is older than `max_age`, or when the newer segments already fill
+/// `max_bytes`. The segment being appended to is always kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalRetention {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+impl WalRetention {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// The oldest segments of a log that fell outside its retention window,
+/// from `WriteAheadLog::expired_segments`.
+#[derive(Debug, Clone)]
+pub struct ExpiredSegments {
+    /// Number of the newest of them; every segment up to it expired.
+    pub through: u32,
+    /// Their entries, in order.
+    pub entries: Vec<WalEntry>,
+}
+
+/// What `WriteAheadLog::verify` found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WalVerification {
+    /// Entries read intact before the first bad one.
+    pub valid_entries: usize,
+    pub file_bytes: u64,
+    /// Offset of the first entry that couldn't be read, and why.
+    pub first_bad: Option<(u64, String)>,
+}
+
+impl WalVerification {
+    pub fn is_valid(&self) -> bool {
+        self.first_bad.is_none()
+    }
+}
+
+impl std::fmt::Display for WalVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} valid entries in {} bytes", self.valid_entries, self.file_bytes)?;
+        match &self.first_bad {
+            Some((offset, problem)) => write!(f, "; first bad entry at offset {}: {}", offset, problem),
+            None => write!(f, "; no problems found"),
         }
     }
 }
@@ -186,6 +1188,7 @@ impl Clone for WriteAheadLog {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::{Clock, MockClock};
     use crate::sql::engine::{Column, Row, SqlDataType, SqlValue, TableSchema};
     use std::collections::HashMap;
     use tempfile::NamedTempFile;
@@ -204,11 +1207,13 @@ mod tests {
         
         let entry = WalEntry {
             id: Uuid::new_v4(),
+            seq: 0,
             timestamp: chrono::Utc::now(),
+            tx_id: None,
             operation: WalOperation::Insert {
                 table: "users".to_string(),
                 key: "users:1".to_string(),
-                row: Row { values: row_values },
+                row: Row { values: row_values, schema_version: 1 },
             },
         };
         
@@ -245,19 +1250,36 @@ mod tests {
                     data_type: SqlDataType::Integer,
                     nullable: false,
                     primary_key: true,
+                    auto_increment: false,
+                    generated: None,
+                    generated_expr: None,
+                    default: None,
+                    added_in: 1,
+                    not_null: false,
                 },
                 Column {
                     name: "name".to_string(),
                     data_type: SqlDataType::Varchar(100),
                     nullable: false,
                     primary_key: false,
+                    auto_increment: false,
+                    generated: None,
+                    generated_expr: None,
+                    default: None,
+                    added_in: 1,
+                    not_null: false,
                 },
             ],
+            version: 1,
+            shard_count: 1,
+            id: 1,
         };
         
         let entry = WalEntry {
             id: Uuid::new_v4(),
+            seq: 0,
             timestamp: chrono::Utc::now(),
+            tx_id: None,
             operation: WalOperation::CreateTable(schema.clone()),
         };
         
@@ -293,11 +1315,13 @@ mod tests {
             
             let entry = WalEntry {
                 id: Uuid::new_v4(),
+                seq: 0,
                 timestamp: chrono::Utc::now(),
-                operation: WalOperation::Insert {
+                tx_id: None,
+            operation: WalOperation::Insert {
                     table: "users".to_string(),
                     key: format!("users:{}", i),
-                    row: Row { values: row_values },
+                    row: Row { values: row_values, schema_version: 1 },
                 },
             };
             
@@ -309,8 +1333,10 @@ mod tests {
         let entries = new_wal.replay().await.unwrap();
         
         assert_eq!(entries.len(), 5);
+        assert_eq!(new_wal.last_seq(), 5);
         
         for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.seq, i as u64 + 1);
             match &entry.operation {
                 WalOperation::Insert { table, key, .. } => {
                     assert_eq!(table, "users");
@@ -333,11 +1359,13 @@ mod tests {
         
         let entry = WalEntry {
             id: Uuid::new_v4(),
+            seq: 0,
             timestamp: chrono::Utc::now(),
+            tx_id: None,
             operation: WalOperation::Insert {
                 table: "users".to_string(),
                 key: "users:1".to_string(),
-                row: Row { values: row_values },
+                row: Row { values: row_values, schema_version: 1 },
             },
         };
         
@@ -365,11 +1393,13 @@ mod tests {
         
         let entry = WalEntry {
             id: Uuid::new_v4(),
+            seq: 0,
             timestamp: chrono::Utc::now(),
+            tx_id: None,
             operation: WalOperation::Insert {
                 table: "users".to_string(),
                 key: "users:1".to_string(),
-                row: Row { values: row_values },
+                row: Row { values: row_values, schema_version: 1 },
             },
         };
         
@@ -385,6 +1415,37 @@ mod tests {
         assert_eq!(metadata.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_wal_compact_keeps_catalog_entries() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_str().unwrap();
+        let mut wal = WriteAheadLog::new(wal_path).await.unwrap();
+
+        let schema = TableSchema { name: "users".to_string(), columns: vec![], version: 1, shard_count: 1, id: 1 };
+        wal.append(&WalEntry::new(WalOperation::CreateTable(schema))).await.unwrap();
+        for i in 0..10 {
+            wal.append(&WalEntry::new(WalOperation::Insert {
+                table: "users".to_string(),
+                key: format!("users:{}", i),
+                row: Row { values: HashMap::new(), schema_version: 1 },
+            }))
+            .await
+            .unwrap();
+        }
+
+        let before = wal.size_bytes().await.unwrap();
+        let reclaimed = wal.compact().await.unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(wal.size_bytes().await.unwrap(), before - reclaimed);
+        assert_eq!(wal.entry_count(), 1);
+
+        // Sequence numbers carry on, and the compacted file replays
+        assert_eq!(wal.append(&WalEntry::new(WalOperation::CreateDatabase { name: "a".into() })).await.unwrap(), 12);
+        let replayed = WriteAheadLog::new(wal_path).await.unwrap().replay().await.unwrap();
+        let seqs: Vec<u64> = replayed.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 12]);
+    }
+
     #[tokio::test]
     async fn test_wal_filter_methods() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -400,11 +1461,13 @@ mod tests {
             
             let entry = WalEntry {
                 id: Uuid::new_v4(),
+                seq: 0,
                 timestamp: now + chrono::Duration::seconds(i),
-                operation: WalOperation::Insert {
+                tx_id: None,
+            operation: WalOperation::Insert {
                     table: table.to_string(),
                     key: format!("{}:{}", table, i),
-                    row: Row { values: row_values },
+                    row: Row { values: row_values, schema_version: 1 },
                 },
             };
             
@@ -422,4 +1485,367 @@ mod tests {
         let since_entries = wal.get_entries_since(now + chrono::Duration::seconds(2)).await;
         assert_eq!(since_entries.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_entries_stamped_by_clock() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut wal = WriteAheadLog::new(temp_file.path().to_str().unwrap()).await.unwrap();
+        let clock = MockClock::new();
+        wal.set_clock(clock.clone());
+
+        let start = clock.utc_now();
+        for name in ["a", "b", "c"] {
+            clock.advance(std::time::Duration::from_secs(10));
+            let entry = wal.entry(WalOperation::CreateDatabase { name: name.to_string() });
+            wal.append(&entry).await.unwrap();
+        }
+        assert_eq!(wal.entries[0].timestamp, start + chrono::Duration::seconds(10));
+        // Strictly after: the entry stamped at exactly 20s is excluded
+        let since = wal.get_entries_since(start + chrono::Duration::seconds(20)).await;
+        assert_eq!(since.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_iter_from_tails_the_file() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut wal = WriteAheadLog::new(temp_file.path().to_str().unwrap()).await.unwrap();
+        let database = |name: &str| WalEntry::new(WalOperation::CreateDatabase { name: name.to_string() });
+        for name in ["a", "b", "c", "d"] {
+            wal.append(&database(name)).await.unwrap();
+        }
+
+        let mut iter = wal.iter_from(3);
+        assert_eq!(iter.next().await.unwrap().unwrap().seq, 3);
+        assert_eq!(iter.next().await.unwrap().unwrap().seq, 4);
+        assert!(iter.next().await.unwrap().is_none());
+
+        let mut follower = wal.iter_from(4).follow(Duration::from_millis(5));
+        assert_eq!(follower.next().await.unwrap().unwrap().seq, 4);
+        let waiting = tokio::spawn(async move { follower.next().await.unwrap().unwrap() });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        wal.append(&database("e")).await.unwrap();
+        let entry = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(entry.seq, 5);
+        assert!(matches!(entry.operation, WalOperation::CreateDatabase { name } if name == "e"));
+
+        // An entry cut off mid-write is left for when it's complete
+        let mut iter = wal.iter_from(1);
+        let full = std::fs::read(temp_file.path()).unwrap();
+        std::fs::write(temp_file.path(), &full[..full.len() - 3]).unwrap();
+        let mut seqs = Vec::new();
+        while let Some(entry) = iter.next().await.unwrap() {
+            seqs.push(entry.seq);
+        }
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+        std::fs::write(temp_file.path(), &full).unwrap();
+        assert_eq!(iter.next().await.unwrap().unwrap().seq, 5);
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_first_bad_offset() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let wal_path = temp_file.path().to_str().unwrap();
+        let mut wal = WriteAheadLog::new(wal_path).await.unwrap();
+        let mut offsets = Vec::new();
+        for name in ["a", "b", "c"] {
+            offsets.push(wal.size_bytes().await.unwrap());
+            wal.append(&WalEntry::new(WalOperation::CreateDatabase { name: name.to_string() })).await.unwrap();
+        }
+        let size = wal.size_bytes().await.unwrap();
+
+        let verification = WriteAheadLog::verify(wal_path).await.unwrap();
+        assert_eq!(verification, WalVerification { valid_entries: 3, file_bytes: size, first_bad: None });
+        assert_eq!(verification.to_string(), format!("3 valid entries in {} bytes; no problems found", size));
+
+        // Garbage in the second entry's body
+        let mut data = std::fs::read(wal_path).unwrap();
+        let body = offsets[1] as usize + 4;
+        data[body..body + 16].fill(0xFF);
+        std::fs::write(wal_path, &data).unwrap();
+        let verification = WriteAheadLog::verify(wal_path).await.unwrap();
+        assert_eq!(verification.valid_entries, 1);
+        let (offset, problem) = verification.first_bad.unwrap();
+        assert_eq!(offset, offsets[1]);
+        assert_eq!(problem, "checksum mismatch");
+
+        // A torn last entry
+        wal.truncate().await.unwrap();
+        wal.append(&WalEntry::new(WalOperation::CreateDatabase { name: "a".to_string() })).await.unwrap();
+        let torn = wal.size_bytes().await.unwrap();
+        wal.append(&WalEntry::new(WalOperation::CreateDatabase { name: "b".to_string() })).await.unwrap();
+        let data = std::fs::read(wal_path).unwrap();
+        std::fs::write(wal_path, &data[..data.len() - 3]).unwrap();
+        let verification = WriteAheadLog::verify(wal_path).await.unwrap();
+        assert_eq!(verification.valid_entries, 1);
+        let (offset, problem) = verification.first_bad.unwrap();
+        assert_eq!(offset, torn);
+        assert!(problem.starts_with("length prefix of"), "{}", problem);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_until_compaction() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut wal = WriteAheadLog::new(temp_file.path().to_str().unwrap()).await.unwrap();
+        let entry = WalEntry::new(WalOperation::CreateDatabase { name: "db".to_string() });
+        wal.append(&entry).await.unwrap();
+
+        // As a full disk leaves it
+        wal.read_only = true;
+        let err = wal.append(&entry).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<WalError>(), Some(WalError::DiskFull)));
+        assert_eq!(wal.entry_count(), 1);
+        assert_eq!(wal.last_seq(), 1);
+
+        wal.compact().await.unwrap();
+        assert!(!wal.is_read_only());
+        assert_eq!(wal.append(&entry).await.unwrap(), 2);
+    }
+
+    /// A log file of `count` small entries, written directly rather than
+    /// appended one fsync at a time.
+    fn small_entries_file(path: &str, count: u64) -> usize {
+        let mut data = Vec::new();
+        for i in 0..count {
+            let entry = WalEntry { seq: i + 1, ..WalEntry::new(WalOperation::Begin(i)) };
+            data.extend_from_slice(&encode_record(&entry).unwrap());
+        }
+        std::fs::write(path, &data).unwrap();
+        data.len()
+    }
+
+    #[tokio::test]
+    async fn test_replay_many_small_entries() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        small_entries_file(path, 100_000);
+
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let entries = wal.replay().await.unwrap();
+        assert_eq!(entries.len(), 100_000);
+        assert!(entries.iter().enumerate().all(|(i, e)| e.seq == i as u64 + 1
+            && matches!(e.operation, WalOperation::Begin(id) if id == i as u64)));
+        assert_eq!(wal.last_seq(), 100_000);
+
+        // A crash mid-append can leave part of a length prefix, or a length
+        // whose body is missing, either of which ends the log and is cut
+        // from it
+        let bytes = small_entries_file(path, 10);
+        for tail in [&[7, 0][..], &[200, 0, 0, 0, 1, 2, 3], &[0xff, 0xff, 0xff, 0xff]] {
+            let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+            std::io::Write::write_all(&mut file, tail).unwrap();
+            assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 10);
+            assert_eq!(std::fs::metadata(path).unwrap().len(), bytes as u64);
+            assert_eq!(std::fs::read(format!("{}.corrupt", path)).unwrap(), tail);
+        }
+        std::fs::remove_file(format!("{}.corrupt", path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_reads_in_bulk() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let bytes = small_entries_file(path, 100_000);
+
+        // What the replay did before: reading through an 8 KiB buffer
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        wal.set_replay_buffer_size(8 * 1024);
+        let reads = file_reads();
+        wal.replay().await.unwrap();
+        let small_buffer_reads = file_reads() - reads;
+        assert!(small_buffer_reads > bytes / (8 * 1024));
+
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let reads = file_reads();
+        wal.replay().await.unwrap();
+        let default_reads = file_reads() - reads;
+        assert!(default_reads <= bytes / DEFAULT_REPLAY_BUFFER_SIZE + 2, "{} reads", default_reads);
+        assert!(default_reads * 100 < small_buffer_reads, "{} vs {} reads", default_reads, small_buffer_reads);
+
+        // A buffer too small for one entry still reads each one whole
+        small_entries_file(path, 1000);
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        wal.set_replay_buffer_size(16);
+        assert_eq!(wal.replay().await.unwrap().len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_replay_stops_at_a_corrupt_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let database = |name: &str| WalEntry::new(WalOperation::CreateDatabase { name: name.to_string() });
+        let mut offsets = Vec::new();
+        for name in ["a", "b", "c", "d"] {
+            offsets.push(wal.size_bytes().await.unwrap());
+            wal.append(&database(name)).await.unwrap();
+        }
+
+        // One flipped byte in the third record's body
+        let mut data = std::fs::read(path).unwrap();
+        data[offsets[2] as usize + 10] ^= 0x01;
+        std::fs::write(path, &data).unwrap();
+        let mut iter = wal.iter_from(1);
+        assert_eq!(iter.next().await.unwrap().unwrap().seq, 1);
+        assert_eq!(iter.next().await.unwrap().unwrap().seq, 2);
+        let err = iter.next().await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<WalError>(), Some(WalError::ChecksumMismatch(o)) if *o == offsets[2]));
+
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let seqs: Vec<u64> = wal.replay().await.unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+        // The bad record and the one after it were set aside, so new
+        // appends replay after the good ones
+        assert_eq!(std::fs::read(format!("{}.corrupt", path)).unwrap(), &data[offsets[2] as usize..]);
+        assert_eq!(wal.size_bytes().await.unwrap(), offsets[2]);
+        assert_eq!(wal.append(&database("e")).await.unwrap(), 3);
+        let replayed = WriteAheadLog::new(path).await.unwrap().replay().await.unwrap();
+        assert_eq!(replayed.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // A flag bit flipped in a length prefix fails the record too: the
+        // checksum covers the prefix, and a record that lost its checksum
+        // flag can't follow one that has it
+        let data = std::fs::read(path).unwrap();
+        for flag in [CHECKSUMMED, PREFIX_CHECKSUMMED, VERSIONED] {
+            let mut flipped = data.clone();
+            flipped[offsets[1] as usize + 3] ^= (flag >> 24) as u8;
+            std::fs::write(path, &flipped).unwrap();
+            assert!(!WriteAheadLog::verify(path).await.unwrap().is_valid());
+            assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 1);
+        }
+        std::fs::remove_file(format!("{}.corrupt", path)).unwrap();
+
+        // Records from before checksums still replay, unchecked, with those
+        // appended since after them
+        let legacy = bincode::serialize(&WalEntry { seq: 1, ..database("f") }).unwrap();
+        let mut data = (legacy.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&legacy);
+        std::fs::write(path, &data).unwrap();
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        assert_eq!(wal.replay().await.unwrap().len(), 1);
+        wal.append(&database("g")).await.unwrap();
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 2);
+        assert!(WriteAheadLog::verify(path).await.unwrap().is_valid());
+    }
+
+    #[tokio::test]
+    async fn test_segments_rotate_and_replay_in_order() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let path = path.to_str().unwrap();
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        // Stamped alike, so every entry is the same size
+        wal.set_clock(MockClock::new());
+        let database = |wal: &WriteAheadLog, i| wal.entry(WalOperation::CreateDatabase { name: format!("db{}", i) });
+        wal.append(&database(&wal, 0)).await.unwrap();
+        let record = wal.size_bytes().await.unwrap();
+
+        // Three entries fit in a segment, so six fill two
+        wal.set_max_segment_bytes(3 * record);
+        for i in 1..6 {
+            wal.append(&database(&wal, i)).await.unwrap();
+        }
+        assert_eq!(wal.segment_count().await.unwrap(), 2);
+        let second = temp_dir.path().join("wal.000002.log");
+        assert_eq!(segment_path(path, 2), second.to_str().unwrap());
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 3 * record);
+        assert_eq!(std::fs::metadata(&second).unwrap().len(), 3 * record);
+        assert_eq!(wal.size_bytes().await.unwrap(), 6 * record);
+
+        // A reopened log reads both and carries on in the second
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        wal.set_clock(MockClock::new());
+        wal.set_max_segment_bytes(3 * record);
+        let seqs: Vec<u64> = wal.replay().await.unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+        wal.append(&database(&wal, 6)).await.unwrap();
+        assert_eq!(wal.segment_count().await.unwrap(), 3);
+        let mut iter = wal.iter_from(2);
+        let mut seqs = Vec::new();
+        while let Some(entry) = iter.next().await.unwrap() {
+            seqs.push(entry.seq);
+        }
+        assert_eq!(seqs, vec![2, 3, 4, 5, 6, 7]);
+        let verification = WriteAheadLog::verify(path).await.unwrap();
+        assert_eq!((verification.valid_entries, verification.file_bytes), (7, 7 * record));
+
+        // Compaction folds the log back into the first segment. A segment a
+        // crash left behind repeats entries it holds, which aren't read twice
+        let left_behind = std::fs::read(&second).unwrap();
+        wal.compact().await.unwrap();
+        assert_eq!(wal.segment_count().await.unwrap(), 1);
+        assert!(!second.exists());
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 7);
+        std::fs::write(&second, left_behind).unwrap();
+        let replayed = WriteAheadLog::new(path).await.unwrap().replay().await.unwrap();
+        assert_eq!(replayed.iter().map(|e| e.seq).collect::<Vec<_>>(), (1..=7).collect::<Vec<_>>());
+        std::fs::remove_file(&second).unwrap();
+        wal.truncate().await.unwrap();
+        assert_eq!(wal.size_bytes().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transient_write_errors_are_retried() {
+        use std::io::ErrorKind;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let entry = WalEntry::new(WalOperation::CreateDatabase { name: "db".to_string() });
+
+        let attempts = write_attempts();
+        fail_next_writes(&[ErrorKind::Interrupted, ErrorKind::WouldBlock]);
+        assert_eq!(wal.append(&entry).await.unwrap(), 1);
+        assert_eq!(write_attempts() - attempts, 3);
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 1);
+
+        // A permanent error isn't retried
+        let attempts = write_attempts();
+        fail_next_writes(&[ErrorKind::PermissionDenied]);
+        let err = wal.append(&entry).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<std::io::Error>().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(write_attempts() - attempts, 1);
+
+        // Nor is a transient one forever
+        let attempts = write_attempts();
+        fail_next_writes(&[ErrorKind::Interrupted; MAX_WRITE_ATTEMPTS as usize]);
+        let err = wal.append(&entry).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<std::io::Error>().unwrap().kind(), ErrorKind::Interrupted);
+        assert_eq!(write_attempts() - attempts, MAX_WRITE_ATTEMPTS as usize);
+
+        assert_eq!(wal.last_seq(), 1);
+        assert_eq!(wal.append(&entry).await.unwrap(), 2);
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_appends_reuse_one_file_handle() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let mut wal = WriteAheadLog::new(path).await.unwrap();
+        let entry = |i: usize| WalEntry::new(WalOperation::CreateDatabase { name: format!("db{}", i) });
+
+        let opens = file_opens();
+        for i in 0..100 {
+            wal.append(&entry(i)).await.unwrap();
+        }
+        wal.sync().await.unwrap();
+        assert_eq!(file_opens() - opens, 1);
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 100);
+
+        // Truncating and compacting replace the file; appends follow them
+        wal.truncate().await.unwrap();
+        wal.append(&entry(100)).await.unwrap();
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 1);
+        wal.compact().await.unwrap();
+        wal.append(&entry(101)).await.unwrap();
+        assert_eq!(WriteAheadLog::new(path).await.unwrap().replay().await.unwrap().len(), 2);
+        assert_eq!(file_opens() - opens, 3);
+    }
+}
@@ -0,0 +1,75 @@
+//! Allocation counts for table scans. Kept in its own test binary so the
+//! counting allocator sits under these tests only.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tempfile::TempDir;
+use wundradb_core::storage::codec;
+use wundradb_core::{Database, Row};
+
+/// Counts allocations made on the current thread, so tests running in
+/// parallel don't disturb each other's counts.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[tokio::test]
+async fn test_table_scan_avoids_copying_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+
+    const ROWS: usize = 2000;
+    db.execute_sql("CREATE TABLE t (id INTEGER PRIMARY KEY, name VARCHAR(20))").await.unwrap();
+    let values: Vec<String> = (0..ROWS).map(|i| format!("({}, 'row{}')", i, i)).collect();
+    db.execute_sql(&format!("INSERT INTO t (id, name) VALUES {}", values.join(", "))).await.unwrap();
+    let storage = db.storage.read().await;
+
+    // The previous approach: collect the keys, copy each value out, decode
+    let before = allocations();
+    let copied: Vec<Row> = {
+        let keys = storage.scan_prefix("1:").unwrap();
+        keys.iter()
+            .map(|key| codec::decode_row(&storage.get(key).unwrap().unwrap()).unwrap())
+            .collect()
+    };
+    let copying = allocations() - before;
+
+    let before = allocations();
+    let mut rows = Vec::new();
+    storage
+        .for_each_prefix("1:", |_, data| {
+            rows.push(codec::decode_row(data)?);
+            Ok(())
+        })
+        .unwrap();
+    let streaming = allocations() - before;
+
+    assert_eq!(rows.len(), copied.len());
+    // Two fewer allocations per row: the key and the value copy
+    assert!(
+        streaming + 2 * ROWS <= copying,
+        "streaming scan made {} allocations, copying scan {}",
+        streaming,
+        copying
+    );
+}
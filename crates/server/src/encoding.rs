@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::time::Duration;
+use wundradb_core::sql::engine::SqlValue;
+use wundradb_core::sql::escape::escape_field;
+use wundradb_core::sql::output::render;
+use wundradb_core::{Output, ResultSet};
+
+/// How responses are framed on a connection. A client picks one with a
+/// `HELLO encoding=<name>;` as its first statement; without one it gets
+/// `Text`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The result as the engine renders it, ended by a `Query OK` or
+    /// `Error` line.
+    #[default]
+    Text,
+    /// One JSON object per response, on a single line.
+    Json,
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Encoding::Text),
+            "json" => Ok(Encoding::Json),
+            _ => Err(anyhow!("Unknown encoding '{}', expected text or json", s)),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Text => "text",
+            Encoding::Json => "json",
+        }
+    }
+
+    /// Parses the handshake `HELLO encoding=<name>`, returning `None` if
+    /// `statement` isn't one.
+    pub fn parse_hello(statement: &str) -> Option<Result<Self>> {
+        let mut words = statement.trim().trim_end_matches(';').split_whitespace();
+        if !words.next()?.eq_ignore_ascii_case("HELLO") {
+            return None;
+        }
+        let requested = match (words.next(), words.next()) {
+            (Some(option), None) => option.split_once('=').filter(|(key, _)| key.eq_ignore_ascii_case("encoding")),
+            _ => None,
+        };
+        Some(match requested {
+            Some((_, name)) => name.parse(),
+            None => Err(anyhow!("Expected HELLO encoding=<text|json>")),
+        })
+    }
+
+    /// Frames the outcome of one client request, the outputs of the
+    /// statements it ran.
+    pub fn encode(self, outcome: &Result<Vec<Output>>, elapsed: Duration) -> String {
+        match (self, outcome) {
+            (Encoding::Text, Ok(outputs)) => format!("{}\nQuery OK Query OK ({:.2?})\n", render(outputs), elapsed),
+            // Escaped so a message quoting a value stays on one line
+            (Encoding::Text, Err(e)) => format!("Error Error: {}\n", escape_field(&e.to_string())),
+            (Encoding::Json, Ok(outputs)) => {
+                let mut response = match outputs.as_slice() {
+                    [output] => json_output(output),
+                    outputs => json!({ "results": outputs.iter().map(json_output).collect::<Vec<_>>() }),
+                };
+                response["elapsed_ms"] = json!(elapsed.as_secs_f64() * 1000.0);
+                format!("{}\n", response)
+            }
+            (Encoding::Json, Err(e)) => format!("{}\n", json!({ "error": e.to_string() })),
+        }
+    }
+}
+
+/// A result set as `{"columns", "rows", "row_count"}`, with any warning
+/// as `"message"`; anything else as `{"message"}`.
+fn json_output(output: &Output) -> Value {
+    let set: &ResultSet = match output {
+        Output::Rows(set) => set,
+        Output::Affected { message, .. } | Output::Message(message) => return json!({ "message": message.trim_end() }),
+    };
+    let rows: Vec<Vec<Value>> = set.rows.iter().map(|row| row.iter().map(json_value).collect()).collect();
+    let mut response = json!({
+        "columns": set.columns,
+        "row_count": rows.len(),
+        "rows": rows,
+    });
+    if let Some(warning) = &set.warning {
+        response["message"] = json!(warning.trim_end());
+    }
+    response
+}
+
+/// `value` as its JSON counterpart: numbers and booleans as such, NULL as
+/// `null`, timestamps as RFC 3339 strings.
+fn json_value(value: &SqlValue) -> Value {
+    match value {
+        SqlValue::Integer(i) => json!(i),
+        SqlValue::Varchar(s) => json!(s),
+        SqlValue::Decimal(d) => json!(d),
+        SqlValue::Boolean(b) => json!(b),
+        SqlValue::Timestamp(t) => json!(t.to_rfc3339()),
+        SqlValue::Null => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hello() {
+        assert_eq!(Encoding::parse_hello("HELLO encoding=json;").unwrap().unwrap(), Encoding::Json);
+        assert_eq!(Encoding::parse_hello("hello ENCODING=Text").unwrap().unwrap(), Encoding::Text);
+        assert!(Encoding::parse_hello("HELLO encoding=xml").unwrap().is_err());
+        assert!(Encoding::parse_hello("HELLO").unwrap().is_err());
+        assert!(Encoding::parse_hello("SELECT 1").is_none());
+    }
+
+    fn json(outputs: Vec<Output>) -> Value {
+        serde_json::from_str(&Encoding::Json.encode(&Ok(outputs), Duration::ZERO)).unwrap()
+    }
+
+    #[test]
+    fn test_json_frames_result_sets_and_messages() {
+        let rows = vec![
+            vec![SqlValue::Integer(1), SqlValue::Varchar("a\tb".into()), SqlValue::Boolean(true)],
+            vec![SqlValue::Integer(2), SqlValue::Varchar("NULL".into()), SqlValue::Null],
+            vec![SqlValue::Integer(3), SqlValue::Varchar("(1 rows)".into()), SqlValue::Decimal(2.5)],
+        ];
+        let columns = vec!["id".to_string(), "body".to_string(), "flag".to_string()];
+        let set = ResultSet { warning: Some("Warning: result truncated\n".into()), ..ResultSet::new(columns, rows) };
+        let response = json(vec![Output::Rows(set)]);
+        assert_eq!(response["columns"], json!(["id", "body", "flag"]));
+        // Typed, and NULL apart from the string 'NULL'; a value that reads
+        // like a row count doesn't end the rows
+        assert_eq!(response["rows"], json!([[1, "a\tb", true], [2, "NULL", null], [3, "(1 rows)", 2.5]]));
+        assert_eq!(response["row_count"], 3);
+        assert_eq!(response["message"], "Warning: result truncated");
+
+        let response = json(vec![Output::Affected { rows: 1, message: "1 row(s) inserted".into() }]);
+        assert_eq!(response["message"], "1 row(s) inserted");
+        let response = json(vec![Output::Message("Transaction 1 started".into()), Output::Message("done".into())]);
+        assert_eq!(response["results"], json!([{ "message": "Transaction 1 started" }, { "message": "done" }]));
+        let response = Encoding::Json.encode(&Err(anyhow!("Table 'x' does not exist")), Duration::ZERO);
+        assert_eq!(response, "{\"error\":\"Table 'x' does not exist\"}\n");
+    }
+}
@@ -1,60 +1,1007 @@
-use wundradb_core::Database;
-use anyhow::Result;
+mod encoding;
+mod process;
+
+use encoding::Encoding;
+use process::{ProcessList, ServerCommand};
+use wundradb_core::audit::{AuditConfig, AuditLog, AuditRecord};
+use wundradb_core::raft::config::ClusterConfig;
+use wundradb_core::raft::transport::Transport;
+use wundradb_core::raft::{NodeId, RaftNode};
+use wundradb_core::sql::ident::IdentifierCase;
+use wundradb_core::storage::codec::RowFormat;
+use wundradb_core::txn::{TransactionLimits, DEFAULT_MAX_SEGMENT_BYTES};
+use wundradb_core::{Cancellation, Database, LimitMode, Output, ResultLimit, Session, StatementTimeout, WalRetention};
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{info, error};
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedRwLockWriteGuard, RwLock};
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{info, error, warn, Level};
+
+#[derive(Parser, Debug)]
+#[command(name = "wundradb-server")]
+struct Args {
+    /// Minimum log level (trace, debug, info, warn, error)
+    #[arg(long, default_value_t = Level::INFO)]
+    log_level: Level,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Record every executed statement to this append-only file
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log once it reaches this many bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    audit_max_bytes: u64,
+
+    /// Replace literal values in audited statements with `?`
+    #[arg(long)]
+    audit_redact: bool,
+
+    /// Encoding for newly written rows (bincode or json); existing rows
+    /// are read in whichever format they were written
+    #[arg(long, default_value = "bincode")]
+    row_format: RowFormat,
+
+    /// How unquoted and quoted names are folded (lower, preserve or
+    /// insensitive); names already created keep their case
+    #[arg(long, default_value = "lower")]
+    identifier_case: IdentifierCase,
+
+    /// Most rows a SELECT without a LIMIT may return (0 for no cap);
+    /// sessions can change it with `SET max_result_rows`
+    #[arg(long, default_value_t = 0)]
+    max_result_rows: usize,
+
+    /// What to do when a result exceeds --max-result-rows
+    #[arg(long, value_enum, default_value_t = ResultLimitMode::Truncate)]
+    result_limit_mode: ResultLimitMode,
+
+    /// Cancel statements running longer than this many milliseconds (0 for
+    /// no limit); sessions can change it with `SET statement_timeout`
+    #[arg(long, default_value_t = 0)]
+    statement_timeout_ms: u64,
+
+    /// Most a session may raise its statement timeout to, in milliseconds
+    /// (0 for no ceiling)
+    #[arg(long, default_value_t = 0)]
+    max_statement_timeout_ms: u64,
+
+    /// Most memory, in kB, one query may buffer in the rows it reads, joins
+    /// and sorts before the query fails (0 for no limit)
+    #[arg(long, default_value_t = 0)]
+    query_memory_limit_kb: usize,
+
+    /// Most transactions that may be open at once; BEGIN fails beyond it
+    #[arg(long, default_value_t = TransactionLimits::default().max_open)]
+    max_open_transactions: usize,
+
+    /// Roll back a transaction left unused for this many seconds
+    #[arg(long, default_value_t = TransactionLimits::default().idle_timeout.as_secs())]
+    transaction_idle_timeout_secs: u64,
+
+    /// Let every connection run admin commands such as `SHOW PROCESSLIST`,
+    /// `KILL` and `SHOW TREE STRUCTURE`; there are no user accounts to
+    /// grant them to
+    #[arg(long)]
+    admin_commands: bool,
+
+    /// How the end of a client's statement is found: `semicolon` reads up
+    /// to a `;` outside strings and comments, so statements may span lines;
+    /// `newline` takes each line as a statement, for clients that don't
+    /// send `;`
+    #[arg(long, value_enum, default_value_t = StatementTerminator::Semicolon)]
+    statement_terminator: StatementTerminator,
+
+    /// Checkpoint automatically once this many rows have been inserted,
+    /// updated or deleted since the last one (0 to only checkpoint on
+    /// `CHECKPOINT`)
+    #[arg(long, default_value_t = 1000)]
+    checkpoint_threshold: usize,
+
+    /// Start a new WAL segment file once the current one would grow past
+    /// this many bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_SEGMENT_BYTES)]
+    wal_segment_bytes: u64,
+
+    /// Keep WAL segments at checkpoint until their newest entry is this
+    /// many seconds old, so the database can be restored to a point in
+    /// that window
+    #[arg(long)]
+    wal_retention_secs: Option<u64>,
+
+    /// Keep at most this many bytes of checkpointed WAL segments for
+    /// point-in-time restore
+    #[arg(long)]
+    wal_retention_bytes: Option<u64>,
+
+    /// This node's id in the cluster
+    #[arg(long, default_value = "n1")]
+    node_id: String,
+
+    /// JSON file listing every cluster member's id and Raft address; without
+    /// it the node runs as a single-node cluster on --raft-addr
+    #[arg(long)]
+    cluster_config: Option<PathBuf>,
+
+    /// Raft address for a single-node cluster
+    #[arg(long, default_value = "127.0.0.1:7000")]
+    raft_addr: SocketAddr,
+
+    /// Read the whole WAL, print how many entries are intact and where the
+    /// first bad one is, and exit without opening the database
+    #[arg(long)]
+    verify_wal: bool,
+
+    /// After opening, scan all of storage for keys of no known table and
+    /// rows that don't match their schema, and log what is found
+    #[arg(long)]
+    startup_check: bool,
+}
+
+const DATA_DIR: &str = "data";
+
+type SharedAuditLog = Option<Arc<Mutex<AuditLog>>>;
+
+/// Handles shared by every connection.
+#[derive(Clone)]
+struct ServerState {
+    db: Arc<RwLock<Database>>,
+    audit: SharedAuditLog,
+    processes: Arc<ProcessList>,
+    /// Starting result cap for each new connection's session.
+    result_limit: ResultLimit,
+    /// Starting statement timeout and its ceiling for each new session.
+    statement_timeout: StatementTimeout,
+    /// Bytes each query's sorts may buffer, for every session.
+    memory_limit: Option<usize>,
+    /// Whether sessions may run admin commands.
+    admin_commands: bool,
+    /// Where each client statement ends.
+    terminator: StatementTerminator,
+}
+
+/// Per-connection state.
+struct ClientConnection {
+    id: u64,
+    addr: SocketAddr,
+    session: Session,
+    /// Response framing, negotiated by the client's `HELLO`.
+    encoding: Encoding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ResultLimitMode {
+    Truncate,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StatementTerminator {
+    Semicolon,
+    Newline,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    init_logging(args.log_level, args.log_format);
+
+    if args.verify_wal {
+        let verification = Database::verify_wal(DATA_DIR).await?;
+        println!("WAL verification: {}", verification);
+        return match verification.is_valid() {
+            true => Ok(()),
+            false => Err(anyhow!("WAL verification failed")),
+        };
+    }
+
+    // Nothing drives the node yet; it is kept for the life of the server
+    let _cluster = init_cluster(&args)?;
 
     let addr = "127.0.0.1:3306";
     let listener = TcpListener::bind(addr).await?;
     info!("WundraDB server listening on {}", addr);
 
-    let db = Arc::new(RwLock::new(Database::new("data").await?));
+    let mut db = Database::open(DATA_DIR, args.row_format, args.identifier_case).await?;
+    if args.startup_check {
+        db.startup_check().await?;
+    }
+    db.engine.set_transaction_limits(TransactionLimits {
+        max_open: args.max_open_transactions,
+        idle_timeout: Duration::from_secs(args.transaction_idle_timeout_secs),
+    });
+    db.set_checkpoint_threshold(args.checkpoint_threshold);
+    db.set_wal_segment_bytes(args.wal_segment_bytes).await;
+    db.set_wal_retention(WalRetention {
+        max_age: args.wal_retention_secs.map(Duration::from_secs),
+        max_bytes: args.wal_retention_bytes,
+    });
+    let db = Arc::new(RwLock::new(db));
+
+    let audit = match args.audit_log {
+        Some(path) => {
+            info!(path = %path.display(), "Audit log enabled");
+            let config = AuditConfig {
+                path,
+                max_bytes: args.audit_max_bytes,
+                redact_values: args.audit_redact,
+            };
+            Some(Arc::new(Mutex::new(AuditLog::open(config).await?)))
+        }
+        None => None,
+    };
+
+    let result_limit = ResultLimit {
+        max_rows: (args.max_result_rows > 0).then_some(args.max_result_rows),
+        mode: match args.result_limit_mode {
+            ResultLimitMode::Truncate => LimitMode::Truncate,
+            ResultLimitMode::Error => LimitMode::Error,
+        },
+    };
+    let millis = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+    let statement_timeout = StatementTimeout {
+        timeout: millis(args.statement_timeout_ms),
+        ceiling: millis(args.max_statement_timeout_ms),
+    };
+    let memory_limit = (args.query_memory_limit_kb > 0).then(|| args.query_memory_limit_kb * 1024);
+    let processes = Arc::new(ProcessList::new());
+    let state = ServerState {
+        db,
+        audit,
+        processes,
+        result_limit,
+        statement_timeout,
+        memory_limit,
+        admin_commands: args.admin_commands,
+        terminator: args.statement_terminator,
+    };
 
     loop {
         let (stream, addr) = listener.accept().await?;
-        info!("New connection from {}", addr);
-        let db = db.clone();
+        info!(client = %addr, "New connection");
+        let state = state.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, db).await {
-                error!("Client error: {:?}", e);
+            if let Err(e) = handle_client(stream, addr, state).await {
+                error!(client = %addr, error = ?e, "Client error");
             }
         });
     }
 }
 
-async fn handle_client(stream: TcpStream, db: Arc<RwLock<Database>>) -> Result<()> {
+/// Builds this node from the cluster config, bootstrapping the cluster if
+/// it is the designated node.
+fn init_cluster(args: &Args) -> Result<(RaftNode, Transport)> {
+    let id = NodeId(args.node_id.clone());
+    let config = match &args.cluster_config {
+        Some(path) => ClusterConfig::load(path)?,
+        None => ClusterConfig::single_node(id.clone(), args.raft_addr),
+    };
+    let (mut node, transport) = RaftNode::from_config(&config, id)?;
+    if config.bootstrap == node.id {
+        node.bootstrap();
+    }
+    info!(node = %node.id.0, peers = node.peers.len(), leader = node.is_leader(), "Cluster initialized");
+    Ok((node, transport))
+}
+
+fn init_logging(level: Level, format: LogFormat) {
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+async fn handle_client(stream: TcpStream, client: SocketAddr, state: ServerState) -> Result<()> {
     let (reader, mut writer) = stream.into_split();
     let mut lines = BufReader::new(reader).lines();
+    let mut conn = ClientConnection {
+        id: state.processes.register(client),
+        addr: client,
+        session: Session::with_result_limit(state.result_limit)
+            .with_statement_timeout(state.statement_timeout)
+            .with_memory_limit(state.memory_limit)
+            .with_admin(state.admin_commands),
+        encoding: Encoding::default(),
+    };
 
+    let result = serve_client(&mut lines, &mut writer, &state, &mut conn).await;
+    state.db.read().await.engine.close_session(&conn.session).await;
+    state.processes.unregister(conn.id);
+    info!(client = %client, "Connection closed");
+    result
+}
+
+async fn serve_client(
+    lines: &mut Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    state: &ServerState,
+    conn: &mut ClientConnection,
+) -> Result<()> {
     writer.write_all(b"").await?;
 
-    while let Ok(Some(line)) = lines.next_line().await {
-        let sql = line.trim();
-        if sql.eq_ignore_ascii_case("exit") || sql.eq_ignore_ascii_case("quit") {
+    let mut first = true;
+    while let Ok(Some(statement)) = read_statement(lines, state.terminator).await {
+        let sql = statement.trim();
+        if is_exit(sql) {
             writer.write_all(b"Goodbye!\n").await?;
             break;
         }
 
-        println!("Received: {}", sql);
-
-        let mut db = db.write().await;
         let start = std::time::Instant::now();
-
-        let response = match db.execute_sql(sql).await {
-            Ok(result) => format!("{}\nQuery OK Query OK ({:.2?})\n", result, start.elapsed()),
-            Err(e) => format!("Error Error: {}\n", e),
+        let outcome = match Encoding::parse_hello(sql) {
+            // The reply to a HELLO already uses the encoding it chose
+            Some(Ok(encoding)) if first => {
+                conn.encoding = encoding;
+                Ok(vec![Output::Message(format!("Encoding set to {}", encoding.name()))])
+            }
+            Some(Ok(_)) => Err(anyhow!("HELLO must be the first statement on a connection")),
+            Some(Err(e)) => Err(e),
+            None => run_statement(state, conn, sql).await,
         };
+        first = false;
 
+        let response = conn.encoding.encode(&outcome, start.elapsed());
         writer.write_all(response.as_bytes()).await?;
-
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Reads the next statement. With `StatementTerminator::Semicolon` lines
+/// are joined until one ends with a `;` outside quotes and comments, so
+/// statements may span lines and strings may contain newlines, and `exit`
+/// or `quit` on a line of its own needs no terminator. With
+/// `StatementTerminator::Newline` each line is a statement. Returns `None`
+/// once the client closes the connection.
+async fn read_statement<R: AsyncBufRead + Unpin>(
+    lines: &mut Lines<R>,
+    terminator: StatementTerminator,
+) -> Result<Option<String>> {
+    let mut statement = String::new();
+    while let Some(line) = lines.next_line().await? {
+        if statement.is_empty() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if is_exit(line.trim()) || terminator == StatementTerminator::Newline {
+                return Ok(Some(line));
+            }
+        } else {
+            statement.push('\n');
+        }
+        statement.push_str(&line);
+        if is_terminated(&statement) {
+            return Ok(Some(statement));
+        }
+    }
+    // The client hung up mid-statement; run what it sent
+    Ok((!statement.is_empty()).then_some(statement))
+}
+
+fn is_exit(line: &str) -> bool {
+    let line = line.trim_end_matches(';');
+    line.eq_ignore_ascii_case("exit") || line.eq_ignore_ascii_case("quit")
+}
+
+/// Where `is_terminated` is in the statement it is scanning.
+enum Scan {
+    Code,
+    /// Inside a string or quoted identifier; `escapes` is set for an
+    /// `E'...'` string, the only kind where a backslash escapes the next
+    /// character.
+    Quoted { quote: char, escapes: bool },
+    LineComment,
+    /// Inside this many nested `/* */` comments.
+    BlockComment(usize),
+}
+
+/// Whether `sql` ends with a `;` outside strings, quoted identifiers and
+/// comments, read the way the parser reads them: a quote inside a string
+/// is doubled, a `--` comment runs to the end of the line and `/* */`
+/// comments nest. A comment after the `;` leaves it terminated.
+fn is_terminated(sql: &str) -> bool {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut scan = Scan::Code;
+    let mut terminated = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match scan {
+            Scan::Code => match c {
+                '\'' | '"' | '`' => {
+                    let word = |c: char| c.is_alphanumeric() || c == '_';
+                    let prefixed = c == '\''
+                        && i > 0
+                        && matches!(chars[i - 1], 'e' | 'E')
+                        && (i < 2 || !word(chars[i - 2]));
+                    scan = Scan::Quoted { quote: c, escapes: prefixed };
+                    terminated = false;
+                }
+                '-' if next == Some('-') => {
+                    scan = Scan::LineComment;
+                    i += 1;
+                }
+                '/' if next == Some('*') => {
+                    scan = Scan::BlockComment(1);
+                    i += 1;
+                }
+                ';' => terminated = true,
+                c if !c.is_whitespace() => terminated = false,
+                _ => {}
+            },
+            Scan::Quoted { escapes: true, .. } if c == '\\' => i += 1,
+            Scan::Quoted { quote, .. } if c == quote => {
+                if next == Some(quote) {
+                    i += 1;
+                } else {
+                    scan = Scan::Code;
+                }
+            }
+            Scan::Quoted { .. } => {}
+            Scan::LineComment if c == '\n' => scan = Scan::Code,
+            Scan::LineComment => {}
+            Scan::BlockComment(depth) if c == '/' && next == Some('*') => {
+                scan = Scan::BlockComment(depth + 1);
+                i += 1;
+            }
+            Scan::BlockComment(depth) if c == '*' && next == Some('/') => {
+                scan = if depth == 1 { Scan::Code } else { Scan::BlockComment(depth - 1) };
+                i += 1;
+            }
+            Scan::BlockComment(_) => {}
+        }
+        i += 1;
+    }
+    terminated && matches!(scan, Scan::Code | Scan::LineComment)
+}
+
+/// Executes one client statement in the connection's session, then logs
+/// and audits the outcome.
+async fn run_statement(state: &ServerState, conn: &mut ClientConnection, sql: &str) -> Result<Vec<Output>> {
+    let start = std::time::Instant::now();
+    let outcome = match ServerCommand::parse(sql) {
+        // They show and stop other connections' statements
+        Some(command) if !conn.session.is_admin() => Err(anyhow!("{} requires an admin session", command.name())),
+        Some(command) => state.processes.execute(command).map(|output| vec![output]),
+        None => {
+            // The timeout runs from here, so waiting for the database lock
+            // counts towards it
+            let cancellation = Cancellation::new().with_timeout(conn.session.statement_timeout().limit());
+            let mut statement_session = conn.session.clone().with_cancellation(cancellation.clone());
+            let statement = sql.to_string();
+            run_tracked(state, conn.id, sql, cancellation, |mut db| async move {
+                let result = db.execute_output_in(&mut statement_session, &statement).await?;
+                Ok((result, statement_session))
+            })
+            .await
+            .map(|(result, updated)| {
+                conn.session = updated;
+                result
+            })
+        }
+    };
+    log_query(conn.addr, sql, start.elapsed(), &outcome);
+
+    if let Some(audit) = &state.audit {
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now(),
+            client: conn.addr.to_string(),
+            statement: sql.to_string(),
+            success: outcome.is_ok(),
+            rows: outcome.as_ref().map_or(0, |outputs| row_count(outputs)),
+        };
+        // A failed audit write must not fail the statement that already ran
+        if let Err(e) = audit.lock().await.record(&record).await {
+            error!(error = %e, "Failed to write audit record");
+        }
+    }
+
+    outcome
+}
+
+/// Runs a statement on its own task with `spawn_guarded`, listing it in
+/// the process list for the duration so `KILL` can cancel it through `cancellation`, which
+/// the statement's session must hold. The task is never aborted: like
+/// `KILL`, a statement timeout is a deadline in `cancellation` that the
+/// engine checks, so a statement that already logged a write finishes it.
+async fn run_tracked<T, F, Fut>(
+    state: &ServerState,
+    conn_id: u64,
+    sql: &str,
+    cancellation: Cancellation,
+    execute: F,
+) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(OwnedRwLockWriteGuard<Database>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+{
+    let task = spawn_guarded(state.db.clone(), execute);
+    state.processes.start_query(conn_id, sql, cancellation);
+    let outcome = join_guarded(task).await;
+    state.processes.finish_query(conn_id);
+    outcome
+}
+
+/// Runs a statement on its own task while holding the write lock only for
+/// the duration of the call. A panic during execution unwinds that task,
+/// dropping the guard, and `join_guarded` reports it to the client as an
+/// internal error instead of tearing down the connection.
+fn spawn_guarded<T, F, Fut>(db: Arc<RwLock<Database>>, execute: F) -> JoinHandle<Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(OwnedRwLockWriteGuard<Database>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let guard = db.write_owned().await;
+        execute(guard).await
+    })
+}
+
+/// Waits for a statement task. Aborting the task or a panic in it drops
+/// the write guard with the task.
+async fn join_guarded<T>(task: JoinHandle<Result<T>>) -> Result<T> {
+    task_outcome(task.await)
+}
+
+/// A finished statement task's result, with a panic or cancellation
+/// turned into an error for the client.
+fn task_outcome<T>(joined: Result<Result<T>, JoinError>) -> Result<T> {
+    match joined {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => {
+            error!("Statement execution panicked");
+            Err(anyhow!("Internal error: statement execution panicked"))
+        }
+        Err(e) if e.is_cancelled() => Err(anyhow!("Query cancelled")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Emits one structured event per executed statement.
+fn log_query(client: SocketAddr, statement: &str, duration: Duration, outcome: &Result<Vec<Output>>) {
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+    match outcome {
+        Ok(result) => info!(
+            client = %client,
+            statement,
+            duration_ms,
+            rows = row_count(result),
+            "Query executed"
+        ),
+        Err(e) => warn!(
+            client = %client,
+            statement,
+            duration_ms,
+            error = %e,
+            "Query failed"
+        ),
+    }
+}
+
+/// The row count of the last statement's output: the rows of a result set,
+/// or those a write changed.
+fn row_count(outputs: &[Output]) -> u64 {
+    outputs.last().map_or(0, Output::row_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+    use wundradb_core::sql::engine::SqlValue;
+    use wundradb_core::sql::functions::Arity;
+    use wundradb_core::sql::output::render;
+    use wundradb_core::ResultSet;
+
+    #[derive(Default)]
+    struct FieldRecorder(HashMap<String, String>);
+
+    impl Visit for FieldRecorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    struct CaptureLayer(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            let mut recorder = FieldRecorder::default();
+            event.record(&mut recorder);
+            self.0.lock().unwrap().push(recorder.0);
+        }
+    }
+
+    #[test]
+    fn test_query_event_fields() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(events.clone()));
+
+        let client: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        let rows = vec![vec![SqlValue::Integer(1)], vec![SqlValue::Integer(2)]];
+        let outcome = Ok(vec![Output::Rows(ResultSet::new(vec!["id".to_string()], rows))]);
+        tracing::subscriber::with_default(subscriber, || {
+            log_query(client, "SELECT id FROM users", Duration::from_millis(3), &outcome);
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let fields = &events[0];
+        assert_eq!(fields["client"], "127.0.0.1:5555");
+        assert_eq!(fields["statement"], "\"SELECT id FROM users\"");
+        assert_eq!(fields["rows"], "2");
+        assert!(fields.contains_key("duration_ms"));
+    }
+
+    #[tokio::test]
+    async fn test_panic_in_execution_releases_lock() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        state.db.write().await.engine.register_function("boom", Arity::Exact(0), |_| panic!("injected failure"));
+        let mut failing = test_connection(&state);
+        let mut other = test_connection(&state);
+
+        let err = run_statement(&state, &mut failing, "SELECT boom()").await.unwrap_err();
+        assert_eq!(err.to_string(), "Internal error: statement execution panicked");
+
+        // Another connection can still acquire the lock and run statements
+        let result = render(&run_statement(&state, &mut other, "SELECT 1").await.unwrap());
+        assert!(result.contains('1'));
+        run_statement(&state, &mut failing, "SELECT 1").await.unwrap();
+    }
+
+    async fn test_state(temp_dir: &tempfile::TempDir, audit: SharedAuditLog) -> ServerState {
+        let db = Database::new(temp_dir.path().to_str().unwrap()).await.unwrap();
+        ServerState {
+            db: Arc::new(RwLock::new(db)),
+            audit,
+            processes: Arc::new(ProcessList::new()),
+            result_limit: ResultLimit::default(),
+            statement_timeout: StatementTimeout::default(),
+            memory_limit: None,
+            admin_commands: false,
+            terminator: StatementTerminator::Semicolon,
+        }
+    }
+
+    fn test_connection(state: &ServerState) -> ClientConnection {
+        let addr: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        ClientConnection {
+            id: state.processes.register(addr),
+            addr,
+            session: Session::with_result_limit(state.result_limit)
+                .with_statement_timeout(state.statement_timeout)
+                .with_memory_limit(state.memory_limit)
+                .with_admin(state.admin_commands),
+            encoding: Encoding::default(),
+        }
+    }
+
+    #[test]
+    fn test_statement_terminator_outside_quotes() {
+        assert!(is_terminated("SELECT 1;"));
+        assert!(is_terminated("INSERT INTO t VALUES ('a;b');  "));
+        assert!(!is_terminated("INSERT INTO t VALUES ('a;"));
+        assert!(!is_terminated("SELECT 'it''s'"));
+        assert!(is_terminated("SELECT 'it''s';"));
+        assert!(!is_terminated("SELECT 1; SELECT 2"));
+    }
+
+    #[test]
+    fn test_statement_terminator_outside_comments_and_escapes() {
+        assert!(is_terminated("SELECT 1; -- it's done"));
+        assert!(!is_terminated("SELECT 1 -- no end yet;"));
+        assert!(is_terminated("SELECT 1 -- it's\n;"));
+        assert!(!is_terminated("SELECT /* 'a; */ 1"));
+        assert!(is_terminated("SELECT /* 'a; /* nested */ still; */ 1;"));
+        assert!(is_terminated("SELECT 1; /* done */"));
+        assert!(!is_terminated("SELECT 1; /* not closed"));
+
+        // Backslash escapes only in E'...' strings, as the parser reads them
+        assert!(!is_terminated("SELECT E'it\\'s;"));
+        assert!(is_terminated("SELECT E'it\\'s';"));
+        assert!(is_terminated("SELECT 'C:\\';"));
+        assert!(is_terminated("SELECT name'\\';"));
+    }
+
+    #[tokio::test]
+    async fn test_newline_terminator_reads_each_line() {
+        let input: &[u8] = b"SELECT 1\n\nSELECT 'a;\nexit\n";
+        let mut lines = BufReader::new(input).lines();
+        let mut statements = Vec::new();
+        while let Some(statement) = read_statement(&mut lines, StatementTerminator::Newline).await.unwrap() {
+            statements.push(statement);
+        }
+        assert_eq!(statements, ["SELECT 1", "SELECT 'a;", "exit"]);
+
+        let mut lines = BufReader::new(input).lines();
+        let first = read_statement(&mut lines, StatementTerminator::Semicolon).await.unwrap();
+        assert_eq!(first.as_deref(), Some("SELECT 1\n\nSELECT 'a;\nexit"));
+    }
+
+    async fn read_reply(lines: &mut Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>) -> String {
+        let mut reply = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let done = line.starts_with("Query OK") || line.starts_with("Error");
+            reply.push(line);
+            if done {
+                break;
+            }
+        }
+        reply.join("\n")
+    }
+
+    #[tokio::test]
+    async fn test_statement_spanning_lines_runs_once() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, client) = listener.accept().await.unwrap();
+            handle_client(stream, client, state).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"CREATE TABLE notes (id INTEGER PRIMARY KEY, body VARCHAR(100));\n").await.unwrap();
+        assert!(read_reply(&mut lines).await.contains("Query OK"));
+        writer.write_all(b"INSERT INTO notes (id, body)\nVALUES (1, 'line one\nline two; still quoted');\n").await.unwrap();
+        let inserted = read_reply(&mut lines).await;
+        assert!(inserted.contains("1 row(s) inserted"), "{}", inserted);
+        writer.write_all(b"SELECT id FROM notes;\n").await.unwrap();
+        let selected = read_reply(&mut lines).await;
+        assert!(selected.contains("(1 rows)"), "{}", selected);
+
+        writer.write_all(b"exit\n").await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_values_with_tabs_and_newlines_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, client) = listener.accept().await.unwrap();
+            handle_client(stream, client, state).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let body = "a\tb\nc \\ d";
+        writer.write_all(b"CREATE TABLE notes (id INTEGER PRIMARY KEY, body VARCHAR(100));\n").await.unwrap();
+        assert!(read_reply(&mut lines).await.contains("Query OK"));
+        writer.write_all(format!("INSERT INTO notes (id, body) VALUES (1, '{}');\n", body).as_bytes()).await.unwrap();
+        assert!(read_reply(&mut lines).await.contains("1 row(s) inserted"));
+        writer.write_all(b"SELECT id, body FROM notes;\n").await.unwrap();
+        let selected = read_reply(&mut lines).await;
+
+        // header, separator, then the row on a single line
+        let row = selected.lines().nth(2).unwrap();
+        let fields: Vec<&str> = row.split('\t').collect();
+        assert_eq!(fields.len(), 2, "{}", selected);
+        assert_eq!(wundradb_core::sql::escape::unescape_field(fields[1]).unwrap(), body);
+        assert!(selected.contains("(1 rows)"), "{}", selected);
+
+        writer.write_all(b"exit\n").await.unwrap();
+        server.await.unwrap();
+    }
+
+    /// Sends `sql` and parses the single-line JSON response.
+    async fn request_json(
+        writer: &mut tokio::net::tcp::OwnedWriteHalf,
+        lines: &mut Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+        sql: &str,
+    ) -> serde_json::Value {
+        writer.write_all(format!("{}\n", sql).as_bytes()).await.unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_encoding_negotiated_at_connect() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, client) = listener.accept().await.unwrap();
+            handle_client(stream, client, state).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let hello = request_json(&mut writer, &mut lines, "HELLO encoding=json;").await;
+        assert_eq!(hello["message"], "Encoding set to json");
+        let sql = "CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100));";
+        request_json(&mut writer, &mut lines, sql).await;
+        let sql = "INSERT INTO users (id, name) VALUES (1, 'Ada'), (2, 'Grace');";
+        assert_eq!(request_json(&mut writer, &mut lines, sql).await["message"], "2 row(s) inserted");
+
+        let sql = "SELECT id, name FROM users ORDER BY id;";
+        let selected = request_json(&mut writer, &mut lines, sql).await;
+        assert_eq!(selected["columns"], serde_json::json!(["id", "name"]));
+        assert_eq!(selected["rows"], serde_json::json!([[1, "Ada"], [2, "Grace"]]));
+        assert_eq!(selected["row_count"], 2);
+        assert!(selected["elapsed_ms"].is_number());
+        let missing = request_json(&mut writer, &mut lines, "SELECT * FROM missing;").await;
+        assert!(missing["error"].as_str().unwrap().contains("missing"), "{}", missing);
+        // Only the first statement negotiates
+        let again = request_json(&mut writer, &mut lines, "HELLO encoding=text;").await;
+        assert_eq!(again["error"], "HELLO must be the first statement on a connection");
+
+        writer.write_all(b"exit\n").await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_records_statements_in_order() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("audit.log");
+        let config = AuditConfig { path: path.clone(), max_bytes: 1024 * 1024, redact_values: true };
+        let audit = Some(Arc::new(tokio::sync::Mutex::new(AuditLog::open(config).await.unwrap())));
+        let state = test_state(&temp_dir, audit).await;
+        let mut conn = test_connection(&state);
+
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(100))",
+            "INSERT INTO users (id, name) VALUES (1, 'Alice'), (2, 'Bob')",
+            "SELECT * FROM missing",
+        ] {
+            let _ = run_statement(&state, &mut conn, sql).await;
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<Vec<&str>> = contents.lines().map(|l| l.split('\t').skip(1).collect()).collect();
+        assert_eq!(
+            records,
+            vec![
+                vec!["127.0.0.1:5555", "ok", "0", "CREATE TABLE users (id INTEGER PRIMARY KEY, name VARCHAR(?))"],
+                vec!["127.0.0.1:5555", "ok", "2", "INSERT INTO users (id, name) VALUES (?, ?), (?, ?)"],
+                vec!["127.0.0.1:5555", "error", "0", "SELECT * FROM missing"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_use_persists_for_the_connection() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        let mut conn = test_connection(&state);
+
+        run_statement(&state, &mut conn, "CREATE DATABASE tenant").await.unwrap();
+        run_statement(&state, &mut conn, "USE tenant").await.unwrap();
+        assert_eq!(conn.session.database(), "tenant");
+
+        // A failed statement leaves the session as it was
+        assert!(run_statement(&state, &mut conn, "USE missing").await.is_err());
+        assert_eq!(conn.session.database(), "tenant");
+    }
+
+    /// Stands in for a statement working for `duration`, checking for
+    /// cancellation as the engine does between rows.
+    async fn slow_statement(session: Session, duration: Duration) -> Result<String> {
+        let end = std::time::Instant::now() + duration;
+        while std::time::Instant::now() < end {
+            session.check_cancelled()?;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        Ok(String::new())
+    }
+
+    #[tokio::test]
+    async fn test_kill_cancels_running_query() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = test_state(&temp_dir, None).await;
+        let busy = test_connection(&state);
+        let mut admin = test_connection(&state);
+        admin.session = admin.session.with_admin(true);
+        let mut other = test_connection(&state);
+
+        // A statement that holds the database lock until cancelled
+        let running = {
+            let state = state.clone();
+            let cancellation = Cancellation::new();
+            let session = busy.session.clone().with_cancellation(cancellation.clone());
+            tokio::spawn(async move {
+                run_tracked(&state, busy.id, "SELECT slow()", cancellation, |_db| {
+                    slow_statement(session, Duration::from_secs(60))
+                })
+                .await
+            })
+        };
+        while !render(&run_statement(&state, &mut admin, "SHOW PROCESSLIST").await.unwrap()).contains("running") {
+            tokio::task::yield_now().await;
+        }
+
+        let list = render(&run_statement(&state, &mut admin, "SHOW PROCESSLIST").await.unwrap());
+        let row = list.lines().find(|l| l.contains("SELECT slow()")).unwrap();
+        assert!(row.starts_with(&format!("{}\t127.0.0.1:5555\trunning\t", busy.id)));
+        assert!(list.contains(&format!("{}\t127.0.0.1:5555\tidle\t", admin.id)));
+
+        // Other sessions can neither list nor stop statements
+        let err = run_statement(&state, &mut other, "SHOW PROCESSLIST").await.unwrap_err();
+        assert_eq!(err.to_string(), "SHOW PROCESSLIST requires an admin session");
+        let err = run_statement(&state, &mut other, &format!("KILL {}", busy.id)).await.unwrap_err();
+        assert_eq!(err.to_string(), "KILL requires an admin session");
+
+        run_statement(&state, &mut admin, &format!("KILL {}", busy.id)).await.unwrap();
+        let err = running.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Query cancelled");
+
+        // The lock was released and the connection is idle again
+        let result = render(&run_statement(&state, &mut admin, "SELECT 1").await.unwrap());
+        assert!(result.contains('1'));
+        assert!(run_statement(&state, &mut admin, &format!("KILL {}", busy.id)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_statement_timeout_overrides_default() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut state = test_state(&temp_dir, None).await;
+        state.statement_timeout =
+            StatementTimeout { timeout: Some(Duration::from_millis(50)), ceiling: Some(Duration::from_secs(5)) };
+        let mut conn = test_connection(&state);
+        let slow = |conn: &ClientConnection| {
+            let cancellation = Cancellation::new().with_timeout(conn.session.statement_timeout().limit());
+            let session = conn.session.clone().with_cancellation(cancellation.clone());
+            run_tracked(&state, conn.id, "SELECT slow()", cancellation, |_db| {
+                slow_statement(session, Duration::from_millis(300))
+            })
+        };
+
+        let err = slow(&conn).await.unwrap_err();
+        assert_eq!(err.to_string(), "Statement timed out after 50 ms (statement_timeout)");
+        // The timed out statement let go of the database
+        run_statement(&state, &mut conn, "SELECT 1").await.unwrap();
+
+        run_statement(&state, &mut conn, "SET statement_timeout = 1000").await.unwrap();
+        assert!(slow(&conn).await.is_ok());
+        let err = run_statement(&state, &mut conn, "SET statement_timeout = 60000").await.unwrap_err();
+        assert_eq!(err.to_string(), "statement_timeout may be at most 5000 ms on this server");
+        // 0 lifts the session's limit, leaving only the ceiling
+        run_statement(&state, &mut conn, "SET statement_timeout = 0").await.unwrap();
+        assert_eq!(conn.session.statement_timeout().limit(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_row_count() {
+        let affected = Output::Affected { rows: 3, message: "3 row(s) inserted".to_string() };
+        assert_eq!(row_count(&[affected]), 3);
+        assert_eq!(row_count(&[Output::Rows(ResultSet::new(vec!["name".to_string()], vec![]))]), 0);
+        // A message that reads like a count isn't one
+        assert_eq!(row_count(&[Output::Message("3 row(s) inserted".to_string())]), 0);
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use wundradb_core::sql::engine::SqlValue;
+use wundradb_core::{Cancellation, Output, ResultSet};
+
+/// Statements about connections, answered by the server itself rather than
+/// the database, since they must work while another query holds the
+/// database lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerCommand {
+    ShowProcessList,
+    Kill(u64),
+}
+
+impl ServerCommand {
+    pub fn parse(sql: &str) -> Option<Self> {
+        let words: Vec<String> = sql
+            .trim()
+            .trim_end_matches(';')
+            .split_whitespace()
+            .map(str::to_ascii_uppercase)
+            .collect();
+        match words.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["SHOW", "PROCESSLIST"] => Some(Self::ShowProcessList),
+            ["KILL", id] | ["KILL", "QUERY", id] => id.parse().ok().map(Self::Kill),
+            _ => None,
+        }
+    }
+
+    /// The statement as the error for a session not allowed it names it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ShowProcessList => "SHOW PROCESSLIST",
+            Self::Kill(_) => "KILL",
+        }
+    }
+}
+
+/// Registry of open connections and the statement each one is running.
+#[derive(Debug, Default)]
+pub struct ProcessList {
+    inner: Mutex<Processes>,
+}
+
+#[derive(Debug, Default)]
+struct Processes {
+    next_id: u64,
+    by_id: BTreeMap<u64, Process>,
+}
+
+#[derive(Debug)]
+struct Process {
+    client: SocketAddr,
+    connected: Instant,
+    query: Option<RunningQuery>,
+}
+
+#[derive(Debug)]
+struct RunningQuery {
+    statement: String,
+    started: Instant,
+    cancellation: Cancellation,
+}
+
+impl ProcessList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a connection, returning the id `KILL` refers to it by.
+    pub fn register(&self, client: SocketAddr) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.by_id.insert(id, Process { client, connected: Instant::now(), query: None });
+        id
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.inner.lock().unwrap().by_id.remove(&id);
+    }
+
+    /// Records the statement connection `id` is running; `cancellation`
+    /// asks it to stop.
+    pub fn start_query(&self, id: u64, statement: &str, cancellation: Cancellation) {
+        if let Some(process) = self.inner.lock().unwrap().by_id.get_mut(&id) {
+            process.query = Some(RunningQuery {
+                statement: statement.to_string(),
+                started: Instant::now(),
+                cancellation,
+            });
+        }
+    }
+
+    pub fn finish_query(&self, id: u64) {
+        if let Some(process) = self.inner.lock().unwrap().by_id.get_mut(&id) {
+            process.query = None;
+        }
+    }
+
+    /// Cancels the statement connection `id` is running, at the next point
+    /// the engine checks. The connection itself stays open and receives an
+    /// error for that statement.
+    pub fn kill(&self, id: u64) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let process = inner.by_id.get(&id).ok_or_else(|| anyhow!("Unknown connection id {}", id))?;
+        let query = process
+            .query
+            .as_ref()
+            .ok_or_else(|| anyhow!("Connection {} is not running a query", id))?;
+        query.cancellation.cancel();
+        Ok(())
+    }
+
+    /// The registry as a result set, one row per connection. Elapsed time
+    /// is for the running statement, or since connecting when idle.
+    pub fn result_set(&self) -> ResultSet {
+        let inner = self.inner.lock().unwrap();
+        let columns = ["id", "client", "state", "elapsed_ms", "statement"].map(String::from).to_vec();
+        let rows = inner
+            .by_id
+            .iter()
+            .map(|(id, process)| {
+                let (state, elapsed, statement) = match &process.query {
+                    Some(query) => ("running", query.started.elapsed(), SqlValue::Varchar(query.statement.clone())),
+                    None => ("idle", process.connected.elapsed(), SqlValue::Null),
+                };
+                vec![
+                    SqlValue::Integer(*id as i64),
+                    SqlValue::Varchar(process.client.to_string()),
+                    SqlValue::Varchar(state.to_string()),
+                    SqlValue::Integer(elapsed.as_millis() as i64),
+                    statement,
+                ]
+            })
+            .collect();
+        ResultSet::new(columns, rows)
+    }
+
+    pub fn execute(&self, command: ServerCommand) -> Result<Output> {
+        match command {
+            ServerCommand::ShowProcessList => Ok(Output::Rows(self.result_set())),
+            ServerCommand::Kill(id) => {
+                self.kill(id)?;
+                Ok(Output::Message(format!("Query on connection {} killed", id)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_commands() {
+        assert_eq!(ServerCommand::parse("show processlist;"), Some(ServerCommand::ShowProcessList));
+        assert_eq!(ServerCommand::parse("KILL 7"), Some(ServerCommand::Kill(7)));
+        assert_eq!(ServerCommand::parse("kill query 7"), Some(ServerCommand::Kill(7)));
+        assert_eq!(ServerCommand::parse("KILL everyone"), None);
+        assert_eq!(ServerCommand::parse("SELECT 1"), None);
+    }
+}